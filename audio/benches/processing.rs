@@ -0,0 +1,97 @@
+use std::f32::consts::PI;
+
+use audio::backend::INPUT_FRAME_SIZE;
+use audio::processing::{
+    AutoGain, CaptureProcessor, EchoCanceller, NoiseSuppressor, ProcessingSettings,
+};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+/// A quiet tone with some noise on it, standing in for a 10ms frame of speech
+fn recorded_frame() -> [f32; INPUT_FRAME_SIZE] {
+    let mut frame = [0.0; INPUT_FRAME_SIZE];
+    let mut noise: u32 = 1;
+    for (i, sample) in frame.iter_mut().enumerate() {
+        noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let hiss = (noise >> 16) as f32 / u16::MAX as f32 - 0.5;
+        *sample = 0.1 * (2.0 * PI * 220.0 * i as f32 / 48000.0).sin() + 0.01 * hiss;
+    }
+    frame
+}
+
+/// What was played back, a louder tone the microphone picks up an echo of
+fn played_frame() -> [f32; INPUT_FRAME_SIZE] {
+    let mut frame = [0.0; INPUT_FRAME_SIZE];
+    for (i, sample) in frame.iter_mut().enumerate() {
+        *sample = 0.3 * (2.0 * PI * 440.0 * i as f32 / 48000.0).sin();
+    }
+    frame
+}
+
+fn stages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("capture_stage");
+    let recorded = recorded_frame();
+    let played = played_frame();
+
+    let mut noise_suppressor = NoiseSuppressor::new();
+    group.bench_function("noise_suppression", |b| {
+        b.iter(|| {
+            let mut frame = recorded;
+            noise_suppressor.process(&mut frame);
+            black_box(frame);
+        })
+    });
+
+    let mut echo_canceller = EchoCanceller::new();
+    group.bench_function("echo_cancellation", |b| {
+        b.iter(|| {
+            let mut frame = recorded;
+            echo_canceller.process(&mut frame, Some(&played));
+            black_box(frame);
+        })
+    });
+
+    let mut auto_gain = AutoGain::new();
+    group.bench_function("auto_gain", |b| {
+        b.iter(|| {
+            let mut frame = recorded;
+            auto_gain.process(&mut frame);
+            black_box(frame);
+        })
+    });
+
+    group.finish();
+}
+
+fn chain(c: &mut Criterion) {
+    let mut group = c.benchmark_group("capture_chain");
+    let recorded = recorded_frame();
+    let played = played_frame();
+
+    let settings = [
+        ("off", ProcessingSettings::default()),
+        (
+            "all",
+            ProcessingSettings {
+                noise_suppression: true,
+                echo_cancellation: true,
+                auto_gain: true,
+            },
+        ),
+    ];
+
+    for (name, settings) in settings {
+        let mut processor = CaptureProcessor::new(settings);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut frame = recorded;
+                processor.process(&mut frame, Some(&played));
+                black_box(frame);
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, stages, chain);
+criterion_main!(benches);
@@ -1,42 +1,83 @@
 use std::collections::BTreeMap;
+use std::time::Instant;
 
-use crate::audio_buffer::AudioBuffer;
-use types::UserIdSize;
+use jitter_buffer::{Frame, JitterBuffer};
+use tracing::error;
+
+use crate::backend::OUTPUT_FRAME_SIZE;
+use crate::capture::frame_level;
+use crate::levels::{AudioLevel, SpeakerMeter};
+use crate::mixer::{SoftLimiter, Volumes};
+use types::{AudioSequenceSize, UserIdSize};
+
+// Most frames held back, so a bad connection can't add more than 200ms of lag
+const MAX_DELAY_FRAMES: usize = 20;
 
 pub struct AudioBufferManager {
-    buffers: BTreeMap<UserIdSize, AudioBuffer>,
+    buffers: BTreeMap<UserIdSize, JitterBuffer>,
+    limiter: SoftLimiter,
+    meter: SpeakerMeter,
+    level_report: Option<AudioLevel>,
 }
 
 impl AudioBufferManager {
     pub fn new() -> AudioBufferManager {
         AudioBufferManager {
             buffers: BTreeMap::new(),
+            limiter: SoftLimiter::new(),
+            meter: SpeakerMeter::new(),
+            level_report: None,
         }
     }
 
-    pub fn buffer_data(&mut self, user_id: UserIdSize, data: [f32; 960]) {
+    /// Queue an encoded frame from a speaker
+    pub fn buffer_data(&mut self, user_id: UserIdSize, sequence: AudioSequenceSize, data: Vec<u8>) {
         if let Some(buffer) = self.buffers.get_mut(&user_id) {
-            buffer.push_back(data);
+            buffer.push(sequence, data, Instant::now());
         } else {
-            let mut buffer = AudioBuffer::new();
-            buffer.push_back(data);
-
-            self.buffers.insert(user_id, buffer);
+            match JitterBuffer::new(MAX_DELAY_FRAMES) {
+                Ok(mut buffer) => {
+                    buffer.push(sequence, data, Instant::now());
+                    self.buffers.insert(user_id, buffer);
+                }
+                Err(e) => error!("failed to create a decoder for user {}: {}", user_id, e),
+            }
         }
     }
 
-    pub fn get_output_data(&mut self) -> [f32; 960] {
-        let mut output_buffer: [f32; 960] = [0.0; 960];
+    /// Mix the next frame from everyone speaking, each at their own volume
+    pub fn get_output_data(&mut self, volumes: &Volumes) -> Frame {
+        let mut output_buffer: Frame = [0.0; OUTPUT_FRAME_SIZE];
+        let mut user_audio: Frame = [0.0; OUTPUT_FRAME_SIZE];
+
+        for (user_id, buffer) in self.buffers.iter_mut() {
+            if buffer.pop(&mut user_audio) {
+                // Measured before volumes so turning someone down doesn't hide them speaking
+                self.meter.add(*user_id, frame_level(&user_audio));
 
-        for buffer in self.buffers.values_mut() {
-            if buffer.is_buffered() {
-                let user_audio = buffer.pop_front();
-                for i in 0..960 {
-                    output_buffer[i] += user_audio[i];
+                let gain = volumes.get_user_volume(*user_id) * volumes.output_gain;
+                for i in 0..OUTPUT_FRAME_SIZE {
+                    output_buffer[i] += user_audio[i] * gain;
                 }
             }
         }
 
+        if let Some(report) = self.meter.end_frame() {
+            self.level_report = Some(report);
+        }
+
+        // Several loud speakers at once would otherwise clip
+        self.limiter.process(&mut output_buffer);
+
+        // Forget decoders of people who left or stopped talking a while ago
+        let now = Instant::now();
+        self.buffers.retain(|_, buffer| !buffer.is_idle(now));
+
         output_buffer
     }
+
+    /// Levels of everyone played back, once enough frames have been for a report
+    pub fn take_level_report(&mut self) -> Option<AudioLevel> {
+        self.level_report.take()
+    }
 }
@@ -1,8 +1,13 @@
 use cpal::{
     traits::{DeviceTrait, HostTrait},
-    Device, Host,
+    BufferSize, Device, Host, SampleFormat, SampleRate, StreamConfig, SupportedBufferSize,
+    SupportedStreamConfigRange,
 };
 
+use tracing::warn;
+
+use crate::backend::{InputBackend, OutputBackend, FRAME_DURATION, SAMPLE_RATE};
+
 #[derive(Debug)]
 pub enum AudioIoError {
     FailedToGetDefaultInput,
@@ -11,13 +16,14 @@ pub enum AudioIoError {
     FailedToGetOutputDevices,
     FailedToGetInputDevice,
     FailedToGetOutputDevice,
-    FailedToFindInputDevice,
-    FailedToFindOutputDevice,
+    NoSupportedConfig,
 }
 
 pub struct AudioIo {
     input_device: Option<String>,
     output_device: Option<String>,
+    input_backend: InputBackend,
+    output_backend: OutputBackend,
     host: Host,
 }
 
@@ -34,6 +40,8 @@ impl AudioIo {
         AudioIo {
             input_device: None,
             output_device: None,
+            input_backend: InputBackend::Device,
+            output_backend: OutputBackend::Device,
             host,
         }
     }
@@ -56,46 +64,88 @@ impl AudioIo {
         }
     }
 
+    /// The selected input, or the default one if nothing is selected or the selection is unplugged
     pub fn get_input_device(&self) -> Result<Device, AudioIoError> {
-        match &self.input_device {
-            Some(device_name) => match self.host.input_devices() {
+        if let Some(device_name) = &self.input_device {
+            match self.host.input_devices() {
                 Ok(mut devices) => {
-                    match devices.find(|d| {
-                        d.name().unwrap_or(String::from("Unknown")) == device_name.clone()
-                    }) {
-                        Some(device) => Ok(device),
-                        None => Err(AudioIoError::FailedToFindInputDevice),
+                    match devices
+                        .find(|d| d.name().unwrap_or(String::from("Unknown")) == *device_name)
+                    {
+                        Some(device) => return Ok(device),
+                        None => warn!(
+                            device = device_name,
+                            "input device not found, using the default"
+                        ),
                     }
                 }
-                Err(_) => Err(AudioIoError::FailedToGetInputDevice),
-            },
-            None => match self.host.default_input_device() {
-                Some(device) => Ok(device),
-                None => Err(AudioIoError::FailedToGetDefaultInput),
-            },
+                Err(_) => return Err(AudioIoError::FailedToGetInputDevice),
+            }
+        }
+
+        match self.host.default_input_device() {
+            Some(device) => Ok(device),
+            None => Err(AudioIoError::FailedToGetDefaultInput),
         }
     }
 
+    /// The selected output, or the default one if nothing is selected or the selection is unplugged
     pub fn get_output_device(&self) -> Result<Device, AudioIoError> {
-        match &self.output_device {
-            Some(device_name) => match self.host.output_devices() {
+        if let Some(device_name) = &self.output_device {
+            match self.host.output_devices() {
                 Ok(mut devices) => {
-                    match devices.find(|d| {
-                        d.name().unwrap_or(String::from("Unknown")) == device_name.clone()
-                    }) {
-                        Some(device) => Ok(device),
-                        None => Err(AudioIoError::FailedToFindOutputDevice),
+                    match devices
+                        .find(|d| d.name().unwrap_or(String::from("Unknown")) == *device_name)
+                    {
+                        Some(device) => return Ok(device),
+                        None => warn!(
+                            device = device_name,
+                            "output device not found, using the default"
+                        ),
                     }
                 }
-                Err(_) => Err(AudioIoError::FailedToGetOutputDevice),
-            },
-            None => match self.host.default_output_device() {
-                Some(device) => Ok(device),
-                None => Err(AudioIoError::FailedToGetDefaultOutput),
-            },
+                Err(_) => return Err(AudioIoError::FailedToGetOutputDevice),
+            }
+        }
+
+        match self.host.default_output_device() {
+            Some(device) => Ok(device),
+            None => Err(AudioIoError::FailedToGetDefaultOutput),
+        }
+    }
+
+    /// How to open `device` for recording, preferring mono
+    pub fn get_input_config(
+        &self,
+        device: &Device,
+    ) -> Result<(StreamConfig, SampleFormat), AudioIoError> {
+        match device.supported_input_configs() {
+            Ok(configs) => choose_config(configs, 1).ok_or(AudioIoError::NoSupportedConfig),
+            Err(_) => Err(AudioIoError::NoSupportedConfig),
         }
     }
 
+    /// How to open `device` for playback, preferring stereo
+    pub fn get_output_config(
+        &self,
+        device: &Device,
+    ) -> Result<(StreamConfig, SampleFormat), AudioIoError> {
+        match device.supported_output_configs() {
+            Ok(configs) => choose_config(configs, 2).ok_or(AudioIoError::NoSupportedConfig),
+            Err(_) => Err(AudioIoError::NoSupportedConfig),
+        }
+    }
+
+    /// Name of the input picked by the user, if any
+    pub fn get_selected_input_device(&self) -> Option<&String> {
+        self.input_device.as_ref()
+    }
+
+    /// Name of the output picked by the user, if any
+    pub fn get_selected_output_device(&self) -> Option<&String> {
+        self.output_device.as_ref()
+    }
+
     pub fn set_input_device(&mut self, device_name: String) {
         self.input_device = Some(device_name);
     }
@@ -103,4 +153,70 @@ impl AudioIo {
     pub fn set_output_device(&mut self, device_name: String) {
         self.output_device = Some(device_name);
     }
+
+    pub fn get_input_backend(&self) -> &InputBackend {
+        &self.input_backend
+    }
+
+    pub fn get_output_backend(&self) -> &OutputBackend {
+        &self.output_backend
+    }
+
+    /// Record from somewhere other than a microphone, such as a file or a tone
+    pub fn set_input_backend(&mut self, backend: InputBackend) {
+        self.input_backend = backend;
+    }
+
+    /// Play to somewhere other than speakers, such as a WAV file or nowhere
+    pub fn set_output_backend(&mut self, backend: OutputBackend) {
+        self.output_backend = backend;
+    }
+}
+
+/// Pick the config that needs the least converting, in order of what matters most:
+/// running at 48kHz or as close as it gets, float samples, then having `channels` channels
+fn choose_config(
+    configs: impl Iterator<Item = SupportedStreamConfigRange>,
+    channels: u16,
+) -> Option<(StreamConfig, SampleFormat)> {
+    let (range, sample_rate) = configs
+        .filter(|range| sample_format_rank(range.sample_format()).is_some())
+        .map(|range| {
+            let sample_rate =
+                SAMPLE_RATE.clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+            (range, sample_rate)
+        })
+        .min_by_key(|(range, sample_rate)| {
+            (
+                sample_rate.abs_diff(SAMPLE_RATE),
+                sample_format_rank(range.sample_format()),
+                range.channels().abs_diff(channels),
+            )
+        })?;
+
+    let sample_format = range.sample_format();
+    let supported = range.with_sample_rate(SampleRate(sample_rate));
+
+    // Ask for 10ms callbacks when the device allows it, whatever arrives is put into frames anyway
+    let frame_length = sample_rate * FRAME_DURATION.as_millis() as u32 / 1000;
+    let buffer_size = match supported.buffer_size() {
+        SupportedBufferSize::Range { min, max } if (*min..=*max).contains(&frame_length) => {
+            BufferSize::Fixed(frame_length)
+        }
+        _ => BufferSize::Default,
+    };
+
+    let mut config = supported.config();
+    config.buffer_size = buffer_size;
+    Some((config, sample_format))
+}
+
+// Lower is better, `None` for formats samples aren't converted from
+fn sample_format_rank(sample_format: SampleFormat) -> Option<u8> {
+    match sample_format {
+        SampleFormat::F32 => Some(0),
+        SampleFormat::I16 => Some(1),
+        SampleFormat::U16 => Some(2),
+        _ => None,
+    }
 }
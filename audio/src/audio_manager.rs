@@ -1,15 +1,28 @@
 use std::fs::File;
 use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Stream, StreamConfig};
-use crossbeam::channel::{Receiver, Sender};
-use opus::{Decoder as OpusDecoder, Encoder};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use crossbeam::channel::{unbounded, Receiver, Sender};
 use rodio::{Decoder, OutputStream, Sink};
+use tracing::{error, info, warn};
 
 use crate::audio_buffer_manager::AudioBufferManager;
 use crate::audio_io::AudioIo;
+use crate::backend::{
+    self, AudioStream, FrameClock, InputBackend, OutputBackend, INPUT_FRAME_SIZE, OUTPUT_FRAME_SIZE,
+};
+use crate::call_recording::{CallRecorder, RecordingTap};
+use crate::capture::{self, CaptureSettings, PushToTalk, VoiceGate};
+use crate::encoding::{BitrateController, EncoderSettings, EncoderTarget, VoiceEncoder};
+use crate::levels::{AudioLevel, InputMeter, LevelReports};
+use crate::mixer::{self, Volumes};
+use crate::processing::{CaptureProcessor, EchoReference, ProcessingSettings};
 use message::message::{Message, MessageHeader, MessageType};
+use types::UserIdSize;
 
 #[derive(Debug)]
 pub enum AudioManagerError {
@@ -20,9 +33,23 @@ pub enum AudioManagerError {
     FailedToSetOutputDevice,
     FailedToCreateInputStream,
     FailedToCreateOutputStream,
+    FailedToStartInputStream,
+    FailedToStartOutputStream,
     FailedToCreateEncoder,
     FailedToCreateDecoder,
     DeviceNotFound,
+    FailedToReadInputFile,
+    FailedToCreateOutputFile,
+}
+
+// How often to try reopening a stream whose device went away
+const DEVICE_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Which stream a device error happened on
+#[derive(Debug, Clone, Copy)]
+enum StreamDirection {
+    Input,
+    Output,
 }
 
 impl std::fmt::Debug for AudioManager {
@@ -35,9 +62,37 @@ pub struct AudioManager {
     audio_out_sender: Sender<Message>,
     audio_in_receiver: Receiver<Message>,
     current_header: MessageHeader,
-    input_stream: Option<Stream>,
-    output_stream: Option<Stream>,
+    input_stream: Option<AudioStream>,
+    output_stream: Option<AudioStream>,
     audio_io: AudioIo,
+    // Whether we should be recording and listening, even while a device is missing
+    is_recording: bool,
+    is_listening: bool,
+    // Whether the microphone is open only to measure it, like for a level meter
+    is_monitoring: bool,
+    device_lost_sender: Sender<StreamDirection>,
+    device_lost_receiver: Receiver<StreamDirection>,
+    // Unset when a stream should be reopened straight away
+    last_device_retry: Option<Instant>,
+    // Sequence number of the next frame recorded, kept across stream restarts
+    audio_sequence: Arc<AtomicU16>,
+    capture_settings: CaptureSettings,
+    processing_settings: ProcessingSettings,
+    // What was played back, for the echo canceller to take out of what's recorded
+    echo_reference: EchoReference,
+    push_to_talk: PushToTalk,
+    // Shared with the streams so they take effect without restarting them
+    muted: Arc<AtomicBool>,
+    server_muted: Arc<AtomicBool>,
+    deafened: Arc<AtomicBool>,
+    volumes: Arc<Mutex<Volumes>>,
+    // Encoder settings and the bitrate the connection allows for now
+    bitrate: Arc<Mutex<BitrateController>>,
+    // How loud we and everyone we hear are, for showing who's speaking
+    levels: LevelReports,
+    // Copies of every frame in the call while it's being recorded
+    recording_tap: RecordingTap,
+    call_recorder: Option<CallRecorder>,
 }
 
 impl AudioManager {
@@ -46,6 +101,8 @@ impl AudioManager {
         audio_in_receiver: Receiver<Message>,
         current_header: MessageHeader,
     ) -> AudioManager {
+        let (device_lost_sender, device_lost_receiver) = unbounded();
+
         AudioManager {
             audio_out_sender,
             audio_in_receiver,
@@ -53,6 +110,27 @@ impl AudioManager {
             input_stream: None,
             output_stream: None,
             audio_io: AudioIo::new(),
+            is_recording: false,
+            is_listening: false,
+            is_monitoring: false,
+            device_lost_sender,
+            device_lost_receiver,
+            last_device_retry: None,
+            audio_sequence: Arc::new(AtomicU16::new(0)),
+            capture_settings: CaptureSettings::default(),
+            processing_settings: ProcessingSettings::default(),
+            echo_reference: EchoReference::new(),
+            push_to_talk: PushToTalk::new(),
+            muted: Arc::new(AtomicBool::new(false)),
+            server_muted: Arc::new(AtomicBool::new(false)),
+            deafened: Arc::new(AtomicBool::new(false)),
+            volumes: Arc::new(Mutex::new(Volumes::default())),
+            bitrate: Arc::new(Mutex::new(BitrateController::new(
+                EncoderSettings::default(),
+            ))),
+            levels: LevelReports::new(),
+            recording_tap: RecordingTap::new(),
+            call_recorder: None,
         }
     }
 
@@ -61,120 +139,372 @@ impl AudioManager {
     }
 
     pub fn start_recording(&mut self) -> Result<(), AudioManagerError> {
-        let host = cpal::default_host();
+        self.is_recording = true;
+        self.open_input_stream()
+    }
 
-        let input_device = match host.default_input_device() {
-            Some(device) => device,
-            None => return Err(AudioManagerError::FailedToGetInputDevices),
-        };
+    fn open_input_stream(&mut self) -> Result<(), AudioManagerError> {
+        // Let go of the old device before opening the new one
+        self.input_stream = None;
 
-        let config = StreamConfig {
-            sample_rate: cpal::SampleRate(48000),
-            channels: 1,
-            buffer_size: cpal::BufferSize::Fixed(480),
+        let encoder_settings = self.bitrate.lock().unwrap().get_settings();
+        let mut encoder = match VoiceEncoder::new(encoder_settings) {
+            Ok(encoder) => encoder,
+            Err(_) => return Err(AudioManagerError::FailedToCreateEncoder),
         };
-
-        let mut encoder =
-            match Encoder::new(48000, opus::Channels::Stereo, opus::Application::Audio) {
-                Ok(encoder) => encoder,
-                Err(_) => return Err(AudioManagerError::FailedToCreateEncoder),
-            };
+        if encoder.set_dtx(self.capture_settings.dtx).is_err() {
+            return Err(AudioManagerError::FailedToCreateEncoder);
+        }
+        let bitrate = self.bitrate.clone();
 
         let audio_sender = self.audio_out_sender.clone();
         let mut header = self.current_header;
+        let sequence = self.audio_sequence.clone();
+        let mut gate = VoiceGate::new(self.capture_settings, self.push_to_talk.clone());
+        let muted = self.muted.clone();
+        let server_muted = self.server_muted.clone();
+        let volumes = self.volumes.clone();
+        let mut processor = CaptureProcessor::new(self.processing_settings);
+        let echo_cancellation = self.processing_settings.echo_cancellation;
+        let echo_reference = self.echo_reference.clone();
+        echo_reference.clear();
+        // Monitoring only measures the microphone, nothing is sent
+        let recording = self.is_recording;
+        let levels = self.levels.clone();
+        let mut meter = InputMeter::new();
+        let recording_tap = self.recording_tap.clone();
+
+        let mut on_frame = move |data: &[f32]| {
+            // Keep counting through silence so listeners can tell time passed
+            let frame_sequence = sequence.fetch_add(1, Ordering::Relaxed);
+
+            let input_gain = volumes.lock().unwrap().input_gain;
+            let mut data_buffer = [0.0; INPUT_FRAME_SIZE];
+            for i in 0..INPUT_FRAME_SIZE {
+                data_buffer[i] = mixer::soft_clip(data[i] * input_gain);
+            }
 
-        let err_fn = move |err| {
-            eprintln!("an error occurred on stream: {}", err);
-        };
+            // Clean up before deciding whether it's speech, so noise doesn't open the gate
+            let playback = match echo_cancellation {
+                true => echo_reference.pop(),
+                false => None,
+            };
+            processor.process(&mut data_buffer, playback.as_ref().map(|frame| &frame[..]));
+
+            let data = &data_buffer;
+
+            let sending = gate.should_send(data)
+                && recording
+                && !muted.load(Ordering::Relaxed)
+                && !server_muted.load(Ordering::Relaxed);
+
+            if let Some(report) = meter.add(capture::frame_level(data), sending) {
+                levels.send(report);
+            }
+
+            if !sending {
+                return;
+            }
 
-        let data_callback = move |data: &[f32], _: &_| {
-            // Make mono recording into stereo
-            let mut stereo_audio = [0.0; 960];
-            for i in 0..480 {
-                stereo_audio[i * 2] = data[i];
-                stereo_audio[i * 2 + 1] = data[i];
+            // Follow the connection, picking up changes since the last frame
+            let target = bitrate.lock().unwrap().target();
+            if let Err(e) = encoder.set_target(target) {
+                warn!("failed to change the encoder bitrate: {:?}", e);
             }
 
-            if let Ok(bytes) = encoder.encode_vec_float(&stereo_audio, 480 * 8) {
+            if let Ok(bytes) = encoder.encode_mono(data) {
+                if VoiceEncoder::is_silence(&bytes) {
+                    return;
+                }
+
                 header.datetime = Some(chrono::Utc::now());
+                recording_tap.send(header.user_id, frame_sequence, &bytes);
 
-                let message = Message::from(MessageType::Audio((header, bytes)));
+                let message = Message::from(MessageType::Audio((header, frame_sequence, bytes)));
 
                 let _ = audio_sender.send(message);
             }
         };
 
-        if let Ok(stream) = input_device.build_input_stream(&config, data_callback, err_fn, None) {
-            stream.play().unwrap();
-            self.input_stream = Some(stream);
-            Ok(())
-        } else {
-            Err(AudioManagerError::FailedToCreateInputStream)
+        let backend = self.audio_io.get_input_backend().clone();
+        if backend != InputBackend::Device {
+            let mut source = backend::open_source(&backend)?;
+            let clock = FrameClock::start(move || {
+                let mut frame = [0.0; INPUT_FRAME_SIZE];
+                source.read(&mut frame);
+                on_frame(&frame);
+            });
+
+            self.input_stream = Some(AudioStream::Clock(clock));
+            info!(
+                backend = backend.to_string(),
+                realm_id = self.current_header.realm_id,
+                channel_id = self.current_header.channel_id,
+                "started recording"
+            );
+            return Ok(());
         }
+
+        let input_device = match self.audio_io.get_input_device() {
+            Ok(device) => device,
+            Err(_) => return Err(AudioManagerError::FailedToGetInputDevices),
+        };
+
+        // Whatever the device runs at is turned into 10ms of mono 48kHz audio
+        let (config, sample_format) = match self.audio_io.get_input_config(&input_device) {
+            Ok(config) => config,
+            Err(_) => return Err(AudioManagerError::FailedToGetSupportedConfigs),
+        };
+
+        let device_lost_sender = self.device_lost_sender.clone();
+        let err_fn = move |err| {
+            error!("an error occurred on the input stream: {}", err);
+            if let cpal::StreamError::DeviceNotAvailable = err {
+                let _ = device_lost_sender.send(StreamDirection::Input);
+            }
+        };
+
+        let stream =
+            backend::build_input_stream(&input_device, &config, sample_format, on_frame, err_fn)?;
+        if let Err(e) = stream.play() {
+            error!("failed to start the input stream: {}", e);
+            return Err(AudioManagerError::FailedToStartInputStream);
+        }
+        self.input_stream = Some(AudioStream::Device(stream));
+        info!(
+            device = input_device.name().unwrap_or_default(),
+            sample_rate = config.sample_rate.0,
+            channels = config.channels,
+            ?sample_format,
+            realm_id = self.current_header.realm_id,
+            channel_id = self.current_header.channel_id,
+            "started recording"
+        );
+        Ok(())
     }
 
     pub fn stop_recording(&mut self) {
+        self.is_recording = false;
         self.input_stream = None;
+
+        // Keep measuring the microphone without sending it
+        if self.is_monitoring {
+            if let Err(e) = self.open_input_stream() {
+                error!("failed to keep monitoring audio input: {:?}", e);
+            }
+        }
     }
 
-    pub fn start_listening(&mut self) -> Result<(), AudioManagerError> {
-        let host = cpal::default_host();
+    /// Open the microphone just to measure how loud it is, even outside a call
+    pub fn start_monitoring_input(&mut self) -> Result<(), AudioManagerError> {
+        self.is_monitoring = true;
+        if self.input_stream.is_some() {
+            return Ok(());
+        }
+        self.open_input_stream()
+    }
 
-        let ouput_device = match host.default_output_device() {
-            Some(device) => device,
-            None => return Err(AudioManagerError::FailedToGetOutputDevices),
-        };
+    /// Close the microphone again unless we're recording
+    pub fn stop_monitoring_input(&mut self) {
+        self.is_monitoring = false;
+        if !self.is_recording {
+            self.input_stream = None;
+        }
+    }
 
-        let config = StreamConfig {
-            sample_rate: cpal::SampleRate(48000),
-            channels: 2,
-            buffer_size: cpal::BufferSize::Fixed(480),
-        };
+    pub fn is_monitoring_input(&self) -> bool {
+        self.is_monitoring
+    }
 
-        let mut decoder = match OpusDecoder::new(48000, opus::Channels::Stereo) {
-            Ok(decoder) => decoder,
-            Err(_) => return Err(AudioManagerError::FailedToCreateDecoder),
-        };
+    /// Save everything said in the call from now on to an Ogg Opus file at `path`,
+    /// including us. `comments` are `TAG=value` pairs stored in the file.
+    pub fn start_call_recording(
+        &mut self,
+        path: &Path,
+        comments: &[String],
+    ) -> Result<(), AudioManagerError> {
+        // Finish any recording already going before starting another
+        self.call_recorder = None;
+        self.call_recorder = Some(CallRecorder::start(
+            path,
+            self.recording_tap.clone(),
+            comments,
+        )?);
+        Ok(())
+    }
+
+    /// Stop and finish the call recording, returning where it was saved
+    pub fn stop_call_recording(&mut self) -> Option<PathBuf> {
+        self.call_recorder
+            .take()
+            .map(|recorder| recorder.get_path().to_path_buf())
+    }
+
+    pub fn is_call_recording(&self) -> bool {
+        self.call_recorder.is_some()
+    }
+
+    /// Where audio from others is copied for a call recording. It's fed as audio arrives
+    /// rather than as it's played, so recordings keep going while deafened or without an output.
+    pub fn get_recording_tap(&self) -> RecordingTap {
+        self.recording_tap.clone()
+    }
+
+    /// Levels measured on the audio threads since this was last called, oldest first
+    pub fn get_audio_levels(&self) -> Vec<AudioLevel> {
+        self.levels.take()
+    }
+
+    pub fn start_listening(&mut self) -> Result<(), AudioManagerError> {
+        self.is_listening = true;
+        self.open_output_stream()
+    }
+
+    fn open_output_stream(&mut self) -> Result<(), AudioManagerError> {
+        self.output_stream = None;
 
         let audio_receiver = self.audio_in_receiver.clone();
         let _header = self.current_header;
 
-        let err_fn = move |err| {
-            eprintln!("an error occurred on stream: {}", err);
-        };
-
         let mut buffer_manager = AudioBufferManager::new();
+        let deafened = self.deafened.clone();
+        let volumes = self.volumes.clone();
+        let echo_reference = self.echo_reference.clone();
+        let levels = self.levels.clone();
 
-        let data_callback = move |data: &mut [f32], _: &_| {
+        let mut on_frame = move |data: &mut [f32]| {
             // There's data to play back, so mix and play it back
             while let Ok(message) = audio_receiver.try_recv() {
-                if let MessageType::Audio((header, audio)) = message.message {
-                    // Volume manipulation may be able to be done here later on
-                    let mut user_audio: [f32; 960] = [0.0; 960];
-                    let _decoded_samples = decoder
-                        .decode_float(audio.as_slice(), &mut user_audio, false)
-                        .unwrap();
-
-                    buffer_manager.buffer_data(header.user_id, user_audio);
+                if deafened.load(Ordering::Relaxed) {
+                    continue;
+                }
+
+                if let MessageType::Audio((header, sequence, audio)) = message.message {
+                    buffer_manager.buffer_data(header.user_id, sequence, audio);
                 }
             }
 
-            data[..960].copy_from_slice(&buffer_manager.get_output_data()[..960]);
+            let volumes = volumes.lock().unwrap();
+            data[..960].copy_from_slice(&buffer_manager.get_output_data(&volumes)[..960]);
+            echo_reference.push(&data[..960]);
+
+            if let Some(report) = buffer_manager.take_level_report() {
+                levels.send(report);
+            }
         };
 
-        if let Ok(stream) = ouput_device.build_output_stream(&config, data_callback, err_fn, None) {
-            stream.play().unwrap();
-            self.output_stream = Some(stream);
-            Ok(())
-        } else {
-            Err(AudioManagerError::FailedToCreateOutputStream)
+        let backend = self.audio_io.get_output_backend().clone();
+        if backend != OutputBackend::Device {
+            let mut sink = backend::open_sink(&backend)?;
+            let clock = FrameClock::start(move || {
+                let mut frame = [0.0; OUTPUT_FRAME_SIZE];
+                on_frame(&mut frame);
+                sink.write(&frame);
+            });
+
+            self.output_stream = Some(AudioStream::Clock(clock));
+            info!(backend = backend.to_string(), "started listening");
+            return Ok(());
         }
+
+        let ouput_device = match self.audio_io.get_output_device() {
+            Ok(device) => device,
+            Err(_) => return Err(AudioManagerError::FailedToGetOutputDevices),
+        };
+
+        // 10ms of stereo 48kHz audio is turned into whatever the device runs at
+        let (config, sample_format) = match self.audio_io.get_output_config(&ouput_device) {
+            Ok(config) => config,
+            Err(_) => return Err(AudioManagerError::FailedToGetSupportedConfigs),
+        };
+
+        let device_lost_sender = self.device_lost_sender.clone();
+        let err_fn = move |err| {
+            error!("an error occurred on the output stream: {}", err);
+            if let cpal::StreamError::DeviceNotAvailable = err {
+                let _ = device_lost_sender.send(StreamDirection::Output);
+            }
+        };
+
+        let stream =
+            backend::build_output_stream(&ouput_device, &config, sample_format, on_frame, err_fn)?;
+        if let Err(e) = stream.play() {
+            error!("failed to start the output stream: {}", e);
+            return Err(AudioManagerError::FailedToStartOutputStream);
+        }
+        self.output_stream = Some(AudioStream::Device(stream));
+        info!(
+            device = ouput_device.name().unwrap_or_default(),
+            sample_rate = config.sample_rate.0,
+            channels = config.channels,
+            ?sample_format,
+            "started listening"
+        );
+        Ok(())
     }
 
     pub fn stop_listening(&mut self) {
+        self.is_listening = false;
         self.output_stream = None;
     }
 
+    /// Reopen streams whose device was unplugged, falling back to the default device.
+    /// Call this regularly while in a call.
+    pub fn check_devices(&mut self) {
+        while let Ok(direction) = self.device_lost_receiver.try_recv() {
+            warn!(?direction, "audio device went away, reopening");
+            match direction {
+                StreamDirection::Input => self.input_stream = None,
+                StreamDirection::Output => self.output_stream = None,
+            }
+            // Try straight away in case another device is ready
+            self.last_device_retry = None;
+        }
+
+        let input_missing =
+            (self.is_recording || self.is_monitoring) && self.input_stream.is_none();
+        let output_missing = self.is_listening && self.output_stream.is_none();
+        let retry_due = match self.last_device_retry {
+            Some(retried) => retried.elapsed() >= DEVICE_RETRY_INTERVAL,
+            None => true,
+        };
+        if !(input_missing || output_missing) || !retry_due {
+            return;
+        }
+        self.last_device_retry = Some(Instant::now());
+
+        if input_missing {
+            match self.open_input_stream() {
+                Ok(_) => info!("recovered the input stream"),
+                Err(e) => warn!("still no input stream: {:?}", e),
+            }
+        }
+
+        if output_missing {
+            match self.open_output_stream() {
+                Ok(_) => info!("recovered the output stream"),
+                Err(e) => warn!("still no output stream: {:?}", e),
+            }
+        }
+    }
+
+    // Rebuild running streams so a new device or backend takes effect mid-call
+    fn restart_input(&mut self) {
+        if self.is_recording || self.is_monitoring {
+            if let Err(e) = self.open_input_stream() {
+                error!("failed to switch audio input: {:?}", e);
+            }
+        }
+    }
+
+    fn restart_output(&mut self) {
+        if self.is_listening {
+            if let Err(e) = self.open_output_stream() {
+                error!("failed to switch audio output: {:?}", e);
+            }
+        }
+    }
+
     pub fn get_audio_inputs(&self) -> Vec<String> {
         self.audio_io.get_input_devices().unwrap_or_default()
     }
@@ -183,17 +513,165 @@ impl AudioManager {
         self.audio_io.get_output_devices().unwrap_or_default()
     }
 
+    pub fn get_selected_audio_input(&self) -> Option<String> {
+        self.audio_io.get_selected_input_device().cloned()
+    }
+
+    pub fn get_selected_audio_output(&self) -> Option<String> {
+        self.audio_io.get_selected_output_device().cloned()
+    }
+
     pub fn set_audio_input(&mut self, input_name: String) {
         self.audio_io.set_input_device(input_name);
+        self.restart_input();
     }
 
     pub fn set_audio_output(&mut self, output_name: String) {
         self.audio_io.set_output_device(output_name);
+        self.restart_output();
+    }
+
+    pub fn set_input_backend(&mut self, backend: InputBackend) {
+        self.audio_io.set_input_backend(backend);
+        self.restart_input();
+    }
+
+    pub fn set_output_backend(&mut self, backend: OutputBackend) {
+        self.audio_io.set_output_backend(backend);
+        self.restart_output();
+    }
+
+    pub fn get_capture_settings(&self) -> CaptureSettings {
+        self.capture_settings
+    }
+
+    pub fn set_capture_settings(&mut self, settings: CaptureSettings) {
+        self.capture_settings = settings;
+        self.restart_input();
+    }
+
+    pub fn get_processing_settings(&self) -> ProcessingSettings {
+        self.processing_settings
+    }
+
+    pub fn set_processing_settings(&mut self, settings: ProcessingSettings) {
+        self.processing_settings = settings;
+        self.restart_input();
+    }
+
+    pub fn get_encoder_settings(&self) -> EncoderSettings {
+        self.bitrate.lock().unwrap().get_settings()
+    }
+
+    pub fn set_encoder_settings(&mut self, settings: EncoderSettings) {
+        self.bitrate
+            .lock()
+            .unwrap()
+            .set_settings(settings.clamped());
+        self.restart_input();
+    }
+
+    /// Bitrate and FEC being encoded with right now
+    pub fn get_encoder_target(&self) -> EncoderTarget {
+        self.bitrate.lock().unwrap().target()
+    }
+
+    /// Keep under the bitrate limit of the voice channel we're in, 0 for none
+    pub fn set_channel_bitrate_limit(&self, limit: u32) {
+        self.bitrate.lock().unwrap().set_channel_limit(limit);
+    }
+
+    /// Round trip time to the server, a long one means sending less
+    pub fn report_round_trip(&self, round_trip: Duration) {
+        self.bitrate.lock().unwrap().report_round_trip(round_trip);
+    }
+
+    /// How many of the frames we sent lately the server expected and received
+    pub fn report_audio_loss(&self, expected: u32, received: u32) {
+        self.bitrate.lock().unwrap().report_loss(expected, received);
+    }
+
+    /// Send audio for `duration` while in push-to-talk mode
+    pub fn hold_push_to_talk(&self, duration: Duration) {
+        self.push_to_talk.hold(duration);
+    }
+
+    /// Stop sending in push-to-talk mode without waiting for the hold to run out
+    pub fn release_push_to_talk(&self) {
+        self.push_to_talk.release();
+    }
+
+    pub fn get_volumes(&self) -> Volumes {
+        self.volumes.lock().unwrap().clone()
+    }
+
+    /// Replace every volume at once, like when loading saved settings
+    pub fn set_volumes(&self, volumes: Volumes) {
+        *self.volumes.lock().unwrap() = volumes;
+    }
+
+    /// How loud we're heard, where 1.0 leaves the microphone as it is
+    pub fn set_input_gain(&self, gain: f32) {
+        self.volumes.lock().unwrap().input_gain = mixer::clamp_volume(gain);
+    }
+
+    /// How loud everyone is played back, where 1.0 leaves them as they are
+    pub fn set_output_gain(&self, gain: f32) {
+        self.volumes.lock().unwrap().output_gain = mixer::clamp_volume(gain);
+    }
+
+    /// How loud one speaker is played back, on top of the output gain
+    pub fn set_user_volume(&self, user_id: UserIdSize, volume: f32) {
+        self.volumes
+            .lock()
+            .unwrap()
+            .set_user_volume(user_id, volume);
+    }
+
+    /// Stop or resume sending what the microphone picks up
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    /// Set by the server when a moderator mutes us
+    pub fn set_server_muted(&self, server_muted: bool) {
+        self.server_muted.store(server_muted, Ordering::Relaxed);
+    }
+
+    pub fn is_server_muted(&self) -> bool {
+        self.server_muted.load(Ordering::Relaxed)
+    }
+
+    /// Stop or resume playing what others say
+    pub fn set_deafened(&self, deafened: bool) {
+        self.deafened.store(deafened, Ordering::Relaxed);
+    }
+
+    pub fn is_deafened(&self) -> bool {
+        self.deafened.load(Ordering::Relaxed)
     }
 
     pub fn play_audio_file(&self, file_path: String) {
+        // Sounds only go to speakers, not into recordings of the call
+        if *self.audio_io.get_output_backend() != OutputBackend::Device {
+            return;
+        }
+
         // Get a device to play audio back with
-        let device = self.audio_io.get_output_device().unwrap();
+        let device = match self.audio_io.get_output_device() {
+            Ok(device) => device,
+            Err(e) => {
+                error!(
+                    "failed to get an output device to play {}: {:?}",
+                    file_path, e
+                );
+                return;
+            }
+        };
 
         // Likely inefficient, but spawn a thread to play this sound
         let _audio_handle = std::thread::spawn(move || {
@@ -0,0 +1,484 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use cpal::traits::DeviceTrait;
+use cpal::{
+    BuildStreamError, Device, FromSample, SampleFormat as CpalSampleFormat, SizedSample, Stream,
+    StreamConfig, StreamError,
+};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use lewton::inside_ogg::OggStreamReader;
+use tracing::error;
+
+use crate::audio_manager::AudioManagerError;
+use crate::resampler::{FrameAccumulator, Resampler};
+
+// Audio is handled in 10ms frames at 48kHz, recorded in mono and played in stereo
+pub const SAMPLE_RATE: u32 = 48000;
+pub const FRAME_DURATION: Duration = Duration::from_millis(10);
+pub const INPUT_FRAME_SIZE: usize = 480;
+pub const OUTPUT_FRAME_SIZE: usize = 960;
+
+/// Where recorded audio comes from
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum InputBackend {
+    /// A microphone on the system's audio host
+    #[default]
+    Device,
+    /// A WAV or Ogg Vorbis file, played on a loop
+    File(PathBuf),
+    /// A sine wave at this frequency in Hz
+    Tone(f32),
+    /// Silence
+    Null,
+}
+
+/// Where received audio is played
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum OutputBackend {
+    /// Speakers or headphones on the system's audio host
+    #[default]
+    Device,
+    /// A 16-bit stereo WAV file, overwritten if it exists
+    Wav(PathBuf),
+    /// Nowhere, the audio is thrown away
+    Null,
+}
+
+impl FromStr for InputBackend {
+    type Err = String;
+
+    /// Parse `device`, `null`, `tone`, `tone:<hz>` or `file:<path>`
+    fn from_str(s: &str) -> Result<InputBackend, String> {
+        match s.split_once(':') {
+            None => match s {
+                "device" => Ok(InputBackend::Device),
+                "null" => Ok(InputBackend::Null),
+                "tone" => Ok(InputBackend::Tone(440.0)),
+                _ => Err(format!(
+                    "unknown audio input {}, expected device, null, tone, tone:<hz> or file:<path>",
+                    s
+                )),
+            },
+            Some(("tone", frequency)) => match frequency.parse::<f32>() {
+                Ok(frequency) if frequency > 0.0 && frequency < SAMPLE_RATE as f32 / 2.0 => {
+                    Ok(InputBackend::Tone(frequency))
+                }
+                _ => Err(format!("{} isn't a usable tone frequency", frequency)),
+            },
+            Some(("file", path)) if !path.is_empty() => Ok(InputBackend::File(PathBuf::from(path))),
+            _ => Err(format!(
+                "unknown audio input {}, expected device, null, tone, tone:<hz> or file:<path>",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for InputBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputBackend::Device => write!(f, "device"),
+            InputBackend::File(path) => write!(f, "file:{}", path.display()),
+            InputBackend::Tone(frequency) => write!(f, "tone:{}", frequency),
+            InputBackend::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl FromStr for OutputBackend {
+    type Err = String;
+
+    /// Parse `device`, `null` or `wav:<path>`
+    fn from_str(s: &str) -> Result<OutputBackend, String> {
+        match s.split_once(':') {
+            None if s == "device" => Ok(OutputBackend::Device),
+            None if s == "null" => Ok(OutputBackend::Null),
+            Some(("wav", path)) if !path.is_empty() => Ok(OutputBackend::Wav(PathBuf::from(path))),
+            _ => Err(format!(
+                "unknown audio output {}, expected device, null or wav:<path>",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputBackend::Device => write!(f, "device"),
+            OutputBackend::Wav(path) => write!(f, "wav:{}", path.display()),
+            OutputBackend::Null => write!(f, "null"),
+        }
+    }
+}
+
+/// Produces mono audio at 48kHz
+pub trait AudioSource: Send {
+    fn read(&mut self, frame: &mut [f32]);
+}
+
+/// Takes stereo audio at 48kHz
+pub trait AudioSink: Send {
+    fn write(&mut self, frame: &[f32]);
+}
+
+struct Silence;
+
+impl AudioSource for Silence {
+    fn read(&mut self, frame: &mut [f32]) {
+        frame.fill(0.0);
+    }
+}
+
+struct Tone {
+    step: f32,
+    phase: f32,
+}
+
+impl AudioSource for Tone {
+    fn read(&mut self, frame: &mut [f32]) {
+        for sample in frame.iter_mut() {
+            // Quiet enough not to clip once mixed with others
+            *sample = self.phase.sin() * 0.2;
+            self.phase = (self.phase + self.step) % std::f32::consts::TAU;
+        }
+    }
+}
+
+struct FileSource {
+    samples: Vec<f32>,
+    position: usize,
+}
+
+impl AudioSource for FileSource {
+    fn read(&mut self, frame: &mut [f32]) {
+        for sample in frame.iter_mut() {
+            *sample = self.samples[self.position];
+            self.position = (self.position + 1) % self.samples.len();
+        }
+    }
+}
+
+struct Discard;
+
+impl AudioSink for Discard {
+    fn write(&mut self, _frame: &[f32]) {}
+}
+
+struct WavSink {
+    writer: WavWriter<BufWriter<File>>,
+}
+
+impl AudioSink for WavSink {
+    fn write(&mut self, frame: &[f32]) {
+        for sample in frame {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            if let Err(e) = self.writer.write_sample(sample) {
+                error!("failed to write audio: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Open an input that isn't a device
+pub fn open_source(backend: &InputBackend) -> Result<Box<dyn AudioSource>, AudioManagerError> {
+    match backend {
+        // Devices are streamed by cpal instead
+        InputBackend::Device => Err(AudioManagerError::DeviceNotFound),
+        InputBackend::File(path) => Ok(Box::new(FileSource {
+            samples: load_file(path)?,
+            position: 0,
+        })),
+        InputBackend::Tone(frequency) => Ok(Box::new(Tone {
+            step: std::f32::consts::TAU * frequency / SAMPLE_RATE as f32,
+            phase: 0.0,
+        })),
+        InputBackend::Null => Ok(Box::new(Silence)),
+    }
+}
+
+/// Open an output that isn't a device
+pub fn open_sink(backend: &OutputBackend) -> Result<Box<dyn AudioSink>, AudioManagerError> {
+    match backend {
+        OutputBackend::Device => Err(AudioManagerError::DeviceNotFound),
+        OutputBackend::Wav(path) => {
+            let spec = WavSpec {
+                channels: 2,
+                sample_rate: SAMPLE_RATE,
+                bits_per_sample: 16,
+                sample_format: SampleFormat::Int,
+            };
+
+            match WavWriter::create(path, spec) {
+                Ok(writer) => Ok(Box::new(WavSink { writer })),
+                Err(e) => {
+                    error!("failed to create {}: {}", path.display(), e);
+                    Err(AudioManagerError::FailedToCreateOutputFile)
+                }
+            }
+        }
+        OutputBackend::Null => Ok(Box::new(Discard)),
+    }
+}
+
+/// Read a whole WAV or Ogg Vorbis file as mono 48kHz audio
+fn load_file(path: &Path) -> Result<Vec<f32>, AudioManagerError> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+
+    let decoded = match extension.as_deref() {
+        Some("wav") => read_wav(path),
+        Some("ogg") => read_ogg(path),
+        _ => Err(String::from("only .wav and .ogg files are supported")),
+    };
+
+    match decoded {
+        Ok((samples, channels, sample_rate)) if !samples.is_empty() && channels > 0 => {
+            let mono: Vec<f32> = samples
+                .chunks(channels)
+                .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+                .collect();
+            Ok(resample(&mono, sample_rate))
+        }
+        Ok(_) => {
+            error!("{} has no audio", path.display());
+            Err(AudioManagerError::FailedToReadInputFile)
+        }
+        Err(e) => {
+            error!("failed to read {}: {}", path.display(), e);
+            Err(AudioManagerError::FailedToReadInputFile)
+        }
+    }
+}
+
+/// Interleaved samples, channel count and sample rate of a WAV file
+fn read_wav(path: &Path) -> Result<(Vec<f32>, usize, u32), String> {
+    let mut reader = WavReader::open(path).map_err(|e| e.to_string())?;
+    let spec = reader.spec();
+
+    let samples = match spec.sample_format {
+        SampleFormat::Float => reader
+            .samples::<f32>()
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|e| e.to_string())?,
+        SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / scale))
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|e| e.to_string())?
+        }
+    };
+
+    Ok((samples, spec.channels as usize, spec.sample_rate))
+}
+
+/// Interleaved samples, channel count and sample rate of an Ogg Vorbis file
+fn read_ogg(path: &Path) -> Result<(Vec<f32>, usize, u32), String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut reader = OggStreamReader::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+
+    let mut samples = Vec::new();
+    while let Some(packet) = reader.read_dec_packet_itl().map_err(|e| e.to_string())? {
+        samples.extend(packet.iter().map(|sample| *sample as f32 / 32768.0));
+    }
+
+    Ok((
+        samples,
+        reader.ident_hdr.audio_channels as usize,
+        reader.ident_hdr.audio_sample_rate,
+    ))
+}
+
+/// Resample mono audio to 48kHz
+fn resample(samples: &[f32], sample_rate: u32) -> Vec<f32> {
+    let mut resampled = Vec::new();
+    Resampler::new(sample_rate, SAMPLE_RATE, 1).process(samples, &mut resampled);
+    resampled
+}
+
+/// Calls back every 10ms on its own thread, standing in for a sound card
+pub struct FrameClock {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FrameClock {
+    pub fn start(mut on_frame: impl FnMut() + Send + 'static) -> FrameClock {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+
+        let handle = std::thread::spawn(move || {
+            let mut next_frame = Instant::now();
+
+            while thread_running.load(Ordering::Relaxed) {
+                on_frame();
+
+                next_frame += FRAME_DURATION;
+                let now = Instant::now();
+                match next_frame > now {
+                    true => std::thread::sleep(next_frame - now),
+                    // Fell behind, so carry on from now rather than rushing to catch up
+                    false => next_frame = now,
+                }
+            }
+        });
+
+        FrameClock {
+            running,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for FrameClock {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Recording or playback in progress, stopped when dropped
+pub enum AudioStream {
+    Device(Stream),
+    Clock(FrameClock),
+}
+
+/// Record from a device in whatever format it was opened with,
+/// calling `on_frame` with each 10ms of mono 48kHz audio
+pub fn build_input_stream(
+    device: &Device,
+    config: &StreamConfig,
+    sample_format: CpalSampleFormat,
+    on_frame: impl FnMut(&[f32]) + Send + 'static,
+    on_error: impl FnMut(StreamError) + Send + 'static,
+) -> Result<Stream, AudioManagerError> {
+    let stream = match sample_format {
+        CpalSampleFormat::F32 => input_stream::<f32>(device, config, on_frame, on_error),
+        CpalSampleFormat::I16 => input_stream::<i16>(device, config, on_frame, on_error),
+        CpalSampleFormat::U16 => input_stream::<u16>(device, config, on_frame, on_error),
+        _ => return Err(AudioManagerError::FailedToGetSupportedConfigs),
+    };
+
+    stream.map_err(|e| {
+        error!("failed to build the input stream: {}", e);
+        AudioManagerError::FailedToCreateInputStream
+    })
+}
+
+fn input_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    mut on_frame: impl FnMut(&[f32]) + Send + 'static,
+    on_error: impl FnMut(StreamError) + Send + 'static,
+) -> Result<Stream, BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let mut resampler = Resampler::new(config.sample_rate.0, SAMPLE_RATE, 1);
+    let mut frames = FrameAccumulator::new(INPUT_FRAME_SIZE);
+
+    // Kept between callbacks so they don't allocate once warmed up
+    let mut mono = Vec::new();
+    let mut resampled = Vec::new();
+
+    let data_callback = move |data: &[T], _: &_| {
+        mono.clear();
+        mono.extend(data.chunks(channels).map(|frame| {
+            frame
+                .iter()
+                .map(|sample| f32::from_sample(*sample))
+                .sum::<f32>()
+                / channels as f32
+        }));
+
+        resampled.clear();
+        resampler.process(&mono, &mut resampled);
+        frames.push(&resampled, &mut on_frame);
+    };
+
+    device.build_input_stream(config, data_callback, on_error, None)
+}
+
+/// Play to a device in whatever format it was opened with,
+/// calling `on_frame` to fill each 10ms of stereo 48kHz audio
+pub fn build_output_stream(
+    device: &Device,
+    config: &StreamConfig,
+    sample_format: CpalSampleFormat,
+    on_frame: impl FnMut(&mut [f32]) + Send + 'static,
+    on_error: impl FnMut(StreamError) + Send + 'static,
+) -> Result<Stream, AudioManagerError> {
+    let stream = match sample_format {
+        CpalSampleFormat::F32 => output_stream::<f32>(device, config, on_frame, on_error),
+        CpalSampleFormat::I16 => output_stream::<i16>(device, config, on_frame, on_error),
+        CpalSampleFormat::U16 => output_stream::<u16>(device, config, on_frame, on_error),
+        _ => return Err(AudioManagerError::FailedToGetSupportedConfigs),
+    };
+
+    stream.map_err(|e| {
+        error!("failed to build the output stream: {}", e);
+        AudioManagerError::FailedToCreateOutputStream
+    })
+}
+
+fn output_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    mut on_frame: impl FnMut(&mut [f32]) + Send + 'static,
+    on_error: impl FnMut(StreamError) + Send + 'static,
+) -> Result<Stream, BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let mut resampler = Resampler::new(SAMPLE_RATE, config.sample_rate.0, 2);
+
+    // Mixed audio converted for the device, waiting for the callback to ask for it
+    let mut pending = VecDeque::new();
+    let mut resampled = Vec::new();
+
+    let data_callback = move |data: &mut [T], _: &_| {
+        while pending.len() < data.len() {
+            let mut frame = [0.0; OUTPUT_FRAME_SIZE];
+            on_frame(&mut frame);
+
+            resampled.clear();
+            resampler.process(&frame, &mut resampled);
+            for stereo in resampled.chunks(2) {
+                match channels {
+                    1 => pending.push_back((stereo[0] + stereo[1]) * 0.5),
+                    // Left and right go to the first two speakers, the rest stay quiet
+                    _ => {
+                        pending.push_back(stereo[0]);
+                        pending.push_back(stereo[1]);
+                        pending.extend(std::iter::repeat_n(0.0, channels - 2));
+                    }
+                }
+            }
+        }
+
+        let wanted = data.len();
+        for (sample, pending) in data.iter_mut().zip(pending.drain(..wanted)) {
+            *sample = T::from_sample(pending);
+        }
+    };
+
+    device.build_output_stream(config, data_callback, on_error, None)
+}
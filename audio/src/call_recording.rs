@@ -0,0 +1,333 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use tracing::{error, info};
+
+use crate::audio_buffer_manager::AudioBufferManager;
+use crate::audio_manager::AudioManagerError;
+use crate::backend::{FrameClock, OUTPUT_FRAME_SIZE, SAMPLE_RATE};
+use crate::encoding::{EncoderApplication, EncoderSettings, EncoderTarget, VoiceEncoder};
+use crate::mixer::Volumes;
+use types::{AudioSequenceSize, UserIdSize};
+
+// Samples the decoder throws away at the start, covering the encoder's lookahead at 48kHz
+const PRE_SKIP: u16 = 312;
+
+// Samples per channel in each 10ms stereo frame
+const FRAME_SAMPLES: u64 = OUTPUT_FRAME_SIZE as u64 / 2;
+
+// Bitrate the mixed call is saved at, plenty for several voices in stereo
+const RECORDING_BITRATE: u32 = 64_000;
+
+// Packets on each page, so no more than a second is lost if we're killed mid-recording
+const PACKETS_PER_PAGE: u64 = 100;
+
+const VENDOR: &str = "kagu";
+
+/// Writes Opus packets to an Ogg Opus stream, as described in RFC 7845
+pub struct OggOpusWriter<W: Write> {
+    packets: PacketWriter<W>,
+    serial: u32,
+    // Samples per channel the decoder will put out so far, pre-skip included
+    granule_position: u64,
+    packets_in_page: u64,
+}
+
+impl<W: Write> OggOpusWriter<W> {
+    /// Start a stream of 48kHz audio, writing the headers straight away
+    pub fn new(writer: W, channels: u8, comments: &[String]) -> io::Result<OggOpusWriter<W>> {
+        // Only needs to tell this stream apart from others chained in the same file
+        let serial = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.subsec_nanos())
+            .unwrap_or_default();
+
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1); // Version
+        head.push(channels);
+        head.extend_from_slice(&PRE_SKIP.to_le_bytes());
+        head.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes()); // Output gain
+        head.push(0); // Mono or stereo, no channel mapping table
+
+        let mut tags = Vec::new();
+        tags.extend_from_slice(b"OpusTags");
+        tags.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+        tags.extend_from_slice(VENDOR.as_bytes());
+        tags.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            tags.extend_from_slice(comment.as_bytes());
+        }
+
+        // Each header gets a page of its own, with a granule position of 0
+        let mut packets = PacketWriter::new(writer);
+        packets.write_packet(head.into(), serial, PacketWriteEndInfo::EndPage, 0)?;
+        packets.write_packet(tags.into(), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        Ok(OggOpusWriter {
+            packets,
+            serial,
+            granule_position: 0,
+            packets_in_page: 0,
+        })
+    }
+
+    /// Add a packet holding `samples` samples per channel
+    pub fn write_packet(&mut self, packet: Vec<u8>, samples: u64) -> io::Result<()> {
+        self.granule_position += samples;
+        self.packets_in_page += 1;
+
+        let end = match self.packets_in_page >= PACKETS_PER_PAGE {
+            true => PacketWriteEndInfo::EndPage,
+            false => PacketWriteEndInfo::NormalPacket,
+        };
+        if end == PacketWriteEndInfo::EndPage {
+            self.packets_in_page = 0;
+        }
+
+        self.packets
+            .write_packet(packet.into(), self.serial, end, self.granule_position)
+    }
+
+    /// End the stream with a packet flushing what the encoder still holds back.
+    /// The final granule position trims off the padding after it,
+    /// so the stream plays for exactly as long as what was written before.
+    pub fn finish(mut self, last_packet: Vec<u8>) -> io::Result<W> {
+        let end_position = self.granule_position + PRE_SKIP as u64;
+        self.packets.write_packet(
+            last_packet.into(),
+            self.serial,
+            PacketWriteEndInfo::EndStream,
+            end_position,
+        )?;
+
+        let mut writer = self.packets.into_inner();
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+type RecordedFrame = (UserIdSize, AudioSequenceSize, Vec<u8>);
+
+/// Where the audio threads copy every frame heard or said, while a call is being recorded
+#[derive(Clone, Default)]
+pub struct RecordingTap {
+    sender: Arc<Mutex<Option<Sender<RecordedFrame>>>>,
+}
+
+impl RecordingTap {
+    pub fn new() -> RecordingTap {
+        RecordingTap::default()
+    }
+
+    /// Copy an encoded frame into the recording, if there is one
+    pub fn send(&self, user_id: UserIdSize, sequence: AudioSequenceSize, audio: &[u8]) {
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            let _ = sender.send((user_id, sequence, audio.to_vec()));
+        }
+    }
+
+    fn set(&self, sender: Option<Sender<RecordedFrame>>) {
+        *self.sender.lock().unwrap() = sender;
+    }
+}
+
+// Everyone's frames mixed together in time, living on the recording's own thread
+struct CallMix {
+    receiver: Receiver<RecordedFrame>,
+    buffers: AudioBufferManager,
+    encoder: VoiceEncoder,
+    // Taken when the recording is finished
+    writer: Option<OggOpusWriter<BufWriter<File>>>,
+}
+
+impl CallMix {
+    fn record_frame(&mut self) {
+        while let Ok((user_id, sequence, audio)) = self.receiver.try_recv() {
+            self.buffers.buffer_data(user_id, sequence, audio);
+        }
+
+        // Everyone as they sound in the call, whatever volumes we picked for ourselves
+        let mix = self.buffers.get_output_data(&Volumes::default());
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+
+        let packet = match self.encoder.encode_stereo(&mix) {
+            Ok(packet) => packet,
+            Err(e) => {
+                error!("failed to encode the call recording: {:?}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = writer.write_packet(packet, FRAME_SAMPLES) {
+            // Most likely the disk is full, so stop rather than fail every frame
+            error!("failed to write the call recording: {}", e);
+            self.writer = None;
+        }
+    }
+}
+
+impl Drop for CallMix {
+    fn drop(&mut self) {
+        let Some(writer) = self.writer.take() else {
+            return;
+        };
+
+        // A frame of silence pushes the last of the audio out of the encoder
+        let last_packet = self
+            .encoder
+            .encode_stereo(&[0.0; OUTPUT_FRAME_SIZE])
+            .unwrap_or_default();
+
+        if let Err(e) = writer.finish(last_packet) {
+            error!("failed to finish the call recording: {}", e);
+        }
+    }
+}
+
+/// A voice call being mixed and saved to an Ogg Opus file, finished when dropped
+pub struct CallRecorder {
+    path: PathBuf,
+    tap: RecordingTap,
+    _clock: FrameClock,
+}
+
+impl CallRecorder {
+    /// Start saving every frame copied into `tap` to `path`
+    pub fn start(
+        path: &Path,
+        tap: RecordingTap,
+        comments: &[String],
+    ) -> Result<CallRecorder, AudioManagerError> {
+        let file = match File::create(path) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("failed to create {}: {}", path.display(), e);
+                return Err(AudioManagerError::FailedToCreateOutputFile);
+            }
+        };
+
+        let writer = match OggOpusWriter::new(BufWriter::new(file), 2, comments) {
+            Ok(writer) => writer,
+            Err(e) => {
+                error!("failed to write to {}: {}", path.display(), e);
+                return Err(AudioManagerError::FailedToCreateOutputFile);
+            }
+        };
+
+        let settings = EncoderSettings {
+            bitrate: RECORDING_BITRATE,
+            stereo: true,
+            application: EncoderApplication::Voip,
+            ..Default::default()
+        };
+        let mut encoder = match VoiceEncoder::new(settings) {
+            Ok(encoder) => encoder,
+            Err(_) => return Err(AudioManagerError::FailedToCreateEncoder),
+        };
+        // Nothing is lost on the way to a file, so FEC would only waste space
+        let target = EncoderTarget {
+            bitrate: RECORDING_BITRATE,
+            packet_loss: 0,
+        };
+        if encoder.set_target(target).is_err() {
+            return Err(AudioManagerError::FailedToCreateEncoder);
+        }
+
+        let (sender, receiver) = unbounded();
+        let mut mix = CallMix {
+            receiver,
+            buffers: AudioBufferManager::new(),
+            encoder,
+            writer: Some(writer),
+        };
+        let clock = FrameClock::start(move || mix.record_frame());
+
+        tap.set(Some(sender));
+        info!(path = %path.display(), "started recording the call");
+
+        Ok(CallRecorder {
+            path: path.to_path_buf(),
+            tap,
+            _clock: clock,
+        })
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for CallRecorder {
+    fn drop(&mut self) {
+        // Stop copying frames before the clock stops and the file is finished
+        self.tap.set(None);
+        info!(path = %self.path.display(), "stopped recording the call");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ogg::reading::PacketReader;
+    use std::io::Cursor;
+
+    fn read_packets(file: Vec<u8>) -> Vec<ogg::Packet> {
+        let mut reader = PacketReader::new(Cursor::new(file));
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn headers_are_on_pages_of_their_own() {
+        let comments = [String::from("TITLE=call")];
+        let writer = OggOpusWriter::new(Vec::new(), 2, &comments).unwrap();
+        let packets = read_packets(writer.finish(vec![0]).unwrap());
+
+        let head = &packets[0];
+        assert!(head.data.starts_with(b"OpusHead"));
+        assert_eq!(head.data[9], 2);
+        assert_eq!(head.data[10..12], PRE_SKIP.to_le_bytes());
+        assert!(head.last_in_page());
+        assert_eq!(head.absgp_page(), 0);
+
+        let tags = &packets[1];
+        assert!(tags.data.starts_with(b"OpusTags"));
+        assert!(tags.data.ends_with(b"TITLE=call"));
+        assert!(tags.last_in_page());
+        assert_eq!(tags.absgp_page(), 0);
+    }
+
+    #[test]
+    fn granule_positions_count_the_samples_written() {
+        let mut writer = OggOpusWriter::new(Vec::new(), 2, &[]).unwrap();
+        for _ in 0..150 {
+            writer.write_packet(vec![0], FRAME_SAMPLES).unwrap();
+        }
+        let packets = read_packets(writer.finish(vec![0]).unwrap());
+        let audio = &packets[2..];
+        assert_eq!(audio.len(), 151);
+
+        // A full page ends after the packets it holds
+        let first_page = &audio[PACKETS_PER_PAGE as usize - 1];
+        assert!(first_page.last_in_page());
+        assert_eq!(first_page.absgp_page(), PACKETS_PER_PAGE * FRAME_SAMPLES);
+
+        // The last page trims the padding the encoder's delay adds
+        let last = audio.last().unwrap();
+        assert!(last.last_in_stream());
+        assert_eq!(last.absgp_page(), 150 * FRAME_SAMPLES + PRE_SKIP as u64);
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::backend::FRAME_DURATION;
+
+/// When recorded audio is sent
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum CaptureMode {
+    /// Always
+    #[default]
+    OpenMic,
+    /// While the input is louder than a threshold, and a little after
+    VoiceActivity,
+    /// While the push-to-talk key is held
+    PushToTalk,
+}
+
+impl CaptureMode {
+    /// The mode after this one, for cycling through them in settings
+    pub fn next(&self) -> CaptureMode {
+        match self {
+            CaptureMode::OpenMic => CaptureMode::VoiceActivity,
+            CaptureMode::VoiceActivity => CaptureMode::PushToTalk,
+            CaptureMode::PushToTalk => CaptureMode::OpenMic,
+        }
+    }
+}
+
+impl FromStr for CaptureMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CaptureMode, String> {
+        match s {
+            "open" => Ok(CaptureMode::OpenMic),
+            "vad" => Ok(CaptureMode::VoiceActivity),
+            "ptt" => Ok(CaptureMode::PushToTalk),
+            _ => Err(format!(
+                "unknown capture mode {}, expected open, vad or ptt",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CaptureMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureMode::OpenMic => write!(f, "open"),
+            CaptureMode::VoiceActivity => write!(f, "vad"),
+            CaptureMode::PushToTalk => write!(f, "ptt"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CaptureSettings {
+    pub mode: CaptureMode,
+    /// Level in dBFS the input has to reach to count as speech
+    pub voice_threshold: f32,
+    /// How long to keep sending after speech drops below the threshold
+    pub hang_time: Duration,
+    /// Let Opus' discontinuous transmission (DTX) stop sending frames while the input is silent.
+    /// The encoder still sends one every 400ms so listeners hear comfort noise.
+    pub dtx: bool,
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        CaptureSettings {
+            mode: CaptureMode::OpenMic,
+            voice_threshold: -45.0,
+            hang_time: Duration::from_millis(300),
+            dtx: true,
+        }
+    }
+}
+
+/// Loudness of a frame in dBFS, from its RMS
+pub fn frame_level(frame: &[f32]) -> f32 {
+    if frame.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+
+    let power = frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32;
+    10.0 * power.log10()
+}
+
+/// Push-to-talk state shared between the UI and the recording thread.
+///
+/// Terminals only report key presses, so holding the key is seen as presses repeating.
+/// Each press keeps the microphone open for a moment, long enough to reach the next repeat.
+#[derive(Clone)]
+pub struct PushToTalk {
+    epoch: Instant,
+    // Milliseconds after `epoch` the key counts as held until
+    held_until: Arc<AtomicU64>,
+}
+
+impl PushToTalk {
+    pub fn new() -> PushToTalk {
+        PushToTalk {
+            epoch: Instant::now(),
+            held_until: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn hold(&self, duration: Duration) {
+        let until = (self.epoch.elapsed() + duration).as_millis() as u64;
+        self.held_until.store(until, Ordering::Relaxed);
+    }
+
+    pub fn release(&self) {
+        self.held_until.store(0, Ordering::Relaxed);
+    }
+
+    pub fn is_held(&self) -> bool {
+        (self.epoch.elapsed().as_millis() as u64) < self.held_until.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for PushToTalk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decides frame by frame whether recorded audio is sent
+pub struct VoiceGate {
+    settings: CaptureSettings,
+    push_to_talk: PushToTalk,
+    // Frames left to send after speech stopped
+    hang_frames: u32,
+}
+
+impl VoiceGate {
+    pub fn new(settings: CaptureSettings, push_to_talk: PushToTalk) -> VoiceGate {
+        VoiceGate {
+            settings,
+            push_to_talk,
+            hang_frames: 0,
+        }
+    }
+
+    pub fn should_send(&mut self, frame: &[f32]) -> bool {
+        match self.settings.mode {
+            CaptureMode::OpenMic => true,
+            CaptureMode::PushToTalk => self.push_to_talk.is_held(),
+            CaptureMode::VoiceActivity => {
+                if frame_level(frame) >= self.settings.voice_threshold {
+                    self.hang_frames =
+                        (self.settings.hang_time.as_millis() / FRAME_DURATION.as_millis()) as u32;
+                    true
+                } else if self.hang_frames > 0 {
+                    self.hang_frames -= 1;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
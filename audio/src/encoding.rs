@@ -0,0 +1,334 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+
+// Range of bitrates Opus can be asked for, in bits per second
+pub const MIN_BITRATE: u32 = 6_000;
+pub const MAX_BITRATE: u32 = 510_000;
+
+// Largest packet Opus makes out of one frame
+const MAX_PACKET_SIZE: usize = 1275;
+
+// With DTX on, packets this small carry no audio and don't need sending
+const DTX_PACKET_SIZE: usize = 2;
+
+// Loss the encoder plans for with in-band FEC until some has been measured, as a percentage
+const EXPECTED_PACKET_LOSS: u8 = 10;
+
+// Planning for more loss than this only wastes bits on FEC that can't keep up
+const MAX_PACKET_LOSS: u8 = 30;
+
+// Losing more than this share of frames means we're sending more than the connection can take
+const CONGESTED_LOSS: f32 = 0.10;
+
+// Below this the bitrate may climb back towards what the user asked for
+const CLEAR_LOSS: f32 = 0.02;
+
+// Round trips longer than this mean queues are building up along the way
+const CONGESTED_ROUND_TRIP: Duration = Duration::from_millis(300);
+
+// How far the bitrate is cut when congested, and raised when clear, each time loss is reported
+const BITRATE_DECREASE: f32 = 0.75;
+const BITRATE_INCREASE: f32 = 1.08;
+
+// Share of each new loss report mixed into the running estimate
+const LOSS_SMOOTHING: f32 = 0.5;
+
+/// What Opus tunes its encoding for
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum EncoderApplication {
+    /// Speech, with in-band FEC to recover lost frames
+    #[default]
+    Voip,
+    /// Anything else, like music played into the channel
+    Music,
+}
+
+impl EncoderApplication {
+    /// The application after this one, for cycling through them in settings
+    pub fn next(&self) -> EncoderApplication {
+        match self {
+            EncoderApplication::Voip => EncoderApplication::Music,
+            EncoderApplication::Music => EncoderApplication::Voip,
+        }
+    }
+}
+
+impl From<EncoderApplication> for Application {
+    fn from(application: EncoderApplication) -> Application {
+        match application {
+            EncoderApplication::Voip => Application::Voip,
+            EncoderApplication::Music => Application::Audio,
+        }
+    }
+}
+
+impl FromStr for EncoderApplication {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EncoderApplication, String> {
+        match s {
+            "voip" => Ok(EncoderApplication::Voip),
+            "music" => Ok(EncoderApplication::Music),
+            _ => Err(format!(
+                "unknown encoder application {}, expected voip or music",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EncoderApplication {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncoderApplication::Voip => write!(f, "voip"),
+            EncoderApplication::Music => write!(f, "music"),
+        }
+    }
+}
+
+/// How recorded audio is encoded
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EncoderSettings {
+    /// Highest bitrate to send at in bits per second, lowered when the connection struggles
+    pub bitrate: u32,
+    /// How much CPU Opus may spend for better quality, from 0 to 10
+    pub complexity: u8,
+    /// Send two channels. Microphones are mono, so this mostly matters for music.
+    pub stereo: bool,
+    pub application: EncoderApplication,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        EncoderSettings {
+            bitrate: 32_000,
+            complexity: 10,
+            stereo: false,
+            application: EncoderApplication::Voip,
+        }
+    }
+}
+
+impl EncoderSettings {
+    /// Keep every setting within what Opus accepts
+    pub fn clamped(self) -> EncoderSettings {
+        EncoderSettings {
+            bitrate: self.bitrate.clamp(MIN_BITRATE, MAX_BITRATE),
+            complexity: self.complexity.min(10),
+            ..self
+        }
+    }
+}
+
+/// Bitrate and expected loss the encoder should be using right now
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EncoderTarget {
+    pub bitrate: u32,
+    /// Loss in-band FEC is planned for as a percentage, FEC is off at 0
+    pub packet_loss: u8,
+}
+
+/// Adjusts the bitrate and FEC to what the connection can carry.
+///
+/// The bitrate is cut back quickly when frames go missing or round trips grow,
+/// then raised slowly again once things settle, never past what the user asked for
+/// or what the voice channel allows.
+#[derive(Debug)]
+pub struct BitrateController {
+    settings: EncoderSettings,
+    // Bitrate limit of the voice channel we're in, 0 for none
+    channel_limit: u32,
+    bitrate: u32,
+    // Smoothed share of frames lost, `None` until the server reports some
+    loss: Option<f32>,
+    round_trip: Option<Duration>,
+}
+
+impl BitrateController {
+    pub fn new(settings: EncoderSettings) -> BitrateController {
+        let mut controller = BitrateController {
+            settings,
+            channel_limit: 0,
+            bitrate: 0,
+            loss: None,
+            round_trip: None,
+        };
+        controller.bitrate = controller.max_bitrate();
+        controller
+    }
+
+    pub fn get_settings(&self) -> EncoderSettings {
+        self.settings
+    }
+
+    /// Start again from the user's new bitrate
+    pub fn set_settings(&mut self, settings: EncoderSettings) {
+        self.settings = settings;
+        self.bitrate = self.max_bitrate();
+    }
+
+    /// Follow the limit of the voice channel we're in, 0 for none
+    pub fn set_channel_limit(&mut self, channel_limit: u32) {
+        self.channel_limit = channel_limit;
+        self.bitrate = self.bitrate.min(self.max_bitrate());
+    }
+
+    /// The most we'll send at, whatever the connection is like
+    pub fn max_bitrate(&self) -> u32 {
+        match self.channel_limit {
+            0 => self.settings.bitrate,
+            limit => self.settings.bitrate.min(limit),
+        }
+        .clamp(MIN_BITRATE, MAX_BITRATE)
+    }
+
+    /// Take in the round trip time to the server
+    pub fn report_round_trip(&mut self, round_trip: Duration) {
+        self.round_trip = Some(round_trip);
+        if round_trip > CONGESTED_ROUND_TRIP {
+            self.decrease();
+        }
+    }
+
+    /// Take in how many of the frames we sent lately were expected and received by the server
+    pub fn report_loss(&mut self, expected: u32, received: u32) {
+        if expected == 0 {
+            return;
+        }
+
+        let reported = expected.saturating_sub(received) as f32 / expected as f32;
+        let loss = match self.loss {
+            Some(loss) => loss + (reported - loss) * LOSS_SMOOTHING,
+            None => reported,
+        };
+        self.loss = Some(loss);
+
+        let congested = self
+            .round_trip
+            .is_some_and(|round_trip| round_trip > CONGESTED_ROUND_TRIP);
+
+        if loss > CONGESTED_LOSS || congested {
+            self.decrease();
+        } else if loss < CLEAR_LOSS {
+            let raised = (self.bitrate as f32 * BITRATE_INCREASE) as u32;
+            self.bitrate = raised.min(self.max_bitrate());
+        }
+    }
+
+    pub fn target(&self) -> EncoderTarget {
+        let packet_loss = match self.loss {
+            Some(loss) => ((loss * 100.0).round() as u8).min(MAX_PACKET_LOSS),
+            None => EXPECTED_PACKET_LOSS,
+        };
+
+        EncoderTarget {
+            bitrate: self.bitrate,
+            packet_loss,
+        }
+    }
+
+    fn decrease(&mut self) {
+        let lowered = (self.bitrate as f32 * BITRATE_DECREASE) as u32;
+        self.bitrate = lowered.max(MIN_BITRATE);
+    }
+}
+
+/// Opus encoder for 10ms frames, taking mono or stereo audio whatever it sends
+pub struct VoiceEncoder {
+    encoder: Encoder,
+    stereo: bool,
+    target: Option<EncoderTarget>,
+    packet: [u8; MAX_PACKET_SIZE],
+    // Audio converted to the encoder's channel count
+    converted: Vec<f32>,
+}
+
+impl VoiceEncoder {
+    pub fn new(settings: EncoderSettings) -> Result<VoiceEncoder, audiopus::Error> {
+        let settings = settings.clamped();
+        let channels = match settings.stereo {
+            true => Channels::Stereo,
+            false => Channels::Mono,
+        };
+
+        let mut encoder = Encoder::new(SampleRate::Hz48000, channels, settings.application.into())?;
+        encoder.set_complexity(settings.complexity)?;
+
+        let mut voice_encoder = VoiceEncoder {
+            encoder,
+            stereo: settings.stereo,
+            target: None,
+            packet: [0; MAX_PACKET_SIZE],
+            converted: Vec::new(),
+        };
+        voice_encoder.set_target(EncoderTarget {
+            bitrate: settings.bitrate,
+            packet_loss: EXPECTED_PACKET_LOSS,
+        })?;
+
+        Ok(voice_encoder)
+    }
+
+    /// Encode at a new bitrate and level of FEC, doing nothing if they haven't changed
+    pub fn set_target(&mut self, target: EncoderTarget) -> Result<(), audiopus::Error> {
+        if self.target == Some(target) {
+            return Ok(());
+        }
+        self.target = Some(target);
+
+        // Tuck a rough copy of each frame into the next so a lost one can be recovered.
+        // This only happens in Opus' speech modes.
+        self.encoder
+            .set_bitrate(Bitrate::BitsPerSecond(target.bitrate as i32))?;
+        self.encoder.set_inband_fec(target.packet_loss > 0)?;
+        self.encoder.set_packet_loss_perc(target.packet_loss)?;
+        Ok(())
+    }
+
+    /// Let the encoder skip silence, see `is_silence`
+    pub fn set_dtx(&mut self, dtx: bool) -> Result<(), audiopus::Error> {
+        self.encoder.set_dtx(dtx)
+    }
+
+    /// Whether a packet only marks silence skipped by DTX, so nothing needs sending
+    pub fn is_silence(packet: &[u8]) -> bool {
+        packet.len() <= DTX_PACKET_SIZE
+    }
+
+    /// Encode 10ms of mono audio, copied into both channels when sending stereo
+    pub fn encode_mono(&mut self, frame: &[f32]) -> Result<Vec<u8>, audiopus::Error> {
+        if !self.stereo {
+            return self.encode(frame);
+        }
+
+        let mut converted = std::mem::take(&mut self.converted);
+        converted.clear();
+        converted.extend(frame.iter().flat_map(|sample| [*sample, *sample]));
+        let packet = self.encode(&converted);
+        self.converted = converted;
+        packet
+    }
+
+    /// Encode 10ms of interleaved stereo audio, mixed down when sending mono
+    pub fn encode_stereo(&mut self, frame: &[f32]) -> Result<Vec<u8>, audiopus::Error> {
+        if self.stereo {
+            return self.encode(frame);
+        }
+
+        let mut converted = std::mem::take(&mut self.converted);
+        converted.clear();
+        converted.extend(frame.chunks(2).map(|pair| (pair[0] + pair[1]) * 0.5));
+        let packet = self.encode(&converted);
+        self.converted = converted;
+        packet
+    }
+
+    fn encode(&mut self, samples: &[f32]) -> Result<Vec<u8>, audiopus::Error> {
+        let length = self.encoder.encode_float(samples, &mut self.packet)?;
+        Ok(self.packet[..length].to_vec())
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+use types::UserIdSize;
+
+// Frames whose levels are reported together, 50ms worth
+const REPORT_FRAMES: u32 = 5;
+
+// Reports waiting to be picked up before newer ones are dropped
+const REPORT_QUEUE: usize = 32;
+
+// Louder than this counts as speaking rather than background noise
+const SPEAKING_THRESHOLD_DB: f32 = -50.0;
+
+/// How loud audio going through the audio threads is, in dBFS
+#[derive(Debug, PartialEq, Clone)]
+pub enum AudioLevel {
+    /// Our microphone after processing, and whether it's being sent
+    Input { level: f32, sending: bool },
+    /// Everyone played back lately, people who went quiet are left out
+    Speakers(Vec<(UserIdSize, f32)>),
+}
+
+/// Whether a level is loud enough to show someone as speaking
+pub fn is_speaking(level: f32) -> bool {
+    level > SPEAKING_THRESHOLD_DB
+}
+
+/// Carries levels from the audio threads to whoever shows them.
+/// Reports are dropped rather than blocking audio when nobody is reading them.
+#[derive(Clone)]
+pub struct LevelReports {
+    sender: Sender<AudioLevel>,
+    receiver: Receiver<AudioLevel>,
+}
+
+impl LevelReports {
+    pub fn new() -> LevelReports {
+        let (sender, receiver) = bounded(REPORT_QUEUE);
+        LevelReports { sender, receiver }
+    }
+
+    pub fn send(&self, level: AudioLevel) {
+        let _ = self.sender.try_send(level);
+    }
+
+    /// Levels reported since this was last called, oldest first
+    pub fn take(&self) -> Vec<AudioLevel> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+impl Default for LevelReports {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Loudest input level over a few frames, so levels come at a pace a UI can keep up with
+#[derive(Default)]
+pub struct InputMeter {
+    peak: f32,
+    sending: bool,
+    frames: u32,
+}
+
+impl InputMeter {
+    pub fn new() -> InputMeter {
+        InputMeter {
+            peak: f32::NEG_INFINITY,
+            ..Default::default()
+        }
+    }
+
+    /// Add a recorded frame's level, returning a report once there's enough for one
+    pub fn add(&mut self, level: f32, sending: bool) -> Option<AudioLevel> {
+        self.peak = self.peak.max(level);
+        self.sending |= sending;
+        self.frames += 1;
+
+        if self.frames < REPORT_FRAMES {
+            return None;
+        }
+
+        let report = AudioLevel::Input {
+            level: self.peak,
+            sending: self.sending,
+        };
+        *self = InputMeter::new();
+        Some(report)
+    }
+}
+
+/// Loudest level of each speaker over a few frames of playback
+#[derive(Default)]
+pub struct SpeakerMeter {
+    peaks: BTreeMap<UserIdSize, f32>,
+    frames: u32,
+}
+
+impl SpeakerMeter {
+    pub fn new() -> SpeakerMeter {
+        SpeakerMeter::default()
+    }
+
+    /// Add the level of one speaker in the frame being played
+    pub fn add(&mut self, user_id: UserIdSize, level: f32) {
+        let peak = self.peaks.entry(user_id).or_insert(f32::NEG_INFINITY);
+        *peak = peak.max(level);
+    }
+
+    /// Finish a played frame, returning a report once there's enough for one
+    pub fn end_frame(&mut self) -> Option<AudioLevel> {
+        self.frames += 1;
+        if self.frames < REPORT_FRAMES {
+            return None;
+        }
+
+        self.frames = 0;
+        let peaks = std::mem::take(&mut self.peaks);
+        Some(AudioLevel::Speakers(peaks.into_iter().collect()))
+    }
+}
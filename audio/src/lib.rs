@@ -1,4 +1,11 @@
-mod audio_buffer;
 mod audio_buffer_manager;
 pub mod audio_io;
 pub mod audio_manager;
+pub mod backend;
+pub mod call_recording;
+pub mod capture;
+pub mod encoding;
+pub mod levels;
+pub mod mixer;
+pub mod processing;
+pub mod resampler;
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+
+use types::UserIdSize;
+
+/// Loudest a volume can be turned up to, as a multiple of the original level
+pub const MAX_VOLUME: f32 = 2.0;
+
+// Level the limiter keeps peaks under, just below full scale
+const LIMIT_THRESHOLD: f32 = 0.9;
+
+// How much of the way back to full gain the limiter goes each frame, about 200ms to recover
+const LIMIT_RELEASE: f32 = 0.05;
+
+/// How loud we're heard and how loud everyone else is played,
+/// each as a multiple of the original level
+#[derive(Debug, PartialEq, Clone)]
+pub struct Volumes {
+    /// Applied to the microphone before anything else
+    pub input_gain: f32,
+    /// Applied to everything played back
+    pub output_gain: f32,
+    // Speakers turned up or down, everyone else plays at 1.0
+    users: BTreeMap<UserIdSize, f32>,
+}
+
+impl Default for Volumes {
+    fn default() -> Self {
+        Volumes {
+            input_gain: 1.0,
+            output_gain: 1.0,
+            users: BTreeMap::new(),
+        }
+    }
+}
+
+impl Volumes {
+    pub fn get_user_volume(&self, user_id: UserIdSize) -> f32 {
+        self.users.get(&user_id).copied().unwrap_or(1.0)
+    }
+
+    pub fn set_user_volume(&mut self, user_id: UserIdSize, volume: f32) {
+        let volume = clamp_volume(volume);
+        match volume == 1.0 {
+            true => self.users.remove(&user_id),
+            false => self.users.insert(user_id, volume),
+        };
+    }
+
+    /// Speakers whose volume was changed, and what it was changed to
+    pub fn user_volumes(&self) -> impl Iterator<Item = (UserIdSize, f32)> + '_ {
+        self.users
+            .iter()
+            .map(|(user_id, volume)| (*user_id, *volume))
+    }
+}
+
+/// Keep a volume between silent and `MAX_VOLUME`
+pub fn clamp_volume(volume: f32) -> f32 {
+    match volume.is_nan() {
+        true => 1.0,
+        false => volume.clamp(0.0, MAX_VOLUME),
+    }
+}
+
+/// Bend samples over the threshold smoothly towards full scale instead of cutting them off
+pub fn soft_clip(sample: f32) -> f32 {
+    let level = sample.abs();
+    if level <= LIMIT_THRESHOLD {
+        return sample;
+    }
+
+    let headroom = 1.0 - LIMIT_THRESHOLD;
+    let bent = LIMIT_THRESHOLD + headroom * ((level - LIMIT_THRESHOLD) / headroom).tanh();
+    bent.copysign(sample)
+}
+
+/// Turns down mixed audio that would clip, then brings it back up gradually.
+/// Whatever still gets past it is soft clipped.
+pub struct SoftLimiter {
+    gain: f32,
+}
+
+impl SoftLimiter {
+    pub fn new() -> SoftLimiter {
+        SoftLimiter { gain: 1.0 }
+    }
+
+    pub fn process(&mut self, frame: &mut [f32]) {
+        if frame.is_empty() {
+            return;
+        }
+
+        let peak = frame
+            .iter()
+            .fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+        let target = match peak > LIMIT_THRESHOLD {
+            true => LIMIT_THRESHOLD / peak,
+            false => 1.0,
+        };
+
+        // Duck straight away, recover slowly so quieter words after a loud one don't pump
+        let next_gain = match target < self.gain {
+            true => target,
+            false => self.gain + (target - self.gain) * LIMIT_RELEASE,
+        };
+
+        // Ramp across the frame so the gain change doesn't click
+        let step = (next_gain - self.gain) / frame.len() as f32;
+        for (i, sample) in frame.iter_mut().enumerate() {
+            let gain = self.gain + step * (i + 1) as f32;
+            *sample = soft_clip(*sample * gain);
+        }
+
+        self.gain = next_gain;
+    }
+}
+
+impl Default for SoftLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
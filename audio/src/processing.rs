@@ -0,0 +1,398 @@
+use crossbeam::channel::{bounded, Receiver, Sender};
+use nnnoiseless::DenoiseState;
+
+use crate::backend::INPUT_FRAME_SIZE;
+use crate::capture::frame_level;
+use crate::mixer;
+
+// Taps in the echo canceller's filter, enough for about 21ms of echo past the reference frame
+const ECHO_FILTER_LENGTH: usize = 1024;
+
+// How quickly the echo canceller adapts, between 0 and 2
+const ECHO_STEP_SIZE: f32 = 0.3;
+
+// The microphone louder than this share of recent playback means someone is talking over it
+const DOUBLE_TALK_RATIO: f32 = 0.5;
+
+// Playback quieter than this has no echo worth learning from
+const ECHO_FLOOR_DB: f32 = -60.0;
+
+// Frames of playback waiting to be matched with recorded frames
+const ECHO_REFERENCE_FRAMES: usize = 4;
+
+// Level automatic gain control aims speech at, and how far it will go to get there
+const AGC_TARGET_DB: f32 = -18.0;
+const AGC_MAX_GAIN_DB: f32 = 30.0;
+const AGC_MIN_GAIN_DB: f32 = -12.0;
+
+// Quieter than this is left alone so background noise isn't turned up between words
+const AGC_SPEECH_FLOOR_DB: f32 = -55.0;
+
+// Share of the way to the wanted gain taken each frame, turning down faster than up
+const AGC_ATTACK: f32 = 0.3;
+const AGC_RELEASE: f32 = 0.02;
+
+/// Which cleanup runs on recorded audio before it's encoded.
+/// Each stage costs CPU, so they're all off unless asked for.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ProcessingSettings {
+    /// Remove steady background noise like fans and typing
+    pub noise_suppression: bool,
+    /// Remove what the microphone picks up from our own speakers
+    pub echo_cancellation: bool,
+    /// Even out how loud we're heard
+    pub auto_gain: bool,
+}
+
+/// Recurrent neural network noise suppression, from RNNoise
+pub struct NoiseSuppressor {
+    state: Box<DenoiseState<'static>>,
+    scaled: [f32; INPUT_FRAME_SIZE],
+}
+
+impl NoiseSuppressor {
+    pub fn new() -> NoiseSuppressor {
+        NoiseSuppressor {
+            state: DenoiseState::new(),
+            scaled: [0.0; INPUT_FRAME_SIZE],
+        }
+    }
+
+    /// Clean up one mono frame in place
+    pub fn process(&mut self, frame: &mut [f32]) {
+        // The model works on 16-bit sample values rather than -1.0 to 1.0
+        for (scaled, sample) in self.scaled.iter_mut().zip(frame.iter()) {
+            *scaled = sample * i16::MAX as f32;
+        }
+
+        self.state.process_frame(frame, &self.scaled);
+
+        for sample in frame.iter_mut() {
+            *sample /= i16::MAX as f32;
+        }
+    }
+}
+
+impl Default for NoiseSuppressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Subtracts what the microphone hears of playback, learning the path from speakers
+/// to microphone with a normalised least mean squares filter
+pub struct EchoCanceller {
+    // Stored newest tap last so they line up with `history`
+    weights: Vec<f32>,
+    // The last `ECHO_FILTER_LENGTH - 1` playback samples followed by the current frame's
+    history: Vec<f32>,
+}
+
+impl EchoCanceller {
+    pub fn new() -> EchoCanceller {
+        EchoCanceller {
+            weights: vec![0.0; ECHO_FILTER_LENGTH],
+            history: vec![0.0; ECHO_FILTER_LENGTH - 1 + INPUT_FRAME_SIZE],
+        }
+    }
+
+    /// Remove echo of `playback` from `frame`, both mono and the same length.
+    /// Without anything played back there's nothing to remove, but the filter still moves on.
+    pub fn process(&mut self, frame: &mut [f32], playback: Option<&[f32]>) {
+        let tail = ECHO_FILTER_LENGTH - 1;
+        self.history.copy_within(INPUT_FRAME_SIZE.., 0);
+        match playback {
+            Some(playback) => self.history[tail..].copy_from_slice(&playback[..INPUT_FRAME_SIZE]),
+            None => self.history[tail..].fill(0.0),
+        }
+
+        // Learning while someone is talking over the echo would teach the filter their voice
+        let far_peak = peak(&self.history);
+        let adapt = frame_level(&self.history[tail..]) > ECHO_FLOOR_DB
+            && peak(frame) < far_peak * DOUBLE_TALK_RATIO;
+
+        // Energy of the playback under the filter, kept up to date as it slides along
+        let mut energy: f32 = self.history[..ECHO_FILTER_LENGTH]
+            .iter()
+            .map(|sample| sample * sample)
+            .sum();
+
+        for (n, sample) in frame.iter_mut().enumerate() {
+            let far = &self.history[n..n + ECHO_FILTER_LENGTH];
+            if n > 0 {
+                let entering = far[ECHO_FILTER_LENGTH - 1];
+                let leaving = self.history[n - 1];
+                energy = (energy + entering * entering - leaving * leaving).max(0.0);
+            }
+
+            let echo: f32 = self
+                .weights
+                .iter()
+                .zip(far)
+                .map(|(weight, far)| weight * far)
+                .sum();
+            let error = *sample - echo;
+            *sample = error;
+
+            if adapt {
+                let step = ECHO_STEP_SIZE * error / (energy + 1e-6);
+                for (weight, far) in self.weights.iter_mut().zip(far) {
+                    *weight += step * far;
+                }
+            }
+        }
+    }
+}
+
+impl Default for EchoCanceller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn peak(frame: &[f32]) -> f32 {
+    frame
+        .iter()
+        .fold(0.0_f32, |peak, sample| peak.max(sample.abs()))
+}
+
+/// Brings speech towards a steady level, turning quiet talkers up and loud ones down
+pub struct AutoGain {
+    gain_db: f32,
+}
+
+impl AutoGain {
+    pub fn new() -> AutoGain {
+        AutoGain { gain_db: 0.0 }
+    }
+
+    pub fn process(&mut self, frame: &mut [f32]) {
+        if frame.is_empty() {
+            return;
+        }
+
+        let level = frame_level(frame);
+        let mut next_gain_db = self.gain_db;
+        if level > AGC_SPEECH_FLOOR_DB {
+            let wanted = (AGC_TARGET_DB - level).clamp(AGC_MIN_GAIN_DB, AGC_MAX_GAIN_DB);
+            let rate = match wanted < self.gain_db {
+                true => AGC_ATTACK,
+                false => AGC_RELEASE,
+            };
+            next_gain_db += (wanted - self.gain_db) * rate;
+        }
+
+        // Ramp across the frame so the gain change doesn't click
+        let gain = db_to_gain(self.gain_db);
+        let step = (db_to_gain(next_gain_db) - gain) / frame.len() as f32;
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample = mixer::soft_clip(*sample * (gain + step * (i + 1) as f32));
+        }
+
+        self.gain_db = next_gain_db;
+    }
+}
+
+impl Default for AutoGain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+/// Mixed playback handed from the output stream to the input stream for echo cancellation.
+/// Only the last few frames are kept so a stalled input doesn't fall further and further behind.
+#[derive(Clone)]
+pub struct EchoReference {
+    sender: Sender<[f32; INPUT_FRAME_SIZE]>,
+    receiver: Receiver<[f32; INPUT_FRAME_SIZE]>,
+}
+
+impl EchoReference {
+    pub fn new() -> EchoReference {
+        let (sender, receiver) = bounded(ECHO_REFERENCE_FRAMES);
+        EchoReference { sender, receiver }
+    }
+
+    /// Keep a mono copy of a stereo frame that was just played
+    pub fn push(&self, played: &[f32]) {
+        let mut mono = [0.0; INPUT_FRAME_SIZE];
+        for (i, sample) in mono.iter_mut().enumerate() {
+            *sample = (played[i * 2] + played[i * 2 + 1]) * 0.5;
+        }
+
+        if self.sender.try_send(mono).is_err() {
+            let _ = self.receiver.try_recv();
+            let _ = self.sender.try_send(mono);
+        }
+    }
+
+    /// The oldest frame played that hasn't been matched up yet
+    pub fn pop(&self) -> Option<[f32; INPUT_FRAME_SIZE]> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Forget what was played, like when the input restarts and timing starts over
+    pub fn clear(&self) {
+        while self.receiver.try_recv().is_ok() {}
+    }
+}
+
+impl Default for EchoReference {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The enabled stages run in order on each recorded frame: echo cancellation first
+/// so the other stages don't mistake echo for speech, then noise suppression, then gain
+pub struct CaptureProcessor {
+    echo_canceller: Option<EchoCanceller>,
+    noise_suppressor: Option<NoiseSuppressor>,
+    auto_gain: Option<AutoGain>,
+}
+
+impl CaptureProcessor {
+    pub fn new(settings: ProcessingSettings) -> CaptureProcessor {
+        CaptureProcessor {
+            echo_canceller: settings.echo_cancellation.then(EchoCanceller::new),
+            noise_suppressor: settings.noise_suppression.then(NoiseSuppressor::new),
+            auto_gain: settings.auto_gain.then(AutoGain::new),
+        }
+    }
+
+    /// Clean up one mono frame of `INPUT_FRAME_SIZE` samples in place,
+    /// given the mono frame played back around the time it was recorded
+    pub fn process(&mut self, frame: &mut [f32], playback: Option<&[f32]>) {
+        if let Some(echo_canceller) = &mut self.echo_canceller {
+            echo_canceller.process(frame, playback);
+        }
+        if let Some(noise_suppressor) = &mut self.noise_suppressor {
+            noise_suppressor.process(frame);
+        }
+        if let Some(auto_gain) = &mut self.auto_gain {
+            auto_gain.process(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Repeatable white noise between -amplitude and amplitude
+    fn noise(samples: usize, amplitude: f32) -> Vec<f32> {
+        let mut state: u32 = 0x1234_5678;
+        (0..samples)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state as f32 / u32::MAX as f32 * 2.0 - 1.0) * amplitude
+            })
+            .collect()
+    }
+
+    fn sine(samples: usize, amplitude: f32) -> Vec<f32> {
+        (0..samples)
+            .map(|n| (n as f32 * 440.0 * std::f32::consts::TAU / 48000.0).sin() * amplitude)
+            .collect()
+    }
+
+    #[test]
+    fn echo_fades_once_the_canceller_has_learned_it() {
+        // The microphone hears the speakers a little quieter and a few samples late
+        let frames = 200;
+        let delay = 24;
+        let played = noise(frames * INPUT_FRAME_SIZE + delay, 0.5);
+        let recorded: Vec<f32> = played.iter().map(|sample| sample * 0.3).collect();
+
+        let mut echo_canceller = EchoCanceller::new();
+        let mut levels = Vec::new();
+        for i in 0..frames {
+            let start = i * INPUT_FRAME_SIZE + delay;
+            let playback = &played[start..start + INPUT_FRAME_SIZE];
+            let mut frame = recorded[start - delay..start - delay + INPUT_FRAME_SIZE].to_vec();
+            echo_canceller.process(&mut frame, Some(playback));
+            levels.push(frame_level(&frame));
+        }
+
+        assert!(levels[frames - 1] < levels[0] - 30.0, "{:?}", levels);
+    }
+
+    #[test]
+    fn auto_gain_brings_quiet_and_loud_speech_to_the_target() {
+        for amplitude in [0.01, 0.7] {
+            let speech = sine(400 * INPUT_FRAME_SIZE, amplitude);
+            let mut auto_gain = AutoGain::new();
+            let mut level = 0.0;
+            for chunk in speech.chunks(INPUT_FRAME_SIZE) {
+                let mut frame = chunk.to_vec();
+                auto_gain.process(&mut frame);
+                level = frame_level(&frame);
+            }
+
+            assert!(
+                (level - AGC_TARGET_DB).abs() < 1.0,
+                "amplitude {} ended at {} dB",
+                amplitude,
+                level
+            );
+        }
+    }
+
+    #[test]
+    fn auto_gain_leaves_background_noise_alone() {
+        let mut frame = noise(INPUT_FRAME_SIZE, 0.001);
+        let original = frame.clone();
+        let mut auto_gain = AutoGain::new();
+        auto_gain.process(&mut frame);
+        assert_eq!(frame, original);
+    }
+
+    #[test]
+    fn noise_suppression_keeps_silence_silent_and_samples_in_range() {
+        let mut noise_suppressor = NoiseSuppressor::new();
+        let mut frame = vec![0.0; INPUT_FRAME_SIZE];
+        noise_suppressor.process(&mut frame);
+        assert!(frame.iter().all(|sample| sample.abs() < 1e-6));
+
+        let speech = sine(20 * INPUT_FRAME_SIZE, 0.5);
+        for chunk in speech.chunks(INPUT_FRAME_SIZE) {
+            let mut frame = chunk.to_vec();
+            noise_suppressor.process(&mut frame);
+            assert!(frame.iter().all(|sample| sample.abs() <= 1.0));
+        }
+    }
+
+    #[test]
+    fn disabled_stages_leave_frames_untouched() {
+        let mut processor = CaptureProcessor::new(ProcessingSettings::default());
+        let playback = noise(INPUT_FRAME_SIZE, 0.5);
+        let mut frame = sine(INPUT_FRAME_SIZE, 0.5);
+        let original = frame.clone();
+
+        processor.process(&mut frame, Some(&playback));
+        assert_eq!(frame, original);
+    }
+
+    #[test]
+    fn capture_processor_runs_the_enabled_stages() {
+        let settings = ProcessingSettings {
+            auto_gain: true,
+            ..ProcessingSettings::default()
+        };
+        let mut processor = CaptureProcessor::new(settings);
+        let mut auto_gain = AutoGain::new();
+
+        let speech = sine(INPUT_FRAME_SIZE, 0.01);
+        let mut frame = speech.clone();
+        let mut expected = speech.clone();
+        processor.process(&mut frame, None);
+        auto_gain.process(&mut expected);
+
+        assert_ne!(frame, speech);
+        assert_eq!(frame, expected);
+    }
+}
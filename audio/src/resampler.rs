@@ -0,0 +1,159 @@
+/// Linearly resamples interleaved audio a block at a time,
+/// carrying its place over so blocks join up without clicks
+pub struct Resampler {
+    channels: usize,
+    // Input samples per output sample
+    step: f64,
+    // Where the next output sample falls, counting the last sample of the previous block as 0
+    position: f64,
+    // Last sample of each channel from the previous block
+    last: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Resampler {
+        Resampler {
+            channels,
+            step: from_rate as f64 / to_rate as f64,
+            position: 1.0,
+            last: vec![0.0; channels],
+        }
+    }
+
+    /// Resample a block of whole interleaved frames, adding the result to `output`
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.step == 1.0 {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        let frames = input.len() / self.channels;
+        if frames == 0 {
+            return;
+        }
+
+        // Frame 0 is the last one of the previous block, frame n is input frame n - 1
+        let sample = |frame: usize, channel: usize| match frame {
+            0 => self.last[channel],
+            frame => input[(frame - 1) * self.channels + channel],
+        };
+
+        while self.position <= frames as f64 {
+            let frame = self.position as usize;
+            let fraction = (self.position - frame as f64) as f32;
+            let next = (frame + 1).min(frames);
+            for channel in 0..self.channels {
+                let current = sample(frame, channel);
+                output.push(current + (sample(next, channel) - current) * fraction);
+            }
+            self.position += self.step;
+        }
+
+        self.position -= frames as f64;
+        self.last
+            .copy_from_slice(&input[(frames - 1) * self.channels..frames * self.channels]);
+    }
+}
+
+/// Collects audio arriving in blocks of any size and hands it on in frames of one size
+pub struct FrameAccumulator {
+    frame_size: usize,
+    pending: Vec<f32>,
+}
+
+impl FrameAccumulator {
+    pub fn new(frame_size: usize) -> FrameAccumulator {
+        FrameAccumulator {
+            frame_size,
+            pending: Vec::with_capacity(frame_size * 2),
+        }
+    }
+
+    /// Add samples, calling `on_frame` for each whole frame now available
+    pub fn push(&mut self, samples: &[f32], mut on_frame: impl FnMut(&[f32])) {
+        self.pending.extend_from_slice(samples);
+
+        let mut start = 0;
+        while self.pending.len() - start >= self.frame_size {
+            on_frame(&self.pending[start..start + self.frame_size]);
+            start += self.frame_size;
+        }
+        self.pending.drain(..start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_rate_passes_audio_through() {
+        let mut resampler = Resampler::new(48000, 48000, 2);
+        let input = [0.1, 0.2, 0.3, 0.4];
+        let mut output = Vec::new();
+        resampler.process(&input, &mut output);
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn upsampling_fills_in_between_samples() {
+        let mut resampler = Resampler::new(24000, 48000, 1);
+        let mut output = Vec::new();
+        resampler.process(&[1.0, 2.0, 3.0], &mut output);
+        assert_eq!(output, [1.0, 1.5, 2.0, 2.5, 3.0]);
+
+        // The next block picks up halfway from where this one ended
+        output.clear();
+        resampler.process(&[4.0, 5.0], &mut output);
+        assert_eq!(output, [3.5, 4.0, 4.5, 5.0]);
+    }
+
+    #[test]
+    fn blocks_join_up_as_if_resampled_in_one_go() {
+        let input: Vec<f32> = (0..960).map(|i| (i as f32 * 0.01).sin()).collect();
+
+        let mut whole = Vec::new();
+        Resampler::new(44100, 48000, 2).process(&input, &mut whole);
+
+        let mut resampler = Resampler::new(44100, 48000, 2);
+        let mut blocks = Vec::new();
+        for block in input.chunks(98) {
+            resampler.process(block, &mut blocks);
+        }
+
+        assert_eq!(whole.len(), blocks.len());
+        for (a, b) in whole.iter().zip(&blocks) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn resampled_length_follows_the_rate() {
+        let mut resampler = Resampler::new(44100, 48000, 1);
+        let mut output = Vec::new();
+        for _ in 0..100 {
+            resampler.process(&[0.0; 441], &mut output);
+        }
+        assert!(output.len().abs_diff(48000) <= 1);
+    }
+
+    #[test]
+    fn frames_are_handed_on_whole() {
+        let mut accumulator = FrameAccumulator::new(480);
+        let input: Vec<f32> = (0..1000).map(|i| i as f32).collect();
+        let mut frames: Vec<Vec<f32>> = Vec::new();
+
+        accumulator.push(&input[..300], |frame| frames.push(frame.to_vec()));
+        assert!(frames.is_empty());
+
+        accumulator.push(&input[300..], |frame| frames.push(frame.to_vec()));
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], input[..480]);
+        assert_eq!(frames[1], input[480..960]);
+
+        // The last 40 samples wait for the next push
+        accumulator.push(&input[..440], |frame| frames.push(frame.to_vec()));
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2][..40], input[960..]);
+    }
+}
@@ -1,31 +1,47 @@
 use std::collections::VecDeque;
 use std::io::prelude::*;
+use std::path::PathBuf;
 use std::process::Command;
 use std::process::Stdio;
 use std::sync::mpsc::Receiver;
-use std::{net::SocketAddr, path::PathBuf};
 
 use message::message::{MessageHeader, MessageType};
 use user::User;
 
+use audio::backend::{InputBackend, OutputBackend};
 use clap::Parser;
-use client::client::Client;
+use client::client::{resolve_server_address, Client};
 use rodio::{Decoder, Source};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Address to connect to.
-    /// Must be in `127.0.0.1:5000` or `[::1]:5000` format
+    /// Must be in `host:port`, `127.0.0.1:5000` or `[::1]:5000` format
     #[arg(short, long)]
-    address: SocketAddr,
+    address: String,
 
     /// Username to log in with
     #[arg(short, long)]
     username: String,
 
+    /// Directory holding the server's `cert.pem`
     #[arg(short, long)]
-    cert_dir: PathBuf,
+    cert_dir: Option<PathBuf>,
+
+    /// Name to verify the server's certificate against
+    #[arg(long)]
+    server_name: Option<String>,
+
+    /// Join the first voice channel and record from `device`, `null`, `tone`, `tone:<hz>`
+    /// or `file:<path>` (WAV or Ogg Vorbis) instead of waiting for commands
+    #[arg(long)]
+    audio_input: Option<InputBackend>,
+
+    /// Join the first voice channel and play to `device`, `null` or `wav:<path>`
+    /// instead of waiting for commands
+    #[arg(long)]
+    audio_output: Option<OutputBackend>,
 }
 
 enum BotCommand {
@@ -35,7 +51,25 @@ enum BotCommand {
 fn main() {
     let args = Args::parse();
 
-    let mut client = Client::new(args.address, args.username, args.cert_dir);
+    // The bot has no TUI, so the client can log straight to the terminal
+    tracing_subscriber::fmt::init();
+
+    let (address, host) = match resolve_server_address(&args.address) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            println!("Failed to resolve {}: {}", args.address, e);
+            std::process::exit(1);
+        }
+    };
+
+    let server_name = args.server_name.unwrap_or(host);
+    let mut client = Client::new(address, server_name, args.username, args.cert_dir);
+
+    // Either backend puts the bot in voice, so calls can be tested without a sound card
+    let stay_in_voice = args.audio_input.is_some() || args.audio_output.is_some();
+    client.set_audio_input_backend(args.audio_input.unwrap_or(InputBackend::Null));
+    client.set_audio_output_backend(args.audio_output.unwrap_or(OutputBackend::Null));
+
     client.run_client();
 
     let (send, recv): (
@@ -87,6 +121,39 @@ fn main() {
                     user = Some(our_user.clone());
                     client.set_user(our_user);
                     println!("Logged in");
+
+                    // Realm changes (like joining a voice channel) only arrive once we've synced
+                    client.get_realms();
+                }
+                MessageType::Realms((_, realms)) => {
+                    if stay_in_voice && current_voice_channel.is_none() {
+                        let channel =
+                            realms
+                                .iter()
+                                .min_by_key(|realm| realm.id)
+                                .and_then(|realm| {
+                                    realm
+                                        .voice_channels
+                                        .iter()
+                                        .min_by_key(|channel| channel.0)
+                                        .map(|channel| (realm.id, channel.0))
+                                });
+
+                        match channel {
+                            Some((realm_id, channel_id)) => {
+                                println!(
+                                    "Joining voice channel {} in realm {}",
+                                    channel_id, realm_id
+                                );
+                                client.join_channel(
+                                    realm_id,
+                                    realms::realm::ChannelType::VoiceChannel,
+                                    channel_id,
+                                );
+                            }
+                            None => println!("No voice channel to join"),
+                        }
+                    }
                 }
                 MessageType::Text((header, mut chunks)) => {
                     if let Some(message) = chunks.pop() {
@@ -169,6 +236,10 @@ fn main() {
                     if let Some(ref user) = user {
                         if header.user_id == user.get_id() {
                             current_voice_channel = Some(header);
+
+                            if stay_in_voice {
+                                client.connect_voice(header.realm_id, header.channel_id);
+                            }
                         }
                     }
                 }
@@ -193,7 +264,7 @@ fn main() {
         }
 
         // Leave a voice channel if audio isn't being broadcasted anymore
-        if !client.is_broadcasting_audio() && current_voice_channel.is_some() {
+        if !stay_in_voice && !client.is_broadcasting_audio() && current_voice_channel.is_some() {
             let channel = current_voice_channel.unwrap();
             client.hang_up(channel.realm_id, channel.channel_id);
 
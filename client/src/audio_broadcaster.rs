@@ -2,6 +2,7 @@ use crate::audio_file_buffer::AudioFileBuffer;
 use message::message::{Message, MessageHeader, MessageType};
 
 use chrono::Utc;
+use types::AudioSequenceSize;
 
 pub struct AudioBroadcaster {
     // Current header to send audio over
@@ -9,6 +10,9 @@ pub struct AudioBroadcaster {
 
     // Place to hold our audio buffers to broadcast
     audio_file_buffer: AudioFileBuffer,
+
+    // Sequence number of the next frame sent
+    sequence: AudioSequenceSize,
 }
 
 impl AudioBroadcaster {
@@ -16,6 +20,7 @@ impl AudioBroadcaster {
         AudioBroadcaster {
             voice_header: None,
             audio_file_buffer: AudioFileBuffer::new(),
+            sequence: 0,
         }
     }
 
@@ -36,7 +41,9 @@ impl AudioBroadcaster {
             Some(mut header) => match self.audio_file_buffer.get_next_frame() {
                 Some(audio) => {
                     header.datetime = Some(Utc::now());
-                    Some(Message::from(MessageType::Audio((header, audio))))
+                    let sequence = self.sequence;
+                    self.sequence = self.sequence.wrapping_add(1);
+                    Some(Message::from(MessageType::Audio((header, sequence, audio))))
                 }
                 None => None,
             },
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use audio::capture::CaptureSettings;
+use audio::encoding::EncoderSettings;
+use audio::mixer::{self, Volumes};
+use audio::processing::ProcessingSettings;
+
+use crate::data_dir::kagu_data_dir;
+
+/// Audio devices and voice settings picked by the user, remembered between launches.
+///
+/// Each line holds a key and a value separated by `=`,
+/// like `input=USB Microphone`, `capture=vad` or `volume=0.5 example.com:5000 alice`
+/// for alice's volume on that server.
+#[derive(Debug, Default)]
+pub struct AudioSettings {
+    path: PathBuf,
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    pub capture: CaptureSettings,
+    pub volumes: Volumes,
+    /// Volume of each user turned up or down, by server and username.
+    /// User ids change every time someone connects, so they can't be used.
+    pub user_volumes: BTreeMap<(String, String), f32>,
+    pub processing: ProcessingSettings,
+    pub encoder: EncoderSettings,
+}
+
+impl AudioSettings {
+    /// Load the audio settings file at `path`. A missing file is treated as empty.
+    pub fn load(path: PathBuf) -> io::Result<AudioSettings> {
+        let mut settings = AudioSettings {
+            path,
+            ..Default::default()
+        };
+
+        match fs::read_to_string(&settings.path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+
+                    match line.split_once('=') {
+                        Some(("input", device)) => settings.input_device = Some(device.to_string()),
+                        Some(("output", device)) => {
+                            settings.output_device = Some(device.to_string())
+                        }
+                        Some(("capture", mode)) => {
+                            if let Ok(mode) = mode.parse() {
+                                settings.capture.mode = mode;
+                            }
+                        }
+                        Some(("voice_threshold", threshold)) => {
+                            if let Ok(threshold) = threshold.parse() {
+                                settings.capture.voice_threshold = threshold;
+                            }
+                        }
+                        Some(("hang_time_ms", hang_time)) => {
+                            if let Ok(hang_time) = hang_time.parse() {
+                                settings.capture.hang_time = Duration::from_millis(hang_time);
+                            }
+                        }
+                        Some(("dtx", dtx)) => {
+                            if let Ok(dtx) = dtx.parse() {
+                                settings.capture.dtx = dtx;
+                            }
+                        }
+                        Some(("noise_suppression", enabled)) => {
+                            if let Ok(enabled) = enabled.parse() {
+                                settings.processing.noise_suppression = enabled;
+                            }
+                        }
+                        Some(("echo_cancellation", enabled)) => {
+                            if let Ok(enabled) = enabled.parse() {
+                                settings.processing.echo_cancellation = enabled;
+                            }
+                        }
+                        Some(("auto_gain", enabled)) => {
+                            if let Ok(enabled) = enabled.parse() {
+                                settings.processing.auto_gain = enabled;
+                            }
+                        }
+                        Some(("bitrate", bitrate)) => {
+                            if let Ok(bitrate) = bitrate.parse() {
+                                settings.encoder.bitrate = bitrate;
+                            }
+                        }
+                        Some(("complexity", complexity)) => {
+                            if let Ok(complexity) = complexity.parse() {
+                                settings.encoder.complexity = complexity;
+                            }
+                        }
+                        Some(("stereo", stereo)) => {
+                            if let Ok(stereo) = stereo.parse() {
+                                settings.encoder.stereo = stereo;
+                            }
+                        }
+                        Some(("application", application)) => {
+                            if let Ok(application) = application.parse() {
+                                settings.encoder.application = application;
+                            }
+                        }
+                        Some(("input_gain", gain)) => {
+                            if let Ok(gain) = gain.parse() {
+                                settings.volumes.input_gain = mixer::clamp_volume(gain);
+                            }
+                        }
+                        Some(("output_gain", gain)) => {
+                            if let Ok(gain) = gain.parse() {
+                                settings.volumes.output_gain = mixer::clamp_volume(gain);
+                            }
+                        }
+                        Some(("volume", user_volume)) => {
+                            let mut parts = user_volume.splitn(3, ' ');
+                            if let (Some(Ok(volume)), Some(server), Some(username)) =
+                                (parts.next().map(str::parse), parts.next(), parts.next())
+                            {
+                                settings.user_volumes.insert(
+                                    (server.to_string(), username.to_string()),
+                                    mixer::clamp_volume(volume),
+                                );
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        Ok(settings)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut contents = String::new();
+        if let Some(device) = &self.input_device {
+            contents.push_str(format!("input={}\n", device).as_str());
+        }
+        if let Some(device) = &self.output_device {
+            contents.push_str(format!("output={}\n", device).as_str());
+        }
+        contents.push_str(format!("capture={}\n", self.capture.mode).as_str());
+        contents.push_str(format!("voice_threshold={}\n", self.capture.voice_threshold).as_str());
+        contents
+            .push_str(format!("hang_time_ms={}\n", self.capture.hang_time.as_millis()).as_str());
+        contents.push_str(format!("dtx={}\n", self.capture.dtx).as_str());
+        contents.push_str(
+            format!("noise_suppression={}\n", self.processing.noise_suppression).as_str(),
+        );
+        contents.push_str(
+            format!("echo_cancellation={}\n", self.processing.echo_cancellation).as_str(),
+        );
+        contents.push_str(format!("auto_gain={}\n", self.processing.auto_gain).as_str());
+        contents.push_str(format!("bitrate={}\n", self.encoder.bitrate).as_str());
+        contents.push_str(format!("complexity={}\n", self.encoder.complexity).as_str());
+        contents.push_str(format!("stereo={}\n", self.encoder.stereo).as_str());
+        contents.push_str(format!("application={}\n", self.encoder.application).as_str());
+        contents.push_str(format!("input_gain={}\n", self.volumes.input_gain).as_str());
+        contents.push_str(format!("output_gain={}\n", self.volumes.output_gain).as_str());
+        for ((server, username), volume) in &self.user_volumes {
+            contents.push_str(format!("volume={} {} {}\n", volume, server, username).as_str());
+        }
+
+        fs::write(&self.path, contents)
+    }
+}
+
+pub fn default_audio_settings_path() -> PathBuf {
+    let mut path = kagu_data_dir();
+    path.push("audio");
+    path
+}
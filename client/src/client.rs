@@ -1,35 +1,99 @@
-use crate::client_handler::ClientHandler;
+use crate::audio_settings::AudioSettings;
+use crate::client_handler::{ClientHandler, ClientHandlerChannels};
 use crate::client_message::ClientMessage;
+use crate::data_dir::kagu_data_dir;
+use crate::known_hosts::{HostTrust, KnownHosts};
 use audio::audio_manager::AudioManager;
-use message::message::{Message, MessageHeader, MessageType};
+use audio::backend::{InputBackend, OutputBackend};
+use audio::capture::CaptureSettings;
+use audio::encoding::{EncoderApplication, EncoderSettings, EncoderTarget, VoiceEncoder};
+use audio::levels::AudioLevel;
+use audio::mixer::Volumes;
+use audio::processing::ProcessingSettings;
+use message::message::{Message, MessageHeader, MessageType, Password};
+use message::voice_state::VoiceState;
 use network_manager::*;
 use realms::realm::ChannelType;
 use types::*;
 use user::User;
 
-use std::collections::VecDeque;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use crossbeam::channel::{Receiver, Sender};
-use opus::Encoder;
 use swiftlet_quic::endpoint::{Config, Endpoint};
 use swiftlet_quic::EndpointHandler;
+use tracing::{error, info, info_span, warn};
+
+/// Locations of CA bundles on common systems, used when no `cert_dir` is given
+const SYSTEM_CA_BUNDLES: [&str; 5] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+    "/usr/local/etc/openssl/cert.pem",
+];
+
+/// How long to wait for the server's certificate before connecting without checking it
+const CERTIFICATE_FETCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Resolve a `host:port` address, returning the socket address to connect to
+/// along with the host name the server's certificate should be verified against.
+pub fn resolve_server_address(address: &str) -> std::io::Result<(SocketAddr, String)> {
+    let socket_address = match address.to_socket_addrs()?.next() {
+        Some(socket_address) => socket_address,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} did not resolve to any address", address),
+            ))
+        }
+    };
+
+    let host = match address.rsplit_once(':') {
+        Some((host, _port)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => address,
+    };
+
+    Ok((socket_address, host.to_string()))
+}
 
 #[derive(Debug)]
 pub struct Client {
     server_address: SocketAddr,
+    server_name: String,
     username: String,
     user: Option<User>,
-    cert_dir: PathBuf,
+    cert_dir: Option<PathBuf>,
+    known_hosts_path: Option<PathBuf>,
+    // Pinned and presented fingerprints if the server's certificate changed, in which case we don't connect
+    certificate_changed: Option<(String, String, String)>,
+    // Sent after logging in to prove we're one of the server's moderators
+    moderator_password: Option<String>,
+    // Set once the server accepts it
+    is_moderator: Mutex<bool>,
     audio_manager: AudioManager,
+    // Where device choices are remembered, if they should be
+    audio_settings: Option<AudioSettings>,
+    // Voice channel we're connected to, if any
+    voice_channel: Option<(RealmIdSize, ChannelIdSize)>,
+    // Bitrate limit of each voice channel, kept from realm updates
+    voice_bitrate_limits: Mutex<BTreeMap<(RealmIdSize, ChannelIdSize), u32>>,
+    // Usernames of everyone online, so volumes can be remembered by name
+    usernames: Mutex<BTreeMap<UserIdSize, String>>,
+    // Text channels whose history we've asked for since the last full copy of the realms
+    requested_histories: Mutex<BTreeSet<(RealmIdSize, ChannelIdSize)>>,
     incoming_sender: Sender<Message>,
     incoming_receiver: Receiver<Message>,
     outgoing_sender: Sender<Message>,
     outgoing_receiver: Receiver<Message>,
     audio_in_sender: Sender<Message>,
+    // Also read by the audio manager while listening
+    audio_in_receiver: Receiver<Message>,
 
     // Channel used to send messages from the inner event loop to this client
     el_to_client_sender: Sender<ClientMessage>,
@@ -50,7 +114,16 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new(server_address: SocketAddr, username: String, cert_dir: PathBuf) -> Client {
+    /// Create a client for the server at `server_address`.
+    ///
+    /// The server's certificate is verified against `server_name`. If `cert_dir` is `None`,
+    /// a certificate pinned by trust-on-first-use or the system CA bundle is used instead.
+    pub fn new(
+        server_address: SocketAddr,
+        server_name: String,
+        username: String,
+        cert_dir: Option<PathBuf>,
+    ) -> Client {
         let (outgoing_sender, outgoing_receiver): (Sender<Message>, Receiver<Message>) =
             crossbeam::channel::bounded(10);
 
@@ -72,23 +145,34 @@ impl Client {
 
         Client {
             server_address,
+            server_name,
             username,
             user: None,
             cert_dir,
+            known_hosts_path: None,
+            certificate_changed: None,
+            moderator_password: None,
+            is_moderator: Mutex::new(false),
 
             audio_manager: AudioManager::new(
                 // Use our outgoing sender for all messages as the audio sender
                 outgoing_sender.clone(),
-                audio_in_receiver,
+                audio_in_receiver.clone(),
                 // Set a dummy MessageHeader for now
                 MessageHeader::new(0, 0, 0),
             ),
+            audio_settings: None,
+            voice_channel: None,
+            voice_bitrate_limits: Mutex::new(BTreeMap::new()),
+            usernames: Mutex::new(BTreeMap::new()),
+            requested_histories: Mutex::new(BTreeSet::new()),
 
             incoming_sender,
             incoming_receiver,
             outgoing_sender,
             outgoing_receiver,
             audio_in_sender,
+            audio_in_receiver,
             el_to_client_sender,
             el_to_client_receiver,
             is_connected: false,
@@ -101,6 +185,43 @@ impl Client {
         }
     }
 
+    /// Pin the server's certificate in `known_hosts_path` the first time it is seen.
+    /// If it ever changes, connecting is refused and a `CertificateChanged` message is sent.
+    pub fn enable_trust_on_first_use(&mut self, known_hosts_path: PathBuf) {
+        self.known_hosts_path = Some(known_hosts_path);
+    }
+
+    /// Log in as one of the server's moderators with the password from its config
+    pub fn set_moderator_password(&mut self, password: String) {
+        self.moderator_password = Some(password);
+    }
+
+    /// Whether the server accepted our moderator password
+    pub fn is_moderator(&self) -> bool {
+        *self.is_moderator.lock().unwrap()
+    }
+
+    /// Use the audio devices saved in `audio_settings_path` and save any picked later
+    pub fn load_audio_settings(&mut self, audio_settings_path: PathBuf) {
+        match AudioSettings::load(audio_settings_path) {
+            Ok(settings) => {
+                if let Some(device) = &settings.input_device {
+                    self.audio_manager.set_audio_input(device.clone());
+                }
+                if let Some(device) = &settings.output_device {
+                    self.audio_manager.set_audio_output(device.clone());
+                }
+                self.audio_manager.set_capture_settings(settings.capture);
+                self.audio_manager
+                    .set_processing_settings(settings.processing);
+                self.audio_manager.set_encoder_settings(settings.encoder);
+                self.audio_manager.set_volumes(settings.volumes.clone());
+                self.audio_settings = Some(settings);
+            }
+            Err(e) => error!("failed to load audio settings: {}", e),
+        }
+    }
+
     pub fn run_client(&mut self) {
         let bind_address = match self.server_address.is_ipv6() {
             true => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
@@ -108,11 +229,14 @@ impl Client {
         };
 
         let server_address = self.server_address;
-        let outgoing_receiver = self.outgoing_receiver.clone();
-        let incoming_sender = self.incoming_sender.clone();
-        let audio_in_sender = self.audio_in_sender.clone();
-        let el_to_client_sender = self.el_to_client_sender.clone();
-        let client_to_el_receiver = self.client_to_el_receiver.clone();
+        let channels = ClientHandlerChannels {
+            outgoing_receiver: self.outgoing_receiver.clone(),
+            incoming_sender: self.incoming_sender.clone(),
+            audio_in_sender: self.audio_in_sender.clone(),
+            el_to_client_sender: self.el_to_client_sender.clone(),
+            client_to_el_receiver: self.client_to_el_receiver.clone(),
+        };
+        let recording_tap = self.audio_manager.get_recording_tap();
         let is_broadcasting = self.is_broadcasting.clone();
         let is_preparing_audio = self.is_preparing_audio.clone();
 
@@ -129,39 +253,64 @@ impl Client {
             rt_recv_first_bytes: 0,
         };
 
-        let (cert, _pkey) = self.get_pem_paths(&self.cert_dir);
+        let host = format!("{}:{}", self.server_name, self.server_address.port());
+        let mut known_hosts = match &self.known_hosts_path {
+            Some(path) => match KnownHosts::load(path.clone()) {
+                Ok(known_hosts) => Some(known_hosts),
+                Err(e) => {
+                    error!("failed to load known hosts from {}: {}", path.display(), e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // A cert_dir is trusted as given, otherwise check what the server presents against what we pinned
+        if self.cert_dir.is_none() {
+            if let Some(known_hosts) = known_hosts.as_mut() {
+                if !self.check_pinned_certificate(&host, known_hosts) {
+                    return;
+                }
+            }
+        }
+
+        let cert = match self.get_trust_anchor(&host, known_hosts.as_ref()) {
+            Some(cert) => cert,
+            None => {
+                error!("no certificate available to verify the server with");
+                return;
+            }
+        };
+
+        let server_name = self.server_name.clone();
+        let span = info_span!("connection", server = %host, username = %self.username);
 
         let client_thread = std::thread::spawn(move || {
+            let _span = span.entered();
+
             let mut client_endpoint = match Endpoint::new_client_with_first_connection(
                 bind_address.is_ipv6(),
-                b"kagu",
+                ALPN_NAME,
                 cert.as_str(),
                 server_address,
-                "localhost",
+                server_name.as_str(),
                 config,
             ) {
                 Ok(endpoint) => endpoint,
-                Err(_) => {
-                    eprintln!("Failed to create client endpoint");
+                Err(e) => {
+                    error!("failed to create client endpoint: {:?}", e);
                     return;
                 }
             };
 
-            let mut client_handler = ClientHandler::new(
-                outgoing_receiver,
-                incoming_sender,
-                audio_in_sender,
-                el_to_client_sender,
-                client_to_el_receiver,
-                is_broadcasting,
-                is_preparing_audio,
-            );
+            let mut client_handler =
+                ClientHandler::new(channels, recording_tap, is_broadcasting, is_preparing_audio);
             let mut rtc_handler = EndpointHandler::new(&mut client_endpoint, &mut client_handler);
 
             match rtc_handler.run_event_loop(std::time::Duration::from_millis(5)) {
                 Ok(_) => (),
                 Err(e) => {
-                    eprintln!("Error running event loop: {:?}", e)
+                    error!("error running event loop: {:?}", e)
                 }
             }
         });
@@ -169,6 +318,95 @@ impl Client {
         self.event_loop_handle = Some(client_thread);
     }
 
+    /// Check the certificate the server presents in its TLS handshake against the one pinned for `host`,
+    /// pinning it the first time the host is seen. Returns false if it changed.
+    ///
+    /// The pinned certificate is then the only one the connection trusts, so a server
+    /// that can't prove it holds the pinned certificate's key can't be connected to.
+    fn check_pinned_certificate(&mut self, host: &str, known_hosts: &mut KnownHosts) -> bool {
+        let certificate = match network_manager::certificate::fetch_server_certificate(
+            self.server_address,
+            &self.server_name,
+            CERTIFICATE_FETCH_TIMEOUT,
+        ) {
+            Ok(certificate) => certificate,
+            Err(e) => {
+                // Connecting still verifies the server against what's pinned, or the system CAs
+                error!("failed to fetch the server's certificate: {}", e);
+                return true;
+            }
+        };
+
+        let fingerprint = network_manager::certificate::fingerprint(&certificate);
+        match known_hosts.check(host, &fingerprint) {
+            HostTrust::Trusted => true,
+            // The server told us about this one over a connection we trusted
+            HostTrust::Rotated => {
+                info!(%host, %fingerprint, "pinning the server's rotated certificate");
+                if let Err(e) = known_hosts.pin(host, &fingerprint, &certificate) {
+                    error!("failed to pin certificate for {}: {}", host, e);
+                }
+                true
+            }
+            HostTrust::FirstUse => {
+                info!(%host, %fingerprint, "pinning the server's certificate on first use");
+                if let Err(e) = known_hosts.pin(host, &fingerprint, &certificate) {
+                    error!("failed to pin certificate for {}: {}", host, e);
+                }
+                true
+            }
+            HostTrust::Changed(pinned) => {
+                warn!(%host, %pinned, presented = %fingerprint, "server certificate changed");
+
+                let changed = (host.to_string(), pinned, fingerprint);
+                let message = Message::from(MessageType::CertificateChanged(changed.clone()));
+                let _ = self.incoming_sender.try_send(message);
+                self.certificate_changed = Some(changed);
+                false
+            }
+        }
+    }
+
+    /// Trust the certificate the server is rotating to once it's presented, if we pinned the current one.
+    /// With a `cert_dir` the connection wasn't verified against the pin, so nothing is learned from it.
+    fn expect_certificate_rotation(&self, fingerprint: &str) {
+        let path = match (&self.known_hosts_path, &self.cert_dir) {
+            (Some(path), None) => path,
+            _ => return,
+        };
+
+        let host = format!("{}:{}", self.server_name, self.server_address.port());
+        let expected = KnownHosts::load(path.clone())
+            .and_then(|mut known_hosts| known_hosts.expect_rotation(&host, fingerprint));
+        match expected {
+            Ok(()) => info!(%host, %fingerprint, "the server is rotating its certificate"),
+            Err(e) => error!("failed to save {}'s upcoming certificate: {}", host, e),
+        }
+    }
+
+    /// The pinned and presented fingerprints if we refused to connect because the server's certificate changed
+    pub fn get_certificate_change(&self) -> Option<&(String, String, String)> {
+        self.certificate_changed.as_ref()
+    }
+
+    /// Pick the certificate (or CA bundle) to verify the server against.
+    /// An explicit `cert_dir` wins, followed by a pinned certificate and then the system CAs.
+    fn get_trust_anchor(&self, host: &str, known_hosts: Option<&KnownHosts>) -> Option<String> {
+        if let Some(cert_dir) = &self.cert_dir {
+            let (cert, _pkey) = self.get_pem_paths(cert_dir);
+            return Some(cert);
+        }
+
+        if let Some(pinned) = known_hosts.and_then(|k| k.get_pinned_certificate(host)) {
+            return pinned.to_str().map(String::from);
+        }
+
+        SYSTEM_CA_BUNDLES
+            .iter()
+            .find(|bundle| Path::new(bundle).exists())
+            .map(|bundle| bundle.to_string())
+    }
+
     fn get_pem_paths(&self, cert_dir: &Path) -> (String, String) {
         let mut cert = cert_dir.to_str().unwrap().to_string();
         cert.push_str("/cert.pem");
@@ -205,6 +443,69 @@ impl Client {
                 let mut messages = Vec::new();
 
                 while let Ok(message) = self.incoming_receiver.try_recv() {
+                    match &message.message {
+                        MessageType::LoginSuccess(_) => {
+                            if let Some(password) = &self.moderator_password {
+                                let password = Password(password.clone());
+                                self.send(Message::from(MessageType::ModeratorLogin(password)));
+                            }
+                        }
+                        MessageType::UserJoined(user) => self.learn_user(user),
+                        MessageType::AllUsers(users) => {
+                            for user in users {
+                                self.learn_user(user);
+                            }
+                        }
+                        MessageType::UserLeft(user_id) => {
+                            self.usernames.lock().unwrap().remove(user_id);
+                            self.audio_manager.set_user_volume(*user_id, 1.0);
+                        }
+                        MessageType::ModeratorLoginResult(proven) => {
+                            if !proven {
+                                warn!("the server didn't accept our moderator password");
+                            }
+                            *self.is_moderator.lock().unwrap() = *proven;
+                        }
+                        // Stop sending audio as soon as a moderator mutes us
+                        MessageType::VoiceStateUpdate((header, state))
+                            if self.is_own_user(header.user_id) =>
+                        {
+                            self.audio_manager.set_server_muted(state.server_muted);
+                        }
+                        MessageType::Realms((_, realms)) => {
+                            // A full copy replaces any history we had, so it can be asked for again
+                            self.requested_histories.lock().unwrap().clear();
+
+                            let mut limits = self.voice_bitrate_limits.lock().unwrap();
+                            limits.clear();
+                            for realm in realms {
+                                for channel in &realm.voice_channels {
+                                    limits.insert((realm.id, channel.0), channel.3);
+                                }
+                            }
+                            drop(limits);
+                            self.apply_voice_bitrate_limit();
+                        }
+                        MessageType::VoiceBitrateChanged((realm_id, channel_id, limit)) => {
+                            self.voice_bitrate_limits
+                                .lock()
+                                .unwrap()
+                                .insert((*realm_id, *channel_id), *limit);
+                            self.apply_voice_bitrate_limit();
+                        }
+                        // Let the encoder follow how the connection is doing
+                        MessageType::PingLatency(round_trip) => {
+                            self.audio_manager.report_round_trip(*round_trip);
+                        }
+                        MessageType::AudioLoss((expected, received)) => {
+                            self.audio_manager.report_audio_loss(*expected, *received);
+                        }
+                        MessageType::UpcomingCertificate(fingerprint) => {
+                            self.expect_certificate_rotation(fingerprint);
+                        }
+                        _ => (),
+                    }
+
                     messages.push(message);
                 }
 
@@ -227,6 +528,37 @@ impl Client {
         }
     }
 
+    /// Connect to the server again after losing the connection, like after a restart.
+    /// Log in once `is_connected` is true.
+    pub fn reconnect(&mut self) {
+        // The old event loop exits once its connection is gone
+        if let Some(handle) = self.event_loop_handle.take() {
+            let _ = handle.join();
+        }
+
+        self.is_connected = false;
+        self.user = None;
+
+        // Drop anything left over from the old connection
+        while self.el_to_client_receiver.try_recv().is_ok() {}
+        while self.outgoing_receiver.try_recv().is_ok() {}
+
+        info!("reconnecting to {}", self.server_name);
+        self.run_client();
+    }
+
+    /// Audio received while not listening, for clients without audio devices.
+    /// Don't use this after `connect_voice`, the audio manager is reading the same messages.
+    pub fn get_new_audio(&self) -> Vec<Message> {
+        let mut messages = Vec::new();
+
+        while let Ok(message) = self.audio_in_receiver.try_recv() {
+            messages.push(message);
+        }
+
+        messages
+    }
+
     pub fn log_in(&self) {
         let message = Message::from(MessageType::LoginAttempt(self.username.clone()));
         self.send(message);
@@ -296,13 +628,20 @@ impl Client {
             // Start recording and sending
             match self.audio_manager.start_recording() {
                 Ok(_) => (),
-                Err(_) => println!("FAILED TO START LISTENING"),
+                Err(e) => error!("failed to start recording: {:?}", e),
             }
 
             // Let the voices be heard
             match self.audio_manager.start_listening() {
                 Ok(_) => (),
-                Err(_) => println!("FAILED TO START RECORDING"),
+                Err(e) => error!("failed to start listening: {:?}", e),
+            }
+
+            // Let the channel know if we joined muted or deafened
+            self.voice_channel = Some((realm_id, channel_id));
+            self.apply_voice_bitrate_limit();
+            if self.get_voice_state() != VoiceState::default() {
+                self.send_voice_state();
             }
         }
     }
@@ -346,6 +685,42 @@ impl Client {
         }
     }
 
+    /// Limit the bitrate of a voice channel in bits per second, 0 for no limit
+    pub fn set_voice_bitrate(
+        &self,
+        realm_id: RealmIdSize,
+        channel_id: ChannelIdSize,
+        max_bitrate: u32,
+    ) {
+        if let Some(user) = &self.user {
+            let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+            let message = Message::from(MessageType::SetVoiceBitrate((header, max_bitrate)));
+            self.send(message);
+        }
+    }
+
+    /// Have the server mix a voice channel into one stream for each listener, or stop
+    pub fn set_voice_mixing(&self, realm_id: RealmIdSize, channel_id: ChannelIdSize, mixing: bool) {
+        if let Some(user) = &self.user {
+            let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+            let message = Message::from(MessageType::SetVoiceMixing((header, mixing)));
+            self.send(message);
+        }
+    }
+
+    pub fn set_slow_mode(
+        &self,
+        realm_id: RealmIdSize,
+        channel_id: ChannelIdSize,
+        slow_mode_secs: u32,
+    ) {
+        if let Some(user) = &self.user {
+            let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+            let message = Message::from(MessageType::SetSlowMode((header, slow_mode_secs)));
+            self.send(message);
+        }
+    }
+
     pub fn add_realm(&self, realm_name: String) {
         if let Some(user) = &self.user {
             let header = MessageHeader::new(user.get_id(), 0, 0);
@@ -387,8 +762,13 @@ impl Client {
     }
 
     pub fn hang_up(&mut self, realm_id: RealmIdSize, channel_id: ChannelIdSize) {
+        self.stop_call_recording();
         self.audio_manager.stop_recording();
         self.audio_manager.stop_listening();
+        // Don't carry a held push-to-talk key over into the next channel joined
+        self.audio_manager.release_push_to_talk();
+        self.voice_channel = None;
+        self.apply_voice_bitrate_limit();
 
         let _ = self
             .client_to_el_sender
@@ -411,6 +791,20 @@ impl Client {
         }
     }
 
+    /// Ask for a text channel's history, unless we already have
+    pub fn get_channel_history(&self, realm_id: RealmIdSize, channel_id: ChannelIdSize) {
+        if let Some(user) = &self.user {
+            let mut requested = self.requested_histories.lock().unwrap();
+            if !requested.insert((realm_id, channel_id)) {
+                return;
+            }
+
+            let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+            let message = Message::from(MessageType::GetChannelHistory(header));
+            self.send(message);
+        }
+    }
+
     pub fn get_all_users(&self) {
         if let Some(user) = &self.user {
             let message = Message::from(MessageType::GetAllUsers(MessageHeader::new(
@@ -430,12 +824,333 @@ impl Client {
         self.audio_manager.get_audio_outputs()
     }
 
+    pub fn get_selected_audio_input(&self) -> Option<String> {
+        self.audio_manager.get_selected_audio_input()
+    }
+
+    pub fn get_selected_audio_output(&self) -> Option<String> {
+        self.audio_manager.get_selected_audio_output()
+    }
+
+    /// Switches over straight away if we're in a call
     pub fn set_audio_input(&mut self, input_name: String) {
-        self.audio_manager.set_audio_input(input_name)
+        self.audio_manager.set_audio_input(input_name.clone());
+
+        if let Some(settings) = &mut self.audio_settings {
+            settings.input_device = Some(input_name);
+            if let Err(e) = settings.save() {
+                error!("failed to save audio settings: {}", e);
+            }
+        }
     }
 
+    /// Switches over straight away if we're in a call
     pub fn set_audio_output(&mut self, output_name: String) {
-        self.audio_manager.set_audio_output(output_name);
+        self.audio_manager.set_audio_output(output_name.clone());
+
+        if let Some(settings) = &mut self.audio_settings {
+            settings.output_device = Some(output_name);
+            if let Err(e) = settings.save() {
+                error!("failed to save audio settings: {}", e);
+            }
+        }
+    }
+
+    pub fn get_capture_settings(&self) -> CaptureSettings {
+        self.audio_manager.get_capture_settings()
+    }
+
+    /// Change when recorded audio is sent. Takes effect straight away.
+    pub fn set_capture_settings(&mut self, capture: CaptureSettings) {
+        self.audio_manager.set_capture_settings(capture);
+
+        if let Some(settings) = &mut self.audio_settings {
+            settings.capture = capture;
+            if let Err(e) = settings.save() {
+                error!("failed to save audio settings: {}", e);
+            }
+        }
+    }
+
+    pub fn get_processing_settings(&self) -> ProcessingSettings {
+        self.audio_manager.get_processing_settings()
+    }
+
+    /// Turn noise suppression, echo cancellation and automatic gain on or off.
+    /// Takes effect straight away.
+    pub fn set_processing_settings(&mut self, processing: ProcessingSettings) {
+        self.audio_manager.set_processing_settings(processing);
+
+        if let Some(settings) = &mut self.audio_settings {
+            settings.processing = processing;
+            if let Err(e) = settings.save() {
+                error!("failed to save audio settings: {}", e);
+            }
+        }
+    }
+
+    pub fn get_encoder_settings(&self) -> EncoderSettings {
+        self.audio_manager.get_encoder_settings()
+    }
+
+    /// Change how our voice is encoded. Takes effect straight away.
+    pub fn set_encoder_settings(&mut self, encoder: EncoderSettings) {
+        self.audio_manager.set_encoder_settings(encoder);
+
+        if let Some(settings) = &mut self.audio_settings {
+            settings.encoder = self.audio_manager.get_encoder_settings();
+            if let Err(e) = settings.save() {
+                error!("failed to save audio settings: {}", e);
+            }
+        }
+    }
+
+    /// Bitrate and FEC our voice is encoded with right now, after adapting to the connection
+    pub fn get_encoder_target(&self) -> EncoderTarget {
+        self.audio_manager.get_encoder_target()
+    }
+
+    /// Bitrate limit of a voice channel, 0 if it has none
+    pub fn get_voice_bitrate_limit(&self, realm_id: RealmIdSize, channel_id: ChannelIdSize) -> u32 {
+        self.voice_bitrate_limits
+            .lock()
+            .unwrap()
+            .get(&(realm_id, channel_id))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // Keep under the limit of the voice channel we're in
+    fn apply_voice_bitrate_limit(&self) {
+        let limit = match self.voice_channel {
+            Some((realm_id, channel_id)) => self.get_voice_bitrate_limit(realm_id, channel_id),
+            None => 0,
+        };
+        self.audio_manager.set_channel_bitrate_limit(limit);
+    }
+
+    /// Keep push-to-talk open for `duration`. Call again while the key is held.
+    pub fn push_to_talk(&self, duration: std::time::Duration) {
+        self.audio_manager.hold_push_to_talk(duration);
+    }
+
+    pub fn get_volumes(&self) -> Volumes {
+        self.audio_manager.get_volumes()
+    }
+
+    /// How loud we're heard, from 0.0 (silent) to `audio::mixer::MAX_VOLUME`
+    pub fn set_input_gain(&mut self, gain: f32) {
+        self.audio_manager.set_input_gain(gain);
+        self.save_volumes();
+    }
+
+    /// How loud everyone is played, from 0.0 (silent) to `audio::mixer::MAX_VOLUME`
+    pub fn set_output_gain(&mut self, gain: f32) {
+        self.audio_manager.set_output_gain(gain);
+        self.save_volumes();
+    }
+
+    /// How loud one user is played, on top of the output gain.
+    /// Remembered by their username on this server.
+    pub fn set_user_volume(&mut self, user_id: UserIdSize, volume: f32) {
+        self.audio_manager.set_user_volume(user_id, volume);
+
+        let username = self.usernames.lock().unwrap().get(&user_id).cloned();
+        let server = self.server_key();
+        if let (Some(settings), Some(username)) = (&mut self.audio_settings, username) {
+            let volume = self.audio_manager.get_volumes().get_user_volume(user_id);
+            match volume == 1.0 {
+                true => settings.user_volumes.remove(&(server, username)),
+                false => settings.user_volumes.insert((server, username), volume),
+            };
+        }
+
+        self.save_volumes();
+    }
+
+    pub fn get_user_volume(&self, user_id: UserIdSize) -> f32 {
+        self.audio_manager.get_volumes().get_user_volume(user_id)
+    }
+
+    /// This server as volumes are remembered for it
+    fn server_key(&self) -> String {
+        format!("{}:{}", self.server_name, self.server_address.port())
+    }
+
+    /// Keep track of who a user is, playing them at the volume we last gave them
+    fn learn_user(&self, user: &User) {
+        self.usernames
+            .lock()
+            .unwrap()
+            .insert(user.get_id(), user.get_username().to_string());
+
+        if let Some(settings) = &self.audio_settings {
+            let key = (self.server_key(), user.get_username().to_string());
+            if let Some(volume) = settings.user_volumes.get(&key) {
+                self.audio_manager.set_user_volume(user.get_id(), *volume);
+            }
+        }
+    }
+
+    fn save_volumes(&mut self) {
+        if let Some(settings) = &mut self.audio_settings {
+            settings.volumes = self.audio_manager.get_volumes();
+            if let Err(e) = settings.save() {
+                error!("failed to save audio settings: {}", e);
+            }
+        }
+    }
+
+    /// Mute or unmute our microphone for everyone. Unmuting also undeafens.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.audio_manager.set_muted(muted);
+        if !muted {
+            self.audio_manager.set_deafened(false);
+        }
+        self.send_voice_state();
+    }
+
+    /// Stop or resume hearing everyone else. Deafening also mutes, and undeafening unmutes.
+    pub fn set_deafened(&mut self, deafened: bool) {
+        self.audio_manager.set_deafened(deafened);
+        self.audio_manager.set_muted(deafened);
+        self.send_voice_state();
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.audio_manager.is_muted()
+    }
+
+    pub fn is_deafened(&self) -> bool {
+        self.audio_manager.is_deafened()
+    }
+
+    /// Whether a moderator has muted us
+    pub fn is_server_muted(&self) -> bool {
+        self.audio_manager.is_server_muted()
+    }
+
+    /// Record the voice channel we're in to an Ogg Opus file in `~/.kagu/recordings`,
+    /// letting everyone in it know. Returns where it's being saved.
+    pub fn start_call_recording(&mut self) -> Option<PathBuf> {
+        let (realm_id, channel_id) = self.voice_channel?;
+        let user_id = self.user.as_ref()?.get_id();
+
+        let mut path = kagu_data_dir();
+        path.push("recordings");
+        if let Err(e) = std::fs::create_dir_all(&path) {
+            error!("failed to create {}: {}", path.display(), e);
+            return None;
+        }
+
+        let now = chrono::Local::now();
+        path.push(format!(
+            "{}-{}-{}.opus",
+            realm_id,
+            channel_id,
+            now.format("%Y%m%d-%H%M%S")
+        ));
+        let comments = [format!("DATE={}", now.to_rfc3339())];
+
+        if let Err(e) = self.audio_manager.start_call_recording(&path, &comments) {
+            error!("failed to start recording the call: {:?}", e);
+            return None;
+        }
+
+        let header = MessageHeader::new(user_id, realm_id, channel_id);
+        self.send(Message::from(MessageType::VoiceRecording((header, true))));
+        Some(path)
+    }
+
+    /// Finish recording the call, returning where it was saved
+    pub fn stop_call_recording(&mut self) -> Option<PathBuf> {
+        let path = self.audio_manager.stop_call_recording()?;
+
+        if let Some(user) = &self.user {
+            let (realm_id, channel_id) = self.voice_channel.unwrap_or((0, 0));
+            let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+            self.send(Message::from(MessageType::VoiceRecording((header, false))));
+        }
+        Some(path)
+    }
+
+    pub fn is_call_recording(&self) -> bool {
+        self.audio_manager.is_call_recording()
+    }
+
+    pub fn get_voice_state(&self) -> VoiceState {
+        VoiceState {
+            muted: self.is_muted(),
+            deafened: self.is_deafened(),
+            server_muted: self.is_server_muted(),
+        }
+    }
+
+    /// Tell everyone whether we're muted or deafened
+    fn send_voice_state(&self) {
+        if let Some(user) = &self.user {
+            let (realm_id, channel_id) = self.voice_channel.unwrap_or((0, 0));
+            let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+            let message = Message::from(MessageType::VoiceStateUpdate((
+                header,
+                self.get_voice_state(),
+            )));
+            self.send(message);
+        }
+    }
+
+    /// Mute or unmute someone for everyone. Only works for the server's moderators.
+    pub fn server_mute(&self, user_id: UserIdSize, muted: bool) {
+        if let Some(user) = &self.user {
+            let (realm_id, channel_id) = self.voice_channel.unwrap_or((0, 0));
+            let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+            let message = Message::from(MessageType::ServerMute((header, user_id, muted)));
+            self.send(message);
+        }
+    }
+
+    fn is_own_user(&self, user_id: UserIdSize) -> bool {
+        match &self.user {
+            Some(user) => user.get_id() == user_id,
+            None => false,
+        }
+    }
+
+    /// Reopen audio devices that were unplugged mid-call. Call this regularly.
+    pub fn check_audio_devices(&mut self) {
+        self.audio_manager.check_devices();
+    }
+
+    /// How loud we and everyone we hear have been since this was last called
+    pub fn get_audio_levels(&self) -> Vec<AudioLevel> {
+        self.audio_manager.get_audio_levels()
+    }
+
+    /// Keep the microphone open to measure it even outside a call, like for a level meter.
+    /// Does nothing if it's already in that state.
+    pub fn set_input_monitoring(&mut self, monitoring: bool) {
+        if monitoring == self.audio_manager.is_monitoring_input() {
+            return;
+        }
+
+        if !monitoring {
+            self.audio_manager.stop_monitoring_input();
+            return;
+        }
+
+        if let Err(e) = self.audio_manager.start_monitoring_input() {
+            error!("failed to monitor audio input: {:?}", e);
+        }
+    }
+
+    /// Takes effect the next time voice is connected
+    pub fn set_audio_input_backend(&mut self, backend: InputBackend) {
+        self.audio_manager.set_input_backend(backend);
+    }
+
+    /// Takes effect the next time voice is connected
+    pub fn set_audio_output_backend(&mut self, backend: OutputBackend) {
+        self.audio_manager.set_output_backend(backend);
     }
 
     /// This is to be called prior to transferring a file
@@ -451,11 +1166,17 @@ impl Client {
     /// Once a FileTransferApproved message is received this may be called.
     pub fn upload_file(&self, _transfer_id: FileTransferIdSize, _file_path: PathBuf) {}
 
-    /// Audio sent using this should be sampled at 48000Hz and in 10ms chunks
-    pub fn send_audio_frame(&self, mut header: MessageHeader, audio: Vec<u8>) {
+    /// Audio sent using this should be sampled at 48000Hz and in 10ms chunks.
+    /// `sequence` should go up by one with each frame.
+    pub fn send_audio_frame(
+        &self,
+        mut header: MessageHeader,
+        sequence: AudioSequenceSize,
+        audio: Vec<u8>,
+    ) {
         if let Some(_user) = &self.user {
             header.datetime = Some(chrono::Utc::now());
-            self.send(Message::from(MessageType::Audio((header, audio))));
+            self.send(Message::from(MessageType::Audio((header, sequence, audio))));
         }
     }
 
@@ -471,10 +1192,27 @@ impl Client {
         let mut guard = self.is_preparing_audio.lock().unwrap();
         *guard = true;
 
+        // Buffers are usually music, so they're kept in stereo at the bitrate we can send at now
+        let settings = EncoderSettings {
+            stereo: true,
+            application: EncoderApplication::Music,
+            ..self.audio_manager.get_encoder_settings()
+        };
+        let target = self.audio_manager.get_encoder_target();
+
         // Maybe encode this in a background thread to prevent blocking
         let _thread_handle = std::thread::spawn(move || {
-            let mut encoder =
-                Encoder::new(48000, opus::Channels::Stereo, opus::Application::Audio).unwrap();
+            let mut encoder = match VoiceEncoder::new(settings) {
+                Ok(encoder) => encoder,
+                Err(e) => {
+                    error!("failed to create an encoder to broadcast with: {:?}", e);
+                    let _ = sender.send(ClientMessage::BroadcastBuffer(Vec::new()));
+                    return;
+                }
+            };
+            if let Err(e) = encoder.set_target(target) {
+                error!("failed to set the broadcast bitrate: {:?}", e);
+            }
 
             // Create a buffer of opus frame buffers
             // Each element will have 10ms of opus-encoded audio
@@ -502,7 +1240,7 @@ impl Client {
                 }
 
                 // Encode this 10ms
-                if let Ok(encoded_frame) = encoder.encode_vec_float(frame.as_slice(), 480 * 8) {
+                if let Ok(encoded_frame) = encoder.encode_stereo(frame.as_slice()) {
                     encoded_frames.push(encoded_frame);
                 }
             }
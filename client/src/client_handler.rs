@@ -1,18 +1,32 @@
 use crate::audio_broadcaster::AudioBroadcaster;
 use crate::client_message::ClientMessage;
 use crate::ping_counter::PingCounter;
+use audio::call_recording::RecordingTap;
 use message::message::{Message, MessageType};
 use network_manager::*;
+use types::RealmsVersionSize;
 use user::User;
 
 use crossbeam::channel::{Receiver, Sender};
 use swiftlet_quic::endpoint::{ConnectionEndReason, ConnectionId, Endpoint};
 use swiftlet_quic::EndpointEventCallbacks;
+use tracing::{debug, info, info_span};
 
 use std::sync::{Arc, Mutex};
 
+/// The channels the event loop uses to talk to the `Client`
+pub struct ClientHandlerChannels {
+    pub outgoing_receiver: Receiver<Message>,
+    pub incoming_sender: Sender<Message>,
+    pub audio_in_sender: Sender<Message>,
+    pub el_to_client_sender: Sender<ClientMessage>,
+    pub client_to_el_receiver: Receiver<ClientMessage>,
+}
+
 pub struct ClientHandler {
     connected: bool,
+    // Set when the connection ends so the event loop can exit
+    connection_lost: bool,
     user: Option<User>,
     connection_id: Option<ConnectionId>,
     outgoing_receiver: Receiver<Message>,
@@ -20,6 +34,7 @@ pub struct ClientHandler {
     audio_in_sender: Sender<Message>,
     el_to_client_sender: Sender<ClientMessage>,
     client_to_el_receiver: Receiver<ClientMessage>,
+    recording_tap: RecordingTap,
     ping_counter: PingCounter,
     audio_broadcaster: AudioBroadcaster,
     broadcast_audio: bool,
@@ -27,20 +42,28 @@ pub struct ClientHandler {
     send_idx: u8,
     is_broadcasting: Arc<Mutex<bool>>,
     is_preparing_audio: Arc<Mutex<bool>>,
+    // Version of the realms we were last synced to, None until we have a full copy
+    realms_version: Option<RealmsVersionSize>,
 }
 
 impl ClientHandler {
     pub fn new(
-        outgoing_receiver: Receiver<Message>,
-        incoming_sender: Sender<Message>,
-        audio_in_sender: Sender<Message>,
-        el_to_client_sender: Sender<ClientMessage>,
-        client_to_el_receiver: Receiver<ClientMessage>,
+        channels: ClientHandlerChannels,
+        recording_tap: RecordingTap,
         is_broadcasting: Arc<Mutex<bool>>,
         is_preparing_audio: Arc<Mutex<bool>>,
     ) -> Self {
+        let ClientHandlerChannels {
+            outgoing_receiver,
+            incoming_sender,
+            audio_in_sender,
+            el_to_client_sender,
+            client_to_el_receiver,
+        } = channels;
+
         ClientHandler {
             connected: false,
+            connection_lost: false,
             user: None,
             connection_id: None,
             outgoing_receiver,
@@ -48,6 +71,7 @@ impl ClientHandler {
             audio_in_sender,
             el_to_client_sender,
             client_to_el_receiver,
+            recording_tap,
             ping_counter: PingCounter::new(),
             audio_broadcaster: AudioBroadcaster::new(),
             broadcast_audio: true,
@@ -55,32 +79,83 @@ impl ClientHandler {
             send_idx: 0,
             is_broadcasting,
             is_preparing_audio,
+            realms_version: None,
         }
     }
 
-    fn process_message(&mut self, _cid: &ConnectionId, message: Message, _endpoint: &mut Endpoint) {
+    fn process_message(&mut self, _cid: &ConnectionId, message: Message, endpoint: &mut Endpoint) {
+        let _span = self.user.as_ref().map(|user| {
+            info_span!("user", id = user.get_id(), name = user.get_username()).entered()
+        });
+
         match message.message {
-            MessageType::Audio(_) => {
+            MessageType::Audio((header, sequence, ref audio)) => {
+                // Recorded here rather than at playback so deafening doesn't leave gaps
+                self.recording_tap.send(header.user_id, sequence, audio);
+
                 // Lazy fix to prevent blocking
                 // todo: implement a way to not receive audio
                 if !self.audio_in_sender.is_full() {
                     self.audio_in_sender.send(message).unwrap();
                 }
             }
+            // The server measuring our latency
+            MessageType::Ping(ping_id) => {
+                let message = Message::from(MessageType::PingReply(ping_id));
+                self.send_message(false, endpoint, message);
+            }
             MessageType::PingReply(_) => {
                 let duration = self.ping_counter.get_rtt_latency();
                 let message = Message::from(MessageType::PingLatency(duration));
                 let _ = self.incoming_sender.try_send(message);
             }
             MessageType::LoginSuccess(ref user) => {
+                info!(user_id = user.get_id(), "logged in");
+
                 // Save our user in the event loop
                 self.user = Some(user.clone());
                 self.incoming_sender.send(message).unwrap();
             }
+            MessageType::Realms((version, _)) => {
+                self.realms_version = Some(version);
+                self.incoming_sender.send(message).unwrap();
+            }
+            MessageType::RealmsDelta((version, delta)) => {
+                self.apply_realms_delta(version, *delta, endpoint)
+            }
             _ => self.incoming_sender.send(message).unwrap(),
         }
     }
 
+    /// Pass along a realm change if it's the next one, otherwise resync
+    fn apply_realms_delta(
+        &mut self,
+        version: RealmsVersionSize,
+        delta: MessageType,
+        endpoint: &mut Endpoint,
+    ) {
+        match self.realms_version {
+            Some(current) if version == current + 1 => {
+                self.realms_version = Some(version);
+                self.incoming_sender.send(Message::from(delta)).unwrap();
+            }
+            // Already part of the realms we have
+            Some(current) if version <= current => (),
+            // We missed a change, so get everything again
+            Some(current) => {
+                debug!(current, version, "missed a realms change, resyncing");
+                self.realms_version = None;
+
+                if let Some(user) = &self.user {
+                    let message = Message::from(MessageType::GetRealms(user.get_id()));
+                    self.send_message(false, endpoint, message);
+                }
+            }
+            // Waiting on a full copy that will include this change
+            None => (),
+        }
+    }
+
     #[inline]
     fn get_message_size(&self, read_data: &[u8]) -> usize {
         usize::from_ne_bytes([read_data[0], read_data[1], 0, 0, 0, 0, 0, 0])
@@ -125,6 +200,7 @@ impl ClientHandler {
 
 impl EndpointEventCallbacks for ClientHandler {
     fn connection_started(&mut self, _endpoint: &mut Endpoint, cid: &ConnectionId) {
+        info!("connected to server");
         self.connected = true;
         self.connection_id = Some(*cid);
 
@@ -137,13 +213,17 @@ impl EndpointEventCallbacks for ClientHandler {
         &mut self,
         _endpoint: &mut Endpoint,
         _cid: &ConnectionId,
-        _reason: ConnectionEndReason,
+        reason: ConnectionEndReason,
         _remaining_connections: usize,
     ) -> bool {
+        info!("connection to server ended: {:?}", reason);
+
         // Deal with multiple servers later
+        self.connected = false;
+        self.connection_lost = true;
         let _ = self
             .incoming_sender
-            .send(Message::from(MessageType::ServerShutdown));
+            .send(Message::from(MessageType::ConnectionLost));
 
         // if let Some(my_conn_id) = &self.connection_id {
         //     if *my_conn_id == *cid {
@@ -155,6 +235,11 @@ impl EndpointEventCallbacks for ClientHandler {
     }
 
     fn tick(&mut self, endpoint: &mut Endpoint) -> bool {
+        // Nothing left to do without a connection, reconnecting starts a new event loop
+        if self.connection_lost {
+            return true;
+        }
+
         let mut exit = false;
 
         if let Some(time) = self.ping_counter.last_ping() {
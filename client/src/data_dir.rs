@@ -0,0 +1,11 @@
+use std::path::PathBuf;
+
+/// Directory Kagu keeps its client-side state in (`~/.kagu`).
+pub fn kagu_data_dir() -> PathBuf {
+    let mut dir = match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home),
+        None => PathBuf::from("."),
+    };
+    dir.push(".kagu");
+    dir
+}
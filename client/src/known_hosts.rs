@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::data_dir::kagu_data_dir;
+
+/// Result of checking a server certificate against the known hosts file.
+#[derive(Debug, PartialEq)]
+pub enum HostTrust {
+    /// The presented certificate matches the pinned fingerprint
+    Trusted,
+    /// This host has never been seen before
+    FirstUse,
+    /// The host presented the certificate it told us it was rotating to
+    Rotated,
+    /// The host presented a different certificate than the one pinned
+    Changed(String),
+}
+
+/// A known_hosts-style store of pinned server certificate fingerprints.
+///
+/// Each line holds a `host:port` and a fingerprint separated by whitespace, followed by
+/// the fingerprint of the certificate the server said it's rotating to, if it has.
+/// The pinned certificate itself is kept next to the file so later
+/// connections can verify the server without a `cert_dir`.
+#[derive(Debug)]
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: BTreeMap<String, String>,
+    upcoming: BTreeMap<String, String>,
+}
+
+impl KnownHosts {
+    /// Load the known hosts file at `path`. A missing file is treated as empty.
+    pub fn load(path: PathBuf) -> io::Result<KnownHosts> {
+        let mut hosts = BTreeMap::new();
+        let mut upcoming = BTreeMap::new();
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+
+                    let mut parts = line.split_whitespace();
+                    if let (Some(host), Some(fingerprint)) = (parts.next(), parts.next()) {
+                        hosts.insert(host.to_string(), fingerprint.to_string());
+                        if let Some(next) = parts.next() {
+                            upcoming.insert(host.to_string(), next.to_string());
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        Ok(KnownHosts {
+            path,
+            hosts,
+            upcoming,
+        })
+    }
+
+    pub fn check(&self, host: &str, fingerprint: &str) -> HostTrust {
+        let upcoming = self.upcoming.get(host).map(String::as_str);
+        match self.hosts.get(host) {
+            Some(pinned) if pinned == fingerprint => HostTrust::Trusted,
+            Some(_) if upcoming == Some(fingerprint) => HostTrust::Rotated,
+            Some(pinned) => HostTrust::Changed(pinned.clone()),
+            None => HostTrust::FirstUse,
+        }
+    }
+
+    /// Pin a host's certificate and write the known hosts file back to disk.
+    pub fn pin(&mut self, host: &str, fingerprint: &str, certificate: &[u8]) -> io::Result<()> {
+        self.hosts.insert(host.to_string(), fingerprint.to_string());
+        self.upcoming.remove(host);
+        network_manager::certificate::save_certificate_pem(
+            &self.pinned_certificate_path(host),
+            certificate,
+        )?;
+        self.save()
+    }
+
+    /// Remember the certificate a pinned host said it's rotating to, so it's trusted when
+    /// presented. Only call this over a connection verified against the pinned certificate.
+    pub fn expect_rotation(&mut self, host: &str, fingerprint: &str) -> io::Result<()> {
+        if !self.hosts.contains_key(host)
+            || self.upcoming.get(host).map(String::as_str) == Some(fingerprint)
+        {
+            return Ok(());
+        }
+
+        self.upcoming
+            .insert(host.to_string(), fingerprint.to_string());
+        self.save()
+    }
+
+    /// Path to the pinned certificate for `host`, if one has been saved.
+    pub fn get_pinned_certificate(&self, host: &str) -> Option<PathBuf> {
+        let path = self.pinned_certificate_path(host);
+        match self.hosts.contains_key(host) && path.exists() {
+            true => Some(path),
+            false => None,
+        }
+    }
+
+    fn pinned_certificate_path(&self, host: &str) -> PathBuf {
+        let file_name: String = host
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+
+        let mut path = self
+            .path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        path.push("pinned");
+        path.push(file_name + ".pem");
+        path
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut contents = String::new();
+        for (host, fingerprint) in &self.hosts {
+            let line = match self.upcoming.get(host) {
+                Some(next) => format!("{} {} {}\n", host, fingerprint, next),
+                None => format!("{} {}\n", host, fingerprint),
+            };
+            contents.push_str(line.as_str());
+        }
+
+        fs::write(&self.path, contents)
+    }
+}
+
+pub fn default_known_hosts_path() -> PathBuf {
+    let mut path = kagu_data_dir();
+    path.push("known_hosts");
+    path
+}
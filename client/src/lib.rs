@@ -1,6 +1,10 @@
 mod audio_broadcaster;
 mod audio_file_buffer;
+pub mod audio_settings;
 pub mod client;
 mod client_handler;
 mod client_message;
+pub mod data_dir;
+pub mod known_hosts;
+pub mod logging;
 mod ping_counter;
@@ -0,0 +1,31 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use tracing::level_filters::LevelFilter;
+
+use crate::data_dir::kagu_data_dir;
+
+/// Where the client logs to unless told otherwise (`~/.kagu/kagu.log`)
+pub fn default_log_path() -> PathBuf {
+    let mut path = kagu_data_dir();
+    path.push("kagu.log");
+    path
+}
+
+/// Send log lines to a file. Anything written to the terminal would corrupt the TUI.
+pub fn init_file_logging(path: &Path, level: LevelFilter) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(false)
+        .with_writer(Mutex::new(file))
+        .try_init()
+        .map_err(io::Error::other)
+}
@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use opus::Decoder;
+use tracing::{debug, warn};
+use types::AudioSequenceSize;
+
+/// Voice is sent in 10ms frames
+pub const FRAME_DURATION: Duration = Duration::from_millis(10);
+
+/// Stereo samples in a 10ms frame at 48kHz
+pub const FRAME_SIZE: usize = 960;
+
+// Frames held back before playing even on a perfect connection
+const MIN_DELAY_FRAMES: usize = 2;
+
+// Lost frames concealed in a row before deciding the speaker has gone quiet
+const MAX_CONCEALED_FRAMES: u32 = 5;
+
+// A frame this far behind means the speaker started counting again
+const RESTART_DISTANCE: i16 = -500;
+
+// How long a quiet speaker's decoder is kept around
+const SPEAKER_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type Frame = [f32; FRAME_SIZE];
+
+/// How a frame is played when its turn comes
+#[derive(Debug, PartialEq)]
+enum Playout {
+    Frame(Vec<u8>),
+    // Lost, but rebuilt from the FEC in the frame after it
+    Recovered(Vec<u8>),
+    // Lost with nothing to rebuild it from, so the decoder fills the gap
+    Concealed,
+}
+
+/// Audio from one speaker, decoded in order. Clients play it back and the server mixes it.
+///
+/// Frames are held back long enough to ride out the jitter seen so far, put back in order
+/// and dropped if they turn up after their turn. Gaps are filled from the Opus in-band FEC
+/// carried by the next frame if it's here, or concealed otherwise.
+pub struct JitterBuffer {
+    // Opus decoders keep state between frames, so every speaker needs their own
+    decoder: Decoder,
+    // Most frames held back, so a bad connection can't add too much lag
+    max_delay_frames: usize,
+    // Encoded frames waiting to be played, by sequence number counted past wrap-arounds
+    frames: BTreeMap<i64, Vec<u8>>,
+    // Next frame to play, unset while buffering
+    next_sequence: Option<i64>,
+    // Anything at or before this has had its turn
+    last_played: Option<i64>,
+    // Newest frame received and when it arrived
+    newest: Option<(i64, Instant)>,
+    // Smoothed difference between when frames arrive and when they were due, in seconds
+    jitter: f64,
+    concealed_frames: u32,
+    last_heard: Instant,
+}
+
+impl JitterBuffer {
+    /// A buffer holding back at most `max_delay_frames` frames
+    pub fn new(max_delay_frames: usize) -> Result<JitterBuffer, opus::Error> {
+        Ok(JitterBuffer {
+            decoder: Decoder::new(48000, opus::Channels::Stereo)?,
+            max_delay_frames,
+            frames: BTreeMap::new(),
+            next_sequence: None,
+            last_played: None,
+            newest: None,
+            jitter: 0.0,
+            concealed_frames: 0,
+            last_heard: Instant::now(),
+        })
+    }
+
+    pub fn push(&mut self, sequence: AudioSequenceSize, frame: Vec<u8>, now: Instant) {
+        self.last_heard = now;
+
+        let sequence = match self.newest {
+            Some((newest, _)) => {
+                let distance = sequence.wrapping_sub(newest as AudioSequenceSize) as i16;
+                if distance < RESTART_DISTANCE {
+                    debug!("speaker restarted their sequence numbers");
+                    self.frames.clear();
+                    self.next_sequence = None;
+                    self.last_played = None;
+                    self.newest = None;
+                    sequence as i64
+                } else {
+                    newest + distance as i64
+                }
+            }
+            None => sequence as i64,
+        };
+
+        match self.newest {
+            Some((newest, arrived)) if sequence > newest => {
+                // RFC 3550 style: compare the gap in arrival times to the gap in send times
+                let expected = FRAME_DURATION.as_secs_f64() * (sequence - newest) as f64;
+                let difference = now.saturating_duration_since(arrived).as_secs_f64() - expected;
+                self.jitter += (difference.abs() - self.jitter) / 16.0;
+                self.newest = Some((sequence, now));
+            }
+            Some(_) => (),
+            None => self.newest = Some((sequence, now)),
+        }
+
+        if let Some(played) = self.last_played {
+            if sequence <= played {
+                debug!(sequence, "dropping a frame that arrived too late to play");
+                return;
+            }
+        }
+
+        self.frames.entry(sequence).or_insert(frame);
+
+        // Don't let lag build up if frames arrive faster than they're played
+        while self.frames.len() > self.max_delay_frames {
+            if let Some((dropped, _)) = self.frames.pop_first() {
+                debug!(sequence = dropped, "buffer full, dropping a frame");
+            }
+            self.next_sequence = self.frames.keys().next().copied();
+        }
+    }
+
+    /// Frames to hold back before starting to play
+    fn target_delay(&self) -> usize {
+        let jitter_frames = (self.jitter * 3.0 / FRAME_DURATION.as_secs_f64()).ceil() as usize;
+        (MIN_DELAY_FRAMES + jitter_frames).min(self.max_delay_frames)
+    }
+
+    /// Decode the next frame into `output`. Returns false if there's nothing to play.
+    pub fn pop(&mut self, output: &mut Frame) -> bool {
+        let decoded = match self.next_frame() {
+            Some(Playout::Frame(frame)) => self.decoder.decode_float(&frame, output, false),
+            Some(Playout::Recovered(frame)) => self.decoder.decode_float(&frame, output, true),
+            Some(Playout::Concealed) => self.decoder.decode_float(&[], output, false),
+            None => return false,
+        };
+
+        if let Err(e) = decoded {
+            warn!("failed to decode audio: {}", e);
+            output.fill(0.0);
+        }
+
+        true
+    }
+
+    /// Take the next frame's turn and work out how to play it
+    fn next_frame(&mut self) -> Option<Playout> {
+        let next = match self.next_sequence {
+            Some(next) => next,
+            None => {
+                if self.frames.len() < self.target_delay() {
+                    return None;
+                }
+                *self.frames.keys().next()?
+            }
+        };
+
+        let playout = match self.frames.remove(&next) {
+            Some(frame) => {
+                self.concealed_frames = 0;
+                Playout::Frame(frame)
+            }
+            None => {
+                self.concealed_frames += 1;
+                match self.frames.get(&(next + 1)) {
+                    // The next frame carries a rougher copy of this one
+                    Some(frame) => Playout::Recovered(frame.clone()),
+                    None => Playout::Concealed,
+                }
+            }
+        };
+
+        self.last_played = Some(next);
+        self.next_sequence = match self.concealed_frames > MAX_CONCEALED_FRAMES {
+            // They've stopped talking or dropped out, so buffer up again when they're back
+            true => {
+                self.concealed_frames = 0;
+                None
+            }
+            false => Some(next + 1),
+        };
+
+        Some(playout)
+    }
+
+    /// Whether this speaker has been quiet long enough to forget
+    pub fn is_idle(&self, now: Instant) -> bool {
+        self.frames.is_empty() && now.saturating_duration_since(self.last_heard) > SPEAKER_TIMEOUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_DELAY_FRAMES: usize = 20;
+
+    /// A buffer with frames arriving on time, each holding its own sequence number
+    fn buffer_with(sequences: &[AudioSequenceSize]) -> (JitterBuffer, Instant) {
+        let mut buffer = JitterBuffer::new(MAX_DELAY_FRAMES).unwrap();
+        let now = Instant::now();
+        for sequence in sequences {
+            push(&mut buffer, *sequence, now);
+        }
+        (buffer, now)
+    }
+
+    fn push(buffer: &mut JitterBuffer, sequence: AudioSequenceSize, start: Instant) {
+        let arrived = start + FRAME_DURATION * sequence as u32;
+        buffer.push(sequence, sequence.to_le_bytes().to_vec(), arrived);
+    }
+
+    fn frame(sequence: AudioSequenceSize) -> Option<Playout> {
+        Some(Playout::Frame(sequence.to_le_bytes().to_vec()))
+    }
+
+    #[test]
+    fn waits_for_a_few_frames_before_playing() {
+        let (mut buffer, now) = buffer_with(&[0]);
+        assert_eq!(buffer.next_frame(), None);
+
+        push(&mut buffer, 1, now);
+        assert_eq!(buffer.next_frame(), frame(0));
+        assert_eq!(buffer.next_frame(), frame(1));
+    }
+
+    #[test]
+    fn reordered_frames_are_played_in_order() {
+        let mut buffer = JitterBuffer::new(MAX_DELAY_FRAMES).unwrap();
+        let now = Instant::now();
+        for sequence in [0, 2, 1, 4, 3] {
+            push(&mut buffer, sequence, now);
+        }
+
+        for sequence in 0..5 {
+            assert_eq!(buffer.next_frame(), frame(sequence));
+        }
+    }
+
+    #[test]
+    fn lost_frames_are_recovered_from_the_next_one_and_late_ones_dropped() {
+        let (mut buffer, now) = buffer_with(&[0, 1, 3]);
+        assert_eq!(buffer.next_frame(), frame(0));
+        assert_eq!(buffer.next_frame(), frame(1));
+        assert_eq!(
+            buffer.next_frame(),
+            Some(Playout::Recovered(3u16.to_le_bytes().to_vec()))
+        );
+
+        // Frame 2 has had its turn
+        push(&mut buffer, 2, now);
+        assert_eq!(buffer.next_frame(), frame(3));
+        assert!(buffer.frames.is_empty());
+    }
+
+    #[test]
+    fn gaps_are_concealed_until_the_speaker_seems_to_have_stopped() {
+        let (mut buffer, now) = buffer_with(&[0, 1]);
+        assert_eq!(buffer.next_frame(), frame(0));
+        assert_eq!(buffer.next_frame(), frame(1));
+
+        for _ in 0..=MAX_CONCEALED_FRAMES {
+            assert_eq!(buffer.next_frame(), Some(Playout::Concealed));
+        }
+        assert_eq!(buffer.next_frame(), None);
+
+        // When they're back it buffers up again before playing
+        push(&mut buffer, 20, now);
+        assert_eq!(buffer.next_frame(), None);
+        push(&mut buffer, 21, now);
+        assert_eq!(buffer.next_frame(), frame(20));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let max = AudioSequenceSize::MAX;
+        let mut buffer = JitterBuffer::new(MAX_DELAY_FRAMES).unwrap();
+        let now = Instant::now();
+        for sequence in [max - 1, max, 0, 1] {
+            buffer.push(sequence, sequence.to_le_bytes().to_vec(), now);
+        }
+
+        for sequence in [max - 1, max, 0, 1] {
+            assert_eq!(buffer.next_frame(), frame(sequence));
+        }
+    }
+
+    #[test]
+    fn restarted_sequence_numbers_start_over() {
+        let (mut buffer, now) = buffer_with(&[1000, 1001]);
+        assert_eq!(buffer.next_frame(), frame(1000));
+
+        // Far enough behind that they must have rejoined
+        push(&mut buffer, 0, now);
+        push(&mut buffer, 1, now);
+        assert_eq!(buffer.next_frame(), frame(0));
+        assert_eq!(buffer.next_frame(), frame(1));
+    }
+
+    #[test]
+    fn old_frames_are_dropped_when_too_many_are_held() {
+        let sequences: Vec<AudioSequenceSize> = (0..30).collect();
+        let (mut buffer, _) = buffer_with(&sequences);
+        assert_eq!(buffer.frames.len(), MAX_DELAY_FRAMES);
+        assert_eq!(buffer.next_frame(), frame(10));
+    }
+}
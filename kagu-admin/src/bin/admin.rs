@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use server::control::{ControlRequest, ControlResponse, DEFAULT_CONTROL_SOCKET};
+use types::{RealmIdSize, UserIdSize};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The server's control socket
+    #[arg(short, long, default_value = DEFAULT_CONTROL_SOCKET)]
+    socket: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List connected users with their address and latency
+    Users,
+
+    /// List realms, their channels and who is in each voice channel
+    Realms,
+
+    /// Disconnect a user
+    Kick { user_id: UserIdSize },
+
+    /// Disconnect a user and refuse new connections from their address
+    Ban { user_id: UserIdSize },
+
+    /// Allow an address to connect again
+    Unban { address: IpAddr },
+
+    /// List banned addresses
+    Bans,
+
+    /// Send a message to everyone connected
+    Announce { message: String },
+
+    /// Create a new realm
+    CreateRealm { name: String },
+
+    /// Delete a realm and all of its channels
+    DeleteRealm { realm_id: RealmIdSize },
+
+    /// Shut the server down, warning users as it counts down
+    Shutdown {
+        /// Seconds until the server shuts down
+        #[arg(long = "in", default_value_t = 60)]
+        seconds: u64,
+
+        /// Shown to users when the server shuts down
+        #[arg(long)]
+        reason: Option<String>,
+
+        /// Seconds until the server is back. Clients reconnect on their own after this
+        #[arg(long)]
+        restart_in: Option<u64>,
+    },
+
+    /// Stop a pending shutdown
+    CancelShutdown,
+}
+
+#[cfg(unix)]
+fn main() {
+    use server::control::ControlClient;
+
+    let args = Args::parse();
+
+    let mut client = match ControlClient::connect(&args.socket) {
+        Ok(client) => client,
+        Err(e) => {
+            println!(
+                "Error: failed to connect to {}: {}",
+                args.socket.display(),
+                e
+            );
+            println!("Is the server running? Use --socket to point at its control socket");
+            std::process::exit(1);
+        }
+    };
+
+    let mut request = |request: ControlRequest| match client.request(request) {
+        Ok(ControlResponse::Failed(reason)) => {
+            println!("Error: {}", reason);
+            std::process::exit(1);
+        }
+        Ok(response) => response,
+        Err(e) => {
+            println!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match args.command {
+        Command::Users => {
+            if let ControlResponse::Users(mut users) = request(ControlRequest::ListUsers) {
+                users.sort_by_key(|user| user.id);
+
+                println!("{:<6} {:<24} {:<40} LATENCY", "ID", "USERNAME", "ADDRESS");
+                for user in users {
+                    let address = match user.address {
+                        Some(address) => address.to_string(),
+                        None => String::from("-"),
+                    };
+                    let latency = match user.latency {
+                        Some(latency) => format!("{} ms", latency.as_millis()),
+                        None => String::from("-"),
+                    };
+                    println!(
+                        "{:<6} {:<24} {:<40} {}",
+                        user.id, user.username, address, latency
+                    );
+                }
+            }
+        }
+        Command::Realms => {
+            // Used to show names instead of ids for voice channel occupants
+            let usernames: HashMap<UserIdSize, String> = match request(ControlRequest::ListUsers) {
+                ControlResponse::Users(users) => users
+                    .into_iter()
+                    .map(|user| (user.id, user.username))
+                    .collect(),
+                _ => HashMap::new(),
+            };
+
+            if let ControlResponse::Realms(mut realms) = request(ControlRequest::ListRealms) {
+                realms.sort_by_key(|realm| realm.id);
+
+                for realm in realms {
+                    println!("[{}] {}", realm.id, realm.name);
+
+                    let mut text_channels = realm.get_text_channels();
+                    text_channels.sort_by_key(|channel| channel.0);
+                    for (id, name, slow_mode_secs) in text_channels {
+                        match slow_mode_secs {
+                            0 => println!("    [{}] #{}", id, name),
+                            secs => println!("    [{}] #{} (slow mode {}s)", id, name, secs),
+                        }
+                    }
+
+                    let mut voice_channels = realm.get_voice_channels();
+                    voice_channels.sort_by_key(|channel| channel.0);
+                    for (id, name, users, max_bitrate, mixing) in voice_channels {
+                        let mut details = vec![format!("{} connected", users.len())];
+                        if max_bitrate > 0 {
+                            details.push(format!("up to {}kbps", max_bitrate / 1000));
+                        }
+                        if mixing {
+                            details.push(String::from("mixed by the server"));
+                        }
+                        println!("    [{}] {} ({})", id, name, details.join(", "));
+                        for user_id in users {
+                            match usernames.get(&user_id) {
+                                Some(username) => println!("        {} ({})", username, user_id),
+                                None => println!("        {}", user_id),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Command::Kick { user_id } => print_done(request(ControlRequest::Kick(user_id))),
+        Command::Ban { user_id } => print_done(request(ControlRequest::Ban(user_id))),
+        Command::Unban { address } => print_done(request(ControlRequest::Unban(address))),
+        Command::Bans => {
+            if let ControlResponse::Bans(bans) = request(ControlRequest::ListBans) {
+                if bans.is_empty() {
+                    println!("No addresses are banned");
+                }
+                for address in bans {
+                    println!("{}", address);
+                }
+            }
+        }
+        Command::Announce { message } => print_done(request(ControlRequest::Announce(message))),
+        Command::CreateRealm { name } => print_done(request(ControlRequest::CreateRealm(name))),
+        Command::DeleteRealm { realm_id } => {
+            print_done(request(ControlRequest::DeleteRealm(realm_id)))
+        }
+        Command::Shutdown {
+            seconds,
+            reason,
+            restart_in,
+        } => print_done(request(ControlRequest::Shutdown((
+            seconds, reason, restart_in,
+        )))),
+        Command::CancelShutdown => print_done(request(ControlRequest::CancelShutdown)),
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    let _ = Args::parse();
+    println!("Error: kagu-admin needs Unix sockets, which aren't supported on this platform");
+    std::process::exit(1);
+}
+
+#[cfg(unix)]
+fn print_done(response: ControlResponse) {
+    if let ControlResponse::Done(result) = response {
+        println!("{}", result);
+    }
+}
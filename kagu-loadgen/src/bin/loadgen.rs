@@ -0,0 +1,447 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use client::client::{resolve_server_address, Client};
+use message::message::{MessageHeader, MessageType};
+use opus::{Application, Channels, Encoder};
+use realms::realm::ChannelType;
+use tracing_subscriber::filter::LevelFilter;
+use types::{AudioSequenceSize, ChannelIdSize, RealmIdSize};
+
+// Audio is sent in 10ms frames of 48kHz stereo
+const FRAME_DURATION: Duration = Duration::from_millis(10);
+const FRAME_SAMPLES: usize = 480;
+const SAMPLE_RATE: f32 = 48000.0;
+
+// How often clients check for messages when they have nothing to send
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+// Time for clients to sync realms and join channels before traffic starts
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+// Time for the last messages to arrive after traffic stops
+const DRAIN_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Address of the server to load test.
+    /// Must be in `host:port`, `127.0.0.1:5000` or `[::1]:5000` format
+    #[arg(short, long, default_value = "localhost:5000")]
+    address: String,
+
+    /// Directory holding the server's `cert.pem`.
+    /// If not provided, the system CAs are used
+    #[arg(short, long)]
+    cert_dir: Option<PathBuf>,
+
+    /// Name to verify the server's certificate against.
+    /// Defaults to the host part of `address`
+    #[arg(long)]
+    server_name: Option<String>,
+
+    /// Number of clients to simulate
+    #[arg(short = 'n', long, default_value_t = 10)]
+    clients: usize,
+
+    /// Seconds to send traffic for once every client has connected
+    #[arg(short, long, default_value_t = 60)]
+    duration: u64,
+
+    /// Milliseconds between starting each client
+    #[arg(long, default_value_t = 50)]
+    ramp_up: u64,
+
+    /// Text messages each client sends per second. 0 to send none
+    #[arg(long, default_value_t = 0.2)]
+    text_rate: f64,
+
+    /// Number of clients that join a voice channel
+    #[arg(long, default_value_t = 10)]
+    voice_clients: usize,
+
+    /// Number of clients in voice channels that stream audio
+    #[arg(long, default_value_t = 2)]
+    speakers: usize,
+
+    /// Prefix for usernames, followed by each client's number
+    #[arg(long, default_value = "loadgen")]
+    username_prefix: String,
+
+    /// Client logs go to the terminal at this level.
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long, default_value = "warn")]
+    log_level: LevelFilter,
+}
+
+/// What a simulated client does besides sending text
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Role {
+    Text,
+    Listener,
+    Speaker,
+}
+
+/// Settings shared by every simulated client
+struct LoadSettings {
+    address: std::net::SocketAddr,
+    server_name: String,
+    cert_dir: Option<PathBuf>,
+    username_prefix: String,
+    text_interval: Option<Duration>,
+    // When every client starts and stops sending traffic
+    traffic_start: Instant,
+    traffic_end: Instant,
+}
+
+/// What one simulated client saw
+#[derive(Debug, Default)]
+struct ClientReport {
+    connected: bool,
+    logged_in: bool,
+    voice_channel: Option<(RealmIdSize, ChannelIdSize)>,
+    text_sent: u64,
+    text_received: u64,
+    audio_sent: u64,
+    audio_received: u64,
+    latencies: Vec<Duration>,
+    rate_limited: u64,
+    login_failed: u64,
+    connection_lost: u64,
+    no_realms: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    tracing_subscriber::fmt()
+        .with_max_level(args.log_level)
+        .init();
+
+    let (address, host) = match resolve_server_address(&args.address) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            println!("Failed to resolve {}: {}", args.address, e);
+            std::process::exit(1);
+        }
+    };
+
+    let ramp_up = Duration::from_millis(args.ramp_up);
+    let traffic_start = Instant::now() + ramp_up * args.clients as u32 + SETTLE_TIME;
+    let settings = Arc::new(LoadSettings {
+        address,
+        server_name: args.server_name.unwrap_or(host),
+        cert_dir: args.cert_dir,
+        username_prefix: args.username_prefix,
+        text_interval: match args.text_rate > 0.0 {
+            true => Some(Duration::from_secs_f64(1.0 / args.text_rate)),
+            false => None,
+        },
+        traffic_start,
+        traffic_end: traffic_start + Duration::from_secs(args.duration),
+    });
+
+    println!(
+        "Starting {} clients against {} ({} in voice, {} speaking)",
+        args.clients,
+        args.address,
+        args.voice_clients.min(args.clients),
+        args.speakers.min(args.voice_clients).min(args.clients)
+    );
+
+    let mut handles = Vec::new();
+    for index in 0..args.clients {
+        let role = match index {
+            i if i < args.speakers.min(args.voice_clients) => Role::Speaker,
+            i if i < args.voice_clients => Role::Listener,
+            _ => Role::Text,
+        };
+
+        let settings = settings.clone();
+        handles.push(std::thread::spawn(move || {
+            simulate_client(index, role, &settings)
+        }));
+
+        std::thread::sleep(ramp_up);
+    }
+
+    println!(
+        "All clients started, sending traffic for {} seconds",
+        args.duration
+    );
+
+    let reports: Vec<ClientReport> = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap_or_default())
+        .collect();
+
+    print_report(&reports, Duration::from_secs(args.duration));
+}
+
+fn simulate_client(index: usize, role: Role, settings: &LoadSettings) -> ClientReport {
+    let mut report = ClientReport::default();
+    let username = format!("{}{}", settings.username_prefix, index);
+
+    let mut client = Client::new(
+        settings.address,
+        settings.server_name.clone(),
+        username.clone(),
+        settings.cert_dir.clone(),
+    );
+    client.run_client();
+
+    let connect_start = Instant::now();
+    while !client.is_connected() {
+        if connect_start.elapsed() > Duration::from_secs(5) {
+            return report;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    report.connected = true;
+
+    client.log_in();
+
+    let mut encoder = match Encoder::new(48000, Channels::Stereo, Application::Audio) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            println!("Failed to create an Opus encoder: {}", e);
+            return report;
+        }
+    };
+    // A different tone for each client
+    let frequency = 220.0 + 20.0 * (index % 40) as f32;
+    let mut phase = 0.0f32;
+
+    let mut user_id = 0;
+    let mut text_channel: Option<(RealmIdSize, ChannelIdSize)> = None;
+    let mut next_text = settings.traffic_start
+        + settings.text_interval.map_or(Duration::ZERO, |interval| {
+            interval.mul_f64(rand_offset(index))
+        });
+    let mut next_frame = settings.traffic_start;
+    let stop_at = settings.traffic_end + DRAIN_TIME;
+
+    while Instant::now() < stop_at {
+        for message in client.get_new_messages() {
+            match message.message {
+                MessageType::LoginSuccess(user) => {
+                    report.logged_in = true;
+                    user_id = user.get_id();
+                    client.set_user(user);
+                    client.get_realms();
+                }
+                MessageType::LoginFailed => {
+                    report.login_failed += 1;
+                    return report;
+                }
+                MessageType::Realms((_, mut realms)) => {
+                    // Only join once, later syncs are the same realms
+                    if text_channel.is_some() || report.no_realms {
+                        continue;
+                    }
+
+                    // Sort so every client sees the same order and clients share channels
+                    realms.sort_by_key(|realm| realm.id);
+                    let realm = match realms.get(index % realms.len().max(1)) {
+                        Some(realm) => realm,
+                        None => {
+                            report.no_realms = true;
+                            continue;
+                        }
+                    };
+
+                    let mut text_channels = realm.get_text_channels();
+                    text_channels.sort_by_key(|channel| channel.0);
+                    if !text_channels.is_empty() {
+                        let channel = &text_channels[index % text_channels.len()];
+                        text_channel = Some((realm.id, channel.0));
+                    }
+
+                    let mut voice_channels = realm.get_voice_channels();
+                    voice_channels.sort_by_key(|channel| channel.0);
+                    if role != Role::Text && !voice_channels.is_empty() {
+                        let channel = &voice_channels[index % voice_channels.len()];
+                        client.join_channel(realm.id, ChannelType::VoiceChannel, channel.0);
+                        report.voice_channel = Some((realm.id, channel.0));
+                    }
+                }
+                MessageType::Text(_) => report.text_received += 1,
+                MessageType::PingLatency(latency) => report.latencies.push(latency),
+                MessageType::RateLimited(_) => report.rate_limited += 1,
+                MessageType::ConnectionLost => {
+                    report.connection_lost += 1;
+                    return report;
+                }
+                _ => (),
+            }
+        }
+
+        report.audio_received += client.get_new_audio().len() as u64;
+
+        let now = Instant::now();
+        if now < settings.traffic_start || now >= settings.traffic_end {
+            std::thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        if let (Some(interval), Some((realm_id, channel_id))) =
+            (settings.text_interval, text_channel)
+        {
+            if now >= next_text {
+                let text = format!("load test message {} from {}", report.text_sent, username);
+                client.send_mention_message(realm_id, channel_id, vec![(text, None)]);
+                report.text_sent += 1;
+                next_text += interval;
+            }
+        }
+
+        if let (Role::Speaker, Some((realm_id, channel_id))) = (role, report.voice_channel) {
+            // Catch up on frames if we fell behind, like a real microphone would
+            while next_frame <= now {
+                let frame = sine_frame(frequency, &mut phase);
+                if let Ok(encoded) = encoder.encode_vec_float(&frame, FRAME_SAMPLES * 8) {
+                    let header = MessageHeader::new(user_id, realm_id, channel_id);
+                    client.send_audio_frame(
+                        header,
+                        report.audio_sent as AudioSequenceSize,
+                        encoded,
+                    );
+                    report.audio_sent += 1;
+                }
+                next_frame += FRAME_DURATION;
+            }
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    client.disconnect();
+    report
+}
+
+/// Spread clients' text messages out instead of sending them all at once
+fn rand_offset(index: usize) -> f64 {
+    // Golden ratio steps give an even spread without a random number generator
+    (index as f64 * 0.618_033_988_75).fract()
+}
+
+/// 10ms of a quiet stereo sine wave
+fn sine_frame(frequency: f32, phase: &mut f32) -> Vec<f32> {
+    let step = 2.0 * std::f32::consts::PI * frequency / SAMPLE_RATE;
+    let mut frame = Vec::with_capacity(FRAME_SAMPLES * 2);
+
+    for _ in 0..FRAME_SAMPLES {
+        let sample = phase.sin() * 0.2;
+        frame.push(sample);
+        frame.push(sample);
+        *phase = (*phase + step) % (2.0 * std::f32::consts::PI);
+    }
+
+    frame
+}
+
+fn print_report(reports: &[ClientReport], duration: Duration) {
+    let connected = reports.iter().filter(|r| r.connected).count();
+    let logged_in = reports.iter().filter(|r| r.logged_in).count();
+    let no_realms = reports.iter().filter(|r| r.no_realms).count();
+
+    let text_sent: u64 = reports.iter().map(|r| r.text_sent).sum();
+    let text_received: u64 = reports.iter().map(|r| r.text_received).sum();
+
+    // Each listener should hear every frame sent by everyone else in its channel
+    let audio_sent: u64 = reports.iter().map(|r| r.audio_sent).sum();
+    let audio_received: u64 = reports.iter().map(|r| r.audio_received).sum();
+    let audio_expected: u64 = reports
+        .iter()
+        .filter_map(|r| r.voice_channel.map(|channel| (r, channel)))
+        .map(|(listener, channel)| {
+            let channel_sent: u64 = reports
+                .iter()
+                .filter(|r| r.voice_channel == Some(channel))
+                .map(|r| r.audio_sent)
+                .sum();
+            channel_sent - listener.audio_sent
+        })
+        .sum();
+    let audio_dropped = audio_expected.saturating_sub(audio_received);
+
+    let mut latencies: Vec<Duration> = reports
+        .iter()
+        .flat_map(|r| r.latencies.iter().copied())
+        .collect();
+    latencies.sort();
+
+    println!();
+    println!("Clients");
+    println!("  connected:        {} of {}", connected, reports.len());
+    println!("  logged in:        {}", logged_in);
+    if no_realms > 0 {
+        println!(
+            "  no realm to join: {} (give the server a [default_realm])",
+            no_realms
+        );
+    }
+
+    println!("Text");
+    println!(
+        "  sent:             {} ({:.1}/s)",
+        text_sent,
+        text_sent as f64 / duration.as_secs_f64()
+    );
+    println!("  received:         {}", text_received);
+
+    println!("Audio");
+    println!(
+        "  frames sent:      {} ({:.1}/s)",
+        audio_sent,
+        audio_sent as f64 / duration.as_secs_f64()
+    );
+    println!("  frames expected:  {}", audio_expected);
+    println!("  frames received:  {}", audio_received);
+    println!(
+        "  frames dropped:   {} ({:.2}%)",
+        audio_dropped,
+        match audio_expected {
+            0 => 0.0,
+            expected => audio_dropped as f64 * 100.0 / expected as f64,
+        }
+    );
+
+    println!("Latency ({} pings)", latencies.len());
+    match latencies.is_empty() {
+        true => println!("  no pings answered"),
+        false => {
+            for (name, percentile) in [("p50", 0.50), ("p90", 0.90), ("p99", 0.99)] {
+                println!(
+                    "  {}:              {:?}",
+                    name,
+                    percentile_of(&latencies, percentile)
+                );
+            }
+            println!("  max:              {:?}", latencies[latencies.len() - 1]);
+        }
+    }
+
+    println!("Server errors");
+    println!("  failed to connect: {}", reports.len() - connected);
+    println!(
+        "  login failed:      {}",
+        reports.iter().map(|r| r.login_failed).sum::<u64>()
+    );
+    println!(
+        "  rate limited:      {}",
+        reports.iter().map(|r| r.rate_limited).sum::<u64>()
+    );
+    println!(
+        "  connection lost:   {}",
+        reports.iter().map(|r| r.connection_lost).sum::<u64>()
+    );
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile_of(sorted: &[Duration], percentile: f64) -> Duration {
+    let rank = (percentile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
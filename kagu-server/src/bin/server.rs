@@ -1,11 +1,22 @@
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Parser, Subcommand};
+use server::certificates;
+use server::config::ServerConfig;
+use server::logging;
 use server::server::NewServer;
+use tracing::{error, info, warn};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Port to listen on
     #[arg(short, long)]
     port: Option<u16>,
@@ -17,32 +28,218 @@ struct Args {
     #[arg(short, long)]
     name: Option<String>,
 
+    /// Directory holding cert.pem and pkey.pem
     #[arg(short, long)]
-    cert_dir: PathBuf,
+    cert_dir: Option<PathBuf>,
+
+    /// TOML config file. Command line options take priority over it
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Unix socket for kagu-admin to connect to
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+
+    /// Serve Prometheus metrics on this address, e.g. `127.0.0.1:9464`
+    #[arg(long)]
+    metrics: Option<SocketAddr>,
+
+    /// Generate a self-signed certificate if `cert_dir` doesn't have one
+    #[arg(long, action=ArgAction::SetTrue)]
+    auto_cert: bool,
+
+    /// Hostname(s) to put in an auto-generated certificate
+    #[arg(long, default_value = "localhost")]
+    hostname: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate a self-signed certificate and private key
+    GenCert {
+        /// Hostname(s) clients will connect with
+        #[arg(long, required = true)]
+        hostname: Vec<String>,
+
+        /// Directory to write cert.pem and pkey.pem to
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+
+    /// Replace the certificate in `cert_dir`, keeping the old one for a grace period
+    RotateCert {
+        /// Hostname(s) clients will connect with
+        #[arg(long, required = true)]
+        hostname: Vec<String>,
+
+        #[arg(short, long)]
+        cert_dir: PathBuf,
+
+        /// Days to keep presenting the old certificate while clients learn the new one
+        #[arg(long, default_value_t = 7)]
+        grace_days: u64,
+    },
+
+    /// Print the fingerprint of the certificate in `cert_dir`
+    Fingerprint {
+        #[arg(short, long)]
+        cert_dir: PathBuf,
+    },
 }
 
 fn main() {
     // Collect arguments
     let args = Args::parse();
 
-    let port = args.port.unwrap_or(5000);
+    if let Some(command) = args.command {
+        run_command(command);
+        return;
+    }
+
+    let mut config = match &args.config {
+        Some(path) => match ServerConfig::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => ServerConfig::default(),
+    };
+
+    logging::init(&config.logging);
+
+    let cert_dir = match args.cert_dir.or(config.server.cert_dir.clone()) {
+        Some(cert_dir) => cert_dir,
+        None => {
+            println!("Error: a cert_dir is needed, either with --cert-dir or in the config file");
+            std::process::exit(1);
+        }
+    };
+
+    if args.auto_cert && certificates::needs_certificate(&cert_dir) {
+        info!(
+            "no certificate found in {}, generating one",
+            cert_dir.display()
+        );
+        generate_certificate(&args.hostname, &cert_dir);
+    }
+    certificates::prune_expired_certificates(&cert_dir);
+
+    let port = args.port.or(config.server.port).unwrap_or(5000);
 
-    let server_name = match args.name {
+    let server_name = match args.name.or(config.server.name.clone()) {
         Some(name) => name,
         None => String::from("KaguServer"),
     };
 
-    let server = NewServer::new(server_name, port, args.ipv6, args.cert_dir);
-    server.start_server();
+    // The flag is always set, so only let it override the config when given
+    let ipv6 = match args.ipv6 {
+        Some(true) => Some(true),
+        _ => config.server.ipv6,
+    };
+
+    if args.control_socket.is_some() {
+        config.server.control_socket = args.control_socket;
+    }
+    if args.metrics.is_some() {
+        config.metrics.listen = args.metrics;
+    }
+
+    let mut server = NewServer::new(server_name, port, ipv6, cert_dir);
+    server.set_config(config);
+    let server_handle = server.start_server();
+
+    let server = Arc::new(server);
+
+    // Reload the config file on SIGHUP
+    #[cfg(unix)]
+    if let Some(config_path) = args.config {
+        watch_for_reload(config_path, server.clone());
+    }
 
     // Set up ctrl-c handler
+    let ctrlc_server = server.clone();
     ctrlc::set_handler(move || {
-        server.stop_server();
+        ctrlc_server.stop_server();
         std::process::exit(0);
     })
     .expect("Error setting Ctrl-C handler");
 
-    loop {
-        std::thread::sleep(std::time::Duration::from_secs(5));
+    // Runs until stopped with ctrl-c or kagu-admin
+    let _ = server_handle.join();
+}
+
+#[cfg(unix)]
+fn watch_for_reload(config_path: PathBuf, server: Arc<NewServer>) {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("reloading {}", config_path.display());
+
+            // Keep running with the old config if the new one is bad
+            match ServerConfig::load(&config_path) {
+                Ok(config) => server.reload_config(config),
+                Err(e) => error!("not reloading config: {}", e),
+            }
+        }
+    });
+}
+
+fn run_command(command: Command) {
+    match command {
+        Command::GenCert { hostname, out } => generate_certificate(&hostname, &out),
+        Command::RotateCert {
+            hostname,
+            cert_dir,
+            grace_days,
+        } => {
+            let grace_period = Duration::from_secs(grace_days * 24 * 60 * 60);
+            match certificates::rotate_certificate(&hostname, &cert_dir, grace_period) {
+                Ok((old, new)) => {
+                    println!("Rotated certificate in {}", cert_dir.display());
+                    println!(
+                        "Old fingerprint (presented for {} days): {}",
+                        grace_days, old
+                    );
+                    println!("New fingerprint: {}", new);
+                    println!("Restart the server so clients are told about it");
+                }
+                Err(e) => exit_with_error(e),
+            }
+        }
+        Command::Fingerprint { cert_dir } => match certificates::get_fingerprint(&cert_dir) {
+            Ok(fingerprint) => println!("{}", fingerprint),
+            Err(e) => exit_with_error(e),
+        },
     }
 }
+
+fn generate_certificate(hostnames: &[String], cert_dir: &Path) {
+    match certificates::generate_certificate(hostnames, cert_dir) {
+        Ok(fingerprint) => {
+            println!(
+                "Wrote cert.pem and pkey.pem for {} to {}",
+                hostnames.join(", "),
+                cert_dir.display()
+            );
+            println!("Fingerprint: {}", fingerprint);
+        }
+        Err(e) => exit_with_error(e),
+    }
+}
+
+fn exit_with_error(error: certificates::CertificateError) {
+    println!("Error: {}", error);
+    std::process::exit(1);
+}
@@ -1,29 +1,92 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::path::PathBuf;
 
-use clap::Parser;
-use client::client::Client;
+use audio::backend::{InputBackend, OutputBackend};
+use clap::{ArgAction, Parser};
+use client::audio_settings::default_audio_settings_path;
+use client::client::{resolve_server_address, Client};
+use client::known_hosts::default_known_hosts_path;
+use client::logging::{default_log_path, init_file_logging};
+use tracing::level_filters::LevelFilter;
 use tui::app::App;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Address to connect to.
-    /// Must be in `127.0.0.1:5000` or `[::1]:5000` format
+    /// Must be in `host:port`, `127.0.0.1:5000` or `[::1]:5000` format
     #[arg(short, long)]
-    address: SocketAddr,
+    address: String,
 
     /// Username to log in with
     #[arg(short, long)]
     username: String,
 
+    /// Directory holding the server's `cert.pem`.
+    /// If not provided, a pinned certificate or the system CAs are used
     #[arg(short, long)]
-    cert_dir: PathBuf,
+    cert_dir: Option<PathBuf>,
+
+    /// Name to verify the server's certificate against.
+    /// Defaults to the host part of `address`
+    #[arg(long)]
+    server_name: Option<String>,
+
+    /// Pin the server's certificate on first use and warn if it changes
+    #[arg(long, action=ArgAction::SetTrue)]
+    tofu: bool,
+
+    /// Known hosts file used with `--tofu`
+    #[arg(long)]
+    known_hosts: Option<PathBuf>,
+
+    /// File to write logs to. Defaults to `~/.kagu/kagu.log`
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long, default_value = "info")]
+    log_level: LevelFilter,
+
+    /// Where to record voice from: `device`, `null`, `tone`, `tone:<hz>`
+    /// or `file:<path>` (WAV or Ogg Vorbis)
+    #[arg(long, default_value = "device")]
+    audio_input: InputBackend,
+
+    /// Where to play voice to: `device`, `null` or `wav:<path>`
+    #[arg(long, default_value = "device")]
+    audio_output: OutputBackend,
 }
 
 fn main() {
     let args = Args::parse();
 
-    let mut client = Client::new(args.address, args.username, args.cert_dir);
+    // Logs can't go to the terminal while the TUI is using it
+    let log_file = args.log_file.unwrap_or(default_log_path());
+    if let Err(e) = init_file_logging(&log_file, args.log_level) {
+        println!("Failed to log to {}: {}", log_file.display(), e);
+    }
+
+    let (address, host) = match resolve_server_address(&args.address) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            println!("Failed to resolve {}: {}", args.address, e);
+            std::process::exit(1);
+        }
+    };
+
+    let server_name = args.server_name.unwrap_or(host);
+
+    let mut client = Client::new(address, server_name, args.username, args.cert_dir);
+    if args.tofu {
+        client.enable_trust_on_first_use(args.known_hosts.unwrap_or(default_known_hosts_path()));
+    }
+    // Read from the environment so it doesn't show up in the process list
+    if let Ok(password) = std::env::var("KAGU_MODERATOR_PASSWORD") {
+        client.set_moderator_password(password);
+    }
+    client.load_audio_settings(default_audio_settings_path());
+    client.set_audio_input_backend(args.audio_input);
+    client.set_audio_output_backend(args.audio_output);
     client.run_client();
 
     let start_time = std::time::Instant::now();
@@ -32,10 +95,19 @@ fn main() {
     loop {
         if client.is_connected() {
             break;
+        } else if let Some((host, pinned, presented)) = client.get_certificate_change() {
+            println!(
+                "The certificate presented by {} has changed!\nPinned:    {}\nPresented: {}\nRefusing to connect. Exiting",
+                host, pinned, presented
+            );
+            std::process::exit(1);
         } else {
             let current_time = std::time::Instant::now();
             if current_time - start_time > std::time::Duration::from_secs(2) {
-                println!("Failed to connect. Exiting");
+                println!(
+                    "Failed to connect, see {} for details. Exiting",
+                    log_file.display()
+                );
                 std::process::exit(1);
             }
         }
@@ -44,4 +116,8 @@ fn main() {
     // Create an application.
     let mut app = App::new(client);
     let _ = app.run_app();
+
+    if let Some(reason) = app.shutdown_reason {
+        println!("{}", reason);
+    }
 }
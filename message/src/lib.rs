@@ -1,2 +1,3 @@
 pub mod file_transfer;
 pub mod message;
+pub mod voice_state;
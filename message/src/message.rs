@@ -1,8 +1,10 @@
 use chrono::{DateTime, Utc};
-use realms::{realm::ChannelType, realm_desc::RealmDescription, realms_manager::RealmsManager};
+use realms::channels::text_channel::TextChannelMessage;
+use realms::{realm::ChannelType, realm_desc::RealmDescription};
 use serde::{Deserialize, Serialize};
 
 use crate::file_transfer::FileTransfer;
+use crate::voice_state::VoiceState;
 use types::*;
 use user::User;
 
@@ -31,10 +33,21 @@ impl MessageHeader {
     }
 }
 
+/// A secret sent to the server, kept out of logs
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Password(pub String);
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Password(..)")
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub enum MessageType {
     // User communications
-    Audio((MessageHeader, Vec<u8>)),
+    // Opus frames are numbered so listeners can put them back in order
+    Audio((MessageHeader, AudioSequenceSize, Vec<u8>)),
     Text((MessageHeader, TextMessageChunks)),
     Reply((MessageHeader, MessageIdSize, TextMessageChunks)),
     AudioConnection(UserIdSize),
@@ -45,6 +58,10 @@ pub enum MessageType {
     LoginAttempt(String),
     LoginSuccess(User),
     LoginFailed,
+    // Proving we're one of the server's moderators, with the password from its config
+    ModeratorLogin(Password),
+    // Whether the moderator password was right, the connection is closed if it wasn't
+    ModeratorLoginResult(bool),
 
     // Users coming and going
     UserJoined(User),
@@ -55,6 +72,17 @@ pub enum MessageType {
     UserLeftVoiceChannel(MessageHeader),
     Disconnecting(UserIdSize),
 
+    // Voice
+    // Someone in a voice channel muted or deafened themselves, relayed to everyone in the realm
+    VoiceStateUpdate((MessageHeader, VoiceState)),
+    // A moderator muting or unmuting the user for everyone
+    ServerMute((MessageHeader, UserIdSize, bool)),
+    // Audio frames a speaker sent lately and how many of them reached the server
+    AudioLoss((u32, u32)),
+    // Someone started or stopped recording the voice channel in the header,
+    // announced to everyone in it
+    VoiceRecording((MessageHeader, bool)),
+
     // Users
     AllUsers(Vec<User>),
     GetAllUsers(MessageHeader),
@@ -67,9 +95,10 @@ pub enum MessageType {
     FriendshipEnded(MessageHeader),
 
     // Realms
-    RealmsManager(RealmsManager),
-    Realms(Vec<RealmDescription>),
+    Realms((RealmsVersionSize, Vec<RealmDescription>)),
     GetRealms(UserIdSize),
+    // A change to the realms, applied only if it follows the version the client has
+    RealmsDelta((RealmsVersionSize, Box<MessageType>)),
     AddRealm((MessageHeader, String)),
     RemoveRealm((MessageHeader, RealmIdSize)),
     RealmAdded((RealmIdSize, String)),
@@ -81,6 +110,16 @@ pub enum MessageType {
     RenameChannel((MessageHeader, ChannelType)),
     ChannelAdded((RealmIdSize, ChannelType, ChannelIdSize, String)),
     ChannelRemoved((RealmIdSize, ChannelType, ChannelIdSize)),
+    GetChannelHistory(MessageHeader),
+    ChannelHistory((RealmIdSize, ChannelIdSize, Vec<TextChannelMessage>)),
+    SetSlowMode((MessageHeader, u32)),
+    SlowModeChanged((RealmIdSize, ChannelIdSize, u32)),
+    // Highest bitrate audio in a voice channel may be encoded at, 0 for no limit
+    SetVoiceBitrate((MessageHeader, u32)),
+    VoiceBitrateChanged((RealmIdSize, ChannelIdSize, u32)),
+    // Whether the server mixes a voice channel into one stream for each listener
+    SetVoiceMixing((MessageHeader, bool)),
+    VoiceMixingChanged((RealmIdSize, ChannelIdSize, bool)),
 
     // User disconnects
     Disconnect,
@@ -90,6 +129,8 @@ pub enum MessageType {
     Ping(PingIdSize),
     PingReply(PingIdSize),
     PingLatency(std::time::Duration),
+    Motd(String),
+    Announcement(String),
 
     // File transferring
     FileTransferRequest(MessageHeader),
@@ -98,8 +139,92 @@ pub enum MessageType {
     FileTransfer(FileTransfer),
     FileTransferComplete(FileTransferIdSize),
 
+    // Server identity, raised by the client when the server's certificate no longer matches the pinned one
+    CertificateChanged((String, String, String)),
+    // Sent after login while the server presents a rotated out certificate, with the next one's fingerprint
+    UpcomingCertificate(String),
+
     // Errors
-    ServerShutdown,
+    // Sent before the server shuts down, with the reason and how long until it's back if restarting
+    ServerShutdown((String, Option<std::time::Duration>)),
+    // Made by the client when its connection to the server ends
+    ConnectionLost,
+    // Sent too much too quickly, wait this long before trying again
+    RateLimited(std::time::Duration),
+}
+
+impl MessageType {
+    /// Name of the variant, used to label metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::Audio(_) => "Audio",
+            MessageType::Text(_) => "Text",
+            MessageType::Reply(_) => "Reply",
+            MessageType::AudioConnection(_) => "AudioConnection",
+            MessageType::Image(_) => "Image",
+            MessageType::Typing(_) => "Typing",
+            MessageType::LoginAttempt(_) => "LoginAttempt",
+            MessageType::LoginSuccess(_) => "LoginSuccess",
+            MessageType::LoginFailed => "LoginFailed",
+            MessageType::ModeratorLogin(_) => "ModeratorLogin",
+            MessageType::ModeratorLoginResult(_) => "ModeratorLoginResult",
+            MessageType::UserJoined(_) => "UserJoined",
+            MessageType::UserLeft(_) => "UserLeft",
+            MessageType::JoinChannel(_) => "JoinChannel",
+            MessageType::LeaveChannel(_) => "LeaveChannel",
+            MessageType::UserJoinedVoiceChannel(_) => "UserJoinedVoiceChannel",
+            MessageType::UserLeftVoiceChannel(_) => "UserLeftVoiceChannel",
+            MessageType::Disconnecting(_) => "Disconnecting",
+            MessageType::VoiceStateUpdate(_) => "VoiceStateUpdate",
+            MessageType::ServerMute(_) => "ServerMute",
+            MessageType::AudioLoss(_) => "AudioLoss",
+            MessageType::VoiceRecording(_) => "VoiceRecording",
+            MessageType::AllUsers(_) => "AllUsers",
+            MessageType::GetAllUsers(_) => "GetAllUsers",
+            MessageType::NewFriendRequest(_) => "NewFriendRequest",
+            MessageType::FriendRequestAccepted(_) => "FriendRequestAccepted",
+            MessageType::FriendRequestRejected(_) => "FriendRequestRejected",
+            MessageType::RemoveFriend(_) => "RemoveFriend",
+            MessageType::FriendshipEnded(_) => "FriendshipEnded",
+            MessageType::Realms(_) => "Realms",
+            MessageType::GetRealms(_) => "GetRealms",
+            MessageType::RealmsDelta(_) => "RealmsDelta",
+            MessageType::AddRealm(_) => "AddRealm",
+            MessageType::RemoveRealm(_) => "RemoveRealm",
+            MessageType::RealmAdded(_) => "RealmAdded",
+            MessageType::RealmRemoved(_) => "RealmRemoved",
+            MessageType::AddChannel(_) => "AddChannel",
+            MessageType::RemoveChannel(_) => "RemoveChannel",
+            MessageType::RenameChannel(_) => "RenameChannel",
+            MessageType::ChannelAdded(_) => "ChannelAdded",
+            MessageType::ChannelRemoved(_) => "ChannelRemoved",
+            MessageType::GetChannelHistory(_) => "GetChannelHistory",
+            MessageType::ChannelHistory(_) => "ChannelHistory",
+            MessageType::SetSlowMode(_) => "SetSlowMode",
+            MessageType::SlowModeChanged(_) => "SlowModeChanged",
+            MessageType::SetVoiceBitrate(_) => "SetVoiceBitrate",
+            MessageType::VoiceBitrateChanged(_) => "VoiceBitrateChanged",
+            MessageType::SetVoiceMixing(_) => "SetVoiceMixing",
+            MessageType::VoiceMixingChanged(_) => "VoiceMixingChanged",
+            MessageType::Disconnect => "Disconnect",
+            MessageType::Heartbeat => "Heartbeat",
+            MessageType::Ping(_) => "Ping",
+            MessageType::PingReply(_) => "PingReply",
+            MessageType::PingLatency(_) => "PingLatency",
+            MessageType::Motd(_) => "Motd",
+            MessageType::Announcement(_) => "Announcement",
+            MessageType::FileTransferRequest(_) => "FileTransferRequest",
+            MessageType::FileTransferDenied => "FileTransferDenied",
+            MessageType::FileTransferApproved(_) => "FileTransferApproved",
+            MessageType::FileTransfer(_) => "FileTransfer",
+            MessageType::FileTransferComplete(_) => "FileTransferComplete",
+            MessageType::CertificateChanged(_) => "CertificateChanged",
+            MessageType::UpcomingCertificate(_) => "UpcomingCertificate",
+            MessageType::ServerShutdown(_) => "ServerShutdown",
+            MessageType::ConnectionLost => "ConnectionLost",
+            MessageType::RateLimited(_) => "RateLimited",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -125,6 +250,13 @@ impl From<MessageType> for Message {
                 Message::new(0, MessageType::LoginAttempt(username))
             }
             MessageType::LoginSuccess(user) => Message::new(0, MessageType::LoginSuccess(user)),
+            MessageType::LoginFailed => Message::new(0, MessageType::LoginFailed),
+            MessageType::ModeratorLogin(password) => {
+                Message::new(0, MessageType::ModeratorLogin(password))
+            }
+            MessageType::ModeratorLoginResult(proven) => {
+                Message::new(0, MessageType::ModeratorLoginResult(proven))
+            }
             MessageType::UserJoined(user) => {
                 Message::new(user.get_id(), MessageType::UserJoined(user))
             }
@@ -145,6 +277,16 @@ impl From<MessageType> for Message {
             MessageType::Disconnecting(user_id) => {
                 Message::new(0, MessageType::Disconnecting(user_id))
             }
+            MessageType::VoiceStateUpdate(update) => {
+                Message::new(update.0.user_id, MessageType::VoiceStateUpdate(update))
+            }
+            MessageType::ServerMute(mute) => {
+                Message::new(mute.0.user_id, MessageType::ServerMute(mute))
+            }
+            MessageType::AudioLoss(loss) => Message::new(0, MessageType::AudioLoss(loss)),
+            MessageType::VoiceRecording(recording) => {
+                Message::new(recording.0.user_id, MessageType::VoiceRecording(recording))
+            }
             MessageType::AllUsers(users) => Message::new(0, MessageType::AllUsers(users)),
             MessageType::GetAllUsers(gau) => Message::new(0, MessageType::GetAllUsers(gau)),
             MessageType::NewFriendRequest(request) => {
@@ -162,7 +304,7 @@ impl From<MessageType> for Message {
                 Message::new(user_id, MessageType::GetRealms(user_id))
             }
             MessageType::Realms(realms) => Message::new(0, MessageType::Realms(realms)),
-            MessageType::RealmsManager(rm) => Message::new(0, MessageType::RealmsManager(rm)),
+            MessageType::RealmsDelta(delta) => Message::new(0, MessageType::RealmsDelta(delta)),
             MessageType::AddRealm(ar) => Message::new(0, MessageType::AddRealm(ar)),
             MessageType::RemoveRealm(rr) => Message::new(0, MessageType::RemoveRealm(rr)),
             MessageType::RealmAdded(ra) => Message::new(0, MessageType::RealmAdded(ra)),
@@ -172,12 +314,52 @@ impl From<MessageType> for Message {
             MessageType::RenameChannel(rc) => Message::new(0, MessageType::RenameChannel(rc)),
             MessageType::ChannelAdded(ca) => Message::new(0, MessageType::ChannelAdded(ca)),
             MessageType::ChannelRemoved(cr) => Message::new(0, MessageType::ChannelRemoved(cr)),
-            MessageType::ServerShutdown => Message::new(0, MessageType::ServerShutdown),
+            MessageType::GetChannelHistory(header) => {
+                Message::new(header.user_id, MessageType::GetChannelHistory(header))
+            }
+            MessageType::ChannelHistory(history) => {
+                Message::new(0, MessageType::ChannelHistory(history))
+            }
+            MessageType::SetSlowMode(slow_mode) => {
+                Message::new(slow_mode.0.user_id, MessageType::SetSlowMode(slow_mode))
+            }
+            MessageType::SlowModeChanged(slow_mode) => {
+                Message::new(0, MessageType::SlowModeChanged(slow_mode))
+            }
+            MessageType::SetVoiceBitrate(bitrate) => {
+                Message::new(bitrate.0.user_id, MessageType::SetVoiceBitrate(bitrate))
+            }
+            MessageType::VoiceBitrateChanged(bitrate) => {
+                Message::new(0, MessageType::VoiceBitrateChanged(bitrate))
+            }
+            MessageType::SetVoiceMixing(mixing) => {
+                Message::new(mixing.0.user_id, MessageType::SetVoiceMixing(mixing))
+            }
+            MessageType::VoiceMixingChanged(mixing) => {
+                Message::new(0, MessageType::VoiceMixingChanged(mixing))
+            }
+            MessageType::RateLimited(retry_after) => {
+                Message::new(0, MessageType::RateLimited(retry_after))
+            }
+            MessageType::ServerShutdown(shutdown) => {
+                Message::new(0, MessageType::ServerShutdown(shutdown))
+            }
+            MessageType::ConnectionLost => Message::new(0, MessageType::ConnectionLost),
+            MessageType::CertificateChanged(changed) => {
+                Message::new(0, MessageType::CertificateChanged(changed))
+            }
+            MessageType::UpcomingCertificate(fingerprint) => {
+                Message::new(0, MessageType::UpcomingCertificate(fingerprint))
+            }
             MessageType::Ping(ping_id) => Message::new(0, MessageType::Ping(ping_id)),
             MessageType::PingReply(ping_id) => Message::new(0, MessageType::PingReply(ping_id)),
             MessageType::PingLatency(duration) => {
                 Message::new(0, MessageType::PingLatency(duration))
             }
+            MessageType::Motd(motd) => Message::new(0, MessageType::Motd(motd)),
+            MessageType::Announcement(announcement) => {
+                Message::new(0, MessageType::Announcement(announcement))
+            }
             MessageType::FileTransferRequest(header) => {
                 Message::new(0, MessageType::FileTransferRequest(header))
             }
@@ -220,6 +402,8 @@ impl Message {
             MessageType::LoginAttempt(username) => MessageType::LoginAttempt(username),
             MessageType::LoginSuccess(user) => MessageType::LoginSuccess(user),
             MessageType::LoginFailed => MessageType::LoginFailed,
+            MessageType::ModeratorLogin(password) => MessageType::ModeratorLogin(password),
+            MessageType::ModeratorLoginResult(proven) => MessageType::ModeratorLoginResult(proven),
             MessageType::UserJoined(user) => MessageType::UserJoined(user),
             MessageType::UserLeft(user) => MessageType::UserLeft(user),
             MessageType::JoinChannel(join_info) => MessageType::JoinChannel(join_info),
@@ -241,7 +425,7 @@ impl Message {
             }
             MessageType::RemoveFriend(rf) => MessageType::RemoveFriend(rf),
             MessageType::FriendshipEnded(fe) => MessageType::FriendshipEnded(fe),
-            MessageType::RealmsManager(rm) => MessageType::RealmsManager(rm),
+            MessageType::RealmsDelta(delta) => MessageType::RealmsDelta(delta),
             MessageType::Realms(realms) => MessageType::Realms(realms),
             MessageType::GetRealms(user_id) => MessageType::GetRealms(user_id),
             MessageType::AddRealm(ar) => MessageType::AddRealm(ar),
@@ -253,13 +437,33 @@ impl Message {
             MessageType::RenameChannel(rc) => MessageType::RenameChannel(rc),
             MessageType::ChannelAdded(ca) => MessageType::ChannelAdded(ca),
             MessageType::ChannelRemoved(cr) => MessageType::ChannelRemoved(cr),
+            MessageType::GetChannelHistory(header) => MessageType::GetChannelHistory(header),
+            MessageType::ChannelHistory(history) => MessageType::ChannelHistory(history),
+            MessageType::SetSlowMode(slow_mode) => MessageType::SetSlowMode(slow_mode),
+            MessageType::SlowModeChanged(slow_mode) => MessageType::SlowModeChanged(slow_mode),
+            MessageType::SetVoiceBitrate(bitrate) => MessageType::SetVoiceBitrate(bitrate),
+            MessageType::VoiceBitrateChanged(bitrate) => MessageType::VoiceBitrateChanged(bitrate),
+            MessageType::SetVoiceMixing(mixing) => MessageType::SetVoiceMixing(mixing),
+            MessageType::VoiceMixingChanged(mixing) => MessageType::VoiceMixingChanged(mixing),
             MessageType::Disconnect => MessageType::Disconnect,
             MessageType::Disconnecting(user_id) => MessageType::Disconnecting(user_id),
+            MessageType::VoiceStateUpdate(update) => MessageType::VoiceStateUpdate(update),
+            MessageType::ServerMute(mute) => MessageType::ServerMute(mute),
+            MessageType::AudioLoss(loss) => MessageType::AudioLoss(loss),
+            MessageType::VoiceRecording(recording) => MessageType::VoiceRecording(recording),
             MessageType::Heartbeat => MessageType::Heartbeat,
-            MessageType::ServerShutdown => MessageType::ServerShutdown,
+            MessageType::ServerShutdown(shutdown) => MessageType::ServerShutdown(shutdown),
+            MessageType::ConnectionLost => MessageType::ConnectionLost,
+            MessageType::RateLimited(retry_after) => MessageType::RateLimited(retry_after),
+            MessageType::CertificateChanged(changed) => MessageType::CertificateChanged(changed),
+            MessageType::UpcomingCertificate(fingerprint) => {
+                MessageType::UpcomingCertificate(fingerprint)
+            }
             MessageType::Ping(ping_id) => MessageType::Ping(ping_id),
             MessageType::PingReply(ping_id) => MessageType::PingReply(ping_id),
             MessageType::PingLatency(duration) => MessageType::PingLatency(duration),
+            MessageType::Motd(motd) => MessageType::Motd(motd),
+            MessageType::Announcement(announcement) => MessageType::Announcement(announcement),
             MessageType::FileTransferRequest(ftr) => MessageType::FileTransferRequest(ftr),
             MessageType::FileTransferApproved(tid) => MessageType::FileTransferApproved(tid),
             MessageType::FileTransferDenied => MessageType::FileTransferDenied,
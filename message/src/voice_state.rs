@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+/// Whether someone in a voice channel can be heard and can hear
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct VoiceState {
+    /// Muted their own microphone
+    pub muted: bool,
+    /// Stopped listening to everyone else
+    pub deafened: bool,
+    /// Muted by a moderator, only the server sets this
+    pub server_muted: bool,
+}
+
+impl VoiceState {
+    /// Whether their audio should reach anyone
+    pub fn can_speak(&self) -> bool {
+        !self.muted && !self.server_muted
+    }
+}
@@ -0,0 +1,157 @@
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::ALPN_NAME;
+
+// Largest UDP payload sent or received while fetching a certificate
+const MAX_DATAGRAM_SIZE: usize = 1350;
+
+/// Read the first certificate from a PEM file and return it in DER form.
+pub fn load_certificate_der(path: &Path) -> io::Result<Vec<u8>> {
+    let contents = fs::read(path)?;
+    match pem::parse_many(contents) {
+        Ok(blocks) => match blocks.into_iter().find(|b| b.tag() == "CERTIFICATE") {
+            Some(block) => Ok(block.into_contents()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no certificate found in {}", path.display()),
+            )),
+        },
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
+/// Write a DER certificate to `path` as PEM.
+pub fn save_certificate_pem(path: &Path, der: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let block = pem::Pem::new("CERTIFICATE", der.to_vec());
+    fs::write(path, pem::encode(&block))
+}
+
+/// SHA-256 fingerprint of a DER certificate, formatted as `SHA256:AB:CD:...`.
+pub fn fingerprint(der: &[u8]) -> String {
+    let hash = digest(&SHA256, der);
+    let hex: Vec<String> = hash.as_ref().iter().map(|b| format!("{:02X}", b)).collect();
+
+    let mut fingerprint = String::from("SHA256:");
+    fingerprint.push_str(hex.join(":").as_str());
+    fingerprint
+}
+
+/// Complete a QUIC handshake with a server and return the leaf certificate it presented, in DER form.
+///
+/// The certificate isn't checked against any CA, so it must be compared against a pinned fingerprint
+/// before it's trusted. Finishing the handshake does prove the server holds the certificate's key.
+pub fn fetch_server_certificate(
+    server_address: SocketAddr,
+    server_name: &str,
+    timeout: Duration,
+) -> io::Result<Vec<u8>> {
+    let quic_error = |e: quiche::Error| io::Error::other(e.to_string());
+
+    let bind_address = match server_address.is_ipv6() {
+        true => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        false => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind_address)?;
+    let local_address = socket.local_addr()?;
+
+    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).map_err(quic_error)?;
+    config
+        .set_application_protos(&[ALPN_NAME])
+        .map_err(quic_error)?;
+    config.verify_peer(false);
+    config.set_max_idle_timeout(timeout.as_millis() as u64);
+    config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
+    config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
+
+    let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+    SystemRandom::new()
+        .fill(&mut scid)
+        .map_err(|_| io::Error::other("failed to generate a connection id"))?;
+    let scid = quiche::ConnectionId::from_ref(&scid);
+
+    let mut connection = quiche::connect(
+        Some(server_name),
+        &scid,
+        local_address,
+        server_address,
+        &mut config,
+    )
+    .map_err(quic_error)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0; 65535];
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+
+    while !connection.is_established() {
+        loop {
+            match connection.send(&mut out) {
+                Ok((length, send_info)) => {
+                    socket.send_to(&out[..length], send_info.to)?;
+                }
+                Err(quiche::Error::Done) => break,
+                Err(e) => return Err(quic_error(e)),
+            }
+        }
+
+        let now = Instant::now();
+        if connection.is_closed() || now >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no handshake with {}", server_address),
+            ));
+        }
+
+        let wait = connection
+            .timeout()
+            .unwrap_or(deadline - now)
+            .min(deadline - now)
+            .max(Duration::from_millis(1));
+        socket.set_read_timeout(Some(wait))?;
+
+        match socket.recv_from(&mut buffer) {
+            Ok((length, from)) => {
+                let recv_info = quiche::RecvInfo {
+                    from,
+                    to: local_address,
+                };
+                connection
+                    .recv(&mut buffer[..length], recv_info)
+                    .map_err(quic_error)?;
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                connection.on_timeout()
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let certificate = connection
+        .peer_cert()
+        .map(|certificate| certificate.to_vec());
+
+    // Let the server know we're done rather than leaving it to time out
+    let _ = connection.close(true, 0, b"");
+    if let Ok((length, send_info)) = connection.send(&mut out) {
+        let _ = socket.send_to(&out[..length], send_info.to);
+    }
+
+    certificate.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} presented no certificate", server_address),
+        )
+    })
+}
@@ -1,3 +1,5 @@
+pub mod certificate;
+
 pub const BUFFER_SIZE_PER_CONNECTION: usize = 65536;
 pub const MESSAGE_HEADER_SIZE: usize = 2;
 
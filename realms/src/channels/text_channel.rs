@@ -26,6 +26,8 @@ pub struct TextChannel {
     pub pending_mention: bool,
     pub chat_history: Vec<TextChannelMessage>,
     pub users_typing: Vec<(UserIdSize, DateTime<Utc>)>,
+    // Seconds a user has to wait between messages, 0 if slow mode is off
+    pub slow_mode_secs: u32,
 }
 
 impl TextChannel {
@@ -37,6 +39,7 @@ impl TextChannel {
             pending_mention: false,
             chat_history: Vec::new(),
             users_typing: Vec::new(),
+            slow_mode_secs: 0,
         }
     }
 
@@ -55,6 +58,15 @@ impl TextChannel {
         id
     }
 
+    /// Add messages sent as history, skipping any we already have and keeping them in order
+    pub fn merge_history(&mut self, messages: Vec<TextChannelMessage>) {
+        self.chat_history.extend(messages);
+
+        // The sort is stable, so of any duplicates the copy we already had is kept
+        self.chat_history.sort_by_key(|m| m.message_id);
+        self.chat_history.dedup_by_key(|m| m.message_id);
+    }
+
     // pub fn push_image(&mut self, user_id: UserIdSize, image: Vec<u8>) {
     //     self.chat_history.push((user_id, Some(image), Vec::new()));
     // }
@@ -6,6 +6,10 @@ pub struct VoiceChannel {
     id: ChannelIdSize,
     name: String,
     connected_users: Vec<UserIdSize>,
+    // Highest bitrate audio may be encoded at in bits per second, 0 for no limit
+    pub max_bitrate: u32,
+    // Whether the server mixes everyone's audio instead of forwarding each speaker
+    pub mixing: bool,
 }
 
 impl VoiceChannel {
@@ -14,6 +18,22 @@ impl VoiceChannel {
             id,
             name,
             connected_users: Vec::new(),
+            max_bitrate: 0,
+            mixing: false,
+        }
+    }
+
+    pub fn with_connected_users(
+        id: ChannelIdSize,
+        name: String,
+        connected_users: Vec<UserIdSize>,
+    ) -> VoiceChannel {
+        VoiceChannel {
+            id,
+            name,
+            connected_users,
+            max_bitrate: 0,
+            mixing: false,
         }
     }
 
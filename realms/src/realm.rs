@@ -50,31 +50,29 @@ impl Realm {
         }
     }
 
+    /// Add a channel with the next free id, or None if every id is taken
     pub fn add_channel(
         &mut self,
         channel_type: ChannelType,
         name: String,
-    ) -> (ChannelIdSize, String) {
-        match channel_type {
-            ChannelType::TextChannel => {
-                let id = self.text_channels.len() as ChannelIdSize;
-                self.text_channels.insert(
-                    self.text_channels.len() as ChannelIdSize,
-                    TextChannel::new(self.text_channels.len() as ChannelIdSize, name.clone()),
-                );
+    ) -> Option<(ChannelIdSize, String)> {
+        let ids = match channel_type {
+            ChannelType::TextChannel => self.text_channels.keys().copied().collect::<Vec<_>>(),
+            ChannelType::VoiceChannel => self.voice_channels.keys().copied().collect(),
+        };
 
-                (id, name)
-            }
-            ChannelType::VoiceChannel => {
-                let id = self.voice_channels.len() as ChannelIdSize;
-                self.voice_channels.insert(
-                    self.voice_channels.len() as ChannelIdSize,
-                    VoiceChannel::new(self.voice_channels.len() as ChannelIdSize, name.clone()),
-                );
+        // Channels can be removed, so don't reuse the id of one that still exists.
+        // Carry on from the highest id, then fall back to the lowest free one.
+        let id = match ids.iter().max() {
+            Some(highest) => highest
+                .checked_add(1)
+                .or_else(|| (0..=ChannelIdSize::MAX).find(|id| !ids.contains(id)))?,
+            None => 0,
+        };
 
-                (id, name)
-            }
-        }
+        self.add_channel_with_id(channel_type, id, name.clone());
+
+        Some((id, name))
     }
 
     pub fn remove_channel(&mut self, channel_type: ChannelType, channel_id: ChannelIdSize) {
@@ -158,3 +156,40 @@ impl Realm {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_ids_wrap_around_to_free_ones_until_none_are_left() {
+        let mut realm = Realm::new(0, String::from("Realm"));
+        realm.add_channel_with_id(ChannelType::TextChannel, 0, String::from("first"));
+        realm.add_channel_with_id(
+            ChannelType::TextChannel,
+            ChannelIdSize::MAX,
+            String::from("last"),
+        );
+
+        let (id, _) = realm
+            .add_channel(ChannelType::TextChannel, String::from("next"))
+            .unwrap();
+        assert_eq!(id, 1);
+
+        for _ in 2..ChannelIdSize::MAX {
+            assert!(realm
+                .add_channel(ChannelType::TextChannel, String::from("more"))
+                .is_some());
+        }
+        assert_eq!(realm.get_text_channels().len(), 256);
+        assert_eq!(
+            realm.add_channel(ChannelType::TextChannel, String::from("full")),
+            None
+        );
+
+        // Voice channels have ids of their own
+        assert!(realm
+            .add_channel(ChannelType::VoiceChannel, String::from("voice"))
+            .is_some());
+    }
+}
@@ -1,7 +1,7 @@
 use crate::channels::text_channel::TextChannel;
 use crate::channels::voice_channel::VoiceChannel;
 use serde::{Deserialize, Serialize};
-use types::{ChannelIdSize, RealmIdSize};
+use types::{ChannelIdSize, RealmIdSize, UserIdSize};
 
 use std::collections::HashMap;
 
@@ -13,8 +13,10 @@ use std::collections::HashMap;
 pub struct RealmDescription {
     pub id: RealmIdSize,
     pub name: String,
-    pub text_channels: Vec<(ChannelIdSize, String)>,
-    pub voice_channels: Vec<(ChannelIdSize, String)>,
+    // Text channel id, name and slow mode interval in seconds
+    pub text_channels: Vec<(ChannelIdSize, String, u32)>,
+    // Voice channel id, name, connected users, bitrate limit and whether it's mixed
+    pub voice_channels: Vec<(ChannelIdSize, String, Vec<UserIdSize>, u32, bool)>,
 }
 
 impl RealmDescription {
@@ -28,11 +30,17 @@ impl RealmDescription {
         let mut voice_channels = Vec::new();
 
         for tc in text_channels_map.values() {
-            text_channels.push((*tc.get_id(), tc.get_name().clone()))
+            text_channels.push((*tc.get_id(), tc.get_name().clone(), tc.slow_mode_secs))
         }
 
         for vc in voice_channels_map.values() {
-            voice_channels.push((*vc.get_id(), vc.get_name().clone()))
+            voice_channels.push((
+                *vc.get_id(),
+                vc.get_name().clone(),
+                vc.get_connected_users().clone(),
+                vc.max_bitrate,
+                vc.mixing,
+            ))
         }
 
         RealmDescription {
@@ -43,11 +51,11 @@ impl RealmDescription {
         }
     }
 
-    pub fn get_text_channels(&self) -> Vec<(ChannelIdSize, String)> {
+    pub fn get_text_channels(&self) -> Vec<(ChannelIdSize, String, u32)> {
         self.text_channels.clone()
     }
 
-    pub fn get_voice_channels(&self) -> Vec<(ChannelIdSize, String)> {
+    pub fn get_voice_channels(&self) -> Vec<(ChannelIdSize, String, Vec<UserIdSize>, u32, bool)> {
         self.voice_channels.clone()
     }
 }
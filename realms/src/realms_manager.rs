@@ -1,3 +1,4 @@
+use crate::channels::voice_channel::VoiceChannel;
 use crate::realm::{ChannelType, Realm};
 use crate::realm_desc::RealmDescription;
 use serde::{Deserialize, Serialize};
@@ -35,6 +36,9 @@ impl RealmsManager {
     pub fn add_realm_with_id(&mut self, realm_id: RealmIdSize, realm_name: String) {
         self.realms
             .insert(realm_id, Realm::new(realm_id, realm_name));
+
+        // Keep generated ids clear of this one
+        self.num_realms = self.num_realms.max(realm_id.saturating_add(1));
     }
 
     pub fn add_channel_with_id(
@@ -54,24 +58,59 @@ impl RealmsManager {
         realm_id: RealmIdSize,
         channel_type: ChannelType,
         name: String,
-    ) -> (ChannelIdSize, String) {
-        let mut channel = (0, String::from(""));
+    ) -> Option<(ChannelIdSize, String)> {
+        self.realms
+            .get_mut(&realm_id)
+            .and_then(|realm| realm.add_channel(channel_type, name))
+    }
 
+    pub fn remove_channel(
+        &mut self,
+        realm_id: RealmIdSize,
+        channel_type: ChannelType,
+        channel_id: ChannelIdSize,
+    ) {
         if let Some(realm) = self.realms.get_mut(&realm_id) {
-            channel = realm.add_channel(channel_type, name);
+            realm.remove_channel(channel_type, channel_id);
         }
+    }
 
-        channel
+    pub fn set_slow_mode(
+        &mut self,
+        realm_id: RealmIdSize,
+        channel_id: ChannelIdSize,
+        slow_mode_secs: u32,
+    ) {
+        if let Some(realm) = self.realms.get_mut(&realm_id) {
+            if let Some(channel) = realm.get_text_channel_mut(channel_id) {
+                channel.slow_mode_secs = slow_mode_secs;
+            }
+        }
     }
 
-    pub fn remove_channel(
+    pub fn set_voice_bitrate(
         &mut self,
         realm_id: RealmIdSize,
-        channel_type: ChannelType,
         channel_id: ChannelIdSize,
+        max_bitrate: u32,
     ) {
         if let Some(realm) = self.realms.get_mut(&realm_id) {
-            realm.remove_channel(channel_type, channel_id);
+            if let Some(channel) = realm.get_voice_channel_mut(channel_id) {
+                channel.max_bitrate = max_bitrate;
+            }
+        }
+    }
+
+    pub fn set_voice_mixing(
+        &mut self,
+        realm_id: RealmIdSize,
+        channel_id: ChannelIdSize,
+        mixing: bool,
+    ) {
+        if let Some(realm) = self.realms.get_mut(&realm_id) {
+            if let Some(channel) = realm.get_voice_channel_mut(channel_id) {
+                channel.mixing = mixing;
+            }
         }
     }
 
@@ -112,6 +151,39 @@ impl RealmsManager {
         realm_descriptions
     }
 
+    /// Replace our realms with the ones described by the server.
+    /// Text channels we already know keep the history loaded for them.
+    pub fn sync_realms(&mut self, realm_descriptions: Vec<RealmDescription>) {
+        let mut old_realms = std::mem::take(&mut self.realms);
+
+        for description in realm_descriptions {
+            let mut realm = Realm::new(description.id, description.name);
+            let mut old_realm = old_realms.remove(&description.id);
+
+            for (id, name, slow_mode_secs) in description.text_channels {
+                match old_realm.as_mut().and_then(|r| r.text_channels.remove(&id)) {
+                    Some(channel) if channel.get_name() == &name => {
+                        realm.text_channels.insert(id, channel);
+                    }
+                    _ => realm.add_channel_with_id(ChannelType::TextChannel, id, name),
+                }
+
+                if let Some(channel) = realm.get_text_channel_mut(id) {
+                    channel.slow_mode_secs = slow_mode_secs;
+                }
+            }
+
+            for (id, name, connected_users, max_bitrate, mixing) in description.voice_channels {
+                let mut channel = VoiceChannel::with_connected_users(id, name, connected_users);
+                channel.max_bitrate = max_bitrate;
+                channel.mixing = mixing;
+                realm.voice_channels.insert(id, channel);
+            }
+
+            self.realms.insert(description.id, realm);
+        }
+    }
+
     pub fn get_realms(&self) -> Vec<(&RealmIdSize, &String)> {
         let mut realms = Vec::new();
         for realm in self.realms.values() {
@@ -120,6 +192,10 @@ impl RealmsManager {
         realms
     }
 
+    pub fn get_realms_mut(&mut self) -> impl Iterator<Item = &mut Realm> {
+        self.realms.values_mut()
+    }
+
     pub fn get_realm(&self, realm_id: RealmIdSize) -> Option<&Realm> {
         self.realms.get(&realm_id)
     }
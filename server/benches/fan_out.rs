@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use server::fan_out::{self, FanOut, SendTo};
+use types::UserIdSize;
+
+// Roughly the size of a relayed 10ms Opus frame with its header
+const AUDIO_FRAME_SIZE: usize = 200;
+
+const CONNECTIONS: [usize; 3] = [100, 250, 500];
+
+// Users talking in the voice channel, out of everyone connected
+const VOICE_CHANNEL_USERS: usize = 25;
+
+/// Connected users with the first few in a voice channel.
+/// Connection ids stand in for swiftlet's.
+fn fan_out_with_users(users: usize) -> FanOut<u64> {
+    let mut fan_out = FanOut::new();
+    for user_id in 0..users {
+        fan_out.add_user(user_id as UserIdSize, user_id as u64 + 1000);
+        if user_id < VOICE_CHANNEL_USERS {
+            fan_out.subscribe(user_id as UserIdSize, 0, 0);
+        }
+    }
+    fan_out
+}
+
+/// How sends work now: serialize once into a shared frame, then hand each recipient
+/// its own copy as swiftlet needs, except the last who takes the original
+fn indexed_send(fan_out: &FanOut<u64>, send_to: &SendTo, buffer: &[u8]) {
+    let recipients = fan_out.recipients(send_to);
+    fan_out::deliver(Arc::new(buffer.to_vec()), recipients, |cid, frame| {
+        black_box((cid, fan_out::into_vec(frame)));
+    });
+}
+
+/// How sends worked before the index: scan every client and copy the buffer for each match
+fn scan_and_send(clients: &BTreeMap<u64, UserIdSize>, users: &[UserIdSize], buffer: &[u8]) {
+    for (cid, user_id) in clients {
+        if users.contains(user_id) {
+            black_box((cid, buffer.to_vec()));
+        }
+    }
+}
+
+fn voice_channel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("voice_channel");
+    let frame = vec![0u8; AUDIO_FRAME_SIZE];
+
+    for connections in CONNECTIONS {
+        let fan_out = fan_out_with_users(connections);
+        group.bench_with_input(
+            BenchmarkId::new("indexed", connections),
+            &connections,
+            |b, _| {
+                b.iter(|| {
+                    indexed_send(
+                        &fan_out,
+                        &SendTo::VoiceChannelExceptUserID((0, 0, 0)),
+                        &frame,
+                    )
+                })
+            },
+        );
+
+        let clients: BTreeMap<u64, UserIdSize> = (0..connections)
+            .map(|user_id| (user_id as u64 + 1000, user_id as UserIdSize))
+            .collect();
+        let users: Vec<UserIdSize> = (1..VOICE_CHANNEL_USERS)
+            .map(|id| id as UserIdSize)
+            .collect();
+        group.bench_with_input(
+            BenchmarkId::new("linear_scan", connections),
+            &connections,
+            |b, _| b.iter(|| scan_and_send(&clients, &users, &frame)),
+        );
+    }
+
+    group.finish();
+}
+
+fn single_user(c: &mut Criterion) {
+    let mut group = c.benchmark_group("single_user");
+    let frame = vec![0u8; AUDIO_FRAME_SIZE];
+
+    for connections in CONNECTIONS {
+        let fan_out = fan_out_with_users(connections);
+        let last_user = (connections - 1) as UserIdSize;
+        group.bench_with_input(
+            BenchmarkId::new("indexed", connections),
+            &connections,
+            |b, _| b.iter(|| indexed_send(&fan_out, &SendTo::SingleUser(last_user), &frame)),
+        );
+
+        let clients: BTreeMap<u64, UserIdSize> = (0..connections)
+            .map(|user_id| (user_id as u64 + 1000, user_id as UserIdSize))
+            .collect();
+        group.bench_with_input(
+            BenchmarkId::new("linear_scan", connections),
+            &connections,
+            |b, _| b.iter(|| scan_and_send(&clients, &[last_user], &frame)),
+        );
+    }
+
+    group.finish();
+}
+
+fn everyone(c: &mut Criterion) {
+    let mut group = c.benchmark_group("everyone");
+    let frame = vec![0u8; AUDIO_FRAME_SIZE];
+
+    for connections in CONNECTIONS {
+        let fan_out = fan_out_with_users(connections);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(connections),
+            &connections,
+            |b, _| b.iter(|| indexed_send(&fan_out, &SendTo::Everyone, &frame)),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, voice_channel, single_user, everyone);
+criterion_main!(benches);
@@ -0,0 +1,178 @@
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use message::message::{Message, MessageHeader, MessageType};
+use opus::{Application, Bitrate, Channels, Encoder};
+use server::fan_out::{self, FanOut, SendTo};
+use server::voice_mixer::VoiceMixer;
+use types::{AudioSequenceSize, UserIdSize};
+
+// People in the voice channel, all of them listening
+const LISTENERS: [usize; 3] = [10, 50, 200];
+
+// How many of them are talking at once
+const SPEAKERS: [usize; 3] = [1, 4, 8];
+
+// Frames of speech encoded ahead of time and looped, a second's worth
+const FRAMES: usize = 100;
+
+// Samples in a 10ms mono frame at 48kHz, what clients send by default
+const FRAME_SAMPLES: usize = 480;
+
+/// A second of a different tone for each speaker, encoded as a client would
+fn speech(speakers: usize) -> Vec<Vec<Vec<u8>>> {
+    (0..speakers)
+        .map(|speaker| {
+            let mut encoder = Encoder::new(48000, Channels::Mono, Application::Voip).unwrap();
+            encoder.set_bitrate(Bitrate::Bits(32_000)).unwrap();
+            let hz = 150.0 + 50.0 * speaker as f32;
+
+            (0..FRAMES)
+                .map(|frame| {
+                    let samples: Vec<f32> = (0..FRAME_SAMPLES)
+                        .map(|i| {
+                            let t = (frame * FRAME_SAMPLES + i) as f32 / 48000.0;
+                            0.3 * (std::f32::consts::TAU * hz * t).sin()
+                        })
+                        .collect();
+                    encoder.encode_vec_float(&samples, 4000).unwrap()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Everyone connected and in the same voice channel
+fn voice_channel(listeners: usize) -> FanOut<u64> {
+    let mut fan_out = FanOut::new();
+    for user_id in 0..listeners {
+        fan_out.add_user(user_id as UserIdSize, user_id as u64 + 1000);
+        fan_out.subscribe(user_id as UserIdSize, 0, 0);
+    }
+    fan_out
+}
+
+fn send(fan_out: &FanOut<u64>, send_to: SendTo, message: MessageType) -> usize {
+    let buffer = Message::from(message).into_vec_u8().unwrap();
+    let mut sent = 0;
+    fan_out::deliver(
+        Arc::new(buffer),
+        fan_out.recipients(&send_to),
+        |cid, frame| {
+            // Copied for swiftlet the way the server's transport does
+            let buffer = fan_out::into_vec(frame);
+            sent += buffer.len();
+            black_box((cid, buffer));
+        },
+    );
+    sent
+}
+
+/// Relay one frame from each speaker to everyone else, returning the bytes sent
+fn forward(fan_out: &FanOut<u64>, speech: &[Vec<Vec<u8>>], frame: usize) -> usize {
+    let mut sent = 0;
+    for (speaker, frames) in speech.iter().enumerate() {
+        let user_id = speaker as UserIdSize;
+        let header = MessageHeader::new(user_id, 0, 0);
+        let audio = frames[frame % FRAMES].clone();
+        sent += send(
+            fan_out,
+            SendTo::VoiceChannelExceptUserID((0, 0, user_id)),
+            MessageType::Audio((header, frame as AudioSequenceSize, audio)),
+        );
+    }
+    sent
+}
+
+/// Mix one frame from each speaker and send everyone their mix, returning the bytes sent
+fn mix(
+    mixer: &mut VoiceMixer,
+    fan_out: &FanOut<u64>,
+    listeners: &[UserIdSize],
+    speech: &[Vec<Vec<u8>>],
+    frame: usize,
+) -> usize {
+    for (speaker, frames) in speech.iter().enumerate() {
+        let audio = frames[frame % FRAMES].clone();
+        mixer.receive(
+            0,
+            0,
+            speaker as UserIdSize,
+            frame as AudioSequenceSize,
+            audio,
+        );
+    }
+
+    let mut sent = 0;
+    for mixed in mixer.mix(0, 0, listeners, 0) {
+        let header = MessageHeader::new(mixed.stream_id, 0, 0);
+        sent += send(
+            fan_out,
+            SendTo::Users(mixed.listeners),
+            MessageType::Audio((header, mixed.sequence, mixed.audio)),
+        );
+    }
+    sent
+}
+
+/// Criterion only times things, so print what a listener downloads in a second of each
+fn report_bandwidth(speakers: usize, listeners: usize, speech: &[Vec<Vec<u8>>]) {
+    let fan_out = voice_channel(listeners);
+    let user_ids: Vec<UserIdSize> = (0..listeners).map(|id| id as UserIdSize).collect();
+    let mut mixer = VoiceMixer::new();
+
+    let mut forwarded = 0;
+    let mut mixed = 0;
+    for frame in 0..FRAMES {
+        forwarded += forward(&fan_out, speech, frame);
+        mixed += mix(&mut mixer, &fan_out, &user_ids, speech, frame);
+    }
+
+    let kbps = |bytes: usize| (bytes * 8) as f64 / listeners as f64 / 1000.0;
+    println!(
+        "{} speakers, {} listeners: {:.1} kbps forwarded, {:.1} kbps mixed, per listener",
+        speakers,
+        listeners,
+        kbps(forwarded),
+        kbps(mixed)
+    );
+}
+
+fn forwarding_and_mixing(c: &mut Criterion) {
+    for speakers in SPEAKERS {
+        let speech = speech(speakers);
+        let mut group = c.benchmark_group(format!("{}_speakers", speakers));
+
+        for listeners in LISTENERS {
+            report_bandwidth(speakers, listeners, &speech);
+
+            let fan_out = voice_channel(listeners);
+            let mut frame = 0;
+            group.bench_with_input(
+                BenchmarkId::new("forwarding", listeners),
+                &listeners,
+                |b, _| {
+                    b.iter(|| {
+                        frame += 1;
+                        forward(&fan_out, &speech, frame)
+                    })
+                },
+            );
+
+            let user_ids: Vec<UserIdSize> = (0..listeners).map(|id| id as UserIdSize).collect();
+            let mut mixer = VoiceMixer::new();
+            let mut frame = 0;
+            group.bench_with_input(BenchmarkId::new("mixing", listeners), &listeners, |b, _| {
+                b.iter(|| {
+                    frame += 1;
+                    mix(&mut mixer, &fan_out, &user_ids, &speech, frame)
+                })
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, forwarding_and_mixing);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+use types::{AudioSequenceSize, UserIdSize};
+
+// Frames a report covers, 2.5 seconds of speech
+const REPORT_INTERVAL: u32 = 250;
+
+// Senders keep numbering frames through silence they don't send,
+// so a jump bigger than this is someone pausing rather than loss
+const MAX_LOSS_GAP: AudioSequenceSize = 10;
+
+#[derive(Default)]
+struct SenderLoss {
+    last_sequence: Option<AudioSequenceSize>,
+    expected: u32,
+    received: u32,
+}
+
+/// Counts the audio frames each speaker sent that never arrived,
+/// so they can be told how lossy their connection to us is
+#[derive(Default)]
+pub struct AudioLoss {
+    senders: BTreeMap<UserIdSize, SenderLoss>,
+}
+
+impl AudioLoss {
+    pub fn new() -> AudioLoss {
+        AudioLoss::default()
+    }
+
+    /// Count a frame from `user_id`, returning the frames expected and received
+    /// since the last report once there are enough to report
+    pub fn receive(
+        &mut self,
+        user_id: UserIdSize,
+        sequence: AudioSequenceSize,
+    ) -> Option<(u32, u32)> {
+        let sender = self.senders.entry(user_id).or_default();
+
+        let expected = match sender.last_sequence {
+            Some(last) => match sequence.wrapping_sub(last) {
+                // A frame that arrived late was already counted as lost, take it back
+                gap if gap == 0 || gap > AudioSequenceSize::MAX / 2 => 0,
+                gap if gap > MAX_LOSS_GAP => 1,
+                gap => gap as u32,
+            },
+            None => 1,
+        };
+
+        if expected > 0 {
+            sender.last_sequence = Some(sequence);
+        }
+        sender.expected += expected;
+        sender.received = (sender.received + 1).min(sender.expected);
+
+        if sender.expected < REPORT_INTERVAL {
+            return None;
+        }
+
+        let report = (sender.expected, sender.received);
+        sender.expected = 0;
+        sender.received = 0;
+        Some(report)
+    }
+
+    /// Forget a user once they leave voice
+    pub fn remove_user(&mut self, user_id: UserIdSize) {
+        self.senders.remove(&user_id);
+    }
+}
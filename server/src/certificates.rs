@@ -0,0 +1,405 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use network_manager::certificate;
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType};
+
+/// Name of the directory inside a cert_dir that rotated certificates are kept in
+const PREVIOUS_DIR: &str = "previous";
+
+#[derive(Debug)]
+pub enum CertificateError {
+    FailedToGenerate(rcgen::Error),
+    FailedToWrite(io::Error),
+    FailedToRead(io::Error),
+    NoCertificateToRotate,
+}
+
+impl std::fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CertificateError::FailedToGenerate(e) => {
+                write!(f, "failed to generate certificate: {}", e)
+            }
+            CertificateError::FailedToWrite(e) => write!(f, "failed to write certificate: {}", e),
+            CertificateError::FailedToRead(e) => write!(f, "failed to read certificate: {}", e),
+            CertificateError::NoCertificateToRotate => {
+                write!(f, "no cert.pem and pkey.pem to rotate")
+            }
+        }
+    }
+}
+
+/// Paths to the certificate and private key Kagu expects inside a cert_dir
+pub fn get_pem_paths(cert_dir: &Path) -> (PathBuf, PathBuf) {
+    (cert_dir.join("cert.pem"), cert_dir.join("pkey.pem"))
+}
+
+/// If `cert_dir` has no certificate yet
+pub fn needs_certificate(cert_dir: &Path) -> bool {
+    let (cert, pkey) = get_pem_paths(cert_dir);
+    !cert.exists() || !pkey.exists()
+}
+
+/// The certificate and key a server with this cert_dir presents
+#[derive(Debug, PartialEq)]
+pub struct ServedCertificate {
+    pub cert: PathBuf,
+    pub pkey: PathBuf,
+    /// Fingerprint of the certificate taking over once a rotation's grace period is over
+    pub upcoming_fingerprint: Option<String>,
+}
+
+/// Generate a self-signed certificate and private key for `hostnames` into `cert_dir`.
+/// Returns the SHA-256 fingerprint clients can pin.
+///
+/// The pair is written to temporary files first and renamed over any existing pair.
+/// The old key is put back if the certificate can't follow the new one into place,
+/// so a failure part way through leaves the old certificate and key in place.
+pub fn generate_certificate(
+    hostnames: &[String],
+    cert_dir: &Path,
+) -> Result<String, CertificateError> {
+    let mut params = CertificateParams::new(hostnames.to_vec());
+    let mut distinguished_name = DistinguishedName::new();
+    distinguished_name.push(DnType::OrganizationName, "Kagu");
+    if let Some(hostname) = hostnames.first() {
+        distinguished_name.push(DnType::CommonName, hostname.as_str());
+    }
+    params.distinguished_name = distinguished_name;
+
+    let cert = Certificate::from_params(params).map_err(CertificateError::FailedToGenerate)?;
+    let cert_pem = cert
+        .serialize_pem()
+        .map_err(CertificateError::FailedToGenerate)?;
+
+    fs::create_dir_all(cert_dir).map_err(CertificateError::FailedToWrite)?;
+    let (cert_path, pkey_path) = get_pem_paths(cert_dir);
+    let cert_temp = cert_path.with_extension("pem.new");
+    let pkey_temp = pkey_path.with_extension("pem.new");
+
+    let written = fs::write(&cert_temp, cert_pem)
+        .and_then(|_| write_private_key(&pkey_temp, cert.serialize_private_key_pem()))
+        .and_then(|_| replace_pair(&cert_temp, &cert_path, &pkey_temp, &pkey_path));
+
+    if let Err(e) = written {
+        let _ = fs::remove_file(&cert_temp);
+        let _ = fs::remove_file(&pkey_temp);
+        return Err(CertificateError::FailedToWrite(e));
+    }
+
+    get_fingerprint(cert_dir)
+}
+
+/// Fingerprint of the certificate in `cert_dir`
+pub fn get_fingerprint(cert_dir: &Path) -> Result<String, CertificateError> {
+    let (cert_path, _) = get_pem_paths(cert_dir);
+    load_fingerprint(&cert_path)
+}
+
+/// Replace the certificate in `cert_dir` with a newly generated one.
+///
+/// The server presents one certificate, so the old pair is kept in `cert_dir/previous` and
+/// still presented for `grace_period`, while clients are told the new fingerprint to expect.
+/// Rotating again before that's over replaces the new pair, which nobody has been shown yet.
+/// Returns the old and new fingerprints.
+pub fn rotate_certificate(
+    hostnames: &[String],
+    cert_dir: &Path,
+    grace_period: Duration,
+) -> Result<(String, String), CertificateError> {
+    rotate_certificate_at(hostnames, cert_dir, grace_period, unix_time())
+}
+
+fn rotate_certificate_at(
+    hostnames: &[String],
+    cert_dir: &Path,
+    grace_period: Duration,
+    now: u64,
+) -> Result<(String, String), CertificateError> {
+    if needs_certificate(cert_dir) {
+        return Err(CertificateError::NoCertificateToRotate);
+    }
+
+    prune_expired_certificates_at(cert_dir, now);
+
+    let old_fingerprint = match previous_pair(cert_dir, now) {
+        Some((cert, _)) => load_fingerprint(&cert)?,
+        None => {
+            // Keep a copy of the pair being presented, named after when it stops being kept
+            let expires = now + grace_period.as_secs();
+            let previous_dir = cert_dir.join(PREVIOUS_DIR);
+            fs::create_dir_all(&previous_dir).map_err(CertificateError::FailedToWrite)?;
+
+            let (cert_path, pkey_path) = get_pem_paths(cert_dir);
+            let (cert_kept, pkey_kept) = previous_paths(cert_dir, expires);
+            fs::copy(&cert_path, &cert_kept)
+                .and_then(|_| fs::copy(&pkey_path, &pkey_kept))
+                .map_err(|e| {
+                    let _ = fs::remove_file(&cert_kept);
+                    let _ = fs::remove_file(&pkey_kept);
+                    CertificateError::FailedToWrite(e)
+                })?;
+
+            load_fingerprint(&cert_kept)?
+        }
+    };
+
+    let new_fingerprint = generate_certificate(hostnames, cert_dir)?;
+
+    Ok((old_fingerprint, new_fingerprint))
+}
+
+/// What a server with this cert_dir presents: a rotated out certificate until its
+/// grace period is over, then the current one
+pub fn get_served_certificate(cert_dir: &Path) -> ServedCertificate {
+    get_served_certificate_at(cert_dir, unix_time())
+}
+
+fn get_served_certificate_at(cert_dir: &Path, now: u64) -> ServedCertificate {
+    let (cert, pkey) = get_pem_paths(cert_dir);
+
+    match previous_pair(cert_dir, now) {
+        Some((previous_cert, previous_pkey)) => ServedCertificate {
+            cert: previous_cert,
+            pkey: previous_pkey,
+            upcoming_fingerprint: load_fingerprint(&cert).ok(),
+        },
+        None => ServedCertificate {
+            cert,
+            pkey,
+            upcoming_fingerprint: None,
+        },
+    }
+}
+
+/// Delete rotated certificates whose grace period has passed
+pub fn prune_expired_certificates(cert_dir: &Path) {
+    prune_expired_certificates_at(cert_dir, unix_time());
+}
+
+fn prune_expired_certificates_at(cert_dir: &Path, now: u64) {
+    for (expires, path) in previous_files(cert_dir) {
+        if expires <= now {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// The rotated out pair still in its grace period, if there is one
+fn previous_pair(cert_dir: &Path, now: u64) -> Option<(PathBuf, PathBuf)> {
+    previous_files(cert_dir)
+        .into_iter()
+        .filter(|(expires, _)| *expires > now)
+        .map(|(expires, _)| previous_paths(cert_dir, expires))
+        .find(|(cert, pkey)| cert.exists() && pkey.exists())
+}
+
+/// Files in the previous directory and when each expires
+fn previous_files(cert_dir: &Path) -> Vec<(u64, PathBuf)> {
+    let entries = match fs::read_dir(cert_dir.join(PREVIOUS_DIR)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let expires = name
+                .strip_suffix(".pem")
+                .and_then(|name| name.rsplit('-').next())
+                .and_then(|expires| expires.parse::<u64>().ok())?;
+            Some((expires, entry.path()))
+        })
+        .collect()
+}
+
+fn previous_paths(cert_dir: &Path, expires: u64) -> (PathBuf, PathBuf) {
+    let previous_dir = cert_dir.join(PREVIOUS_DIR);
+    (
+        previous_dir.join(format!("cert-{}.pem", expires)),
+        previous_dir.join(format!("pkey-{}.pem", expires)),
+    )
+}
+
+fn load_fingerprint(cert_path: &Path) -> Result<String, CertificateError> {
+    let der =
+        certificate::load_certificate_der(cert_path).map_err(CertificateError::FailedToRead)?;
+    Ok(certificate::fingerprint(&der))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Rename a new certificate and key over the old pair. Only one file can be renamed at
+/// a time, so the old key is set aside first and put back if the certificate can't follow.
+fn replace_pair(
+    cert_temp: &Path,
+    cert_path: &Path,
+    pkey_temp: &Path,
+    pkey_path: &Path,
+) -> io::Result<()> {
+    let pkey_old = pkey_path.with_extension("pem.old");
+    let had_key = match fs::rename(pkey_path, &pkey_old) {
+        Ok(()) => true,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e),
+    };
+
+    let renamed = fs::rename(pkey_temp, pkey_path).and_then(|_| fs::rename(cert_temp, cert_path));
+    match renamed {
+        Ok(()) if had_key => {
+            let _ = fs::remove_file(&pkey_old);
+        }
+        Ok(()) => (),
+        Err(_) if had_key => {
+            fs::rename(&pkey_old, pkey_path)?;
+        }
+        Err(_) => {
+            let _ = fs::remove_file(pkey_path);
+        }
+    }
+
+    renamed
+}
+
+fn write_private_key(path: &Path, pem: String) -> io::Result<()> {
+    // A leftover file would keep its own permissions
+    let _ = fs::remove_file(path);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    // Only the server should be able to read its key, from the moment it exists
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(pem.as_bytes())?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn hostnames() -> Vec<String> {
+        vec![String::from("localhost")]
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kagu-certs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn generated_keys_are_only_readable_by_the_server() {
+        let cert_dir = temp_dir("generate");
+        assert!(needs_certificate(&cert_dir));
+
+        let fingerprint = generate_certificate(&hostnames(), &cert_dir).unwrap();
+        assert!(!needs_certificate(&cert_dir));
+        assert_eq!(get_fingerprint(&cert_dir).unwrap(), fingerprint);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let (_, pkey) = get_pem_paths(&cert_dir);
+            let mode = fs::metadata(pkey).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&cert_dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_certificates_are_presented_until_the_grace_period_is_over() {
+        let cert_dir = temp_dir("rotate");
+        let first = generate_certificate(&hostnames(), &cert_dir).unwrap();
+
+        let now = unix_time();
+        let (old, new) = rotate_certificate_at(&hostnames(), &cert_dir, DAY, now).unwrap();
+        assert_eq!(old, first);
+        assert_ne!(new, first);
+        assert_eq!(get_fingerprint(&cert_dir).unwrap(), new);
+
+        let served = get_served_certificate_at(&cert_dir, now);
+        assert_eq!(load_fingerprint(&served.cert).unwrap(), first);
+        assert_eq!(served.upcoming_fingerprint, Some(new.clone()));
+
+        // Once it's over, the new one is presented and the old one deleted
+        let later = now + DAY.as_secs();
+        prune_expired_certificates_at(&cert_dir, later);
+        assert!(previous_files(&cert_dir).is_empty());
+        assert_eq!(
+            get_served_certificate_at(&cert_dir, later),
+            ServedCertificate {
+                cert: get_pem_paths(&cert_dir).0,
+                pkey: get_pem_paths(&cert_dir).1,
+                upcoming_fingerprint: None,
+            }
+        );
+
+        fs::remove_dir_all(&cert_dir).unwrap();
+    }
+
+    #[test]
+    fn rotating_again_keeps_presenting_the_same_certificate() {
+        let cert_dir = temp_dir("rotate-twice");
+        let first = generate_certificate(&hostnames(), &cert_dir).unwrap();
+
+        let now = unix_time();
+        rotate_certificate_at(&hostnames(), &cert_dir, DAY, now).unwrap();
+        let (old, newest) = rotate_certificate_at(&hostnames(), &cert_dir, DAY, now + 1).unwrap();
+        assert_eq!(old, first);
+
+        // The pair in between was never presented, so it isn't kept
+        assert_eq!(previous_files(&cert_dir).len(), 2);
+        let served = get_served_certificate_at(&cert_dir, now + 1);
+        assert_eq!(load_fingerprint(&served.cert).unwrap(), first);
+        assert_eq!(served.upcoming_fingerprint, Some(newest));
+
+        fs::remove_dir_all(&cert_dir).unwrap();
+    }
+
+    #[test]
+    fn rotating_needs_a_certificate() {
+        let cert_dir = temp_dir("rotate-nothing");
+        assert!(matches!(
+            rotate_certificate(&hostnames(), &cert_dir, DAY),
+            Err(CertificateError::NoCertificateToRotate)
+        ));
+    }
+
+    #[test]
+    fn a_failed_swap_puts_the_old_key_back() {
+        let cert_dir = temp_dir("swap");
+        generate_certificate(&hostnames(), &cert_dir).unwrap();
+        let (cert_path, pkey_path) = get_pem_paths(&cert_dir);
+        let old_key = fs::read(&pkey_path).unwrap();
+
+        // The new key is in place, but there's no new certificate to follow it
+        let pkey_temp = pkey_path.with_extension("pem.new");
+        fs::write(&pkey_temp, "new key").unwrap();
+        let missing = cert_path.with_extension("pem.new");
+        assert!(replace_pair(&missing, &cert_path, &pkey_temp, &pkey_path).is_err());
+
+        assert_eq!(fs::read(&pkey_path).unwrap(), old_key);
+        assert!(!pkey_path.with_extension("pem.old").exists());
+
+        fs::remove_dir_all(&cert_dir).unwrap();
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::logging::{LogFormat, LogLevel};
+use crate::rate_limiter::RateLimits;
+
+/// Settings read from the server's TOML config file.
+/// Anything left out of the file uses its default.
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: ServerSection,
+    pub network: NetworkConfig,
+    pub limits: LimitsConfig,
+    pub rate_limits: RateLimits,
+    pub default_realm: Option<DefaultRealmConfig>,
+    /// Message of the day, sent to users after they log in
+    pub motd: Option<String>,
+    /// Usernames of moderators and the password each proves they're one with
+    pub moderators: BTreeMap<String, String>,
+    pub retention: RetentionConfig,
+    pub logging: LoggingConfig,
+    pub metrics: MetricsConfig,
+}
+
+/// Settings that may also be given on the command line
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub name: Option<String>,
+    pub port: Option<u16>,
+    pub ipv6: Option<bool>,
+    pub cert_dir: Option<PathBuf>,
+    /// Unix socket `kagu-admin` connects to
+    pub control_socket: Option<PathBuf>,
+    /// Realms and chat history are saved here on shutdown and loaded on start
+    pub state_file: Option<PathBuf>,
+}
+
+/// Settings for the QUIC endpoint. These only take effect on start.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub idle_timeout_ms: u64,
+    pub keep_alive_timeout_ms: Option<u64>,
+    pub reliable_stream_buffer: usize,
+    pub unreliable_stream_buffer: usize,
+    pub recv_buffer_size: usize,
+    pub rt_recv_buffer_size: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            idle_timeout_ms: 5000,
+            keep_alive_timeout_ms: None,
+            reliable_stream_buffer: 65536,
+            unreliable_stream_buffer: 65536,
+            recv_buffer_size: network_manager::BUFFER_SIZE_PER_CONNECTION,
+            rt_recv_buffer_size: 65536,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: usize,
+    pub max_username_length: usize,
+    /// Characters allowed in a single text message
+    pub max_message_length: usize,
+    pub max_realm_name_length: usize,
+    pub max_channel_name_length: usize,
+    pub max_realms: usize,
+    pub max_channels_per_realm: usize,
+    /// Bytes allowed in a single file transfer
+    pub max_file_size: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            max_connections: 256,
+            max_username_length: 32,
+            max_message_length: 2000,
+            max_realm_name_length: 64,
+            max_channel_name_length: 64,
+            max_realms: 64,
+            max_channels_per_realm: 64,
+            max_file_size: 10_000_000,
+        }
+    }
+}
+
+/// A realm created when the server starts without any
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultRealmConfig {
+    pub name: String,
+    #[serde(default)]
+    pub text_channels: Vec<String>,
+    #[serde(default)]
+    pub voice_channels: Vec<String>,
+}
+
+/// How much chat history the server keeps
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// History is kept in memory, so it's always capped
+    pub max_messages_per_channel: usize,
+    pub max_message_age_days: Option<u64>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> RetentionConfig {
+        RetentionConfig {
+            max_messages_per_channel: 10000,
+            max_message_age_days: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LogLevel,
+    /// Only takes effect on restart
+    pub format: LogFormat,
+    /// Also write log lines to this file
+    pub file: Option<PathBuf>,
+}
+
+/// Prometheus metrics endpoint. Only takes effect on restart.
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to serve `/metrics` on, e.g. `127.0.0.1:9464`. Off when left out.
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    FailedToRead(PathBuf, io::Error),
+    FailedToParse(PathBuf, toml::de::Error),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::FailedToRead(path, e) => {
+                write!(f, "failed to read {}: {}", path.display(), e)
+            }
+            ConfigError::FailedToParse(path, e) => {
+                write!(f, "failed to parse {}: {}", path.display(), e)
+            }
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid config:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Read, parse and validate a config file
+    pub fn load(path: &Path) -> Result<ServerConfig, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::FailedToRead(path.to_path_buf(), e))?;
+        let config: ServerConfig = toml::from_str(&contents)
+            .map_err(|e| ConfigError::FailedToParse(path.to_path_buf(), e))?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Check for values that parse fine but can't work
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.network.idle_timeout_ms == 0 {
+            problems.push(String::from(
+                "network.idle_timeout_ms must be greater than 0",
+            ));
+        }
+        if let Some(keep_alive) = self.network.keep_alive_timeout_ms {
+            if keep_alive >= self.network.idle_timeout_ms {
+                problems.push(String::from(
+                    "network.keep_alive_timeout_ms must be less than network.idle_timeout_ms",
+                ));
+            }
+        }
+        for (name, size) in [
+            (
+                "network.reliable_stream_buffer",
+                self.network.reliable_stream_buffer,
+            ),
+            (
+                "network.unreliable_stream_buffer",
+                self.network.unreliable_stream_buffer,
+            ),
+            ("network.recv_buffer_size", self.network.recv_buffer_size),
+            (
+                "network.rt_recv_buffer_size",
+                self.network.rt_recv_buffer_size,
+            ),
+        ] {
+            // Messages are length-prefixed with a u16, so a full one has to fit
+            if size < u16::MAX as usize + 1 {
+                problems.push(format!("{} must be at least 65536 (got {})", name, size));
+            }
+        }
+
+        for (name, limit) in [
+            ("limits.max_connections", self.limits.max_connections),
+            (
+                "limits.max_username_length",
+                self.limits.max_username_length,
+            ),
+            ("limits.max_message_length", self.limits.max_message_length),
+            (
+                "limits.max_realm_name_length",
+                self.limits.max_realm_name_length,
+            ),
+            (
+                "limits.max_channel_name_length",
+                self.limits.max_channel_name_length,
+            ),
+            ("limits.max_realms", self.limits.max_realms),
+            (
+                "limits.max_channels_per_realm",
+                self.limits.max_channels_per_realm,
+            ),
+            ("limits.max_file_size", self.limits.max_file_size),
+        ] {
+            if limit == 0 {
+                problems.push(format!("{} must be greater than 0", name));
+            }
+        }
+        // Channel ids are a u8
+        if self.limits.max_channels_per_realm > 256 {
+            problems.push(format!(
+                "limits.max_channels_per_realm can be at most 256 (got {})",
+                self.limits.max_channels_per_realm
+            ));
+        }
+
+        for (name, bucket) in [
+            ("rate_limits.chat", self.rate_limits.chat),
+            ("rate_limits.typing", self.rate_limits.typing),
+            (
+                "rate_limits.realm_management",
+                self.rate_limits.realm_management,
+            ),
+            ("rate_limits.file_transfer", self.rate_limits.file_transfer),
+            ("rate_limits.requests", self.rate_limits.requests),
+        ] {
+            if bucket.burst == 0 {
+                problems.push(format!("{}.burst must be at least 1", name));
+            }
+            if bucket.per_second <= 0.0 || !bucket.per_second.is_finite() {
+                problems.push(format!(
+                    "{}.per_second must be a positive number (got {})",
+                    name, bucket.per_second
+                ));
+            }
+        }
+        if self.rate_limits.max_strikes == 0 {
+            problems.push(String::from("rate_limits.max_strikes must be at least 1"));
+        }
+        if self.rate_limits.strike_window_secs == 0 {
+            problems.push(String::from(
+                "rate_limits.strike_window_secs must be greater than 0",
+            ));
+        }
+
+        if let Some(realm) = &self.default_realm {
+            if realm.name.trim().is_empty() {
+                problems.push(String::from("default_realm.name can't be empty"));
+            } else if realm.name.chars().count() > self.limits.max_realm_name_length {
+                problems.push(format!(
+                    "default_realm.name is longer than limits.max_realm_name_length ({})",
+                    self.limits.max_realm_name_length
+                ));
+            }
+
+            for (kind, channels) in [
+                ("text_channels", &realm.text_channels),
+                ("voice_channels", &realm.voice_channels),
+            ] {
+                if channels.len() > self.limits.max_channels_per_realm {
+                    problems.push(format!(
+                        "default_realm.{} has more than limits.max_channels_per_realm ({})",
+                        kind, self.limits.max_channels_per_realm
+                    ));
+                }
+                for channel in channels {
+                    if channel.trim().is_empty() {
+                        problems.push(format!("default_realm.{} has an empty name", kind));
+                    } else if channel.chars().count() > self.limits.max_channel_name_length {
+                        problems.push(format!(
+                            "default_realm.{} entry \"{}\" is longer than limits.max_channel_name_length ({})",
+                            kind, channel, self.limits.max_channel_name_length
+                        ));
+                    }
+                }
+            }
+        }
+
+        if self.retention.max_messages_per_channel == 0 {
+            problems.push(String::from(
+                "retention.max_messages_per_channel must be greater than 0",
+            ));
+        }
+        if let Some(0) = self.retention.max_message_age_days {
+            problems.push(String::from(
+                "retention.max_message_age_days must be greater than 0, or left out to keep everything",
+            ));
+        }
+
+        for (username, password) in &self.moderators {
+            if password.is_empty() {
+                problems.push(format!("moderators.{} has an empty password", username));
+            }
+        }
+
+        if let Some(motd) = &self.motd {
+            if motd.chars().count() > self.limits.max_message_length {
+                problems.push(format!(
+                    "motd is longer than limits.max_message_length ({})",
+                    self.limits.max_message_length
+                ));
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Invalid(problems)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Problems `validate` found, or none if it passed
+    fn problems(config: &ServerConfig) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(ConfigError::Invalid(problems)) => problems,
+            Err(e) => panic!("expected validation problems, got {}", e),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(problems(&ServerConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn example_config_parses_and_is_valid() {
+        let example = include_str!("../../kagu-server/server.example.toml");
+        let config: ServerConfig = toml::from_str(example).unwrap();
+
+        assert!(problems(&config).is_empty());
+
+        // Keys the example marks as defaults have to match them
+        assert_eq!(config.network, NetworkConfig::default());
+        assert_eq!(config.limits, LimitsConfig::default());
+        assert_eq!(config.rate_limits, RateLimits::default());
+        assert_eq!(
+            config.retention.max_messages_per_channel,
+            RetentionConfig::default().max_messages_per_channel
+        );
+        assert_eq!(config.logging.level, LogLevel::default());
+        assert_eq!(config.logging.format, LogFormat::default());
+        assert_eq!(
+            config.server.control_socket,
+            Some(PathBuf::from(crate::control::DEFAULT_CONTROL_SOCKET))
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(toml::from_str::<ServerConfig>("[limits]\nmax_connection = 10").is_err());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut config = ServerConfig::default();
+        config.network.keep_alive_timeout_ms = Some(config.network.idle_timeout_ms);
+        config.limits.max_connections = 0;
+        config.rate_limits.chat.per_second = f64::NAN;
+        config.retention.max_messages_per_channel = 0;
+
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 4);
+        assert!(problems[0].starts_with("network.keep_alive_timeout_ms"));
+        assert!(problems[1].starts_with("limits.max_connections"));
+        assert!(problems[2].starts_with("rate_limits.chat.per_second"));
+        assert!(problems[3].starts_with("retention.max_messages_per_channel"));
+    }
+
+    #[test]
+    fn buffers_must_fit_a_whole_message() {
+        let mut config = ServerConfig::default();
+        config.network.recv_buffer_size = 65535;
+
+        assert_eq!(
+            problems(&config),
+            vec![String::from(
+                "network.recv_buffer_size must be at least 65536 (got 65535)"
+            )]
+        );
+    }
+
+    #[test]
+    fn default_realm_has_to_fit_the_limits() {
+        let mut config = ServerConfig::default();
+        config.limits.max_channels_per_realm = 1;
+        config.limits.max_channel_name_length = 4;
+        config.default_realm = Some(DefaultRealmConfig {
+            name: String::from(" "),
+            text_channels: vec![String::from("general")],
+            voice_channels: vec![String::from("one"), String::from("two")],
+        });
+
+        assert_eq!(
+            problems(&config),
+            vec![
+                String::from("default_realm.name can't be empty"),
+                String::from(
+                    "default_realm.text_channels entry \"general\" is longer than limits.max_channel_name_length (4)"
+                ),
+                String::from(
+                    "default_realm.voice_channels has more than limits.max_channels_per_realm (1)"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn moderators_need_a_password() {
+        let config: ServerConfig =
+            toml::from_str("[moderators]\nalice = \"correct horse\"\nbob = \"\"").unwrap();
+
+        assert_eq!(
+            problems(&config),
+            vec![String::from("moderators.bob has an empty password")]
+        );
+    }
+}
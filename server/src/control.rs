@@ -0,0 +1,224 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use realms::realm_desc::RealmDescription;
+use serde::{Deserialize, Serialize};
+use types::{RealmIdSize, UserIdSize};
+
+#[cfg(unix)]
+use crate::server_message::ServerMessage;
+#[cfg(unix)]
+use crossbeam::channel::Sender;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use tracing::{info, warn};
+
+/// Where the control socket is created unless told otherwise
+pub const DEFAULT_CONTROL_SOCKET: &str = "kagu-server.sock";
+
+// How long to wait on the server to answer a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Largest frame either side will read, well above any real request or response
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Requests sent by `kagu-admin` over the control socket
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum ControlRequest {
+    ListUsers,
+    ListRealms,
+    Kick(UserIdSize),
+    /// Kick a user and refuse new connections from their address
+    Ban(UserIdSize),
+    Unban(IpAddr),
+    ListBans,
+    Announce(String),
+    CreateRealm(String),
+    DeleteRealm(RealmIdSize),
+    /// Shut down after this many seconds, warning users along the way.
+    /// Also the reason shown to users and, if restarting, seconds until the server is back.
+    Shutdown((u64, Option<String>, Option<u64>)),
+    CancelShutdown,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ConnectedUser {
+    pub id: UserIdSize,
+    pub username: String,
+    pub address: Option<SocketAddr>,
+    pub latency: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum ControlResponse {
+    Users(Vec<ConnectedUser>),
+    Realms(Vec<RealmDescription>),
+    Bans(Vec<IpAddr>),
+    Done(String),
+    Failed(String),
+}
+
+/// Write a length-prefixed bincode frame
+fn write_frame<T: Serialize>(stream: &mut impl Write, value: &T) -> io::Result<()> {
+    let buffer =
+        bincode::serialize(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    stream.write_all(&(buffer.len() as u32).to_le_bytes())?;
+    stream.write_all(&buffer)?;
+    stream.flush()
+}
+
+/// Read a length-prefixed bincode frame
+fn read_frame<T: for<'de> Deserialize<'de>>(stream: &mut impl Read) -> io::Result<T> {
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length)?;
+
+    // Checked before allocating, so a bad length can't make us reserve gigabytes
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", length),
+        ));
+    }
+
+    let mut buffer = vec![0u8; length];
+    stream.read_exact(&mut buffer)?;
+
+    bincode::deserialize(&buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Connection to a running server's control socket
+#[cfg(unix)]
+pub struct ControlClient {
+    stream: UnixStream,
+}
+
+#[cfg(unix)]
+impl ControlClient {
+    pub fn connect(path: &Path) -> io::Result<ControlClient> {
+        Ok(ControlClient {
+            stream: UnixStream::connect(path)?,
+        })
+    }
+
+    pub fn request(&mut self, request: ControlRequest) -> io::Result<ControlResponse> {
+        write_frame(&mut self.stream, &request)?;
+        read_frame(&mut self.stream)
+    }
+}
+
+/// Listen on a Unix socket and pass requests to the server's event loop
+#[cfg(unix)]
+pub fn start_control_socket(
+    path: &Path,
+    server_message_send: Sender<ServerMessage>,
+) -> io::Result<PathBuf> {
+    // A socket left behind by a server that didn't exit cleanly
+    if path.exists() && UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = bind_private(path)?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = server_message_send.clone();
+                    std::thread::spawn(move || handle_control_connection(stream, sender));
+                }
+                Err(e) => warn!("control socket error: {}", e),
+            }
+        }
+    });
+
+    Ok(path.to_path_buf())
+}
+
+/// Bind a socket only the user running the server may connect to.
+///
+/// The socket is bound inside a new directory only we can enter, made private there,
+/// and then moved into place, so it's never reachable with looser permissions.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let mut private_dir = path.as_os_str().to_owned();
+    private_dir.push(format!(".{}.tmp", std::process::id()));
+    let private_dir = PathBuf::from(private_dir);
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+
+    let private_path = private_dir.join("control.sock");
+    let bound = UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+
+    let _ = std::fs::remove_file(&private_path);
+    let _ = std::fs::remove_dir(&private_dir);
+    bound
+}
+
+#[cfg(unix)]
+fn handle_control_connection(mut stream: UnixStream, server_message_send: Sender<ServerMessage>) {
+    while let Ok(request) = read_frame::<ControlRequest>(&mut stream) {
+        info!("control request: {:?}", request);
+
+        let (reply_send, reply_recv) = crossbeam::channel::bounded(1);
+        let response = match server_message_send.send(ServerMessage::Control((request, reply_send)))
+        {
+            Ok(()) => match reply_recv.recv_timeout(RESPONSE_TIMEOUT) {
+                Ok(response) => response,
+                Err(_) => ControlResponse::Failed(String::from("server did not respond")),
+            },
+            Err(_) => ControlResponse::Failed(String::from("server is not running")),
+        };
+
+        if write_frame(&mut stream, &response).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &ControlRequest::Kick(3)).unwrap();
+
+        let request: ControlRequest = read_frame(&mut buffer.as_slice()).unwrap();
+        assert_eq!(request, ControlRequest::Kick(3));
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_before_reading() {
+        let buffer = u32::MAX.to_le_bytes();
+
+        let error = read_frame::<ControlRequest>(&mut buffer.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_is_only_reachable_by_us() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("kagu-control-{}.sock", std::process::id()));
+        let _listener = bind_private(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(UnixStream::connect(&path).is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use types::{ChannelIdSize, RealmIdSize, UserIdSize};
+
+/// A serialized message, shared by everyone it's sent to
+pub type Frame = Arc<Vec<u8>>;
+
+pub enum SendTo {
+    Everyone,
+    EveryoneExceptUserID(UserIdSize),
+    SingleUser(UserIdSize),
+    Users(Vec<UserIdSize>),
+    // Everyone in a voice channel except one user, usually the one speaking
+    VoiceChannelExceptUserID((RealmIdSize, ChannelIdSize, UserIdSize)),
+}
+
+/// Finds the connections a message goes to without scanning every client.
+/// Generic over the connection id so it can be used without a real endpoint.
+pub struct FanOut<C> {
+    connections: BTreeMap<UserIdSize, C>,
+    // Users in each voice channel, who receive its audio
+    subscribers: BTreeMap<(RealmIdSize, ChannelIdSize), BTreeSet<UserIdSize>>,
+    // Users who asked not to receive audio
+    deafened: BTreeSet<UserIdSize>,
+}
+
+impl<C: Ord + Copy> Default for FanOut<C> {
+    fn default() -> Self {
+        FanOut {
+            connections: BTreeMap::new(),
+            subscribers: BTreeMap::new(),
+            deafened: BTreeSet::new(),
+        }
+    }
+}
+
+impl<C: Ord + Copy> FanOut<C> {
+    pub fn new() -> FanOut<C> {
+        FanOut::default()
+    }
+
+    pub fn add_user(&mut self, user_id: UserIdSize, connection: C) {
+        self.connections.insert(user_id, connection);
+    }
+
+    /// Forget a user and take them out of any voice channel
+    pub fn remove_user(&mut self, user_id: UserIdSize) -> Option<C> {
+        self.unsubscribe_all(user_id);
+        self.deafened.remove(&user_id);
+        self.connections.remove(&user_id)
+    }
+
+    pub fn get_connection(&self, user_id: UserIdSize) -> Option<C> {
+        self.connections.get(&user_id).copied()
+    }
+
+    pub fn subscribe(
+        &mut self,
+        user_id: UserIdSize,
+        realm_id: RealmIdSize,
+        channel_id: ChannelIdSize,
+    ) {
+        self.subscribers
+            .entry((realm_id, channel_id))
+            .or_default()
+            .insert(user_id);
+    }
+
+    pub fn unsubscribe(
+        &mut self,
+        user_id: UserIdSize,
+        realm_id: RealmIdSize,
+        channel_id: ChannelIdSize,
+    ) {
+        if let Some(users) = self.subscribers.get_mut(&(realm_id, channel_id)) {
+            users.remove(&user_id);
+            if users.is_empty() {
+                self.subscribers.remove(&(realm_id, channel_id));
+            }
+        }
+    }
+
+    pub fn unsubscribe_all(&mut self, user_id: UserIdSize) {
+        self.subscribers.retain(|_, users| {
+            users.remove(&user_id);
+            !users.is_empty()
+        });
+    }
+
+    /// Stop or resume sending a user voice channel audio
+    pub fn set_deafened(&mut self, user_id: UserIdSize, deafened: bool) {
+        match deafened {
+            true => self.deafened.insert(user_id),
+            false => self.deafened.remove(&user_id),
+        };
+    }
+
+    /// Drop the subscribers of a voice channel that was removed
+    pub fn remove_channel(&mut self, realm_id: RealmIdSize, channel_id: ChannelIdSize) {
+        self.subscribers.remove(&(realm_id, channel_id));
+    }
+
+    /// Drop the subscribers of every voice channel in a realm that was removed
+    pub fn remove_realm(&mut self, realm_id: RealmIdSize) {
+        self.subscribers.retain(|key, _| key.0 != realm_id);
+    }
+
+    /// Users in a voice channel who want to hear it
+    pub fn listeners(&self, realm_id: RealmIdSize, channel_id: ChannelIdSize) -> Vec<UserIdSize> {
+        match self.subscribers.get(&(realm_id, channel_id)) {
+            Some(users) => users
+                .iter()
+                .filter(|user_id| !self.deafened.contains(user_id))
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Connections a message should be sent to
+    pub fn recipients(&self, send_to: &SendTo) -> Vec<C> {
+        match send_to {
+            SendTo::Everyone => self.connections.values().copied().collect(),
+            SendTo::EveryoneExceptUserID(except_id) => self
+                .connections
+                .iter()
+                .filter(|(user_id, _)| *user_id != except_id)
+                .map(|(_, connection)| *connection)
+                .collect(),
+            SendTo::SingleUser(user_id) => self.get_connection(*user_id).into_iter().collect(),
+            SendTo::Users(user_ids) => user_ids
+                .iter()
+                .filter_map(|user_id| self.get_connection(*user_id))
+                .collect(),
+            SendTo::VoiceChannelExceptUserID((realm_id, channel_id, except_id)) => {
+                match self.subscribers.get(&(*realm_id, *channel_id)) {
+                    Some(users) => users
+                        .iter()
+                        .filter(|user_id| *user_id != except_id)
+                        .filter(|user_id| !self.deafened.contains(user_id))
+                        .filter_map(|user_id| self.get_connection(*user_id))
+                        .collect(),
+                    None => Vec::new(),
+                }
+            }
+        }
+    }
+}
+
+/// Hand a frame to each recipient. They all share the one buffer, and the last
+/// recipient is given the original so it can take the buffer without copying.
+pub fn deliver<C>(frame: Frame, recipients: Vec<C>, mut send: impl FnMut(C, Frame)) {
+    let mut recipients = recipients.into_iter().peekable();
+    while let Some(connection) = recipients.next() {
+        match recipients.peek() {
+            Some(_) => send(connection, Arc::clone(&frame)),
+            None => return send(connection, frame),
+        }
+    }
+}
+
+/// Swiftlet takes ownership of what it sends, so this copies the frame for every
+/// recipient but the one holding the last reference
+pub fn into_vec(frame: Frame) -> Vec<u8> {
+    Arc::try_unwrap(frame).unwrap_or_else(|frame| frame.as_ref().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_last_recipient_gets_the_buffer_without_a_copy() {
+        let frame: Frame = Arc::new(vec![1, 2, 3]);
+        let original = frame.as_ptr();
+
+        let mut buffers = Vec::new();
+        deliver(frame, vec![1, 2, 3], |_, frame| {
+            buffers.push(into_vec(frame))
+        });
+
+        assert_eq!(buffers, vec![vec![1, 2, 3]; 3]);
+        assert_ne!(buffers[0].as_ptr(), original);
+        assert_ne!(buffers[1].as_ptr(), original);
+        assert_eq!(buffers[2].as_ptr(), original);
+    }
+}
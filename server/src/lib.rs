@@ -1,3 +1,14 @@
+pub mod audio_loss;
+pub mod certificates;
+pub mod config;
+pub mod control;
+pub mod fan_out;
+pub mod logging;
+pub mod metrics;
+pub mod rate_limiter;
 pub mod server;
 mod server_message;
 mod server_state;
+mod state_file;
+pub mod transport;
+pub mod voice_mixer;
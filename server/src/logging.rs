@@ -0,0 +1,117 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+
+use serde::Deserialize;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Layer, Registry};
+
+use crate::config::LoggingConfig;
+
+#[derive(Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, including the fields of any open spans
+    Json,
+}
+
+static LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Writes to the log file, if there is one. Swapped out when the config is reloaded.
+struct LogFileWriter;
+
+impl Write for LogFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Ok(mut log_file) = LOG_FILE.lock() {
+            if let Some(file) = log_file.as_mut() {
+                file.write_all(buf)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Ok(mut log_file) = LOG_FILE.lock() {
+            if let Some(file) = log_file.as_mut() {
+                file.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set up logging to stdout and the log file.
+/// Called again when the config is reloaded, which changes the level and file but not the format.
+pub fn init(config: &LoggingConfig) {
+    match LEVEL.get() {
+        Some(level) => {
+            let _ = level.modify(|level| *level = config.level.into());
+        }
+        None => init_subscriber(config),
+    }
+
+    let file = config.file.as_ref().and_then(|path| {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                tracing::error!("failed to open log file {}: {}", path.display(), e);
+                None
+            }
+        }
+    });
+
+    if let Ok(mut log_file) = LOG_FILE.lock() {
+        *log_file = file;
+    }
+}
+
+fn init_subscriber(config: &LoggingConfig) {
+    let (level, handle) = reload::Layer::new(LevelFilter::from(config.level));
+
+    // Every line goes to stdout and the log file
+    let writer = io::stdout.and(|| LogFileWriter);
+    let output = match config.format {
+        LogFormat::Text => fmt::layer().with_ansi(false).with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+    };
+
+    // Whatever embeds the server may have set up its own logging already
+    if tracing_subscriber::registry()
+        .with(level)
+        .with(output)
+        .try_init()
+        .is_ok()
+    {
+        let _ = LEVEL.set(handle);
+    }
+}
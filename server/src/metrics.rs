@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+use types::UserIdSize;
+
+// Upper bounds in seconds of the event loop tick duration buckets
+const TICK_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StreamType {
+    Main,
+    Background,
+    Realtime,
+}
+
+impl StreamType {
+    const ALL: [StreamType; 3] = [
+        StreamType::Main,
+        StreamType::Background,
+        StreamType::Realtime,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            StreamType::Main => "main",
+            StreamType::Background => "background",
+            StreamType::Realtime => "rt",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; TICK_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(TICK_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Counters the server keeps for the Prometheus endpoint
+#[derive(Default)]
+pub struct Metrics {
+    connected_users: AtomicU64,
+    messages_received: Mutex<BTreeMap<&'static str, u64>>,
+    audio_packets_relayed: AtomicU64,
+    bytes_received: [AtomicU64; 3],
+    bytes_sent: [AtomicU64; 3],
+    file_transfer_bytes: AtomicU64,
+    // Username and round trip time of each user, measured with our own pings
+    // since the QUIC endpoint doesn't expose its per-connection stats
+    latencies: Mutex<BTreeMap<UserIdSize, (String, Duration)>>,
+    // Time spent handling each tick, not counting the wait for the next one
+    tick_duration: Mutex<Histogram>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn set_connected_users(&self, users: usize) {
+        self.connected_users.store(users as u64, Ordering::Relaxed);
+    }
+
+    pub fn message_received(&self, name: &'static str) {
+        if let Ok(mut messages) = self.messages_received.lock() {
+            *messages.entry(name).or_default() += 1;
+        }
+    }
+
+    pub fn audio_relayed(&self, packets: usize) {
+        self.audio_packets_relayed
+            .fetch_add(packets as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_received(&self, stream: StreamType, bytes: usize) {
+        self.bytes_received[stream as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_sent(&self, stream: StreamType, bytes: usize) {
+        self.bytes_sent[stream as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn file_transfer_received(&self, bytes: usize) {
+        self.file_transfer_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_latency(&self, user_id: UserIdSize, username: &str, latency: Duration) {
+        if let Ok(mut latencies) = self.latencies.lock() {
+            latencies.insert(user_id, (username.to_string(), latency));
+        }
+    }
+
+    pub fn remove_user(&self, user_id: UserIdSize) {
+        if let Ok(mut latencies) = self.latencies.lock() {
+            latencies.remove(&user_id);
+        }
+    }
+
+    pub fn tick_finished(&self, duration: Duration) {
+        if let Ok(mut histogram) = self.tick_duration.lock() {
+            histogram.observe(duration.as_secs_f64());
+        }
+    }
+
+    /// Everything in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP kagu_connected_users Users currently logged in");
+        let _ = writeln!(out, "# TYPE kagu_connected_users gauge");
+        let _ = writeln!(
+            out,
+            "kagu_connected_users {}",
+            self.connected_users.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP kagu_messages_received_total Messages received by type"
+        );
+        let _ = writeln!(out, "# TYPE kagu_messages_received_total counter");
+        if let Ok(messages) = self.messages_received.lock() {
+            for (name, count) in messages.iter() {
+                let _ = writeln!(
+                    out,
+                    "kagu_messages_received_total{{type=\"{}\"}} {}",
+                    name, count
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP kagu_audio_packets_relayed_total Audio packets sent on to other users"
+        );
+        let _ = writeln!(out, "# TYPE kagu_audio_packets_relayed_total counter");
+        let _ = writeln!(
+            out,
+            "kagu_audio_packets_relayed_total {}",
+            self.audio_packets_relayed.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP kagu_stream_bytes_total Bytes by stream and direction"
+        );
+        let _ = writeln!(out, "# TYPE kagu_stream_bytes_total counter");
+        for stream in StreamType::ALL {
+            for (direction, counters) in [("in", &self.bytes_received), ("out", &self.bytes_sent)] {
+                // The server never sends on the background stream, it's only for uploads
+                if stream == StreamType::Background && direction == "out" {
+                    continue;
+                }
+
+                let _ = writeln!(
+                    out,
+                    "kagu_stream_bytes_total{{stream=\"{}\",direction=\"{}\"}} {}",
+                    stream.label(),
+                    direction,
+                    counters[stream as usize].load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP kagu_file_transfer_bytes_total Bytes of files uploaded"
+        );
+        let _ = writeln!(out, "# TYPE kagu_file_transfer_bytes_total counter");
+        let _ = writeln!(
+            out,
+            "kagu_file_transfer_bytes_total {}",
+            self.file_transfer_bytes.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP kagu_connection_rtt_seconds Round trip time to each user, measured with pings on the main stream"
+        );
+        let _ = writeln!(out, "# TYPE kagu_connection_rtt_seconds gauge");
+        if let Ok(latencies) = self.latencies.lock() {
+            for (user_id, (username, latency)) in latencies.iter() {
+                let _ = writeln!(
+                    out,
+                    "kagu_connection_rtt_seconds{{user_id=\"{}\",username=\"{}\"}} {}",
+                    user_id,
+                    escape_label(username),
+                    latency.as_secs_f64()
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP kagu_tick_duration_seconds Time spent handling each event loop tick"
+        );
+        let _ = writeln!(out, "# TYPE kagu_tick_duration_seconds histogram");
+        if let Ok(histogram) = self.tick_duration.lock() {
+            for (count, bound) in histogram.buckets.iter().zip(TICK_BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "kagu_tick_duration_seconds_bucket{{le=\"{}\"}} {}",
+                    bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "kagu_tick_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(out, "kagu_tick_duration_seconds_sum {}", histogram.sum);
+            let _ = writeln!(out, "kagu_tick_duration_seconds_count {}", histogram.count);
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `GET /metrics` over plain HTTP. Meant to be bound to a local address.
+pub fn start_metrics_server(address: SocketAddr, metrics: Arc<Metrics>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let local_address = listener.local_addr()?;
+
+    std::thread::spawn(move || {
+        // Scrapes are infrequent, so one at a time is plenty
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_scrape(stream, &metrics) {
+                        debug!("metrics request failed: {}", e);
+                    }
+                }
+                Err(e) => warn!("metrics connection error: {}", e),
+            }
+        }
+    });
+
+    Ok(local_address)
+}
+
+fn handle_scrape(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Skip the headers
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", metrics.render())
+        }
+        _ => ("404 Not Found", "text/plain", String::from("not found\n")),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use message::message::MessageType;
+use serde::Deserialize;
+use types::UserIdSize;
+
+/// Kinds of messages that are limited separately from each other
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum RateLimitCategory {
+    Chat,
+    Typing,
+    RealmManagement,
+    FileTransfer,
+    Requests,
+}
+
+impl RateLimitCategory {
+    /// Get the category a message falls under, if it is limited at all
+    pub fn from_message(message: &MessageType) -> Option<RateLimitCategory> {
+        match message {
+            MessageType::Text(_) | MessageType::Reply(_) | MessageType::Image(_) => {
+                Some(RateLimitCategory::Chat)
+            }
+            MessageType::Typing(_) => Some(RateLimitCategory::Typing),
+            MessageType::AddRealm(_)
+            | MessageType::RemoveRealm(_)
+            | MessageType::AddChannel(_)
+            | MessageType::RemoveChannel(_)
+            | MessageType::RenameChannel(_)
+            | MessageType::SetSlowMode(_)
+            | MessageType::SetVoiceBitrate(_)
+            | MessageType::SetVoiceMixing(_) => Some(RateLimitCategory::RealmManagement),
+            MessageType::FileTransferRequest(_) => Some(RateLimitCategory::FileTransfer),
+            MessageType::GetRealms(_)
+            | MessageType::GetAllUsers(_)
+            | MessageType::GetChannelHistory(_)
+            | MessageType::NewFriendRequest(_)
+            | MessageType::VoiceStateUpdate(_)
+            | MessageType::ServerMute(_)
+            | MessageType::ModeratorLogin(_)
+            | MessageType::VoiceRecording(_) => Some(RateLimitCategory::Requests),
+            _ => None,
+        }
+    }
+}
+
+/// Size of a bucket and how fast it refills
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct BucketLimits {
+    /// Messages that may be sent at once
+    pub burst: u32,
+    /// Messages regained every second
+    pub per_second: f64,
+}
+
+impl BucketLimits {
+    pub fn new(burst: u32, per_second: f64) -> BucketLimits {
+        BucketLimits { burst, per_second }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub chat: BucketLimits,
+    pub typing: BucketLimits,
+    pub realm_management: BucketLimits,
+    pub file_transfer: BucketLimits,
+    pub requests: BucketLimits,
+    /// Limited messages allowed within `strike_window_secs` before disconnecting
+    pub max_strikes: u32,
+    pub strike_window_secs: u64,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            chat: BucketLimits::new(10, 2.0),
+            typing: BucketLimits::new(5, 1.0),
+            realm_management: BucketLimits::new(5, 0.2),
+            file_transfer: BucketLimits::new(3, 0.1),
+            requests: BucketLimits::new(20, 5.0),
+            max_strikes: 50,
+            strike_window_secs: 30,
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn get(&self, category: RateLimitCategory) -> BucketLimits {
+        match category {
+            RateLimitCategory::Chat => self.chat,
+            RateLimitCategory::Typing => self.typing,
+            RateLimitCategory::RealmManagement => self.realm_management,
+            RateLimitCategory::FileTransfer => self.file_transfer,
+            RateLimitCategory::Requests => self.requests,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limits: BucketLimits, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: limits.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Take a token, or get how long until one is available
+    fn try_take(&mut self, limits: BucketLimits, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limits.per_second).min(limits.burst as f64);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if limits.per_second > 0.0 {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limits.per_second,
+            ))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: BTreeMap<RateLimitCategory, TokenBucket>,
+}
+
+impl Buckets {
+    fn try_take(
+        &mut self,
+        category: RateLimitCategory,
+        limits: BucketLimits,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        self.buckets
+            .entry(category)
+            .or_insert_with(|| TokenBucket::new(limits, now))
+            .try_take(limits, now)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RateLimitResult {
+    Allowed,
+    /// Over the limit, may try again after this long
+    Limited(Duration),
+    /// Over the limit too often, should be disconnected
+    Abusive,
+}
+
+/// Token buckets for each connection and user, per message category
+pub struct RateLimiter<C> {
+    limits: RateLimits,
+    connections: BTreeMap<C, Buckets>,
+    users: BTreeMap<UserIdSize, Buckets>,
+    strikes: BTreeMap<C, Vec<Instant>>,
+}
+
+impl<C: Ord + Copy> RateLimiter<C> {
+    pub fn new(limits: RateLimits) -> RateLimiter<C> {
+        RateLimiter {
+            limits,
+            connections: BTreeMap::new(),
+            users: BTreeMap::new(),
+            strikes: BTreeMap::new(),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: RateLimits) {
+        self.limits = limits;
+    }
+
+    pub fn check(
+        &mut self,
+        connection: C,
+        user_id: UserIdSize,
+        category: RateLimitCategory,
+    ) -> RateLimitResult {
+        let now = Instant::now();
+        let limits = self.limits.get(category);
+
+        // Both the connection and the user need a token
+        let connection_result = self
+            .connections
+            .entry(connection)
+            .or_default()
+            .try_take(category, limits, now);
+        let user_result = self
+            .users
+            .entry(user_id)
+            .or_default()
+            .try_take(category, limits, now);
+
+        let retry_after = match (connection_result, user_result) {
+            (Ok(()), Ok(())) => return RateLimitResult::Allowed,
+            (Err(a), Err(b)) => a.max(b),
+            (Err(retry_after), _) | (_, Err(retry_after)) => retry_after,
+        };
+
+        // Only count strikes within the window
+        let strike_window = Duration::from_secs(self.limits.strike_window_secs);
+        let strikes = self.strikes.entry(connection).or_default();
+        strikes.retain(|strike| now.duration_since(*strike) < strike_window);
+        strikes.push(now);
+
+        if strikes.len() as u32 >= self.limits.max_strikes {
+            RateLimitResult::Abusive
+        } else {
+            RateLimitResult::Limited(retry_after)
+        }
+    }
+
+    /// Forget a connection once it has closed
+    pub fn remove_connection(&mut self, connection: &C) {
+        self.connections.remove(connection);
+        self.strikes.remove(connection);
+    }
+
+    /// Forget a user once they have no connections left
+    pub fn remove_user(&mut self, user_id: UserIdSize) {
+        self.users.remove(&user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_then_limits() {
+        let limits = BucketLimits::new(3, 1.0);
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(limits, now);
+
+        for _ in 0..3 {
+            assert_eq!(bucket.try_take(limits, now), Ok(()));
+        }
+        assert_eq!(bucket.try_take(limits, now), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn bucket_refills_over_time_up_to_the_burst() {
+        let limits = BucketLimits::new(2, 4.0);
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(limits, start);
+        bucket.try_take(limits, start).unwrap();
+        bucket.try_take(limits, start).unwrap();
+
+        // A quarter of a second buys one token back
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.try_take(limits, later), Ok(()));
+        assert!(bucket.try_take(limits, later).is_err());
+
+        // However long it waits, it never holds more than the burst
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.try_take(limits, much_later), Ok(()));
+        assert_eq!(bucket.try_take(limits, much_later), Ok(()));
+        assert!(bucket.try_take(limits, much_later).is_err());
+    }
+
+    #[test]
+    fn bucket_that_never_refills_says_so() {
+        let limits = BucketLimits::new(1, 0.0);
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(limits, now);
+
+        assert_eq!(bucket.try_take(limits, now), Ok(()));
+        assert_eq!(
+            bucket.try_take(limits, now + Duration::from_secs(3600)),
+            Err(Duration::MAX)
+        );
+    }
+
+    fn limits(burst: u32, max_strikes: u32) -> RateLimits {
+        RateLimits {
+            chat: BucketLimits::new(burst, 0.0),
+            max_strikes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn categories_are_limited_separately() {
+        let mut limiter = RateLimiter::new(limits(1, 10));
+
+        assert_eq!(
+            limiter.check(0u64, 0, RateLimitCategory::Chat),
+            RateLimitResult::Allowed
+        );
+        assert_eq!(
+            limiter.check(0u64, 0, RateLimitCategory::Typing),
+            RateLimitResult::Allowed
+        );
+        assert!(matches!(
+            limiter.check(0u64, 0, RateLimitCategory::Chat),
+            RateLimitResult::Limited(_)
+        ));
+    }
+
+    #[test]
+    fn a_user_is_limited_across_connections() {
+        let mut limiter = RateLimiter::new(limits(1, 10));
+
+        assert_eq!(
+            limiter.check(0u64, 7, RateLimitCategory::Chat),
+            RateLimitResult::Allowed
+        );
+        assert!(matches!(
+            limiter.check(1u64, 7, RateLimitCategory::Chat),
+            RateLimitResult::Limited(_)
+        ));
+
+        // Someone else on their own connection isn't
+        assert_eq!(
+            limiter.check(2u64, 8, RateLimitCategory::Chat),
+            RateLimitResult::Allowed
+        );
+    }
+
+    #[test]
+    fn too_many_strikes_is_abusive() {
+        let mut limiter = RateLimiter::new(limits(0, 3));
+
+        assert!(matches!(
+            limiter.check(0u64, 0, RateLimitCategory::Chat),
+            RateLimitResult::Limited(_)
+        ));
+        assert!(matches!(
+            limiter.check(0u64, 0, RateLimitCategory::Chat),
+            RateLimitResult::Limited(_)
+        ));
+        assert_eq!(
+            limiter.check(0u64, 0, RateLimitCategory::Chat),
+            RateLimitResult::Abusive
+        );
+
+        // Forgetting the connection clears its strikes
+        limiter.remove_connection(&0);
+        assert!(matches!(
+            limiter.check(0u64, 0, RateLimitCategory::Chat),
+            RateLimitResult::Limited(_)
+        ));
+    }
+}
@@ -1,6 +1,14 @@
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::path::{Path, PathBuf};
-
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::certificates;
+use crate::config::ServerConfig;
+use crate::control;
+use crate::logging;
+use crate::metrics::{self, Metrics};
 use crate::server_message::ServerMessage;
 use crate::server_state::ServerState;
 use network_manager::*;
@@ -8,12 +16,18 @@ use network_manager::*;
 use crossbeam::channel::{Receiver, Sender};
 use swiftlet_quic::endpoint::{Config, Endpoint};
 use swiftlet_quic::EndpointHandler;
+use tracing::{error, info, warn};
+
+// How long shutdown waits for the event loop to confirm it has stopped
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct NewServer {
     server_name: String,
     port: u16,
     ipv6: Option<bool>,
     cert_dir: PathBuf,
+    config: ServerConfig,
+    metrics: Arc<Metrics>,
     server_message_send: Sender<ServerMessage>,
     server_message_recv: Receiver<ServerMessage>,
 
@@ -35,6 +49,8 @@ impl NewServer {
             port,
             ipv6,
             cert_dir,
+            config: ServerConfig::default(),
+            metrics: Arc::new(Metrics::new()),
             server_message_send: send,
             server_message_recv: recv,
             el_to_server_recv: el_recv,
@@ -42,7 +58,21 @@ impl NewServer {
         }
     }
 
-    pub fn start_server(&self) {
+    /// Use settings from a config file instead of the defaults
+    pub fn set_config(&mut self, config: ServerConfig) {
+        self.config = config;
+    }
+
+    /// Apply a new config to a running server without dropping connections
+    pub fn reload_config(&self, config: ServerConfig) {
+        let _ = self
+            .server_message_send
+            .send(ServerMessage::ReloadConfig(Box::new(config)));
+    }
+
+    /// Start the server on its own thread.
+    /// The returned handle finishes once the server has shut down.
+    pub fn start_server(&self) -> JoinHandle<()> {
         let bind_address = match self.ipv6 {
             Some(ipv6) => match ipv6 {
                 true => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, self.port, 0, 0)),
@@ -51,27 +81,54 @@ impl NewServer {
             None => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.port)),
         };
 
+        logging::init(&self.config.logging);
+
+        let network = &self.config.network;
         let config = Config {
-            idle_timeout_in_ms: 5000,
-            reliable_stream_buffer: 65536,
-            unreliable_stream_buffer: 65536,
-            keep_alive_timeout: None,
-            initial_main_recv_size: BUFFER_SIZE_PER_CONNECTION,
+            idle_timeout_in_ms: network.idle_timeout_ms,
+            reliable_stream_buffer: network.reliable_stream_buffer,
+            unreliable_stream_buffer: network.unreliable_stream_buffer,
+            keep_alive_timeout: network
+                .keep_alive_timeout_ms
+                .map(std::time::Duration::from_millis),
+            initial_main_recv_size: network.recv_buffer_size,
             main_recv_first_bytes: MESSAGE_HEADER_SIZE,
-            initial_background_recv_size: BUFFER_SIZE_PER_CONNECTION,
+            initial_background_recv_size: network.recv_buffer_size,
             background_recv_first_bytes: MESSAGE_HEADER_SIZE,
-            initial_rt_recv_size: 65536,
+            initial_rt_recv_size: network.rt_recv_buffer_size,
             rt_recv_first_bytes: 0,
         };
 
-        let (cert, pkey) = self.get_pem_paths(&self.cert_dir);
+        let served = certificates::get_served_certificate(&self.cert_dir);
+        let cert = served.cert.to_string_lossy().to_string();
+        let pkey = served.pkey.to_string_lossy().to_string();
+        let upcoming_fingerprint = served.upcoming_fingerprint;
+
+        // Lets operators confirm the fingerprint clients pin on first use
+        match certificate::load_certificate_der(&served.cert) {
+            Ok(der) => info!(
+                "certificate fingerprint: {}",
+                certificate::fingerprint(&der)
+            ),
+            Err(e) => error!("failed to read certificate {}: {}", cert, e),
+        }
+        if let Some(fingerprint) = &upcoming_fingerprint {
+            info!(
+                next = %fingerprint,
+                "presenting a rotated certificate until its grace period is over"
+            );
+        }
 
         let port = self.port;
         let server_name = self.server_name.clone();
         let server_message_recv = self.server_message_recv.clone();
         let el_to_server_send = self.el_to_server_send.clone();
+        let server_config = self.config.clone();
+        let server_metrics = self.metrics.clone();
+        #[cfg(unix)]
+        let control_socket = self.get_control_socket_path();
 
-        let _server_handle = std::thread::spawn(move || {
+        let server_handle = std::thread::spawn(move || {
             let mut server_endpoint = match Endpoint::new_server(
                 bind_address.is_ipv6(),
                 port,
@@ -82,43 +139,96 @@ impl NewServer {
             ) {
                 Ok(endpoint) => endpoint,
                 Err(e) => {
-                    println!("[server] failed to create server endpoint: {:?}", e);
+                    error!("failed to create server endpoint: {:?}", e);
+                    // Nothing to wait for, so don't leave shutdown hanging
+                    let _ = el_to_server_send.send(ServerMessage::GracefullyEnded);
                     return;
                 }
             };
 
-            let mut server_state =
-                ServerState::new(server_name, server_message_recv, el_to_server_send);
+            let mut server_state = ServerState::new(
+                server_name,
+                server_message_recv,
+                el_to_server_send.clone(),
+                server_config,
+                server_metrics,
+            );
+            server_state.set_upcoming_certificate(upcoming_fingerprint);
 
             let mut endpoint_handler =
                 EndpointHandler::new(&mut server_endpoint, &mut server_state);
             match endpoint_handler.run_event_loop(std::time::Duration::from_millis(2)) {
                 Ok(_) => (),
                 Err(e) => {
-                    println!("[server]: event loop error: {:?}", e);
+                    error!("event loop error: {:?}", e);
+                    let _ = el_to_server_send.send(ServerMessage::GracefullyEnded);
                 }
             }
+
+            #[cfg(unix)]
+            let _ = std::fs::remove_file(&control_socket);
+            info!("gracefully shut down");
         });
 
-        println!("[server] server started");
+        #[cfg(unix)]
+        {
+            let control_socket = self.get_control_socket_path();
+            match control::start_control_socket(&control_socket, self.server_message_send.clone()) {
+                Ok(path) => info!("control socket at {}", path.display()),
+                Err(e) => warn!(
+                    "failed to create control socket {}: {}",
+                    control_socket.display(),
+                    e
+                ),
+            }
+        }
+
+        if let Some(address) = self.config.metrics.listen {
+            match metrics::start_metrics_server(address, self.metrics.clone()) {
+                Ok(address) => info!("serving metrics at http://{}/metrics", address),
+                Err(e) => warn!("failed to serve metrics on {}: {}", address, e),
+            }
+        }
+
+        info!("server started");
+
+        server_handle
     }
 
     pub fn stop_server(&self) {
-        println!();
-        println!("[server] stopping server...");
-        let _ = self.server_message_send.send(ServerMessage::ShutDownServer);
-
-        if let Ok(ServerMessage::GracefullyEnded) = self.el_to_server_recv.recv() {}
-        println!("[server] gracefully shut down. exiting");
+        self.shutdown(String::from("The server is shutting down"), None);
     }
 
-    fn get_pem_paths(&self, cert_dir: &Path) -> (String, String) {
-        let mut cert = cert_dir.to_str().unwrap().to_string();
-        cert.push_str("/cert.pem");
+    /// Tell clients why the server is going away and when it'll be back, save state,
+    /// then close all connections. Returns once connections are closed, or after
+    /// `SHUTDOWN_TIMEOUT` if the event loop isn't running to close them.
+    pub fn shutdown(&self, reason: String, restart_eta: Option<Duration>) {
+        info!("stopping server...");
+        let _ = self
+            .server_message_send
+            .send(ServerMessage::ShutDownServer((reason, restart_eta)));
+
+        let ended = self.el_to_server_recv.recv_timeout(SHUTDOWN_TIMEOUT);
+        self.remove_control_socket();
+        match ended {
+            Ok(_) => info!("gracefully shut down. exiting"),
+            Err(_) => warn!(
+                "event loop didn't stop within {:?}, exiting anyway",
+                SHUTDOWN_TIMEOUT
+            ),
+        }
+    }
 
-        let mut pkey = cert_dir.to_str().unwrap().to_string();
-        pkey.push_str("/pkey.pem");
+    fn get_control_socket_path(&self) -> PathBuf {
+        self.config
+            .server
+            .control_socket
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(control::DEFAULT_CONTROL_SOCKET))
+    }
 
-        (cert, pkey)
+    fn remove_control_socket(&self) {
+        #[cfg(unix)]
+        let _ = std::fs::remove_file(self.get_control_socket_path());
     }
 }
@@ -1,4 +1,14 @@
+use crate::config::ServerConfig;
+use crate::control::{ControlRequest, ControlResponse};
+
+use crossbeam::channel::Sender;
+use std::time::Duration;
+
 pub enum ServerMessage {
-    ShutDownServer,
+    // Why the server is shutting down and when it will be back, if restarting
+    ShutDownServer((String, Option<Duration>)),
     GracefullyEnded,
+    ReloadConfig(Box<ServerConfig>),
+    // A request from the control socket and where to send the response
+    Control((ControlRequest, Sender<ControlResponse>)),
 }
@@ -1,25 +1,58 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::audio_loss::AudioLoss;
+use crate::config::ServerConfig;
+use crate::control::{ConnectedUser, ControlRequest, ControlResponse};
+use crate::fan_out::{self, FanOut, Frame, SendTo};
+use crate::logging;
+use crate::metrics::{Metrics, StreamType};
+use crate::rate_limiter::{RateLimitCategory, RateLimitResult, RateLimiter};
 use crate::server_message::ServerMessage;
-use message::message::{Message, MessageType};
+use crate::state_file;
+use crate::transport::Transport;
+use crate::voice_mixer::VoiceMixer;
+use message::message::{Message, MessageHeader, MessageType};
+use message::voice_state::VoiceState;
 use network_manager::MESSAGE_HEADER_SIZE;
+use realms::channels::text_channel::TextChannelMessage;
+use realms::realm::ChannelType;
 use realms::realms_manager::RealmsManager;
-use types::{FileTransferIdSize, UserIdSize};
+use types::{
+    ChannelIdSize, FileTransferIdSize, PingIdSize, RealmIdSize, RealmsVersionSize,
+    TextMessageChunks, UserIdSize,
+};
 use user::User;
 
 use chrono::Utc;
 use crossbeam::channel::{Receiver, Sender};
 use swiftlet_quic::endpoint::{ConnectionEndReason, ConnectionId, Endpoint};
 use swiftlet_quic::EndpointEventCallbacks;
+use tracing::{debug, error, info, info_span, warn, Span};
 
-enum SendTo {
-    Everyone,
-    EveryoneExceptUserID(UserIdSize),
-    SingleUser(UserIdSize),
-    Users(Vec<UserIdSize>),
-}
+// Bitrate limits a voice channel can be given, the range Opus encodes at
+const MIN_VOICE_BITRATE: u32 = 6_000;
+const MAX_VOICE_BITRATE: u32 = 510_000;
+
+// How far over a voice channel's limit a frame may go before it's dropped,
+// since variable bitrate spends more on some frames than others
+const VOICE_BITRATE_TOLERANCE: u64 = 2;
+
+// How often old chat history is pruned
+const RETENTION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often clients are pinged to measure their latency
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+// Seconds left in a shutdown countdown when users are warned
+const SHUTDOWN_WARNINGS: [u64; 9] = [300, 60, 30, 10, 5, 4, 3, 2, 1];
+
+// How long clients get to receive ServerShutdown before their connections are closed
+const SHUTDOWN_DRAIN_TIME: Duration = Duration::from_millis(500);
 
 type DisconnectReasonSize = u64;
 #[derive(Debug)]
@@ -27,39 +60,311 @@ type DisconnectReasonSize = u64;
 enum DisconnectReason {
     ServerShutdown = 0,
     NotLoggedIn,
+    RateLimited,
+    ServerFull,
+    LoginFailed,
+    Kicked,
+    Banned,
 }
 
-pub struct ServerState {
+/// Everything the server keeps track of. Generic over the connection id so it can be
+/// driven by something other than a QUIC endpoint.
+pub struct ServerState<C = ConnectionId> {
     _name: String,
-    clients: BTreeMap<ConnectionId, User>,
+    clients: BTreeMap<C, User>,
+    // Connection of each user and who is in each voice channel
+    fan_out: FanOut<C>,
+    // Connections that proved they're a moderator, and the password they used
+    moderators: BTreeMap<C, String>,
+    // Who muted or deafened themselves or was muted by a moderator
+    voice_states: BTreeMap<UserIdSize, VoiceState>,
+    // Audio frames each speaker sent that didn't make it here
+    audio_loss: AudioLoss,
+    // Voice channel each user recording one is recording
+    recordings: BTreeMap<UserIdSize, (RealmIdSize, ChannelIdSize)>,
+    // Audio waiting to be mixed in voice channels the server mixes
+    voice_mixer: VoiceMixer,
     client_count: UserIdSize,
     realms_manager: RealmsManager,
-    disconnect_queue: Vec<(ConnectionId, DisconnectReasonSize)>,
-    _exiting: bool,
+    // Incremented for every realm change sent to clients
+    realms_version: RealmsVersionSize,
+    disconnect_queue: Vec<(C, DisconnectReasonSize)>,
+    exiting: bool,
     message_receiver: Receiver<ServerMessage>,
     server_message_sender: Sender<ServerMessage>,
     num_files: FileTransferIdSize,
     file_buffers: BTreeMap<FileTransferIdSize, Vec<u8>>,
+    rate_limiter: RateLimiter<C>,
+    // When each user last posted in each slow mode channel
+    slow_mode_posts: BTreeMap<(UserIdSize, RealmIdSize, ChannelIdSize), Instant>,
+    config: ServerConfig,
+    // False if the state file exists but couldn't be read or moved aside
+    state_file_writable: bool,
+    last_retention_check: Instant,
+    // Every open connection, logged in or not
+    connections: BTreeSet<C>,
+    addresses: BTreeMap<C, SocketAddr>,
+    banned_addresses: BTreeSet<IpAddr>,
+    // Outstanding ping to each connection and the latency last measured
+    pings: BTreeMap<C, (PingIdSize, Instant)>,
+    latencies: BTreeMap<C, Duration>,
+    num_pings: PingIdSize,
+    last_ping_round: Instant,
+    // When a countdown shutdown happens and the last warning sent
+    shutdown_at: Option<Instant>,
+    last_shutdown_warning: Option<u64>,
+    // Reason and restart time given for the pending shutdown
+    shutdown_notice: (String, Option<Duration>),
+    // Set once clients have been told we're shutting down
+    draining_since: Option<Instant>,
+    // Fingerprint of the certificate replacing a rotated out one we're still presenting
+    upcoming_certificate: Option<String>,
+    metrics: Arc<Metrics>,
 }
 
-impl ServerState {
+impl<C: Ord + Copy> ServerState<C> {
     pub fn new(
         server_name: String,
         server_message_recv: Receiver<ServerMessage>,
         el_to_server_sender: Sender<ServerMessage>,
-    ) -> ServerState {
-        ServerState {
+        config: ServerConfig,
+        metrics: Arc<Metrics>,
+    ) -> ServerState<C> {
+        let mut server_state = ServerState {
             _name: server_name,
             clients: BTreeMap::new(),
+            fan_out: FanOut::new(),
+            moderators: BTreeMap::new(),
+            voice_states: BTreeMap::new(),
+            audio_loss: AudioLoss::new(),
+            recordings: BTreeMap::new(),
+            voice_mixer: VoiceMixer::new(),
             client_count: 0,
             realms_manager: RealmsManager::default(),
+            realms_version: 0,
             disconnect_queue: Vec::new(),
-            _exiting: false,
+            exiting: false,
             message_receiver: server_message_recv,
             server_message_sender: el_to_server_sender,
             num_files: 0,
             file_buffers: BTreeMap::new(),
+            rate_limiter: RateLimiter::new(config.rate_limits.clone()),
+            slow_mode_posts: BTreeMap::new(),
+            config,
+            state_file_writable: true,
+            last_retention_check: Instant::now(),
+            connections: BTreeSet::new(),
+            addresses: BTreeMap::new(),
+            banned_addresses: BTreeSet::new(),
+            pings: BTreeMap::new(),
+            latencies: BTreeMap::new(),
+            num_pings: 0,
+            last_ping_round: Instant::now(),
+            shutdown_at: None,
+            last_shutdown_warning: None,
+            shutdown_notice: (String::new(), None),
+            draining_since: None,
+            upcoming_certificate: None,
+            metrics,
+        };
+
+        server_state.load_state();
+        server_state.create_default_realm();
+
+        server_state
+    }
+
+    /// Tell everyone who logs in the fingerprint of the certificate we'll present next,
+    /// so clients that pinned the current one can trust it once it takes over
+    pub fn set_upcoming_certificate(&mut self, fingerprint: Option<String>) {
+        self.upcoming_certificate = fingerprint;
+    }
+
+    /// Restore realms saved by the last shutdown
+    fn load_state(&mut self) {
+        let path = match &self.config.server.state_file {
+            Some(path) if path.exists() => path,
+            _ => return,
+        };
+
+        match state_file::load(path) {
+            Ok(realms_manager) => {
+                self.realms_manager = realms_manager;
+                info!("loaded state from {}", path.display());
+            }
+            // Keep what we couldn't read rather than overwriting it on shutdown
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                error!("failed to load state from {}: {}", path.display(), e);
+                match state_file::move_aside(path) {
+                    Ok(aside) => warn!("moved unreadable state file to {}", aside.display()),
+                    Err(e) => {
+                        error!(
+                            "failed to move {} aside, not saving state: {}",
+                            path.display(),
+                            e
+                        );
+                        self.state_file_writable = false;
+                    }
+                }
+            }
+            Err(e) => {
+                error!(
+                    "failed to load state from {}, not saving state: {}",
+                    path.display(),
+                    e
+                );
+                self.state_file_writable = false;
+            }
+        }
+    }
+
+    /// Write realms to the state file, if there is one
+    fn save_state(&self) {
+        if !self.state_file_writable {
+            return;
+        }
+
+        if let Some(path) = &self.config.server.state_file {
+            match state_file::save(path, &self.realms_manager) {
+                Ok(()) => info!("saved state to {}", path.display()),
+                Err(e) => warn!("failed to save state to {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Create the configured default realm if there aren't any realms yet
+    fn create_default_realm(&mut self) {
+        if !self.realms_manager.get_realms().is_empty() {
+            return;
+        }
+
+        if let Some(default_realm) = &self.config.default_realm {
+            let realm_id = self.realms_manager.add_realm(default_realm.name.clone());
+
+            for name in &default_realm.text_channels {
+                self.realms_manager
+                    .add_channel(realm_id, ChannelType::TextChannel, name.clone());
+            }
+
+            for name in &default_realm.voice_channels {
+                self.realms_manager
+                    .add_channel(realm_id, ChannelType::VoiceChannel, name.clone());
+            }
+
+            info!("created default realm {}", default_realm.name);
+        }
+    }
+
+    /// Apply a reloaded config. Network settings only take effect on restart.
+    fn reload_config(&mut self, config: ServerConfig) {
+        if config.network != self.config.network
+            || config.server != self.config.server
+            || config.metrics != self.config.metrics
+        {
+            warn!("server and network settings changed, these take effect on restart");
+        }
+        if config.logging.format != self.config.logging.format {
+            warn!("logging format changed, this takes effect on restart");
+        }
+
+        logging::init(&config.logging);
+        self.rate_limiter.set_limits(config.rate_limits.clone());
+        self.config = config;
+
+        // Moderators removed or given a new password have to prove themselves again
+        let clients = &self.clients;
+        let moderators = &self.config.moderators;
+        self.moderators.retain(|cid, proven| {
+            clients
+                .get(cid)
+                .and_then(|user| moderators.get(user.get_username()))
+                .is_some_and(|password| password == proven)
+        });
+
+        // Apply new retention settings right away
+        self.apply_retention();
+
+        info!("reloaded config");
+    }
+
+    /// Drop chat history past the configured count or age
+    fn apply_retention(&mut self) {
+        let retention = &self.config.retention;
+        let oldest = retention
+            .max_message_age_days
+            .map(|days| Utc::now() - chrono::Duration::days(days as i64));
+
+        for realm in self.realms_manager.get_realms_mut() {
+            for channel in realm.get_text_channels_mut().values_mut() {
+                if let Some(oldest) = oldest {
+                    channel
+                        .chat_history
+                        .retain(|m| m.time_sent.is_none_or(|sent| sent >= oldest));
+                }
+
+                let len = channel.chat_history.len();
+                if len > retention.max_messages_per_channel {
+                    channel
+                        .chat_history
+                        .drain(..len - retention.max_messages_per_channel);
+                }
+            }
+        }
+
+        self.last_retention_check = Instant::now();
+    }
+
+    /// Span for everything that happens on a connection
+    fn connection_span(&self, cid: &C) -> Span {
+        let span = info_span!("connection", address = tracing::field::Empty);
+        if let Some(address) = self.addresses.get(cid) {
+            span.record("address", tracing::field::display(address));
         }
+        span
+    }
+
+    /// Span for everything a logged in user does
+    fn user_span(&self, cid: &C) -> Option<Span> {
+        self.clients
+            .get(cid)
+            .map(|user| info_span!("user", id = user.get_id(), name = user.get_username()))
+    }
+
+    /// Whether the user on `cid` has proved they may change realms and moderate others
+    fn is_moderator(&self, cid: &C) -> bool {
+        self.moderators.contains_key(cid)
+    }
+
+    /// Compare passwords without stopping at the first difference,
+    /// so how long it takes doesn't give away how much was right
+    fn passwords_match(expected: &str, given: &str) -> bool {
+        expected.len() == given.len()
+            && expected
+                .bytes()
+                .zip(given.bytes())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    }
+
+    /// Whether an Opus packet is well over a bitrate limit, 0 for none
+    fn exceeds_bitrate(audio: &[u8], max_bitrate: u32) -> bool {
+        if max_bitrate == 0 {
+            return false;
+        }
+
+        // Packets that can't be parsed have no length in time to measure against
+        let samples = match opus::packet::get_nb_samples(audio, 48000) {
+            Ok(samples) if samples > 0 => samples as u64,
+            _ => return false,
+        };
+
+        let allowed_bits = max_bitrate as u64 * VOICE_BITRATE_TOLERANCE * samples / 48000;
+        audio.len() as u64 * 8 > allowed_bits
+    }
+
+    /// Number of characters in a text message
+    fn text_length(chunks: &TextMessageChunks) -> usize {
+        chunks.iter().map(|chunk| chunk.0.chars().count()).sum()
     }
 
     #[inline]
@@ -67,35 +372,59 @@ impl ServerState {
         usize::from_ne_bytes([read_data[0], read_data[1], 0, 0, 0, 0, 0, 0])
     }
 
-    fn process_message(&mut self, cid: &ConnectionId, message: Message, endpoint: &mut Endpoint) {
-        //println!("{:?}", message);
+    fn process_message(&mut self, cid: &C, message: Message, transport: &mut dyn Transport<C>) {
+        let _connection_span = self.connection_span(cid).entered();
+        let _user_span = self.user_span(cid).map(|span| span.entered());
+
+        debug!(message = ?message.message, "received message");
+        self.metrics.message_received(message.message.name());
 
         // If the user hasn't been logged in, disconnect
         // unless the user is trying to log in
         if !self.clients.contains_key(cid) {
             match message.message {
                 MessageType::LoginAttempt(username) => {
+                    let length = username.chars().count();
+                    if length == 0 || length > self.config.limits.max_username_length {
+                        let message = Message::from(MessageType::LoginFailed);
+                        self.send_to_connection(cid, false, message, transport);
+                        self.disconnect_queue
+                            .push((*cid, DisconnectReason::LoginFailed as u64));
+                        return;
+                    }
+
                     // "Authenticate" this user
                     let user = self.authenticate_user(cid, username);
                     let user_id = user.get_id();
 
                     // Notify the user of a successful login
                     let message = Message::from(MessageType::LoginSuccess(user.clone()));
-                    self.send(SendTo::SingleUser(user_id), false, message, endpoint);
+                    self.send(SendTo::SingleUser(user_id), false, message, transport);
 
-                    println!(
-                        "[server] Authenticated user {} with id {}",
-                        user.get_username(),
-                        user.get_id()
+                    info!(
+                        user_id = user.get_id(),
+                        username = user.get_username(),
+                        "authenticated user"
                     );
 
+                    if let Some(motd) = &self.config.motd {
+                        let message = Message::from(MessageType::Motd(motd.clone()));
+                        self.send(SendTo::SingleUser(user_id), false, message, transport);
+                    }
+
+                    if let Some(fingerprint) = &self.upcoming_certificate {
+                        let message =
+                            Message::from(MessageType::UpcomingCertificate(fingerprint.clone()));
+                        self.send(SendTo::SingleUser(user_id), false, message, transport);
+                    }
+
                     // Announce the new user to everyone
                     let message = Message::from(MessageType::UserJoined(user));
                     self.send(
                         SendTo::EveryoneExceptUserID(user_id),
                         false,
                         message,
-                        endpoint,
+                        transport,
                     );
                 }
                 _ => self
@@ -103,17 +432,36 @@ impl ServerState {
                     .push((*cid, DisconnectReason::NotLoggedIn as u64)),
             }
         } else {
+            if !self.check_rate_limit(cid, &message.message, transport) {
+                return;
+            }
+
             match message.message {
-                MessageType::Disconnecting(user_id) => {
-                    // Remove this user from our list of users
-                    self.clients.retain(|_, u| u.get_id() != user_id);
+                MessageType::Disconnecting(_) => {
+                    // Log out whoever is on this connection, whatever the message says.
+                    // The connection stays open and may log in again, so its rate limits
+                    // and strikes are kept until it closes.
+                    let user_id = self.clients[cid].get_id();
+                    self.clients.remove(cid);
+                    self.moderators.remove(cid);
+                    self.pings.remove(cid);
+                    self.latencies.remove(cid);
+                    self.fan_out.remove_user(user_id);
+                    self.voice_states.remove(&user_id);
+                    self.audio_loss.remove_user(user_id);
+                    self.voice_mixer.remove_user(user_id);
+                    self.rate_limiter.remove_user(user_id);
+                    self.metrics.remove_user(user_id);
+                    self.slow_mode_posts.retain(|key, _| key.0 != user_id);
 
-                    // If this user was in a voice channel, remove them from the channel
+                    // If this user was in a voice channel, remove them from the channel.
+                    // Clients do the same when they see UserLeft, so this isn't a delta
                     self.realms_manager
                         .remove_user_from_voice_channel_global(user_id);
+                    self.stop_recording(user_id, transport);
 
                     let message = Message::from(MessageType::UserLeft(user_id));
-                    self.send(SendTo::Everyone, false, message, endpoint);
+                    self.send(SendTo::Everyone, false, message, transport);
                 }
                 MessageType::GetAllUsers(gau) => {
                     let mut users = Vec::new();
@@ -122,31 +470,141 @@ impl ServerState {
                     }
 
                     let message = Message::from(MessageType::AllUsers(users));
-                    self.send(SendTo::SingleUser(gau.user_id), false, message, endpoint);
+                    self.send(SendTo::SingleUser(gau.user_id), false, message, transport);
                 }
                 MessageType::GetRealms(user_id) => {
-                    let rm = self.realms_manager.clone();
-                    let message = Message::from(MessageType::RealmsManager(rm));
-                    self.send(SendTo::SingleUser(user_id), false, message, endpoint);
+                    let realms = self.realms_manager.get_realm_descriptions();
+                    let message = Message::from(MessageType::Realms((self.realms_version, realms)));
+                    self.send(SendTo::SingleUser(user_id), false, message, transport);
+
+                    // Followed by anyone muted or deafened so they can be shown as such
+                    for (muted_id, state) in &self.voice_states {
+                        if *state != VoiceState::default() {
+                            let header = MessageHeader::new(*muted_id, 0, 0);
+                            let message =
+                                Message::from(MessageType::VoiceStateUpdate((header, *state)));
+                            self.send(SendTo::SingleUser(user_id), false, message, transport);
+                        }
+                    }
+                }
+                MessageType::GetChannelHistory(header) => {
+                    let user_id = self.clients[cid].get_id();
+                    self.send_channel_history(user_id, header, transport);
                 }
                 MessageType::AddRealm(ar) => {
+                    let limits = &self.config.limits;
+                    let length = ar.1.chars().count();
+                    if length == 0
+                        || length > limits.max_realm_name_length
+                        || self.realms_manager.get_realms().len() >= limits.max_realms
+                    {
+                        debug!("rejected realm {} from user {}", ar.1, ar.0.user_id);
+                        return;
+                    }
+
                     let realm_id = self.realms_manager.add_realm(ar.1.clone());
-                    let message = Message::from(MessageType::RealmAdded((realm_id, ar.1)));
-                    self.send(SendTo::Everyone, false, message, endpoint);
+                    self.send_realms_delta(MessageType::RealmAdded((realm_id, ar.1)), transport);
+                }
+                MessageType::RemoveRealm((_, realm_id)) => {
+                    if !self.is_moderator(cid) {
+                        warn!(realm_id, "realm removal from a user who isn't a moderator");
+                        return;
+                    }
+
+                    if self.realms_manager.get_realm(realm_id).is_some() {
+                        self.realms_manager.remove_realm(realm_id);
+                        self.fan_out.remove_realm(realm_id);
+                        self.voice_mixer.remove_realm(realm_id);
+                        self.send_realms_delta(MessageType::RealmRemoved(realm_id), transport);
+                    }
                 }
                 MessageType::AddChannel(ac) => {
-                    let channel =
-                        self.realms_manager
-                            .add_channel(ac.0.realm_id, ac.1.clone(), ac.2);
-                    let message = Message::from(MessageType::ChannelAdded((
-                        ac.0.realm_id,
-                        ac.1,
-                        channel.0,
-                        channel.1,
-                    )));
-                    self.send(SendTo::Everyone, false, message, endpoint);
+                    let limits = &self.config.limits;
+                    let length = ac.2.chars().count();
+                    if length == 0 || length > limits.max_channel_name_length {
+                        return;
+                    }
+
+                    if let Some(realm) = self.realms_manager.get_realm(ac.0.realm_id) {
+                        let num_channels = match ac.1 {
+                            ChannelType::TextChannel => realm.get_text_channels().len(),
+                            ChannelType::VoiceChannel => realm.get_voice_channels().len(),
+                        };
+                        if num_channels >= limits.max_channels_per_realm {
+                            return;
+                        }
+
+                        match self
+                            .realms_manager
+                            .add_channel(ac.0.realm_id, ac.1.clone(), ac.2)
+                        {
+                            Some((channel_id, name)) => {
+                                let delta = MessageType::ChannelAdded((
+                                    ac.0.realm_id,
+                                    ac.1,
+                                    channel_id,
+                                    name,
+                                ));
+                                self.send_realms_delta(delta, transport);
+                            }
+                            None => warn!(
+                                realm_id = ac.0.realm_id,
+                                "channel not added, every channel id is taken"
+                            ),
+                        }
+                    }
+                }
+                MessageType::RemoveChannel((header, channel_type)) => {
+                    if !self.is_moderator(cid) {
+                        warn!(
+                            realm_id = header.realm_id,
+                            channel_id = header.channel_id,
+                            "channel removal from a user who isn't a moderator"
+                        );
+                        return;
+                    }
+
+                    if let Some(realm) = self.realms_manager.get_realm_mut(header.realm_id) {
+                        let exists = match channel_type {
+                            ChannelType::TextChannel => {
+                                realm.get_text_channel(header.channel_id).is_some()
+                            }
+                            ChannelType::VoiceChannel => {
+                                realm.get_voice_channel(header.channel_id).is_some()
+                            }
+                        };
+
+                        if exists {
+                            realm.remove_channel(channel_type.clone(), header.channel_id);
+                            if channel_type == ChannelType::VoiceChannel {
+                                self.fan_out
+                                    .remove_channel(header.realm_id, header.channel_id);
+                                self.voice_mixer
+                                    .remove_channel(header.realm_id, header.channel_id);
+                            }
+                            let delta = MessageType::ChannelRemoved((
+                                header.realm_id,
+                                channel_type,
+                                header.channel_id,
+                            ));
+                            self.send_realms_delta(delta, transport);
+                        }
+                    }
                 }
                 MessageType::Text(mut message) => {
+                    if Self::text_length(&message.1) > self.config.limits.max_message_length {
+                        return;
+                    }
+
+                    // Posted as whoever is on this connection, whatever the header says
+                    message.0.user_id = self.clients[cid].get_id();
+
+                    if let Err(retry_after) = self.check_slow_mode(&message.0) {
+                        let limited = Message::from(MessageType::RateLimited(retry_after));
+                        self.send_to_connection(cid, false, limited, transport);
+                        return;
+                    }
+
                     // Before sending, we need to generate an id for this message
                     if let Some(realm) = self.realms_manager.get_realm_mut(message.0.realm_id) {
                         if let Some(channel) = realm.get_text_channel_mut(message.0.channel_id) {
@@ -158,14 +616,42 @@ impl ServerState {
                             // Set the time the message was sent
                             message.0.datetime = Some(Utc::now());
 
+                            // Keep it for clients that load this channel later
+                            channel.chat_history.push(TextChannelMessage {
+                                message_id: message.0.message_id,
+                                user_id: message.0.user_id,
+                                target_reply_message_id: None,
+                                time_sent: message.0.datetime,
+                                image: None,
+                                message_chunks: message.1.clone(),
+                            });
+
+                            let max = self.config.retention.max_messages_per_channel;
+                            if channel.chat_history.len() > max {
+                                channel.chat_history.remove(0);
+                            }
+
                             let text = Message::from(MessageType::Text(message));
-                            self.send(SendTo::Everyone, false, text, endpoint);
+                            self.send(SendTo::Everyone, false, text, transport);
                         }
                     }
 
                     // If we couldn't find the realm or channel, don't send it
                 }
                 MessageType::Reply(mut message) => {
+                    if Self::text_length(&message.2) > self.config.limits.max_message_length {
+                        return;
+                    }
+
+                    // Posted as whoever is on this connection, whatever the header says
+                    message.0.user_id = self.clients[cid].get_id();
+
+                    if let Err(retry_after) = self.check_slow_mode(&message.0) {
+                        let limited = Message::from(MessageType::RateLimited(retry_after));
+                        self.send_to_connection(cid, false, limited, transport);
+                        return;
+                    }
+
                     // Before sending, we need to generate an id for this message
                     if let Some(realm) = self.realms_manager.get_realm_mut(message.0.realm_id) {
                         if let Some(channel) = realm.get_text_channel_mut(message.0.channel_id) {
@@ -177,26 +663,149 @@ impl ServerState {
                             // Set the time the message was sent
                             message.0.datetime = Some(Utc::now());
 
+                            // Keep it for clients that load this channel later
+                            channel.chat_history.push(TextChannelMessage {
+                                message_id: message.0.message_id,
+                                user_id: message.0.user_id,
+                                target_reply_message_id: Some(message.1),
+                                time_sent: message.0.datetime,
+                                image: None,
+                                message_chunks: message.2.clone(),
+                            });
+
+                            let max = self.config.retention.max_messages_per_channel;
+                            if channel.chat_history.len() > max {
+                                channel.chat_history.remove(0);
+                            }
+
                             let message = Message::from(MessageType::Reply(message));
-                            self.send(SendTo::Everyone, false, message, endpoint);
+                            self.send(SendTo::Everyone, false, message, transport);
                         }
                     }
 
                     // If we couldn't find the realm or channel, don't send it
                 }
+                MessageType::SetSlowMode((header, slow_mode_secs)) => {
+                    if !self.is_moderator(cid) {
+                        warn!(
+                            realm_id = header.realm_id,
+                            channel_id = header.channel_id,
+                            "slow mode change from a user who isn't a moderator"
+                        );
+                        return;
+                    }
+
+                    if let Some(realm) = self.realms_manager.get_realm_mut(header.realm_id) {
+                        if let Some(channel) = realm.get_text_channel_mut(header.channel_id) {
+                            channel.slow_mode_secs = slow_mode_secs;
+
+                            let delta = MessageType::SlowModeChanged((
+                                header.realm_id,
+                                header.channel_id,
+                                slow_mode_secs,
+                            ));
+                            self.send_realms_delta(delta, transport);
+                        }
+                    }
+                }
+                MessageType::SetVoiceBitrate((header, max_bitrate)) => {
+                    if !self.is_moderator(cid) {
+                        warn!(
+                            realm_id = header.realm_id,
+                            channel_id = header.channel_id,
+                            "voice bitrate change from a user who isn't a moderator"
+                        );
+                        return;
+                    }
+                    if max_bitrate != 0
+                        && !(MIN_VOICE_BITRATE..=MAX_VOICE_BITRATE).contains(&max_bitrate)
+                    {
+                        warn!(max_bitrate, "voice bitrate limit out of range");
+                        return;
+                    }
+
+                    if let Some(realm) = self.realms_manager.get_realm_mut(header.realm_id) {
+                        if let Some(channel) = realm.get_voice_channel_mut(header.channel_id) {
+                            channel.max_bitrate = max_bitrate;
+
+                            let delta = MessageType::VoiceBitrateChanged((
+                                header.realm_id,
+                                header.channel_id,
+                                max_bitrate,
+                            ));
+                            self.send_realms_delta(delta, transport);
+                        }
+                    }
+                }
+                MessageType::SetVoiceMixing((header, mixing)) => {
+                    if !self.is_moderator(cid) {
+                        warn!(
+                            realm_id = header.realm_id,
+                            channel_id = header.channel_id,
+                            "voice mixing change from a user who isn't a moderator"
+                        );
+                        return;
+                    }
+
+                    if let Some(realm) = self.realms_manager.get_realm_mut(header.realm_id) {
+                        if let Some(channel) = realm.get_voice_channel_mut(header.channel_id) {
+                            channel.mixing = mixing;
+                            if !mixing {
+                                self.voice_mixer
+                                    .remove_channel(header.realm_id, header.channel_id);
+                            }
+
+                            info!(
+                                realm_id = header.realm_id,
+                                channel_id = header.channel_id,
+                                mixing,
+                                "voice channel mixing changed"
+                            );
+                            let delta = MessageType::VoiceMixingChanged((
+                                header.realm_id,
+                                header.channel_id,
+                                mixing,
+                            ));
+                            self.send_realms_delta(delta, transport);
+                        }
+                    }
+                }
                 MessageType::Typing(message) => {
                     let id = message.user_id;
                     let message = Message::from(MessageType::Typing(message));
-                    self.send(SendTo::EveryoneExceptUserID(id), false, message, endpoint);
+                    self.send(SendTo::EveryoneExceptUserID(id), false, message, transport);
                 }
                 MessageType::UserJoinedVoiceChannel(message) => {
                     if let Some(realm) = self.realms_manager.get_realm_mut(message.realm_id) {
                         if let Some(channel) = realm.get_voice_channel_mut(message.channel_id) {
                             channel.get_connected_users_mut().push(message.user_id);
+                            self.fan_out.subscribe(
+                                message.user_id,
+                                message.realm_id,
+                                message.channel_id,
+                            );
 
-                            let message =
-                                Message::from(MessageType::UserJoinedVoiceChannel(message));
-                            self.send(SendTo::Everyone, false, message, endpoint);
+                            let delta = MessageType::UserJoinedVoiceChannel(message);
+                            self.send_realms_delta(delta, transport);
+
+                            // Nobody joins a channel without knowing it's being recorded
+                            for (recorder_id, channel) in &self.recordings {
+                                if *channel == (message.realm_id, message.channel_id) {
+                                    let header = MessageHeader::new(
+                                        *recorder_id,
+                                        message.realm_id,
+                                        message.channel_id,
+                                    );
+                                    let recording =
+                                        Message::from(MessageType::VoiceRecording((header, true)));
+                                    self.send(
+                                        SendTo::SingleUser(message.user_id),
+                                        false,
+                                        recording,
+                                        transport,
+                                    );
+                                }
+                            }
                         }
                     }
                 }
@@ -206,44 +815,211 @@ impl ServerState {
                             channel
                                 .get_connected_users_mut()
                                 .retain(|user_id| *user_id != message.user_id);
+                            self.fan_out.unsubscribe(
+                                message.user_id,
+                                message.realm_id,
+                                message.channel_id,
+                            );
+                            self.audio_loss.remove_user(message.user_id);
+                            self.voice_mixer.remove_user(message.user_id);
 
-                            let message = Message::from(MessageType::UserLeftVoiceChannel(message));
-                            self.send(SendTo::Everyone, false, message, endpoint);
+                            let delta = MessageType::UserLeftVoiceChannel(message);
+                            self.send_realms_delta(delta, transport);
+                            self.stop_recording(message.user_id, transport);
                         }
                     }
                 }
                 MessageType::NewFriendRequest((header, requested_id)) => {
                     let message =
                         Message::from(MessageType::NewFriendRequest((header, requested_id)));
-                    self.send(SendTo::SingleUser(requested_id), false, message, endpoint);
+                    self.send(SendTo::SingleUser(requested_id), false, message, transport);
                 }
                 MessageType::RemoveFriend((header, old_friend_id)) => {
                     // Break the bad news to this now former friend
                     let message = Message::from(MessageType::FriendshipEnded(header));
-                    self.send(SendTo::SingleUser(old_friend_id), false, message, endpoint);
+                    self.send(SendTo::SingleUser(old_friend_id), false, message, transport);
                 }
                 MessageType::FriendRequestAccepted((header, new_friend_id)) => {
                     let message =
                         Message::from(MessageType::FriendRequestAccepted((header, new_friend_id)));
-                    self.send(SendTo::SingleUser(new_friend_id), false, message, endpoint);
+                    self.send(SendTo::SingleUser(new_friend_id), false, message, transport);
                 }
                 MessageType::FriendRequestRejected((header, rejected_id)) => {
                     let message =
                         Message::from(MessageType::FriendRequestRejected((header, rejected_id)));
-                    self.send(SendTo::SingleUser(rejected_id), false, message, endpoint);
+                    self.send(SendTo::SingleUser(rejected_id), false, message, transport);
+                }
+                MessageType::VoiceStateUpdate((header, state)) => {
+                    // Users can only change their own state, and can't unmute a server mute
+                    let user_id = self.clients[cid].get_id();
+                    let server_muted = self
+                        .voice_states
+                        .get(&user_id)
+                        .map(|state| state.server_muted)
+                        .unwrap_or(false);
+                    let state = VoiceState {
+                        server_muted,
+                        ..state
+                    };
+
+                    let header = MessageHeader::new(user_id, header.realm_id, header.channel_id);
+                    self.set_voice_state(header, state, transport);
+                }
+                MessageType::ServerMute((header, target_id, muted)) => {
+                    if !self.is_moderator(cid) {
+                        warn!(target_id, "server mute from a user who isn't a moderator");
+                        return;
+                    }
+
+                    info!(target_id, muted, "server mute");
+                    let state = VoiceState {
+                        server_muted: muted,
+                        ..self
+                            .voice_states
+                            .get(&target_id)
+                            .copied()
+                            .unwrap_or_default()
+                    };
+                    let header = MessageHeader::new(target_id, header.realm_id, header.channel_id);
+                    self.set_voice_state(header, state, transport);
+                }
+                MessageType::ModeratorLogin(password) => {
+                    let username = self.clients[cid].get_username();
+                    let proven = self
+                        .config
+                        .moderators
+                        .get(username)
+                        .filter(|expected| Self::passwords_match(expected, &password.0))
+                        .cloned();
+
+                    let message =
+                        Message::from(MessageType::ModeratorLoginResult(proven.is_some()));
+                    self.send_to_connection(cid, false, message, transport);
+
+                    match proven {
+                        Some(password) => {
+                            info!("logged in as a moderator");
+                            self.moderators.insert(*cid, password);
+                        }
+                        // Make every guess cost a new connection
+                        None => {
+                            warn!("wrong moderator password");
+                            self.disconnect_queue
+                                .push((*cid, DisconnectReason::LoginFailed as u64));
+                        }
+                    }
                 }
-                MessageType::Audio((header, audio)) => {
-                    if let Some(realm) = self.realms_manager.get_realm(header.realm_id) {
-                        if let Some(channel) = realm.get_voice_channel(header.channel_id) {
-                            let mut users = channel.get_connected_users().clone();
+                MessageType::VoiceRecording((header, recording)) => {
+                    let user_id = self.clients[cid].get_id();
+                    if !recording {
+                        self.stop_recording(user_id, transport);
+                        return;
+                    }
 
-                            // Don't echo audio back to the user speaking
-                            users.retain(|user| user != &header.user_id);
+                    // Only people in a voice channel can record it
+                    let channel = (header.realm_id, header.channel_id);
+                    if !self
+                        .voice_channel_users(header.realm_id, header.channel_id)
+                        .contains(&user_id)
+                    {
+                        warn!(
+                            user_id,
+                            realm_id = header.realm_id,
+                            channel_id = header.channel_id,
+                            "recording a voice channel the user isn't in"
+                        );
+                        return;
+                    }
+
+                    if self.recordings.get(&user_id) == Some(&channel) {
+                        return;
+                    }
+                    self.stop_recording(user_id, transport);
 
-                            let message = Message::from(MessageType::Audio((header, audio)));
-                            self.send(SendTo::Users(users), true, message, endpoint);
+                    info!(
+                        user_id,
+                        realm_id = header.realm_id,
+                        channel_id = header.channel_id,
+                        "started recording a voice channel"
+                    );
+                    self.recordings.insert(user_id, channel);
+                    self.update_deafened(user_id);
+                    let header = MessageHeader::new(user_id, header.realm_id, header.channel_id);
+                    self.announce_recording(header, true, transport);
+                }
+                MessageType::Audio((mut header, sequence, audio)) => {
+                    // Speak as whoever is on this connection, whatever the header says
+                    header.user_id = self.clients[cid].get_id();
+
+                    // Muted users shouldn't be sending audio, and nobody hears it if they do
+                    if let Some(state) = self.voice_states.get(&header.user_id) {
+                        if !state.can_speak() {
+                            return;
                         }
                     }
+
+                    // Only the channel the speaker is in hears them, whatever the header says
+                    let channel = self
+                        .realms_manager
+                        .get_realm(header.realm_id)
+                        .and_then(|realm| realm.get_voice_channel(header.channel_id));
+                    let in_channel = channel.is_some_and(|channel| {
+                        channel.get_connected_users().contains(&header.user_id)
+                    });
+                    if !in_channel {
+                        debug!(
+                            user_id = header.user_id,
+                            realm_id = header.realm_id,
+                            channel_id = header.channel_id,
+                            "dropping audio for a voice channel the speaker isn't in"
+                        );
+                        return;
+                    }
+
+                    // Frames over the channel's limit are dropped, which the speaker sees as loss
+                    let max_bitrate = channel.map_or(0, |channel| channel.max_bitrate);
+                    if Self::exceeds_bitrate(&audio, max_bitrate) {
+                        debug!(
+                            bytes = audio.len(),
+                            max_bitrate, "dropping audio over the channel's bitrate limit"
+                        );
+                        return;
+                    }
+
+                    // Let the speaker know how much of what they send is lost on the way here,
+                    // so they can lower their bitrate or add more error correction
+                    if let Some(loss) = self.audio_loss.receive(header.user_id, sequence) {
+                        let message = Message::from(MessageType::AudioLoss(loss));
+                        self.send(
+                            SendTo::SingleUser(header.user_id),
+                            false,
+                            message,
+                            transport,
+                        );
+                    }
+
+                    // Listeners in mixed channels get the server's mix instead, sent each tick
+                    if self.is_mixed(header.realm_id, header.channel_id) {
+                        self.voice_mixer.receive(
+                            header.realm_id,
+                            header.channel_id,
+                            header.user_id,
+                            sequence,
+                            audio,
+                        );
+                        return;
+                    }
+
+                    // Don't echo audio back to the user speaking
+                    let send_to = SendTo::VoiceChannelExceptUserID((
+                        header.realm_id,
+                        header.channel_id,
+                        header.user_id,
+                    ));
+
+                    let message = Message::from(MessageType::Audio((header, sequence, audio)));
+                    let relayed = self.send(send_to, true, message, transport);
+                    self.metrics.audio_relayed(relayed);
                 }
                 MessageType::Ping(ping_id) => {
                     let ping_message = Message::from(MessageType::PingReply(ping_id));
@@ -251,9 +1027,26 @@ impl ServerState {
                         SendTo::SingleUser(message.user_id),
                         true,
                         ping_message,
-                        endpoint,
+                        transport,
                     );
                 }
+                MessageType::PingReply(ping_id) => {
+                    if let Some((id, sent)) = self.pings.get(cid) {
+                        if *id == ping_id {
+                            let latency = sent.elapsed();
+                            self.latencies.insert(*cid, latency);
+                            self.pings.remove(cid);
+
+                            if let Some(user) = self.clients.get(cid) {
+                                self.metrics.set_latency(
+                                    user.get_id(),
+                                    user.get_username(),
+                                    latency,
+                                );
+                            }
+                        }
+                    }
+                }
                 MessageType::FileTransferRequest(ftr) => {
                     // Get file transfer session id
                     let id = self.num_files;
@@ -265,12 +1058,20 @@ impl ServerState {
                     self.file_buffers.insert(id, Vec::new());
 
                     let message = Message::from(MessageType::FileTransferApproved(id));
-                    self.send(SendTo::SingleUser(ftr.user_id), false, message, endpoint);
+                    self.send(SendTo::SingleUser(ftr.user_id), false, message, transport);
                 }
                 MessageType::FileTransfer(transfer) => {
+                    self.metrics.file_transfer_received(transfer.data.len());
+
                     // todo: handle file transfers that shouldn't be happening (not approved/added)
                     if let Some(buffer) = self.file_buffers.get_mut(&transfer.id) {
-                        buffer.extend(transfer.data);
+                        if buffer.len() + transfer.data.len() > self.config.limits.max_file_size {
+                            // Too big, so stop accepting this transfer
+                            warn!("file transfer {} exceeded the size limit", transfer.id);
+                            self.file_buffers.remove(&transfer.id);
+                        } else {
+                            buffer.extend(transfer.data);
+                        }
                     }
                 }
                 MessageType::FileTransferComplete(tid) => {
@@ -286,12 +1087,240 @@ impl ServerState {
                         let _ = file.write_all(buffer);
                     }
                 }
-                _ => println!("Not implemented: {:?}", message),
+                _ => debug!("not implemented: {:?}", message),
+            }
+        }
+    }
+
+    /// Returns false if this message should be dropped for going over a rate limit
+    fn check_rate_limit(
+        &mut self,
+        cid: &C,
+        message: &MessageType,
+        transport: &mut dyn Transport<C>,
+    ) -> bool {
+        let category = match RateLimitCategory::from_message(message) {
+            Some(category) => category,
+            None => return true,
+        };
+
+        let user_id = match self.clients.get(cid) {
+            Some(user) => user.get_id(),
+            None => return false,
+        };
+
+        match self.rate_limiter.check(*cid, user_id, category) {
+            RateLimitResult::Allowed => true,
+            RateLimitResult::Limited(retry_after) => {
+                // Typing indicators are quietly dropped, there's nothing for the user to retry
+                if category != RateLimitCategory::Typing {
+                    let message = Message::from(MessageType::RateLimited(retry_after));
+                    self.send_to_connection(cid, false, message, transport);
+                }
+                false
+            }
+            RateLimitResult::Abusive => {
+                warn!("disconnecting user {} for exceeding rate limits", user_id);
+                self.disconnect_queue
+                    .push((*cid, DisconnectReason::RateLimited as u64));
+                false
+            }
+        }
+    }
+
+    /// Check a user is allowed to post in a channel, recording the post if so.
+    /// `header.user_id` must already be the poster's own id.
+    fn check_slow_mode(&mut self, header: &MessageHeader) -> Result<(), Duration> {
+        let slow_mode_secs = self
+            .realms_manager
+            .get_realm(header.realm_id)
+            .and_then(|realm| realm.get_text_channel(header.channel_id))
+            .map_or(0, |channel| channel.slow_mode_secs);
+
+        if slow_mode_secs == 0 {
+            return Ok(());
+        }
+
+        let key = (header.user_id, header.realm_id, header.channel_id);
+        let interval = Duration::from_secs(slow_mode_secs as u64);
+        let now = Instant::now();
+
+        if let Some(last_post) = self.slow_mode_posts.get(&key) {
+            let elapsed = now.duration_since(*last_post);
+            if elapsed < interval {
+                return Err(interval - elapsed);
+            }
+        }
+
+        self.slow_mode_posts.insert(key, now);
+        Ok(())
+    }
+
+    /// Record a user's voice state and tell everyone, so it can be shown next to them
+    fn set_voice_state(
+        &mut self,
+        header: MessageHeader,
+        state: VoiceState,
+        transport: &mut dyn Transport<C>,
+    ) {
+        match state == VoiceState::default() {
+            true => self.voice_states.remove(&header.user_id),
+            false => self.voice_states.insert(header.user_id, state),
+        };
+        self.update_deafened(header.user_id);
+
+        let message = Message::from(MessageType::VoiceStateUpdate((header, state)));
+        self.send(SendTo::Everyone, false, message, transport);
+    }
+
+    /// Stop sending a user audio while they're deafened, unless they're recording the call
+    fn update_deafened(&mut self, user_id: UserIdSize) {
+        let deafened = self
+            .voice_states
+            .get(&user_id)
+            .is_some_and(|state| state.deafened);
+        let recording = self.recordings.contains_key(&user_id);
+        self.fan_out.set_deafened(user_id, deafened && !recording);
+    }
+
+    /// Users connected to a voice channel, none if it doesn't exist
+    fn voice_channel_users(
+        &self,
+        realm_id: RealmIdSize,
+        channel_id: ChannelIdSize,
+    ) -> Vec<UserIdSize> {
+        self.realms_manager
+            .get_realm(realm_id)
+            .and_then(|realm| realm.get_voice_channel(channel_id))
+            .map(|channel| channel.get_connected_users().clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether the server mixes a voice channel rather than forwarding each speaker
+    fn is_mixed(&self, realm_id: RealmIdSize, channel_id: ChannelIdSize) -> bool {
+        self.realms_manager
+            .get_realm(realm_id)
+            .and_then(|realm| realm.get_voice_channel(channel_id))
+            .is_some_and(|channel| channel.mixing)
+    }
+
+    /// Send listeners in mixed voice channels every frame mixed since the last tick
+    fn mix_voice(&mut self, transport: &mut dyn Transport<C>) {
+        for _ in 0..self.voice_mixer.frames_due(Instant::now()) {
+            for (realm_id, channel_id) in self.voice_mixer.channels() {
+                let listeners = self.fan_out.listeners(realm_id, channel_id);
+                let max_bitrate = self
+                    .realms_manager
+                    .get_realm(realm_id)
+                    .and_then(|realm| realm.get_voice_channel(channel_id))
+                    .map_or(0, |channel| channel.max_bitrate);
+
+                let frames = self
+                    .voice_mixer
+                    .mix(realm_id, channel_id, &listeners, max_bitrate);
+                for frame in frames {
+                    let header = MessageHeader::new(frame.stream_id, realm_id, channel_id);
+                    let message =
+                        Message::from(MessageType::Audio((header, frame.sequence, frame.audio)));
+                    let relayed =
+                        self.send(SendTo::Users(frame.listeners), true, message, transport);
+                    self.metrics.audio_relayed(relayed);
+                }
+            }
+        }
+    }
+
+    /// Tell everyone in a voice channel that someone started or stopped recording it
+    fn announce_recording(
+        &mut self,
+        header: MessageHeader,
+        recording: bool,
+        transport: &mut dyn Transport<C>,
+    ) {
+        let users = self.voice_channel_users(header.realm_id, header.channel_id);
+        let message = Message::from(MessageType::VoiceRecording((header, recording)));
+        self.send(SendTo::Users(users), false, message, transport);
+    }
+
+    /// Stop a user's recording if they had one going, letting the channel know
+    fn stop_recording(&mut self, user_id: UserIdSize, transport: &mut dyn Transport<C>) {
+        if let Some((realm_id, channel_id)) = self.recordings.remove(&user_id) {
+            self.update_deafened(user_id);
+            info!(
+                user_id,
+                realm_id, channel_id, "stopped recording a voice channel"
+            );
+            let header = MessageHeader::new(user_id, realm_id, channel_id);
+            self.announce_recording(header, false, transport);
+        }
+    }
+
+    /// Bump the realms version and send a change to everyone
+    fn send_realms_delta(&mut self, delta: MessageType, transport: &mut dyn Transport<C>) {
+        self.realms_version += 1;
+
+        let message = Message::from(MessageType::RealmsDelta((
+            self.realms_version,
+            Box::new(delta),
+        )));
+        self.send(SendTo::Everyone, false, message, transport);
+    }
+
+    /// Send a text channel's history, split so each chunk fits in one length-prefixed message
+    fn send_channel_history(
+        &self,
+        user_id: UserIdSize,
+        header: MessageHeader,
+        transport: &mut dyn Transport<C>,
+    ) {
+        if let Some(realm) = self.realms_manager.get_realm(header.realm_id) {
+            if let Some(channel) = realm.get_text_channel(header.channel_id) {
+                for chunk in Self::history_chunks(&channel.chat_history) {
+                    let message = Message::from(MessageType::ChannelHistory((
+                        header.realm_id,
+                        header.channel_id,
+                        chunk,
+                    )));
+                    self.send(SendTo::SingleUser(user_id), false, message, transport);
+                }
             }
         }
     }
 
-    fn authenticate_user(&mut self, cid: &ConnectionId, username: String) -> User {
+    // Split history into chunks whose ChannelHistory message stays within the
+    // u16 length prefix. A message too big to fit on its own is left out.
+    fn history_chunks(history: &[TextChannelMessage]) -> Vec<Vec<TextChannelMessage>> {
+        let empty = Message::from(MessageType::ChannelHistory((0, 0, Vec::new())));
+        let budget = u16::MAX as u64 - bincode::serialized_size(&empty).unwrap();
+
+        let mut chunks = Vec::new();
+        let mut chunk = Vec::new();
+        let mut chunk_size = 0;
+        for message in history {
+            let size = bincode::serialized_size(message).unwrap();
+            if size > budget {
+                warn!(
+                    "Leaving message {:?} out of channel history, it's too big to send ({} bytes)",
+                    message.message_id, size
+                );
+                continue;
+            }
+
+            if chunk_size + size > budget {
+                chunks.push(std::mem::take(&mut chunk));
+                chunk_size = 0;
+            }
+            chunk.push(message.clone());
+            chunk_size += size;
+        }
+
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    fn authenticate_user(&mut self, cid: &C, username: String) -> User {
         // Generate a user id for this user
         let user_id = self.client_count;
         self.client_count += 1;
@@ -299,31 +1328,246 @@ impl ServerState {
         // Add this user to our list of clients
         let user = User::new(user_id, username);
         self.clients.insert(*cid, user.clone());
+        self.fan_out.add_user(user_id, *cid);
 
         user
     }
 
-    fn disconnect_users(&mut self, endpoint: &mut Endpoint) {
+    fn disconnect_users(&mut self, transport: &mut dyn Transport<C>) {
         // Check to see if a user should be disconnected
         while let Some(disconnect) = self.disconnect_queue.pop() {
-            let _ = endpoint.close_connection(&disconnect.0, disconnect.1);
+            transport.close_connection(&disconnect.0, disconnect.1);
+        }
+    }
+
+    fn handle_control_request(
+        &mut self,
+        request: ControlRequest,
+        transport: &mut dyn Transport<C>,
+    ) -> ControlResponse {
+        match request {
+            ControlRequest::ListUsers => {
+                let users = self
+                    .clients
+                    .iter()
+                    .map(|(cid, user)| ConnectedUser {
+                        id: user.get_id(),
+                        username: user.get_username().to_string(),
+                        address: self.addresses.get(cid).copied(),
+                        latency: self.latencies.get(cid).copied(),
+                    })
+                    .collect();
+
+                ControlResponse::Users(users)
+            }
+            ControlRequest::ListRealms => {
+                ControlResponse::Realms(self.realms_manager.get_realm_descriptions())
+            }
+            ControlRequest::Kick(user_id) => match self.get_connection_id(user_id) {
+                Some(cid) => {
+                    self.disconnect_queue
+                        .push((cid, DisconnectReason::Kicked as u64));
+                    ControlResponse::Done(format!("kicked user {}", user_id))
+                }
+                None => ControlResponse::Failed(format!("no user with id {}", user_id)),
+            },
+            ControlRequest::Ban(user_id) => {
+                let cid = match self.get_connection_id(user_id) {
+                    Some(cid) => cid,
+                    None => return ControlResponse::Failed(format!("no user with id {}", user_id)),
+                };
+
+                match self.addresses.get(&cid) {
+                    Some(address) => {
+                        let ip = address.ip();
+                        self.banned_addresses.insert(ip);
+                        self.disconnect_queue
+                            .push((cid, DisconnectReason::Banned as u64));
+                        ControlResponse::Done(format!("banned user {} ({})", user_id, ip))
+                    }
+                    None => ControlResponse::Failed(format!(
+                        "don't know the address of user {}",
+                        user_id
+                    )),
+                }
+            }
+            ControlRequest::Unban(ip) => match self.banned_addresses.remove(&ip) {
+                true => ControlResponse::Done(format!("unbanned {}", ip)),
+                false => ControlResponse::Failed(format!("{} isn't banned", ip)),
+            },
+            ControlRequest::ListBans => {
+                ControlResponse::Bans(self.banned_addresses.iter().copied().collect())
+            }
+            ControlRequest::Announce(announcement) => {
+                let message = Message::from(MessageType::Announcement(announcement));
+                self.send(SendTo::Everyone, false, message, transport);
+                ControlResponse::Done(String::from("announced"))
+            }
+            ControlRequest::CreateRealm(name) => {
+                let realm_id = self.realms_manager.add_realm(name.clone());
+                self.send_realms_delta(MessageType::RealmAdded((realm_id, name)), transport);
+                ControlResponse::Done(format!("created realm {}", realm_id))
+            }
+            ControlRequest::DeleteRealm(realm_id) => {
+                if self.realms_manager.get_realm(realm_id).is_none() {
+                    return ControlResponse::Failed(format!("no realm with id {}", realm_id));
+                }
+
+                self.realms_manager.remove_realm(realm_id);
+                self.fan_out.remove_realm(realm_id);
+                self.voice_mixer.remove_realm(realm_id);
+                self.send_realms_delta(MessageType::RealmRemoved(realm_id), transport);
+                ControlResponse::Done(format!("deleted realm {}", realm_id))
+            }
+            ControlRequest::Shutdown((seconds, reason, restart_in)) => {
+                let reason = reason.unwrap_or_else(|| String::from("The server is shutting down"));
+                self.shutdown_notice = (reason, restart_in.map(Duration::from_secs));
+                self.shutdown_at = Some(Instant::now() + Duration::from_secs(seconds));
+                self.last_shutdown_warning = None;
+                self.warn_of_shutdown(transport);
+                ControlResponse::Done(format!("shutting down in {} seconds", seconds))
+            }
+            ControlRequest::CancelShutdown => match self.shutdown_at.take() {
+                Some(_) => {
+                    let message = Message::from(MessageType::Announcement(String::from(
+                        "The server is no longer shutting down",
+                    )));
+                    self.send(SendTo::Everyone, false, message, transport);
+                    ControlResponse::Done(String::from("shutdown cancelled"))
+                }
+                None => ControlResponse::Failed(String::from("no shutdown is pending")),
+            },
+        }
+    }
+
+    fn get_connection_id(&self, user_id: UserIdSize) -> Option<C> {
+        self.fan_out.get_connection(user_id)
+    }
+
+    /// Count down a pending shutdown, announcing it as it gets close
+    fn warn_of_shutdown(&mut self, transport: &mut dyn Transport<C>) {
+        let shutdown_at = match self.shutdown_at {
+            Some(shutdown_at) => shutdown_at,
+            None => return,
+        };
+
+        let now = Instant::now();
+        if now >= shutdown_at {
+            self.shutdown_at = None;
+            let (reason, restart_eta) = self.shutdown_notice.clone();
+            self.begin_shutdown(reason, restart_eta, transport);
+            return;
+        }
+
+        // Round up so the first warning says the full countdown
+        let remaining = (shutdown_at - now).as_secs_f64().ceil() as u64;
+
+        // Warn when first asked, then as each threshold passes
+        let should_warn = match self.last_shutdown_warning {
+            None => true,
+            Some(last) => SHUTDOWN_WARNINGS
+                .iter()
+                .any(|warning| *warning < last && remaining <= *warning),
+        };
+
+        if should_warn {
+            let message = Message::from(MessageType::Announcement(format!(
+                "The server is {} in {} second{}",
+                match self.shutdown_notice.1 {
+                    Some(_) => "restarting",
+                    None => "shutting down",
+                },
+                remaining,
+                if remaining == 1 { "" } else { "s" }
+            )));
+            self.send(SendTo::Everyone, false, message, transport);
+            self.last_shutdown_warning = Some(remaining);
+        }
+    }
+
+    /// Ping every client so we know their latency
+    fn ping_clients(&mut self, transport: &mut dyn Transport<C>) {
+        let connections: Vec<C> = self.clients.keys().copied().collect();
+
+        for cid in connections {
+            self.num_pings += 1;
+            self.pings.insert(cid, (self.num_pings, Instant::now()));
+
+            let message = Message::from(MessageType::Ping(self.num_pings));
+            self.send_to_connection(&cid, false, message, transport);
         }
+
+        self.last_ping_round = Instant::now();
+    }
+
+    /// Tell clients why we're going away and save state.
+    /// Connections are closed once clients have had a moment to receive the notice.
+    fn begin_shutdown(
+        &mut self,
+        reason: String,
+        restart_eta: Option<Duration>,
+        transport: &mut dyn Transport<C>,
+    ) {
+        if self.draining_since.is_some() {
+            return;
+        }
+
+        info!(%reason, ?restart_eta, "shutting down");
+        self.shutdown_at = None;
+
+        let message = Message::from(MessageType::ServerShutdown((reason, restart_eta)));
+        self.send(SendTo::Everyone, false, message, transport);
+
+        self.save_state();
+        self.draining_since = Some(Instant::now());
     }
 
-    fn terminate_server(&mut self, endpoint: &mut Endpoint) {
-        println!("[server] closing all client connections");
-        for connection in self.clients.iter() {
-            let _ =
-                endpoint.close_connection(connection.0, DisconnectReason::ServerShutdown as u64);
+    fn terminate_server(&mut self, transport: &mut dyn Transport<C>) {
+        info!("closing all client connections");
+        // Include connections that never logged in
+        for cid in self.connections.clone() {
+            transport.close_connection(&cid, DisconnectReason::ServerShutdown as u64);
         }
         let _ = self
             .server_message_sender
             .send(ServerMessage::GracefullyEnded);
-        self._exiting = true;
+        self.exiting = true;
+    }
+
+    /// Send a message to a single connection, even if it hasn't logged in yet
+    fn send_to_connection(
+        &self,
+        cid: &C,
+        realtime: bool,
+        message: Message,
+        transport: &mut dyn Transport<C>,
+    ) {
+        let send_buffer = Arc::new(Self::get_send_buffer(realtime, message));
+        self.send_buffer(cid, realtime, send_buffer, transport);
     }
 
-    fn send(&self, send_to: SendTo, realtime: bool, message: Message, endpoint: &mut Endpoint) {
-        //println!("{:?}", message);
+    /// Send a message to logged in users. Returns how many it was sent to.
+    fn send(
+        &self,
+        send_to: SendTo,
+        realtime: bool,
+        message: Message,
+        transport: &mut dyn Transport<C>,
+    ) -> usize {
+        let recipients = self.fan_out.recipients(&send_to);
+        let count = recipients.len();
+
+        // Serialize once for everyone
+        let frame: Frame = Arc::new(Self::get_send_buffer(realtime, message));
+        fan_out::deliver(frame, recipients, |cid, send_buffer| {
+            self.send_buffer(&cid, realtime, send_buffer, transport)
+        });
+
+        count
+    }
+
+    /// Serialize a message, prefixing its length when it goes over the main stream
+    fn get_send_buffer(realtime: bool, message: Message) -> Vec<u8> {
         let message_buffer = message.into_vec_u8().unwrap();
         let mut send_buffer = Vec::new();
 
@@ -332,141 +1576,167 @@ impl ServerState {
         }
 
         send_buffer.extend(message_buffer);
+        send_buffer
+    }
 
-        match send_to {
-            SendTo::Everyone => {
-                for connection in &self.clients {
-                    match realtime {
-                        true => {
-                            let _ = endpoint.rt_stream_send(
-                                connection.0,
-                                Some(send_buffer.clone()),
-                                true,
-                            );
-                        }
-                        false => {
-                            let _ = endpoint.main_stream_send(connection.0, send_buffer.clone());
-                        }
-                    }
-                }
+    fn send_buffer(
+        &self,
+        cid: &C,
+        realtime: bool,
+        send_buffer: Frame,
+        transport: &mut dyn Transport<C>,
+    ) {
+        match realtime {
+            true => {
+                self.metrics
+                    .bytes_sent(StreamType::Realtime, send_buffer.len());
+                transport.send_realtime(cid, send_buffer);
             }
-            SendTo::EveryoneExceptUserID(user_id) => {
-                for connection in &self.clients {
-                    if connection.1.get_id() != user_id {
-                        match realtime {
-                            true => {
-                                let _ = endpoint.rt_stream_send(
-                                    connection.0,
-                                    Some(send_buffer.clone()),
-                                    true,
-                                );
-                            }
-                            false => {
-                                let _ =
-                                    endpoint.main_stream_send(connection.0, send_buffer.clone());
-                            }
-                        }
-                    }
-                }
+            false => {
+                self.metrics.bytes_sent(StreamType::Main, send_buffer.len());
+                transport.send_reliable(cid, send_buffer);
             }
-            SendTo::SingleUser(user_id) => {
-                for connection in &self.clients {
-                    if connection.1.get_id() == user_id {
-                        match realtime {
-                            true => {
-                                let _ = endpoint.rt_stream_send(
-                                    connection.0,
-                                    Some(send_buffer.clone()),
-                                    true,
-                                );
-                            }
-                            false => {
-                                let _ =
-                                    endpoint.main_stream_send(connection.0, send_buffer.clone());
-                            }
-                        }
-                    }
+        }
+    }
+
+    /// Run once per event loop iteration. Returns true when the server should stop
+    pub fn on_tick(&mut self, transport: &mut dyn Transport<C>) -> bool {
+        // Connections were closed last tick, so end the event loop now
+        if self.exiting {
+            return true;
+        }
+
+        let tick_started = Instant::now();
+        self.metrics.set_connected_users(self.clients.len());
+
+        while let Ok(message) = self.message_receiver.try_recv() {
+            match message {
+                ServerMessage::ShutDownServer((reason, restart_eta)) => {
+                    self.begin_shutdown(reason, restart_eta, transport)
                 }
-            }
-            SendTo::Users(user_ids) => {
-                for connection in &self.clients {
-                    if user_ids.contains(&connection.1.get_id()) {
-                        match realtime {
-                            true => {
-                                let _ = endpoint.rt_stream_send(
-                                    connection.0,
-                                    Some(send_buffer.clone()),
-                                    true,
-                                );
-                            }
-                            false => {
-                                let _ =
-                                    endpoint.main_stream_send(connection.0, send_buffer.clone());
-                            }
-                        }
-                    }
+                ServerMessage::ReloadConfig(config) => self.reload_config(*config),
+                ServerMessage::Control((request, reply)) => {
+                    let response = self.handle_control_request(request, transport);
+                    let _ = reply.send(response);
                 }
+                _ => (),
             }
         }
-    }
-}
 
-impl EndpointEventCallbacks for ServerState {
-    fn tick(&mut self, endpoint: &mut Endpoint) -> bool {
-        if let Ok(ServerMessage::ShutDownServer) = self.message_receiver.try_recv() {
-            self.terminate_server(endpoint);
+        if self.config.retention.max_message_age_days.is_some()
+            && self.last_retention_check.elapsed() > RETENTION_CHECK_INTERVAL
+        {
+            self.apply_retention();
         }
 
+        if self.last_ping_round.elapsed() > PING_INTERVAL {
+            self.ping_clients(transport);
+        }
+
+        self.warn_of_shutdown(transport);
+        self.mix_voice(transport);
+
         // Handle disconnect of users to be disconnected
-        self.disconnect_users(endpoint);
+        self.disconnect_users(transport);
 
+        if let Some(draining_since) = self.draining_since {
+            if !self.exiting
+                && (self.clients.is_empty() || draining_since.elapsed() > SHUTDOWN_DRAIN_TIME)
+            {
+                self.terminate_server(transport);
+            }
+        }
+
+        self.metrics.tick_finished(tick_started.elapsed());
         false
     }
 
-    fn connection_started(&mut self, endpoint: &mut Endpoint, cid: &ConnectionId) {
-        println!(
-            "[server] client connected from {}",
-            endpoint.get_connection_socket_addr(cid).unwrap()
-        );
+    pub fn on_connection_started(&mut self, transport: &mut dyn Transport<C>, cid: &C) {
+        self.connections.insert(*cid);
+        if let Some(address) = transport.peer_addr(cid) {
+            self.addresses.insert(*cid, address);
+        }
+
+        let _span = self.connection_span(cid).entered();
+        info!("client connected");
+
+        if let Some(address) = self.addresses.get(cid) {
+            if self.banned_addresses.contains(&address.ip()) {
+                info!("turning away banned address {}", address.ip());
+                self.disconnect_queue
+                    .push((*cid, DisconnectReason::Banned as u64));
+                return;
+            }
+        }
+
+        if self.draining_since.is_some() {
+            self.disconnect_queue
+                .push((*cid, DisconnectReason::ServerShutdown as u64));
+            return;
+        }
+
+        // Counting this one, and anyone yet to log in
+        if self.connections.len() > self.config.limits.max_connections {
+            warn!("server is full, turning away connection");
+            self.disconnect_queue
+                .push((*cid, DisconnectReason::ServerFull as u64));
+        }
     }
 
-    fn connection_ended(
+    /// `lost` is why the connection ended, unless the peer closed it
+    pub fn on_connection_ended(
         &mut self,
-        endpoint: &mut Endpoint,
-        cid: &ConnectionId,
-        reason: ConnectionEndReason,
-        _remaining_connections: usize,
-    ) -> bool {
+        transport: &mut dyn Transport<C>,
+        cid: &C,
+        lost: Option<String>,
+    ) {
+        let _connection_span = self.connection_span(cid).entered();
+        let _user_span = self.user_span(cid).map(|span| span.entered());
+
+        self.rate_limiter.remove_connection(cid);
+        self.connections.remove(cid);
+        self.moderators.remove(cid);
+        self.addresses.remove(cid);
+        self.pings.remove(cid);
+        self.latencies.remove(cid);
+
         if let Some(user) = self.clients.get(cid) {
-            match reason {
-                ConnectionEndReason::PeerApplication(_) => (),
-                _ => {
-                    println!(
-                        "[server] client {} lost connection: {:?}",
-                        user.get_id(),
-                        reason
-                    );
+            match lost {
+                None => (),
+                Some(reason) => {
+                    info!("lost connection: {}", reason);
 
                     // Remove this user from any voice channel
                     self.realms_manager
                         .remove_user_from_voice_channel_global(user.get_id());
 
-                    let message = Message::from(MessageType::UserLeft(user.get_id()));
-                    self.send(SendTo::Everyone, false, message, endpoint);
+                    self.fan_out.remove_user(user.get_id());
+                    self.voice_states.remove(&user.get_id());
+                    self.audio_loss.remove_user(user.get_id());
+                    self.voice_mixer.remove_user(user.get_id());
+                    self.rate_limiter.remove_user(user.get_id());
+                    self.metrics.remove_user(user.get_id());
+                    self.slow_mode_posts.retain(|key, _| key.0 != user.get_id());
+
+                    let user_id = user.get_id();
+                    let message = Message::from(MessageType::UserLeft(user_id));
+                    self.send(SendTo::Everyone, false, message, transport);
                     self.clients.remove(cid);
+                    self.stop_recording(user_id, transport);
                 }
             }
         }
-
-        false
     }
 
-    fn main_stream_recv(
+    pub fn on_main_stream_recv(
         &mut self,
-        endpoint: &mut Endpoint,
-        cid: &ConnectionId,
+        transport: &mut dyn Transport<C>,
+        cid: &C,
         read_data: &[u8],
     ) -> Option<usize> {
+        self.metrics
+            .bytes_received(StreamType::Main, read_data.len());
+
         if read_data.len() == MESSAGE_HEADER_SIZE {
             Some(self.get_message_size(read_data))
         } else {
@@ -474,25 +1744,28 @@ impl EndpointEventCallbacks for ServerState {
             let message_buffer = read_data.to_vec();
             let message = Message::from_vec_u8(message_buffer).unwrap();
 
-            self.process_message(cid, message, endpoint);
+            self.process_message(cid, message, transport);
 
             // Tell swiftlet to read another message header
             Some(MESSAGE_HEADER_SIZE)
         }
     }
 
-    fn background_stream_recv(
+    pub fn on_background_stream_recv(
         &mut self,
-        endpoint: &mut Endpoint,
-        cid: &ConnectionId,
+        transport: &mut dyn Transport<C>,
+        cid: &C,
         read_data: &[u8],
     ) -> Option<usize> {
+        self.metrics
+            .bytes_received(StreamType::Background, read_data.len());
+
         if read_data.len() == MESSAGE_HEADER_SIZE {
             Some(self.get_message_size(read_data))
         } else {
             let message_buffer = read_data.to_vec();
             if let Ok(message) = Message::from_vec_u8(message_buffer) {
-                self.process_message(cid, message, endpoint);
+                self.process_message(cid, message, transport);
             }
 
             // Tell swiftlet to read another message header
@@ -500,18 +1773,77 @@ impl EndpointEventCallbacks for ServerState {
         }
     }
 
-    fn rt_stream_recv(
+    pub fn on_rt_stream_recv(
         &mut self,
-        endpoint: &mut Endpoint,
-        cid: &ConnectionId,
+        transport: &mut dyn Transport<C>,
+        cid: &C,
         read_data: &[u8],
-        _rt_id: u64,
     ) -> usize {
+        self.metrics
+            .bytes_received(StreamType::Realtime, read_data.len());
+
         let message_buffer = read_data.to_vec();
         if let Ok(message) = Message::from_vec_u8(message_buffer) {
-            self.process_message(cid, message, endpoint);
+            self.process_message(cid, message, transport);
         }
 
         0
     }
 }
+
+impl EndpointEventCallbacks for ServerState {
+    fn tick(&mut self, endpoint: &mut Endpoint) -> bool {
+        self.on_tick(endpoint)
+    }
+
+    fn connection_started(&mut self, endpoint: &mut Endpoint, cid: &ConnectionId) {
+        self.on_connection_started(endpoint, cid);
+    }
+
+    fn connection_ended(
+        &mut self,
+        endpoint: &mut Endpoint,
+        cid: &ConnectionId,
+        reason: ConnectionEndReason,
+        _remaining_connections: usize,
+    ) -> bool {
+        let lost = match reason {
+            ConnectionEndReason::PeerApplication(_) => None,
+            reason => Some(format!("{:?}", reason)),
+        };
+        self.on_connection_ended(endpoint, cid, lost);
+
+        false
+    }
+
+    fn main_stream_recv(
+        &mut self,
+        endpoint: &mut Endpoint,
+        cid: &ConnectionId,
+        read_data: &[u8],
+    ) -> Option<usize> {
+        self.on_main_stream_recv(endpoint, cid, read_data)
+    }
+
+    fn background_stream_recv(
+        &mut self,
+        endpoint: &mut Endpoint,
+        cid: &ConnectionId,
+        read_data: &[u8],
+    ) -> Option<usize> {
+        self.on_background_stream_recv(endpoint, cid, read_data)
+    }
+
+    fn rt_stream_recv(
+        &mut self,
+        endpoint: &mut Endpoint,
+        cid: &ConnectionId,
+        read_data: &[u8],
+        _rt_id: u64,
+    ) -> usize {
+        self.on_rt_stream_recv(endpoint, cid, read_data)
+    }
+}
+
+#[cfg(test)]
+mod tests;
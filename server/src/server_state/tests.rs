@@ -0,0 +1,1327 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use message::message::{Message, MessageHeader, MessageType, Password};
+use message::voice_state::VoiceState;
+use realms::channels::text_channel::TextChannelMessage;
+use realms::realm::ChannelType;
+use realms::realm_desc::RealmDescription;
+use types::{ChannelIdSize, RealmIdSize, UserIdSize};
+use user::User;
+
+use super::{DisconnectReason, ServerState};
+use crate::config::{DefaultRealmConfig, ServerConfig, ServerSection};
+use crate::metrics::Metrics;
+use crate::rate_limiter::{BucketLimits, RateLimits};
+use crate::state_file;
+use crate::transport::MemoryTransport;
+use crate::voice_mixer::{MIXED_STREAM_ID, MIX_INTERVAL, OWN_MIX_STREAM_ID};
+
+/// A server driven by hand, with connections numbered from 0
+struct TestServer {
+    state: ServerState<u64>,
+    transport: MemoryTransport,
+    next_connection: u64,
+}
+
+impl TestServer {
+    fn new() -> TestServer {
+        TestServer::with_config(ServerConfig {
+            default_realm: Some(DefaultRealmConfig {
+                name: String::from("Kagu"),
+                text_channels: vec![String::from("general")],
+                voice_channels: vec![String::from("General"), String::from("Other")],
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn with_config(config: ServerConfig) -> TestServer {
+        let (_server_message_send, server_message_recv) = crossbeam::channel::bounded(1);
+        let (el_to_server_send, _el_to_server_recv) = crossbeam::channel::bounded(1);
+
+        TestServer {
+            state: ServerState::new(
+                String::from("TestServer"),
+                server_message_recv,
+                el_to_server_send,
+                config,
+                Arc::new(Metrics::new()),
+            ),
+            transport: MemoryTransport::new(),
+            next_connection: 0,
+        }
+    }
+
+    fn connect(&mut self) -> u64 {
+        let cid = self.next_connection;
+        self.next_connection += 1;
+        self.state.on_connection_started(&mut self.transport, &cid);
+        cid
+    }
+
+    /// Deliver a message from a connection as if it came over the main stream
+    fn receive(&mut self, cid: u64, message: MessageType) {
+        let buffer = Message::from(message).into_vec_u8().unwrap();
+        self.state
+            .on_main_stream_recv(&mut self.transport, &cid, &buffer);
+    }
+
+    fn receive_realtime(&mut self, cid: u64, message: MessageType) {
+        let buffer = Message::from(message).into_vec_u8().unwrap();
+        self.state
+            .on_rt_stream_recv(&mut self.transport, &cid, &buffer);
+    }
+
+    fn tick(&mut self) -> bool {
+        self.state.on_tick(&mut self.transport)
+    }
+
+    /// Messages sent to a connection since this was last called
+    fn messages(&mut self, cid: u64) -> Vec<MessageType> {
+        self.transport
+            .take_messages(cid)
+            .into_iter()
+            .map(|message| message.message)
+            .collect()
+    }
+
+    /// A server with one moderator, who logs in with `log_in_moderator`
+    fn with_moderator() -> TestServer {
+        let mut server = TestServer::new();
+        server.state.config.moderators =
+            BTreeMap::from([(String::from("moderator"), String::from("hunter2"))]);
+        server
+    }
+
+    /// Log in as the moderator and prove it with their password
+    fn log_in_moderator(&mut self) -> (u64, User) {
+        let (cid, user) = self.log_in("moderator");
+        let password = Password(String::from("hunter2"));
+        self.receive(cid, MessageType::ModeratorLogin(password));
+        assert_eq!(
+            self.messages(cid),
+            vec![MessageType::ModeratorLoginResult(true)]
+        );
+        (cid, user)
+    }
+
+    /// Connect and log in, clearing what the new connection was sent
+    fn log_in(&mut self, username: &str) -> (u64, User) {
+        let cid = self.connect();
+        self.receive(cid, MessageType::LoginAttempt(username.to_string()));
+
+        let user = self
+            .messages(cid)
+            .into_iter()
+            .find_map(|message| match message {
+                MessageType::LoginSuccess(user) => Some(user),
+                _ => None,
+            })
+            .expect("login should succeed");
+
+        (cid, user)
+    }
+
+    fn realms(&mut self, cid: u64, user_id: UserIdSize) -> Vec<RealmDescription> {
+        self.receive(cid, MessageType::GetRealms(user_id));
+
+        match self.messages(cid).pop() {
+            Some(MessageType::Realms((_, realms))) => realms,
+            other => panic!("expected realms, got {:?}", other),
+        }
+    }
+
+    /// The default realm with its text channel and first voice channel
+    fn default_channels(
+        &mut self,
+        cid: u64,
+        user_id: UserIdSize,
+    ) -> (RealmIdSize, ChannelIdSize, ChannelIdSize) {
+        let realm = self.realms(cid, user_id).remove(0);
+        let text_channel = realm.text_channels[0].0;
+        let voice_channel = realm
+            .voice_channels
+            .iter()
+            .find(|channel| channel.1 == "General")
+            .unwrap()
+            .0;
+
+        (realm.id, text_channel, voice_channel)
+    }
+
+    fn join_voice(
+        &mut self,
+        cid: u64,
+        user_id: UserIdSize,
+        realm_id: RealmIdSize,
+        channel_id: ChannelIdSize,
+    ) {
+        let header = MessageHeader::new(user_id, realm_id, channel_id);
+        self.receive(cid, MessageType::UserJoinedVoiceChannel(header));
+    }
+}
+
+/// Unwrap a realms delta, checking it follows the version expected
+fn delta(message: &MessageType, expected_version: u32) -> MessageType {
+    match message {
+        MessageType::RealmsDelta((version, delta)) => {
+            assert_eq!(*version as u32, expected_version);
+            *delta.clone()
+        }
+        other => panic!("expected a realms delta, got {:?}", other),
+    }
+}
+
+#[test]
+fn login_assigns_ids_and_announces_new_users() {
+    let mut server = TestServer::new();
+
+    let (first_cid, first) = server.log_in("first");
+    let (second_cid, second) = server.log_in("second");
+
+    assert_eq!(first.get_username(), "first");
+    assert_eq!(second.get_username(), "second");
+    assert_ne!(first.get_id(), second.get_id());
+
+    // Only users already logged in hear about a new one
+    assert_eq!(
+        server.messages(first_cid),
+        vec![MessageType::UserJoined(second.clone())]
+    );
+    assert!(server.messages(second_cid).is_empty());
+
+    server.receive(
+        first_cid,
+        MessageType::GetAllUsers(MessageHeader::new(first.get_id(), 0, 0)),
+    );
+    match server.messages(first_cid).pop() {
+        Some(MessageType::AllUsers(users)) => {
+            assert_eq!(users.len(), 2);
+            assert!(users.contains(&first));
+            assert!(users.contains(&second));
+        }
+        other => panic!("expected all users, got {:?}", other),
+    }
+}
+
+#[test]
+fn login_sends_the_motd() {
+    let mut server = TestServer::with_config(ServerConfig {
+        motd: Some(String::from("Welcome")),
+        ..Default::default()
+    });
+
+    let cid = server.connect();
+    server.receive(cid, MessageType::LoginAttempt(String::from("user")));
+
+    let messages = server.messages(cid);
+    assert!(matches!(messages[0], MessageType::LoginSuccess(_)));
+    assert_eq!(messages[1], MessageType::Motd(String::from("Welcome")));
+}
+
+#[test]
+fn login_sends_the_upcoming_certificate_during_a_rotation() {
+    let mut server = TestServer::new();
+    server
+        .state
+        .set_upcoming_certificate(Some(String::from("AB:CD")));
+
+    let cid = server.connect();
+    server.receive(cid, MessageType::LoginAttempt(String::from("user")));
+    assert!(server
+        .messages(cid)
+        .contains(&MessageType::UpcomingCertificate(String::from("AB:CD"))));
+}
+
+#[test]
+fn invalid_username_fails_and_disconnects() {
+    let mut server = TestServer::new();
+
+    let cid = server.connect();
+    server.receive(cid, MessageType::LoginAttempt(String::new()));
+    assert_eq!(server.messages(cid), vec![MessageType::LoginFailed]);
+
+    server.tick();
+    assert_eq!(
+        server.transport.closed_reason(cid),
+        Some(DisconnectReason::LoginFailed as u64)
+    );
+}
+
+#[test]
+fn messages_before_login_disconnect() {
+    let mut server = TestServer::new();
+
+    let cid = server.connect();
+    server.receive(cid, MessageType::GetRealms(0));
+    assert!(server.messages(cid).is_empty());
+
+    server.tick();
+    assert_eq!(
+        server.transport.closed_reason(cid),
+        Some(DisconnectReason::NotLoggedIn as u64)
+    );
+}
+
+#[test]
+fn connections_past_the_limit_are_turned_away() {
+    let mut config = ServerConfig::default();
+    config.limits.max_connections = 1;
+    let mut server = TestServer::with_config(config);
+
+    // Connections count before they log in
+    let first_cid = server.connect();
+    let cid = server.connect();
+
+    server.tick();
+    assert_eq!(
+        server.transport.closed_reason(cid),
+        Some(DisconnectReason::ServerFull as u64)
+    );
+    assert_eq!(server.transport.closed_reason(first_cid), None);
+
+    // And stop counting once closed
+    server
+        .state
+        .on_connection_ended(&mut server.transport, &cid, None);
+    server
+        .state
+        .on_connection_ended(&mut server.transport, &first_cid, None);
+    let cid = server.connect();
+    server.tick();
+    assert_eq!(server.transport.closed_reason(cid), None);
+}
+
+#[test]
+fn default_realm_is_created() {
+    let mut server = TestServer::new();
+    let (cid, user) = server.log_in("user");
+
+    let realms = server.realms(cid, user.get_id());
+    assert_eq!(realms.len(), 1);
+    assert_eq!(realms[0].name, "Kagu");
+    assert_eq!(realms[0].text_channels.len(), 1);
+    assert_eq!(realms[0].voice_channels.len(), 2);
+}
+
+#[test]
+fn unreadable_state_files_are_moved_aside() {
+    let dir = std::env::temp_dir().join(format!("kagu-state-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("kagu-server.state");
+    std::fs::write(&path, b"saved by something else").unwrap();
+
+    let server = TestServer::with_config(ServerConfig {
+        server: ServerSection {
+            state_file: Some(path.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+
+    // Saving on shutdown doesn't overwrite it
+    server.state.save_state();
+    let aside: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|entry| entry != &path)
+        .collect();
+    assert_eq!(aside.len(), 1);
+    assert_eq!(
+        std::fs::read(&aside[0]).unwrap(),
+        b"saved by something else"
+    );
+    assert!(state_file::load(&path).is_ok());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn realm_changes_are_sent_to_everyone_as_deltas() {
+    let mut server = TestServer::with_moderator();
+    let (first_cid, first) = server.log_in_moderator();
+    let (second_cid, second) = server.log_in("second");
+    server.messages(first_cid);
+
+    let header = MessageHeader::new(first.get_id(), 0, 0);
+    server.receive(
+        first_cid,
+        MessageType::AddRealm((header, String::from("New Realm"))),
+    );
+
+    let realm_id = match delta(&server.messages(first_cid)[0], 1) {
+        MessageType::RealmAdded((realm_id, name)) => {
+            assert_eq!(name, "New Realm");
+            realm_id
+        }
+        other => panic!("expected a realm added, got {:?}", other),
+    };
+    assert_eq!(
+        delta(&server.messages(second_cid)[0], 1),
+        MessageType::RealmAdded((realm_id, String::from("New Realm")))
+    );
+
+    // Only moderators can remove it
+    let second_header = MessageHeader::new(second.get_id(), 0, 0);
+    server.receive(
+        second_cid,
+        MessageType::RemoveRealm((second_header, realm_id)),
+    );
+    assert!(server.messages(second_cid).is_empty());
+
+    server.receive(first_cid, MessageType::RemoveRealm((header, realm_id)));
+    assert_eq!(
+        delta(&server.messages(second_cid)[0], 2),
+        MessageType::RealmRemoved(realm_id)
+    );
+
+    // The realms version goes along with a full sync too
+    server.receive(first_cid, MessageType::GetRealms(first.get_id()));
+    match server.messages(first_cid).pop() {
+        Some(MessageType::Realms((version, realms))) => {
+            assert_eq!(version, 2);
+            assert!(realms.iter().all(|realm| realm.id != realm_id));
+        }
+        other => panic!("expected realms, got {:?}", other),
+    }
+}
+
+#[test]
+fn realms_over_the_limit_are_rejected() {
+    let mut config = ServerConfig::default();
+    config.limits.max_realms = 1;
+    let mut server = TestServer::with_config(config);
+    let (cid, user) = server.log_in("user");
+
+    let header = MessageHeader::new(user.get_id(), 0, 0);
+    server.receive(cid, MessageType::AddRealm((header, String::from("One"))));
+    server.receive(cid, MessageType::AddRealm((header, String::from("Two"))));
+
+    assert_eq!(server.messages(cid).len(), 1);
+    assert_eq!(server.realms(cid, user.get_id()).len(), 1);
+}
+
+#[test]
+fn channels_are_added_and_removed() {
+    let mut server = TestServer::with_moderator();
+    let (cid, user) = server.log_in_moderator();
+    let (other_cid, other) = server.log_in("other");
+    server.messages(cid);
+    let (realm_id, _, _) = server.default_channels(cid, user.get_id());
+
+    let header = MessageHeader::new(user.get_id(), realm_id, 0);
+    server.receive(
+        cid,
+        MessageType::AddChannel((header, ChannelType::TextChannel, String::from("random"))),
+    );
+
+    let channel_id = match delta(&server.messages(cid)[0], 1) {
+        MessageType::ChannelAdded((added_realm, ChannelType::TextChannel, channel_id, name)) => {
+            assert_eq!(added_realm, realm_id);
+            assert_eq!(name, "random");
+            channel_id
+        }
+        other => panic!("expected a channel added, got {:?}", other),
+    };
+    server.messages(other_cid);
+
+    // Anyone can add a channel but only moderators can remove one
+    let other_header = MessageHeader::new(other.get_id(), realm_id, channel_id);
+    server.receive(
+        other_cid,
+        MessageType::RemoveChannel((other_header, ChannelType::TextChannel)),
+    );
+    assert!(server.messages(other_cid).is_empty());
+
+    let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+    server.receive(
+        cid,
+        MessageType::RemoveChannel((header, ChannelType::TextChannel)),
+    );
+    assert_eq!(
+        delta(&server.messages(cid)[0], 2),
+        MessageType::ChannelRemoved((realm_id, ChannelType::TextChannel, channel_id))
+    );
+
+    // Removing it again changes nothing
+    server.receive(
+        cid,
+        MessageType::RemoveChannel((header, ChannelType::TextChannel)),
+    );
+    assert!(server.messages(cid).is_empty());
+}
+
+#[test]
+fn added_channels_take_a_free_id_once_the_last_one_is_used() {
+    let mut server = TestServer::new();
+    let (cid, user) = server.log_in("user");
+    let (realm_id, text_id, _) = server.default_channels(cid, user.get_id());
+    server.state.realms_manager.add_channel_with_id(
+        realm_id,
+        ChannelIdSize::MAX,
+        ChannelType::TextChannel,
+        String::from("last"),
+    );
+
+    let header = MessageHeader::new(user.get_id(), realm_id, 0);
+    server.receive(
+        cid,
+        MessageType::AddChannel((header, ChannelType::TextChannel, String::from("random"))),
+    );
+
+    match delta(&server.messages(cid)[0], 1) {
+        MessageType::ChannelAdded((_, ChannelType::TextChannel, channel_id, _)) => {
+            assert_ne!(channel_id, text_id);
+            assert_ne!(channel_id, ChannelIdSize::MAX);
+        }
+        other => panic!("expected a channel added, got {:?}", other),
+    }
+}
+
+#[test]
+fn text_gets_an_id_and_is_kept_in_history() {
+    let mut server = TestServer::new();
+    let (first_cid, first) = server.log_in("first");
+    let (second_cid, second) = server.log_in("second");
+    server.messages(first_cid);
+    let (realm_id, channel_id, _) = server.default_channels(first_cid, first.get_id());
+
+    let chunks = vec![(String::from("hello"), None)];
+    let header = MessageHeader::new(first.get_id(), realm_id, channel_id);
+    server.receive(first_cid, MessageType::Text((header, chunks.clone())));
+    server.receive(first_cid, MessageType::Text((header, chunks.clone())));
+
+    let received: Vec<MessageHeader> = server
+        .messages(second_cid)
+        .into_iter()
+        .map(|message| match message {
+            MessageType::Text((header, received_chunks)) => {
+                assert_eq!(received_chunks, chunks);
+                header
+            }
+            other => panic!("expected text, got {:?}", other),
+        })
+        .collect();
+    assert_eq!(received.len(), 2);
+    assert!(received[0].message_id.is_some());
+    assert_ne!(received[0].message_id, received[1].message_id);
+
+    // The sender gets it back too, with the same id
+    match &server.messages(first_cid)[0] {
+        MessageType::Text((header, _)) => assert_eq!(header.message_id, received[0].message_id),
+        other => panic!("expected text, got {:?}", other),
+    }
+
+    let header = MessageHeader::new(second.get_id(), realm_id, channel_id);
+    server.receive(second_cid, MessageType::GetChannelHistory(header));
+    match server.messages(second_cid).pop() {
+        Some(MessageType::ChannelHistory((_, _, history))) => {
+            assert_eq!(history.len(), 2);
+            assert_eq!(history[0].message_id, received[0].message_id);
+            assert_eq!(history[0].user_id, first.get_id());
+        }
+        other => panic!("expected channel history, got {:?}", other),
+    }
+}
+
+#[test]
+fn large_channel_history_is_split_to_fit_each_message() {
+    let mut server = TestServer::new();
+    let (cid, user) = server.log_in("user");
+    let (realm_id, channel_id, _) = server.default_channels(cid, user.get_id());
+
+    // 10 messages of 20 KB each can't all fit behind one u16 length prefix,
+    // and one of 70 KB can't be sent at all
+    let channel = server
+        .state
+        .realms_manager
+        .get_realm_mut(realm_id)
+        .unwrap()
+        .get_text_channel_mut(channel_id)
+        .unwrap();
+    for (message_id, size) in (0..).zip([20_000; 5].iter().chain(&[70_000]).chain(&[20_000; 5])) {
+        channel.chat_history.push(TextChannelMessage {
+            message_id: Some(message_id),
+            user_id: user.get_id(),
+            target_reply_message_id: None,
+            time_sent: None,
+            image: Some(vec![0; *size]),
+            message_chunks: Vec::new(),
+        });
+    }
+
+    let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+    server.receive(cid, MessageType::GetChannelHistory(header));
+
+    // The test transport checks each message against its length prefix
+    let chunks: Vec<_> = server
+        .messages(cid)
+        .into_iter()
+        .map(|message| match message {
+            MessageType::ChannelHistory((_, _, history)) => history,
+            other => panic!("expected channel history, got {:?}", other),
+        })
+        .collect();
+    assert!(chunks.len() > 1);
+
+    let message_ids: Vec<_> = chunks
+        .concat()
+        .iter()
+        .map(|m| m.message_id.unwrap())
+        .collect();
+    assert_eq!(message_ids, vec![0, 1, 2, 3, 4, 6, 7, 8, 9, 10]);
+}
+
+#[test]
+fn slow_mode_is_set_by_moderators_and_applies_to_the_sender() {
+    let mut server = TestServer::with_moderator();
+    let (moderator_cid, moderator) = server.log_in_moderator();
+    let (user_cid, user) = server.log_in("user");
+    server.messages(moderator_cid);
+    let (realm_id, channel_id, _) = server.default_channels(user_cid, user.get_id());
+
+    let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+    server.receive(user_cid, MessageType::SetSlowMode((header, 60)));
+    assert!(server.messages(user_cid).is_empty());
+
+    let header = MessageHeader::new(moderator.get_id(), realm_id, channel_id);
+    server.receive(moderator_cid, MessageType::SetSlowMode((header, 60)));
+    assert_eq!(
+        delta(&server.messages(user_cid)[0], 1),
+        MessageType::SlowModeChanged((realm_id, channel_id, 60))
+    );
+    server.messages(moderator_cid);
+
+    let chunks = vec![(String::from("hello"), None)];
+    let header = MessageHeader::new(user.get_id(), realm_id, channel_id);
+    server.receive(user_cid, MessageType::Text((header, chunks.clone())));
+    assert!(matches!(
+        server.messages(user_cid).pop(),
+        Some(MessageType::Text((header, _))) if header.user_id == user.get_id()
+    ));
+
+    // Claiming to be someone else doesn't get around it
+    let header = MessageHeader::new(moderator.get_id(), realm_id, channel_id);
+    server.receive(user_cid, MessageType::Text((header, chunks)));
+    assert!(matches!(
+        server.messages(user_cid).pop(),
+        Some(MessageType::RateLimited(_))
+    ));
+    assert_eq!(server.messages(moderator_cid).len(), 1);
+}
+
+#[test]
+fn text_to_a_missing_channel_is_dropped() {
+    let mut server = TestServer::new();
+    let (cid, user) = server.log_in("user");
+
+    let header = MessageHeader::new(user.get_id(), 200, 200);
+    server.receive(
+        cid,
+        MessageType::Text((header, vec![(String::from("hello"), None)])),
+    );
+
+    assert!(server.messages(cid).is_empty());
+}
+
+#[test]
+fn typing_is_not_echoed() {
+    let mut server = TestServer::new();
+    let (first_cid, first) = server.log_in("first");
+    let (second_cid, _) = server.log_in("second");
+    server.messages(first_cid);
+
+    let header = MessageHeader::new(first.get_id(), 0, 0);
+    server.receive(first_cid, MessageType::Typing(header));
+
+    assert!(server.messages(first_cid).is_empty());
+    assert_eq!(
+        server.messages(second_cid),
+        vec![MessageType::Typing(header)]
+    );
+}
+
+#[test]
+fn voice_join_and_leave_update_the_channel() {
+    let mut server = TestServer::new();
+    let (cid, user) = server.log_in("user");
+    let (realm_id, _, voice_channel) = server.default_channels(cid, user.get_id());
+
+    server.join_voice(cid, user.get_id(), realm_id, voice_channel);
+    assert!(matches!(
+        delta(&server.messages(cid)[0], 1),
+        MessageType::UserJoinedVoiceChannel(_)
+    ));
+
+    let realm = server.realms(cid, user.get_id()).remove(0);
+    let channel = realm
+        .voice_channels
+        .iter()
+        .find(|channel| channel.0 == voice_channel)
+        .unwrap();
+    assert_eq!(channel.2, vec![user.get_id()]);
+
+    let header = MessageHeader::new(user.get_id(), realm_id, voice_channel);
+    server.receive(cid, MessageType::UserLeftVoiceChannel(header));
+    assert!(matches!(
+        delta(&server.messages(cid)[0], 2),
+        MessageType::UserLeftVoiceChannel(_)
+    ));
+
+    let realm = server.realms(cid, user.get_id()).remove(0);
+    assert!(realm
+        .voice_channels
+        .iter()
+        .all(|channel| channel.2.is_empty()));
+}
+
+#[test]
+fn audio_only_reaches_others_in_the_channel() {
+    let mut server = TestServer::new();
+    let (speaker_cid, speaker) = server.log_in("speaker");
+    let (listener_cid, listener) = server.log_in("listener");
+    let (elsewhere_cid, elsewhere) = server.log_in("elsewhere");
+    let (text_cid, _) = server.log_in("text");
+
+    let realm = server.realms(speaker_cid, speaker.get_id()).remove(0);
+    let general = realm
+        .voice_channels
+        .iter()
+        .find(|c| c.1 == "General")
+        .unwrap()
+        .0;
+    let other = realm
+        .voice_channels
+        .iter()
+        .find(|c| c.1 == "Other")
+        .unwrap()
+        .0;
+
+    server.join_voice(speaker_cid, speaker.get_id(), realm.id, general);
+    server.join_voice(listener_cid, listener.get_id(), realm.id, general);
+    server.join_voice(elsewhere_cid, elsewhere.get_id(), realm.id, other);
+    for cid in [speaker_cid, listener_cid, elsewhere_cid, text_cid] {
+        server.messages(cid);
+    }
+
+    let header = MessageHeader::new(speaker.get_id(), realm.id, general);
+    let audio = MessageType::Audio((header, 0, vec![1, 2, 3, 4]));
+    server.receive_realtime(speaker_cid, audio.clone());
+
+    assert_eq!(
+        server.transport.last_sent_realtime(listener_cid),
+        Some(true)
+    );
+    assert_eq!(server.messages(listener_cid), vec![audio.clone()]);
+    assert!(server.messages(speaker_cid).is_empty());
+    assert!(server.messages(elsewhere_cid).is_empty());
+    assert!(server.messages(text_cid).is_empty());
+
+    // Nobody hears the listener once they've left
+    let header = MessageHeader::new(listener.get_id(), realm.id, general);
+    server.receive(listener_cid, MessageType::UserLeftVoiceChannel(header));
+    server.messages(listener_cid);
+    server.receive_realtime(speaker_cid, audio);
+    assert!(server.messages(listener_cid).is_empty());
+}
+
+#[test]
+fn audio_for_a_channel_the_speaker_isnt_in_is_dropped() {
+    let mut server = TestServer::with_moderator();
+    let (listener_cid, listener) = server.log_in_moderator();
+    let (outsider_cid, outsider) = server.log_in("outsider");
+    let (realm_id, _, voice_channel) = server.default_channels(listener_cid, listener.get_id());
+    server.join_voice(listener_cid, listener.get_id(), realm_id, voice_channel);
+    server.messages(listener_cid);
+
+    let header = MessageHeader::new(outsider.get_id(), realm_id, voice_channel);
+    server.receive_realtime(outsider_cid, MessageType::Audio((header, 0, vec![0; 8])));
+    assert!(server.messages(listener_cid).is_empty());
+
+    // Nor does it make it into the mix
+    let listener_header = MessageHeader::new(listener.get_id(), realm_id, voice_channel);
+    server.receive(
+        listener_cid,
+        MessageType::SetVoiceMixing((listener_header, true)),
+    );
+    server.messages(listener_cid);
+    for sequence in 1..4 {
+        let audio = MessageType::Audio((header, sequence, vec![0; 8]));
+        server.receive_realtime(outsider_cid, audio);
+    }
+    server.tick();
+    assert!(audio_streams(server.messages(listener_cid)).is_empty());
+}
+
+#[test]
+fn disconnecting_leaves_voice_and_is_announced() {
+    let mut server = TestServer::new();
+    let (first_cid, first) = server.log_in("first");
+    let (second_cid, second) = server.log_in("second");
+    let (realm_id, _, voice_channel) = server.default_channels(first_cid, first.get_id());
+
+    server.join_voice(first_cid, first.get_id(), realm_id, voice_channel);
+    server.join_voice(second_cid, second.get_id(), realm_id, voice_channel);
+    server.messages(first_cid);
+
+    server.receive(second_cid, MessageType::Disconnecting(second.get_id()));
+    server
+        .state
+        .on_connection_ended(&mut server.transport, &second_cid, None);
+    assert_eq!(
+        server.messages(first_cid),
+        vec![MessageType::UserLeft(second.get_id())]
+    );
+
+    // Audio isn't sent to the user who left
+    server.messages(second_cid);
+    let header = MessageHeader::new(first.get_id(), realm_id, voice_channel);
+    server.receive_realtime(first_cid, MessageType::Audio((header, 0, vec![0; 8])));
+    assert!(server.messages(second_cid).is_empty());
+
+    let realm = server.realms(first_cid, first.get_id()).remove(0);
+    let channel = realm
+        .voice_channels
+        .iter()
+        .find(|channel| channel.0 == voice_channel)
+        .unwrap();
+    assert_eq!(channel.2, vec![first.get_id()]);
+}
+
+#[test]
+fn logging_in_again_on_the_same_connection_keeps_its_limits() {
+    let mut server = TestServer::with_moderator();
+    server.state.rate_limiter.set_limits(RateLimits {
+        chat: BucketLimits::new(1, 0.001),
+        ..Default::default()
+    });
+    let (cid, moderator) = server.log_in_moderator();
+    let (other_cid, other) = server.log_in("other");
+    let (realm_id, text_channel, _) = server.default_channels(other_cid, other.get_id());
+    server.messages(cid);
+
+    // Use up the connection's chat allowance
+    let chunks = vec![(String::from("hello"), None)];
+    let header = MessageHeader::new(moderator.get_id(), realm_id, text_channel);
+    for _ in 0..2 {
+        server.receive(cid, MessageType::Text((header, chunks.clone())));
+    }
+    assert!(matches!(
+        server.messages(cid).pop(),
+        Some(MessageType::RateLimited(_))
+    ));
+    server.messages(other_cid);
+
+    // Only whoever is on the connection is logged out, whatever the message says
+    server.receive(cid, MessageType::Disconnecting(other.get_id()));
+    assert_eq!(
+        server.messages(other_cid),
+        vec![MessageType::UserLeft(moderator.get_id())]
+    );
+
+    server.receive(cid, MessageType::LoginAttempt(String::from("again")));
+    let again = server
+        .messages(cid)
+        .into_iter()
+        .find_map(|message| match message {
+            MessageType::LoginSuccess(user) => Some(user),
+            _ => None,
+        })
+        .expect("login should succeed");
+    server.messages(other_cid);
+
+    // The new login isn't a moderator
+    let header = MessageHeader::new(again.get_id(), realm_id, text_channel);
+    server.receive(cid, MessageType::SetSlowMode((header, 60)));
+    assert!(server.messages(other_cid).is_empty());
+
+    // And the connection is still over its limit
+    server.receive(cid, MessageType::Text((header, chunks)));
+    assert!(matches!(
+        server.messages(cid).pop(),
+        Some(MessageType::RateLimited(_))
+    ));
+    assert!(server.messages(other_cid).is_empty());
+}
+
+#[test]
+fn lost_connections_are_announced() {
+    let mut server = TestServer::new();
+    let (first_cid, _) = server.log_in("first");
+    let (second_cid, second) = server.log_in("second");
+    server.messages(first_cid);
+
+    server.state.on_connection_ended(
+        &mut server.transport,
+        &second_cid,
+        Some(String::from("idle timeout")),
+    );
+
+    assert_eq!(
+        server.messages(first_cid),
+        vec![MessageType::UserLeft(second.get_id())]
+    );
+}
+
+#[test]
+fn ping_is_answered_in_realtime() {
+    let mut server = TestServer::new();
+    server.log_in("first");
+    let (cid, user) = server.log_in("second");
+
+    // Clients put their user id on pings
+    let mut ping = Message::from(MessageType::Ping(7));
+    ping.user_id = user.get_id();
+    let buffer = ping.into_vec_u8().unwrap();
+    server
+        .state
+        .on_rt_stream_recv(&mut server.transport, &cid, &buffer);
+
+    assert_eq!(server.transport.last_sent_realtime(cid), Some(true));
+    assert_eq!(server.messages(cid), vec![MessageType::PingReply(7)]);
+}
+
+#[test]
+fn muted_users_are_shown_and_not_heard() {
+    let mut server = TestServer::new();
+    let (speaker_cid, speaker) = server.log_in("speaker");
+    let (listener_cid, listener) = server.log_in("listener");
+    let (realm_id, _, voice_channel) = server.default_channels(speaker_cid, speaker.get_id());
+
+    server.join_voice(speaker_cid, speaker.get_id(), realm_id, voice_channel);
+    server.join_voice(listener_cid, listener.get_id(), realm_id, voice_channel);
+    server.messages(speaker_cid);
+    server.messages(listener_cid);
+
+    let header = MessageHeader::new(speaker.get_id(), realm_id, voice_channel);
+    let muted = VoiceState {
+        muted: true,
+        ..Default::default()
+    };
+    server.receive(speaker_cid, MessageType::VoiceStateUpdate((header, muted)));
+
+    // Everyone sees the change
+    for cid in [speaker_cid, listener_cid] {
+        match server.messages(cid).pop() {
+            Some(MessageType::VoiceStateUpdate((header, state))) => {
+                assert_eq!(header.user_id, speaker.get_id());
+                assert_eq!(state, muted);
+            }
+            other => panic!("expected a voice state update, got {:?}", other),
+        }
+    }
+
+    server.receive_realtime(speaker_cid, MessageType::Audio((header, 0, vec![0; 8])));
+    assert!(server.messages(listener_cid).is_empty());
+
+    // Users who look at the realms later are told too
+    let (late_cid, late) = server.log_in("late");
+    server.receive(late_cid, MessageType::GetRealms(late.get_id()));
+    assert!(matches!(
+        server.messages(late_cid).pop(),
+        Some(MessageType::VoiceStateUpdate((header, state)))
+            if header.user_id == speaker.get_id() && state == muted
+    ));
+}
+
+#[test]
+fn deafened_users_are_not_sent_audio() {
+    let mut server = TestServer::new();
+    let (speaker_cid, speaker) = server.log_in("speaker");
+    let (listener_cid, listener) = server.log_in("listener");
+    let (realm_id, _, voice_channel) = server.default_channels(speaker_cid, speaker.get_id());
+
+    server.join_voice(speaker_cid, speaker.get_id(), realm_id, voice_channel);
+    server.join_voice(listener_cid, listener.get_id(), realm_id, voice_channel);
+
+    let header = MessageHeader::new(listener.get_id(), realm_id, voice_channel);
+    let deafened = VoiceState {
+        muted: true,
+        deafened: true,
+        ..Default::default()
+    };
+    server.receive(
+        listener_cid,
+        MessageType::VoiceStateUpdate((header, deafened)),
+    );
+    server.messages(listener_cid);
+
+    let header = MessageHeader::new(speaker.get_id(), realm_id, voice_channel);
+    let audio = MessageType::Audio((header, 0, vec![0; 8]));
+    server.receive_realtime(speaker_cid, audio.clone());
+    assert!(server.messages(listener_cid).is_empty());
+
+    // Undeafening brings the audio back
+    let header = MessageHeader::new(listener.get_id(), realm_id, voice_channel);
+    server.receive(
+        listener_cid,
+        MessageType::VoiceStateUpdate((header, VoiceState::default())),
+    );
+    server.messages(listener_cid);
+    server.receive_realtime(speaker_cid, audio.clone());
+    assert_eq!(server.messages(listener_cid), vec![audio]);
+}
+
+#[test]
+fn only_moderators_can_server_mute() {
+    let mut server = TestServer::with_moderator();
+    let (moderator_cid, moderator) = server.log_in_moderator();
+    let (user_cid, user) = server.log_in("user");
+    server.messages(moderator_cid);
+
+    let header = MessageHeader::new(user.get_id(), 0, 0);
+    server.receive(
+        user_cid,
+        MessageType::ServerMute((header, moderator.get_id(), true)),
+    );
+    assert!(server.messages(moderator_cid).is_empty());
+    assert!(server.messages(user_cid).is_empty());
+
+    let header = MessageHeader::new(moderator.get_id(), 0, 0);
+    server.receive(
+        moderator_cid,
+        MessageType::ServerMute((header, user.get_id(), true)),
+    );
+    let server_muted = VoiceState {
+        server_muted: true,
+        ..Default::default()
+    };
+    assert!(matches!(
+        server.messages(user_cid).pop(),
+        Some(MessageType::VoiceStateUpdate((header, state)))
+            if header.user_id == user.get_id() && state == server_muted
+    ));
+
+    // Unmuting themselves doesn't lift a server mute
+    let header = MessageHeader::new(user.get_id(), 0, 0);
+    server.receive(
+        user_cid,
+        MessageType::VoiceStateUpdate((header, VoiceState::default())),
+    );
+    assert!(matches!(
+        server.messages(moderator_cid).pop(),
+        Some(MessageType::VoiceStateUpdate((_, state))) if state == server_muted
+    ));
+}
+
+#[test]
+fn moderators_have_to_prove_who_they_are() {
+    let mut server = TestServer::with_moderator();
+    let (user_cid, user) = server.log_in("user");
+
+    // The moderator's username alone isn't enough
+    let (impostor_cid, _) = server.log_in("moderator");
+    server.messages(user_cid);
+    let header = MessageHeader::new(user.get_id(), 0, 0);
+    server.receive(
+        impostor_cid,
+        MessageType::ServerMute((header, user.get_id(), true)),
+    );
+    assert!(server.messages(user_cid).is_empty());
+
+    // Neither is a wrong password, and guessing costs the connection
+    let password = Password(String::from("hunter3"));
+    server.receive(impostor_cid, MessageType::ModeratorLogin(password));
+    assert_eq!(
+        server.messages(impostor_cid),
+        vec![MessageType::ModeratorLoginResult(false)]
+    );
+    server.tick();
+    assert_eq!(
+        server.transport.closed_reason(impostor_cid),
+        Some(DisconnectReason::LoginFailed as u64)
+    );
+
+    let (moderator_cid, _) = server.log_in_moderator();
+    server.messages(user_cid);
+    server.receive(
+        moderator_cid,
+        MessageType::ServerMute((header, user.get_id(), true)),
+    );
+    assert_eq!(server.messages(user_cid).len(), 1);
+
+    // A new password in the config means proving it again
+    let mut config = server.state.config.clone();
+    config
+        .moderators
+        .insert(String::from("moderator"), String::from("correct horse"));
+    server.state.reload_config(config);
+    server.receive(
+        moderator_cid,
+        MessageType::ServerMute((header, user.get_id(), false)),
+    );
+    assert!(server.messages(user_cid).is_empty());
+}
+
+#[test]
+fn audio_is_sent_as_whoever_is_on_the_connection() {
+    let mut server = TestServer::with_moderator();
+    let (moderator_cid, moderator) = server.log_in_moderator();
+    let (speaker_cid, speaker) = server.log_in("speaker");
+    let (listener_cid, listener) = server.log_in("listener");
+    let (realm_id, _, voice_channel) = server.default_channels(speaker_cid, speaker.get_id());
+
+    server.join_voice(speaker_cid, speaker.get_id(), realm_id, voice_channel);
+    server.join_voice(listener_cid, listener.get_id(), realm_id, voice_channel);
+
+    // Claiming to be someone else doesn't work
+    let header = MessageHeader::new(moderator.get_id(), realm_id, voice_channel);
+    server.messages(listener_cid);
+    server.receive_realtime(speaker_cid, MessageType::Audio((header, 0, vec![0; 8])));
+    assert!(matches!(
+        server.messages(listener_cid).pop(),
+        Some(MessageType::Audio((header, _, _))) if header.user_id == speaker.get_id()
+    ));
+
+    // Nor does it get around a server mute
+    let header = MessageHeader::new(moderator.get_id(), realm_id, voice_channel);
+    server.receive(
+        moderator_cid,
+        MessageType::ServerMute((header, speaker.get_id(), true)),
+    );
+    server.messages(listener_cid);
+    server.receive_realtime(speaker_cid, MessageType::Audio((header, 1, vec![0; 8])));
+    assert!(server.messages(listener_cid).is_empty());
+}
+
+#[test]
+fn voice_bitrate_limits_are_set_by_moderators_and_sent_as_deltas() {
+    let mut server = TestServer::with_moderator();
+    let (first_cid, first) = server.log_in_moderator();
+    let (second_cid, second) = server.log_in("second");
+    let (realm_id, _, voice_channel) = server.default_channels(first_cid, first.get_id());
+    server.messages(first_cid);
+
+    let header = MessageHeader::new(second.get_id(), realm_id, voice_channel);
+    server.receive(second_cid, MessageType::SetVoiceBitrate((header, 24000)));
+    assert!(server.messages(first_cid).is_empty());
+
+    // Opus can't go this low
+    let header = MessageHeader::new(first.get_id(), realm_id, voice_channel);
+    server.receive(first_cid, MessageType::SetVoiceBitrate((header, 1000)));
+    assert!(server.messages(second_cid).is_empty());
+
+    server.receive(first_cid, MessageType::SetVoiceBitrate((header, 24000)));
+    assert_eq!(
+        delta(&server.messages(second_cid)[0], 1),
+        MessageType::VoiceBitrateChanged((realm_id, voice_channel, 24000))
+    );
+
+    // Clients syncing later get the limit with the channel
+    let realm = server.realms(first_cid, first.get_id()).remove(0);
+    let channel = realm
+        .voice_channels
+        .iter()
+        .find(|channel| channel.0 == voice_channel)
+        .unwrap();
+    assert_eq!(channel.3, 24000);
+}
+
+#[test]
+fn audio_over_the_bitrate_limit_is_dropped() {
+    let mut server = TestServer::with_moderator();
+    let (moderator_cid, moderator) = server.log_in_moderator();
+    let (speaker_cid, speaker) = server.log_in("speaker");
+    let (listener_cid, listener) = server.log_in("listener");
+    let (realm_id, _, voice_channel) = server.default_channels(speaker_cid, speaker.get_id());
+    server.join_voice(speaker_cid, speaker.get_id(), realm_id, voice_channel);
+    server.join_voice(listener_cid, listener.get_id(), realm_id, voice_channel);
+
+    let header = MessageHeader::new(moderator.get_id(), realm_id, voice_channel);
+    server.receive(moderator_cid, MessageType::SetVoiceBitrate((header, 8000)));
+    server.messages(listener_cid);
+
+    // A 10ms frame at 8 kbps is 10 bytes, so up to 20 get through
+    let header = MessageHeader::new(speaker.get_id(), realm_id, voice_channel);
+    let audio = MessageType::Audio((header, 0, vec![0; 20]));
+    server.receive_realtime(speaker_cid, audio.clone());
+    assert_eq!(server.messages(listener_cid), vec![audio]);
+
+    server.receive_realtime(speaker_cid, MessageType::Audio((header, 1, vec![0; 21])));
+    assert!(server.messages(listener_cid).is_empty());
+}
+
+#[test]
+fn lost_audio_is_reported_to_the_speaker() {
+    let mut server = TestServer::new();
+    let (cid, speaker) = server.log_in("speaker");
+    let (realm_id, _, voice_channel) = server.default_channels(cid, speaker.get_id());
+    server.join_voice(cid, speaker.get_id(), realm_id, voice_channel);
+    server.messages(cid);
+
+    // Every fifth frame goes missing
+    let header = MessageHeader::new(speaker.get_id(), realm_id, voice_channel);
+    for sequence in (0..=250).filter(|sequence| sequence % 5 != 4) {
+        server.receive_realtime(cid, MessageType::Audio((header, sequence, vec![0; 8])));
+    }
+    assert_eq!(
+        server.messages(cid),
+        vec![MessageType::AudioLoss((251, 201))]
+    );
+
+    // A pause in speaking isn't loss
+    for sequence in 400..650 {
+        server.receive_realtime(cid, MessageType::Audio((header, sequence, vec![0; 8])));
+    }
+    assert_eq!(
+        server.messages(cid),
+        vec![MessageType::AudioLoss((250, 250))]
+    );
+}
+
+/// Who a recording announcement is about and whether they started or stopped
+fn recording(message: Option<MessageType>) -> Option<(UserIdSize, ChannelIdSize, bool)> {
+    match message {
+        Some(MessageType::VoiceRecording((header, recording))) => {
+            Some((header.user_id, header.channel_id, recording))
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn recording_is_announced_to_the_voice_channel() {
+    let mut server = TestServer::new();
+    let (recorder_cid, recorder) = server.log_in("recorder");
+    let (listener_cid, listener) = server.log_in("listener");
+    let (outsider_cid, outsider) = server.log_in("outsider");
+    let (realm_id, _, voice_channel) = server.default_channels(recorder_cid, recorder.get_id());
+
+    server.join_voice(recorder_cid, recorder.get_id(), realm_id, voice_channel);
+    server.join_voice(listener_cid, listener.get_id(), realm_id, voice_channel);
+    for cid in [recorder_cid, listener_cid, outsider_cid] {
+        server.messages(cid);
+    }
+
+    // Nobody can record a channel they aren't in
+    let header = MessageHeader::new(outsider.get_id(), realm_id, voice_channel);
+    server.receive(outsider_cid, MessageType::VoiceRecording((header, true)));
+    assert!(server.messages(listener_cid).is_empty());
+
+    let header = MessageHeader::new(recorder.get_id(), realm_id, voice_channel);
+    server.receive(recorder_cid, MessageType::VoiceRecording((header, true)));
+    let started = Some((recorder.get_id(), voice_channel, true));
+    assert_eq!(recording(server.messages(recorder_cid).pop()), started);
+    assert_eq!(recording(server.messages(listener_cid).pop()), started);
+    assert!(server.messages(outsider_cid).is_empty());
+
+    // Someone joining later is told too
+    server.join_voice(outsider_cid, outsider.get_id(), realm_id, voice_channel);
+    assert_eq!(recording(server.messages(outsider_cid).pop()), started);
+
+    // Leaving the channel ends the recording
+    server.receive(recorder_cid, MessageType::UserLeftVoiceChannel(header));
+    let stopped = Some((recorder.get_id(), voice_channel, false));
+    assert_eq!(recording(server.messages(listener_cid).pop()), stopped);
+    assert_eq!(recording(server.messages(outsider_cid).pop()), stopped);
+}
+
+#[test]
+fn deafened_users_recording_are_still_sent_audio() {
+    let mut server = TestServer::new();
+    let (speaker_cid, speaker) = server.log_in("speaker");
+    let (recorder_cid, recorder) = server.log_in("recorder");
+    let (realm_id, _, voice_channel) = server.default_channels(speaker_cid, speaker.get_id());
+
+    server.join_voice(speaker_cid, speaker.get_id(), realm_id, voice_channel);
+    server.join_voice(recorder_cid, recorder.get_id(), realm_id, voice_channel);
+
+    let header = MessageHeader::new(recorder.get_id(), realm_id, voice_channel);
+    let deafened = VoiceState {
+        muted: true,
+        deafened: true,
+        ..Default::default()
+    };
+    server.receive(
+        recorder_cid,
+        MessageType::VoiceStateUpdate((header, deafened)),
+    );
+    server.receive(recorder_cid, MessageType::VoiceRecording((header, true)));
+    server.messages(recorder_cid);
+
+    let speaker_header = MessageHeader::new(speaker.get_id(), realm_id, voice_channel);
+    let audio = MessageType::Audio((speaker_header, 0, vec![0; 8]));
+    server.receive_realtime(speaker_cid, audio.clone());
+    assert_eq!(server.messages(recorder_cid), vec![audio.clone()]);
+
+    // Once the recording stops they're deafened again
+    server.receive(recorder_cid, MessageType::VoiceRecording((header, false)));
+    server.messages(recorder_cid);
+    server.receive_realtime(speaker_cid, audio);
+    assert!(server.messages(recorder_cid).is_empty());
+}
+
+/// Who each audio frame sent to a connection is from, leaving out everything else
+fn audio_streams(messages: Vec<MessageType>) -> Vec<UserIdSize> {
+    messages
+        .into_iter()
+        .filter_map(|message| match message {
+            MessageType::Audio((header, _, _)) => Some(header.user_id),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn mixed_voice_channels_send_each_listener_one_stream() {
+    let mut server = TestServer::with_moderator();
+    let (speaker_cid, speaker) = server.log_in_moderator();
+    let (listener_cid, listener) = server.log_in("listener");
+    let (realm_id, _, voice_channel) = server.default_channels(speaker_cid, speaker.get_id());
+    for cid in [speaker_cid, listener_cid] {
+        server.messages(cid);
+    }
+
+    // Only moderators can turn mixing on
+    let listener_header = MessageHeader::new(listener.get_id(), realm_id, voice_channel);
+    server.receive(
+        listener_cid,
+        MessageType::SetVoiceMixing((listener_header, true)),
+    );
+    assert!(server.messages(speaker_cid).is_empty());
+
+    let speaker_header = MessageHeader::new(speaker.get_id(), realm_id, voice_channel);
+    server.receive(
+        speaker_cid,
+        MessageType::SetVoiceMixing((speaker_header, true)),
+    );
+    assert_eq!(
+        delta(&server.messages(listener_cid)[0], 1),
+        MessageType::VoiceMixingChanged((realm_id, voice_channel, true))
+    );
+
+    server.join_voice(speaker_cid, speaker.get_id(), realm_id, voice_channel);
+    server.join_voice(listener_cid, listener.get_id(), realm_id, voice_channel);
+    for cid in [speaker_cid, listener_cid] {
+        server.messages(cid);
+    }
+
+    // Frames are held back to be mixed rather than forwarded
+    for sequence in 0..3 {
+        let audio = MessageType::Audio((speaker_header, sequence, vec![0; 8]));
+        server.receive_realtime(speaker_cid, audio);
+    }
+    assert!(server.messages(listener_cid).is_empty());
+
+    // The listener hears the mix, the speaker has nobody else to hear
+    server.tick();
+    assert_eq!(
+        audio_streams(server.messages(listener_cid)),
+        vec![MIXED_STREAM_ID]
+    );
+    assert!(audio_streams(server.messages(speaker_cid)).is_empty());
+
+    // Once both talk, each hears a mix without their own voice
+    for sequence in 0..3 {
+        let audio = MessageType::Audio((listener_header, sequence, vec![0; 8]));
+        server.receive_realtime(listener_cid, audio);
+    }
+    std::thread::sleep(MIX_INTERVAL);
+    server.tick();
+    for cid in [speaker_cid, listener_cid] {
+        let streams = audio_streams(server.messages(cid));
+        assert!(!streams.is_empty());
+        assert!(streams.iter().all(|stream| *stream == OWN_MIX_STREAM_ID));
+    }
+}
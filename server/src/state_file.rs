@@ -0,0 +1,312 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use realms::channels::text_channel::{TextChannel, TextChannelMessage};
+use realms::channels::voice_channel::VoiceChannel;
+use realms::realms_manager::RealmsManager;
+use serde::{Deserialize, Serialize};
+use types::{ChannelIdSize, MessageIdSize, RealmIdSize, TextMessageChunks, UserIdSize};
+
+// Start of every state file, followed by the format version
+const MAGIC: &[u8; 8] = b"KAGUSTAT";
+
+/// Version of the saved structs below. Bump it when they change and keep reading older versions.
+/// They're kept apart from the realms types so those can change without breaking saved files.
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SavedState {
+    realms: Vec<SavedRealm>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SavedRealm {
+    id: RealmIdSize,
+    name: String,
+    text_channels: Vec<SavedTextChannel>,
+    voice_channels: Vec<SavedVoiceChannel>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SavedTextChannel {
+    id: ChannelIdSize,
+    name: String,
+    num_messages: MessageIdSize,
+    slow_mode_secs: u32,
+    chat_history: Vec<SavedMessage>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SavedMessage {
+    message_id: Option<MessageIdSize>,
+    user_id: UserIdSize,
+    target_reply_message_id: Option<MessageIdSize>,
+    time_sent: Option<DateTime<Utc>>,
+    image: Option<Vec<u8>>,
+    message_chunks: TextMessageChunks,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SavedVoiceChannel {
+    id: ChannelIdSize,
+    name: String,
+    max_bitrate: u32,
+    mixing: bool,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Read realms, their channels and chat history saved by a previous run.
+/// Fails with `InvalidData` if the file isn't a state file this version can read.
+pub fn load(path: &Path) -> io::Result<RealmsManager> {
+    let buffer = fs::read(path)?;
+
+    let body = buffer
+        .strip_prefix(MAGIC.as_slice())
+        .ok_or_else(|| invalid_data(String::from("not a Kagu state file")))?;
+    let (version, body) = match body.split_first_chunk::<4>() {
+        Some((version, body)) => (u32::from_le_bytes(*version), body),
+        None => return Err(invalid_data(String::from("state file is truncated"))),
+    };
+
+    let state: SavedState = match version {
+        VERSION => bincode::deserialize(body).map_err(|e| invalid_data(e.to_string()))?,
+        _ => {
+            return Err(invalid_data(format!(
+                "state file version {} isn't supported (expected {})",
+                version, VERSION
+            )))
+        }
+    };
+
+    Ok(restore(state))
+}
+
+/// Save realms so they survive a restart
+pub fn save(path: &Path, realms_manager: &RealmsManager) -> io::Result<()> {
+    let body =
+        bincode::serialize(&snapshot(realms_manager)).map_err(|e| invalid_data(e.to_string()))?;
+
+    let mut buffer = Vec::with_capacity(MAGIC.len() + 4 + body.len());
+    buffer.extend_from_slice(MAGIC);
+    buffer.extend_from_slice(&VERSION.to_le_bytes());
+    buffer.extend(body);
+
+    // Write to a temporary file first so a crash can't leave half a state file
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, buffer)?;
+    fs::rename(&temp_path, path)
+}
+
+/// Rename a state file that couldn't be read so it isn't overwritten, returning where it went
+pub fn move_aside(path: &Path) -> io::Result<PathBuf> {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(format!(".unreadable-{}", seconds));
+    let aside = PathBuf::from(aside);
+
+    fs::rename(path, &aside)?;
+    Ok(aside)
+}
+
+fn snapshot(realms_manager: &RealmsManager) -> SavedState {
+    let mut realm_ids: Vec<RealmIdSize> = realms_manager
+        .get_realms()
+        .into_iter()
+        .map(|(id, _)| *id)
+        .collect();
+    realm_ids.sort();
+
+    let realms = realm_ids
+        .into_iter()
+        .filter_map(|id| realms_manager.get_realm(id))
+        .map(|realm| {
+            let mut text_channels: Vec<SavedTextChannel> = realm
+                .get_text_channels()
+                .values()
+                .map(|channel| SavedTextChannel {
+                    id: *channel.get_id(),
+                    name: channel.get_name().clone(),
+                    num_messages: channel.num_messages,
+                    slow_mode_secs: channel.slow_mode_secs,
+                    chat_history: channel
+                        .chat_history
+                        .iter()
+                        .map(|message| SavedMessage {
+                            message_id: message.message_id,
+                            user_id: message.user_id,
+                            target_reply_message_id: message.target_reply_message_id,
+                            time_sent: message.time_sent,
+                            image: message.image.clone(),
+                            message_chunks: message.message_chunks.clone(),
+                        })
+                        .collect(),
+                })
+                .collect();
+            text_channels.sort_by_key(|channel| channel.id);
+
+            let mut voice_channels: Vec<SavedVoiceChannel> = realm
+                .get_voice_channels()
+                .values()
+                .map(|channel| SavedVoiceChannel {
+                    id: *channel.get_id(),
+                    name: channel.get_name().clone(),
+                    max_bitrate: channel.max_bitrate,
+                    mixing: channel.mixing,
+                })
+                .collect();
+            voice_channels.sort_by_key(|channel| channel.id);
+
+            SavedRealm {
+                id: realm.id,
+                name: realm.name.clone(),
+                text_channels,
+                voice_channels,
+            }
+        })
+        .collect();
+
+    SavedState { realms }
+}
+
+fn restore(state: SavedState) -> RealmsManager {
+    let mut realms_manager = RealmsManager::default();
+
+    for saved in state.realms {
+        realms_manager.add_realm_with_id(saved.id, saved.name);
+        let realm = match realms_manager.get_realm_mut(saved.id) {
+            Some(realm) => realm,
+            None => continue,
+        };
+
+        for text in saved.text_channels {
+            let mut channel = TextChannel::new(text.id, text.name);
+            channel.num_messages = text.num_messages;
+            channel.slow_mode_secs = text.slow_mode_secs;
+            channel.chat_history = text
+                .chat_history
+                .into_iter()
+                .map(|message| TextChannelMessage {
+                    message_id: message.message_id,
+                    user_id: message.user_id,
+                    target_reply_message_id: message.target_reply_message_id,
+                    time_sent: message.time_sent,
+                    image: message.image,
+                    message_chunks: message.message_chunks,
+                })
+                .collect();
+            realm.text_channels.insert(text.id, channel);
+        }
+
+        // Nobody is connected to voice when the server starts
+        for voice in saved.voice_channels {
+            let mut channel = VoiceChannel::new(voice.id, voice.name);
+            channel.max_bitrate = voice.max_bitrate;
+            channel.mixing = voice.mixing;
+            realm.voice_channels.insert(voice.id, channel);
+        }
+    }
+
+    realms_manager
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use realms::realm::ChannelType;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("kagu-{}-{}.state", name, std::process::id()))
+    }
+
+    #[test]
+    fn realms_survive_a_round_trip() {
+        let mut realms_manager = RealmsManager::default();
+        realms_manager.add_realm(String::from("Zero"));
+        let realm_id = realms_manager.add_realm(String::from("One"));
+        let (text_id, _) = realms_manager
+            .add_channel(realm_id, ChannelType::TextChannel, String::from("general"))
+            .unwrap();
+        let (voice_id, _) = realms_manager
+            .add_channel(realm_id, ChannelType::VoiceChannel, String::from("Voice"))
+            .unwrap();
+        realms_manager.remove_realm(0);
+
+        let realm = realms_manager.get_realm_mut(realm_id).unwrap();
+        let text_channel = realm.get_text_channel_mut(text_id).unwrap();
+        text_channel.slow_mode_secs = 30;
+        let message_id = text_channel.generate_message_id();
+        text_channel.chat_history.push(TextChannelMessage {
+            message_id: Some(message_id),
+            user_id: 4,
+            target_reply_message_id: None,
+            time_sent: Some(Utc::now()),
+            image: None,
+            message_chunks: vec![(String::from("hello"), None)],
+        });
+        let voice_channel = realm.get_voice_channel_mut(voice_id).unwrap();
+        voice_channel.max_bitrate = 24000;
+        voice_channel.mixing = true;
+        voice_channel.get_connected_users_mut().push(4);
+
+        let path = temp_path("round-trip");
+        save(&path, &realms_manager).unwrap();
+        let mut loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let realm = loaded.get_realm(realm_id).unwrap();
+        let text_channel = realm.get_text_channel(text_id).unwrap();
+        assert_eq!(text_channel.get_name(), "general");
+        assert_eq!(text_channel.slow_mode_secs, 30);
+        assert_eq!(text_channel.num_messages, 1);
+        assert_eq!(
+            text_channel.chat_history,
+            realms_manager
+                .get_realm(realm_id)
+                .unwrap()
+                .get_text_channel(text_id)
+                .unwrap()
+                .chat_history
+        );
+
+        let voice_channel = realm.get_voice_channel(voice_id).unwrap();
+        assert_eq!(voice_channel.max_bitrate, 24000);
+        assert!(voice_channel.mixing);
+        assert!(voice_channel.get_connected_users().is_empty());
+
+        // New realms don't reuse a loaded realm's id
+        assert_eq!(loaded.add_realm(String::from("Two")), realm_id + 1);
+    }
+
+    #[test]
+    fn files_without_the_header_are_rejected() {
+        let path = temp_path("unversioned");
+        let unversioned = bincode::serialize(&RealmsManager::default()).unwrap();
+        fs::write(&path, unversioned).unwrap();
+
+        let error = load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let path = temp_path("newer");
+        let mut buffer = MAGIC.to_vec();
+        buffer.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&path, buffer).unwrap();
+
+        let error = load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,122 @@
+use std::net::SocketAddr;
+
+use swiftlet_quic::endpoint::{ConnectionId, Endpoint};
+
+use crate::fan_out::{into_vec, Frame};
+
+/// What the server needs from the network to talk to its connections.
+/// Generic over the connection id so the server can be run without real sockets.
+pub trait Transport<C> {
+    /// Send a length-prefixed buffer over the reliable main stream
+    fn send_reliable(&mut self, cid: &C, buffer: Frame);
+
+    /// Send a buffer over the unreliable realtime stream
+    fn send_realtime(&mut self, cid: &C, buffer: Frame);
+
+    fn close_connection(&mut self, cid: &C, reason: u64);
+
+    fn peer_addr(&self, cid: &C) -> Option<SocketAddr>;
+}
+
+impl Transport<ConnectionId> for Endpoint {
+    fn send_reliable(&mut self, cid: &ConnectionId, buffer: Frame) {
+        let _ = self.main_stream_send(cid, into_vec(buffer));
+    }
+
+    fn send_realtime(&mut self, cid: &ConnectionId, buffer: Frame) {
+        let _ = self.rt_stream_send(cid, Some(into_vec(buffer)), true);
+    }
+
+    fn close_connection(&mut self, cid: &ConnectionId, reason: u64) {
+        let _ = Endpoint::close_connection(self, cid, reason);
+    }
+
+    fn peer_addr(&self, cid: &ConnectionId) -> Option<SocketAddr> {
+        self.get_connection_socket_addr(cid).ok()
+    }
+}
+
+#[cfg(test)]
+pub use memory::MemoryTransport;
+
+#[cfg(test)]
+mod memory {
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+
+    use message::message::Message;
+    use network_manager::MESSAGE_HEADER_SIZE;
+
+    use super::Transport;
+    use crate::fan_out::Frame;
+
+    /// Keeps everything sent in memory so tests can look at it
+    #[derive(Default)]
+    pub struct MemoryTransport {
+        // Buffers sent to each connection in order, and whether they went realtime
+        sent: BTreeMap<u64, Vec<(bool, Frame)>>,
+        closed: BTreeMap<u64, u64>,
+        peers: BTreeMap<u64, SocketAddr>,
+    }
+
+    impl MemoryTransport {
+        pub fn new() -> MemoryTransport {
+            MemoryTransport::default()
+        }
+
+        pub fn set_peer_addr(&mut self, cid: u64, address: SocketAddr) {
+            self.peers.insert(cid, address);
+        }
+
+        /// Messages sent to a connection since this was last called
+        pub fn take_messages(&mut self, cid: u64) -> Vec<Message> {
+            self.sent
+                .remove(&cid)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(realtime, buffer)| {
+                    let buffer = match realtime {
+                        true => buffer.to_vec(),
+                        false => {
+                            let length = u16::from_ne_bytes([buffer[0], buffer[1]]) as usize;
+                            assert_eq!(buffer.len(), MESSAGE_HEADER_SIZE + length);
+                            buffer[MESSAGE_HEADER_SIZE..].to_vec()
+                        }
+                    };
+                    Message::from_vec_u8(buffer).unwrap()
+                })
+                .collect()
+        }
+
+        /// Whether the last buffer sent to a connection went over the realtime stream
+        pub fn last_sent_realtime(&self, cid: u64) -> Option<bool> {
+            self.sent
+                .get(&cid)
+                .and_then(|sent| sent.last())
+                .map(|(realtime, _)| *realtime)
+        }
+
+        /// Reason a connection was closed with, if it was
+        pub fn closed_reason(&self, cid: u64) -> Option<u64> {
+            self.closed.get(&cid).copied()
+        }
+    }
+
+    impl Transport<u64> for MemoryTransport {
+        fn send_reliable(&mut self, cid: &u64, buffer: Frame) {
+            self.sent.entry(*cid).or_default().push((false, buffer));
+        }
+
+        fn send_realtime(&mut self, cid: &u64, buffer: Frame) {
+            self.sent.entry(*cid).or_default().push((true, buffer));
+        }
+
+        fn close_connection(&mut self, cid: &u64, reason: u64) {
+            self.closed.insert(*cid, reason);
+        }
+
+        fn peer_addr(&self, cid: &u64) -> Option<SocketAddr> {
+            self.peers.get(cid).copied()
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use jitter_buffer::{Frame, JitterBuffer, FRAME_DURATION, FRAME_SIZE};
+use opus::{Application, Bitrate, Channels, Encoder};
+use tracing::{debug, error, warn};
+use types::{AudioSequenceSize, ChannelIdSize, RealmIdSize, UserIdSize};
+
+/// Clients send 10ms frames, so mixed channels are mixed 10ms at a time
+pub const MIX_INTERVAL: Duration = FRAME_DURATION;
+
+/// Who the mix of everyone speaking is sent as. Voices can't be picked out of a mix,
+/// so clients don't show who's speaking or offer per-user volume in mixed channels.
+pub const MIXED_STREAM_ID: UserIdSize = UserIdSize::MAX;
+
+/// Who a mix leaving out the listener's own voice is sent as.
+/// It can't share an id with the full mix, as each is encoded separately.
+pub const OWN_MIX_STREAM_ID: UserIdSize = UserIdSize::MAX - 1;
+
+// Most frames held from a speaker, so a bad connection can't add lag to everyone
+const MAX_DELAY_FRAMES: usize = 10;
+
+// Frames mixed in one go after a slow tick, anything further behind is skipped
+const MAX_CATCH_UP_FRAMES: u32 = 5;
+
+// Bitrate of each mixed stream unless the channel is limited to less
+const MIXED_BITRATE: u32 = 48_000;
+
+// Largest encoded frame
+const MAX_PACKET_SIZE: usize = 4000;
+
+/// An encoded frame of a mix and who it's for
+#[derive(Debug)]
+pub struct MixedFrame {
+    pub listeners: Vec<UserIdSize>,
+    pub stream_id: UserIdSize,
+    pub sequence: AudioSequenceSize,
+    pub audio: Vec<u8>,
+}
+
+// One encoded stream of a mix, with the state its encoder keeps between frames
+struct MixStream {
+    encoder: Encoder,
+    bitrate: u32,
+    sequence: AudioSequenceSize,
+}
+
+impl MixStream {
+    fn new(bitrate: u32) -> Result<MixStream, opus::Error> {
+        let mut encoder = Encoder::new(48000, Channels::Stereo, Application::Voip)?;
+        encoder.set_bitrate(Bitrate::Bits(bitrate as i32))?;
+
+        Ok(MixStream {
+            encoder,
+            bitrate,
+            sequence: 0,
+        })
+    }
+
+    fn encode(
+        &mut self,
+        mix: &Frame,
+        bitrate: u32,
+    ) -> Result<(AudioSequenceSize, Vec<u8>), opus::Error> {
+        if bitrate != self.bitrate {
+            self.encoder.set_bitrate(Bitrate::Bits(bitrate as i32))?;
+            self.bitrate = bitrate;
+        }
+
+        // Listeners decode to floats, so loud mixes are left for their limiter to tame
+        let audio = self.encoder.encode_vec_float(mix, MAX_PACKET_SIZE)?;
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok((sequence, audio))
+    }
+}
+
+#[derive(Default)]
+struct ChannelMix {
+    // Frames from each speaker put back in order, decoded a frame at a time
+    speakers: BTreeMap<UserIdSize, JitterBuffer>,
+    // Streams by whose voice is left out, None for the one everyone not speaking shares
+    streams: BTreeMap<Option<UserIdSize>, MixStream>,
+}
+
+impl ChannelMix {
+    fn mix_frame(&mut self, listeners: &[UserIdSize], bitrate: u32) -> Vec<MixedFrame> {
+        let mut voices = Vec::new();
+        let mut voice: Frame = [0.0; FRAME_SIZE];
+        for (user_id, speaker) in self.speakers.iter_mut() {
+            if speaker.pop(&mut voice) {
+                voices.push((*user_id, voice));
+            }
+        }
+
+        // Nothing is sent while nobody is talking, as clients do themselves
+        if voices.is_empty() {
+            return Vec::new();
+        }
+
+        let mut everyone: Frame = [0.0; FRAME_SIZE];
+        for (_, voice) in &voices {
+            for i in 0..FRAME_SIZE {
+                everyone[i] += voice[i];
+            }
+        }
+
+        let mut frames = Vec::new();
+
+        // Everyone not speaking hears the same mix, so it only needs encoding once
+        let quiet: Vec<UserIdSize> = listeners
+            .iter()
+            .filter(|listener| !voices.iter().any(|(user_id, _)| user_id == *listener))
+            .copied()
+            .collect();
+        if !quiet.is_empty() {
+            if let Some((sequence, audio)) = self.encode(None, &everyone, bitrate) {
+                frames.push(MixedFrame {
+                    listeners: quiet,
+                    stream_id: MIXED_STREAM_ID,
+                    sequence,
+                    audio,
+                });
+            }
+        }
+
+        // Speakers hear everyone but themselves, so each gets a mix of their own
+        if voices.len() > 1 {
+            for (user_id, voice) in &voices {
+                if !listeners.contains(user_id) {
+                    continue;
+                }
+
+                let mut others = everyone;
+                for i in 0..FRAME_SIZE {
+                    others[i] -= voice[i];
+                }
+
+                if let Some((sequence, audio)) = self.encode(Some(*user_id), &others, bitrate) {
+                    frames.push(MixedFrame {
+                        listeners: vec![*user_id],
+                        stream_id: OWN_MIX_STREAM_ID,
+                        sequence,
+                        audio,
+                    });
+                }
+            }
+        }
+
+        frames
+    }
+
+    fn encode(
+        &mut self,
+        left_out: Option<UserIdSize>,
+        mix: &Frame,
+        bitrate: u32,
+    ) -> Option<(AudioSequenceSize, Vec<u8>)> {
+        let stream = match self.streams.entry(left_out) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match MixStream::new(bitrate) {
+                Ok(stream) => entry.insert(stream),
+                Err(e) => {
+                    error!("failed to create an encoder for a mix: {}", e);
+                    return None;
+                }
+            },
+        };
+
+        match stream.encode(mix, bitrate) {
+            Ok(encoded) => Some(encoded),
+            Err(e) => {
+                warn!("failed to encode a mix: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Mixes voice channels on the server, so each listener is sent one stream
+/// however many people are talking. Costs a decode per speaker and an encode
+/// per stream every frame, in exchange for download bandwidth that stays flat.
+#[derive(Default)]
+pub struct VoiceMixer {
+    channels: BTreeMap<(RealmIdSize, ChannelIdSize), ChannelMix>,
+    // When the next frame is due, unset while nothing is being mixed
+    next_frame: Option<Instant>,
+}
+
+impl VoiceMixer {
+    pub fn new() -> VoiceMixer {
+        VoiceMixer::default()
+    }
+
+    /// Queue a frame from someone speaking in a mixed channel
+    pub fn receive(
+        &mut self,
+        realm_id: RealmIdSize,
+        channel_id: ChannelIdSize,
+        user_id: UserIdSize,
+        sequence: AudioSequenceSize,
+        audio: Vec<u8>,
+    ) {
+        let channel = self.channels.entry((realm_id, channel_id)).or_default();
+        let speaker = match channel.speakers.entry(user_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match JitterBuffer::new(MAX_DELAY_FRAMES) {
+                Ok(speaker) => entry.insert(speaker),
+                Err(e) => {
+                    error!("failed to create a decoder for user {}: {}", user_id, e);
+                    return;
+                }
+            },
+        };
+
+        speaker.push(sequence, audio, Instant::now());
+    }
+
+    /// Stop mixing in a user and drop the stream made for them
+    pub fn remove_user(&mut self, user_id: UserIdSize) {
+        self.channels.retain(|_, channel| {
+            channel.speakers.remove(&user_id);
+            channel.streams.remove(&Some(user_id));
+            !channel.speakers.is_empty()
+        });
+    }
+
+    pub fn remove_channel(&mut self, realm_id: RealmIdSize, channel_id: ChannelIdSize) {
+        self.channels.remove(&(realm_id, channel_id));
+    }
+
+    pub fn remove_realm(&mut self, realm_id: RealmIdSize) {
+        self.channels.retain(|key, _| key.0 != realm_id);
+    }
+
+    /// Channels with someone to mix
+    pub fn channels(&self) -> Vec<(RealmIdSize, ChannelIdSize)> {
+        self.channels.keys().copied().collect()
+    }
+
+    /// Frames to mix by `now`, catching up after a slow tick but not by too much
+    pub fn frames_due(&mut self, now: Instant) -> u32 {
+        if self.channels.is_empty() {
+            self.next_frame = None;
+            return 0;
+        }
+
+        let next = *self.next_frame.get_or_insert(now);
+        if now < next {
+            return 0;
+        }
+
+        let due = (now.duration_since(next).as_nanos() / MIX_INTERVAL.as_nanos()) as u32 + 1;
+        if due > MAX_CATCH_UP_FRAMES {
+            debug!(due, "mixing fell behind, skipping frames");
+            self.next_frame = Some(now + MIX_INTERVAL);
+            return MAX_CATCH_UP_FRAMES;
+        }
+
+        self.next_frame = Some(next + MIX_INTERVAL * due);
+        due
+    }
+
+    /// Mix the next frame of a channel for the people listening to it.
+    /// `max_bitrate` is the channel's limit, 0 for none.
+    pub fn mix(
+        &mut self,
+        realm_id: RealmIdSize,
+        channel_id: ChannelIdSize,
+        listeners: &[UserIdSize],
+        max_bitrate: u32,
+    ) -> Vec<MixedFrame> {
+        let bitrate = match max_bitrate {
+            0 => MIXED_BITRATE,
+            limit => limit.min(MIXED_BITRATE),
+        };
+
+        match self.channels.get_mut(&(realm_id, channel_id)) {
+            Some(channel) => channel.mix_frame(listeners, bitrate),
+            None => Vec::new(),
+        }
+    }
+}
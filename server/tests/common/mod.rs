@@ -0,0 +1,217 @@
+//! Runs a real server and real clients over loopback for integration tests
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use client::client::Client;
+use message::message::MessageType;
+use realms::realm_desc::RealmDescription;
+use server::config::{DefaultRealmConfig, ServerConfig};
+use server::logging::LogLevel;
+use server::server::NewServer;
+use user::User;
+
+// How long to wait for a message that should arrive
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to watch for a message that shouldn't arrive
+pub const QUIET_PERIOD: Duration = Duration::from_millis(300);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+static SERVERS_STARTED: AtomicUsize = AtomicUsize::new(0);
+
+/// The certificate checked into the repo, made out to `localhost`
+fn cert_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../certs")
+}
+
+/// Find a port nothing is listening on by letting the OS pick one
+fn free_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .and_then(|socket| socket.local_addr())
+        .map(|address| address.port())
+        .expect("failed to find a free port")
+}
+
+/// A server on its own thread, stopped when dropped
+pub struct TestServer {
+    server: NewServer,
+    handle: Option<JoinHandle<()>>,
+    address: SocketAddr,
+    scratch_dir: PathBuf,
+}
+
+impl TestServer {
+    /// Start a server with one realm holding a `general` text channel and a `General` voice channel
+    pub fn start() -> TestServer {
+        let mut config = ServerConfig {
+            default_realm: Some(DefaultRealmConfig {
+                name: String::from("Kagu"),
+                text_channels: vec![String::from("general")],
+                voice_channels: vec![String::from("General")],
+            }),
+            ..Default::default()
+        };
+        config.logging.level = LogLevel::Warn;
+
+        TestServer::start_with_config(config)
+    }
+
+    pub fn start_with_config(mut config: ServerConfig) -> TestServer {
+        // Keep the control socket of each server apart so tests can run in parallel
+        let scratch_dir = std::env::temp_dir().join(format!(
+            "kagu-test-{}-{}",
+            std::process::id(),
+            SERVERS_STARTED.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&scratch_dir).expect("failed to create a scratch directory");
+        config.server.control_socket = Some(scratch_dir.join("kagu-server.sock"));
+
+        let port = free_port();
+        let mut server = NewServer::new(String::from("TestServer"), port, Some(false), cert_dir());
+        server.set_config(config);
+        let handle = server.start_server();
+
+        TestServer {
+            server,
+            handle: Some(handle),
+            address: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)),
+            scratch_dir,
+        }
+    }
+
+    /// Connect a client and log in, waiting for realms so realm changes are passed along
+    pub fn connect(&self, username: &str) -> TestClient {
+        let mut client = Client::new(
+            self.address,
+            String::from("localhost"),
+            username.to_string(),
+            Some(cert_dir()),
+        );
+        client.run_client();
+
+        let started = Instant::now();
+        while !client.is_connected() {
+            assert!(
+                started.elapsed() < TIMEOUT,
+                "{} failed to connect to {}",
+                username,
+                self.address
+            );
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        client.log_in();
+        let mut test_client = TestClient {
+            client,
+            user: None,
+            username: username.to_string(),
+            disconnected: false,
+        };
+
+        let user = test_client.wait_for(|message| match message {
+            MessageType::LoginSuccess(user) => Some(user.clone()),
+            _ => None,
+        });
+        test_client.client.set_user(user.clone());
+        test_client.user = Some(user);
+
+        test_client.realms();
+        test_client
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.server.stop_server();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        let _ = std::fs::remove_dir_all(&self.scratch_dir);
+    }
+}
+
+/// A logged in client. Disconnects when dropped.
+pub struct TestClient {
+    pub client: Client,
+    user: Option<User>,
+    username: String,
+    disconnected: bool,
+}
+
+impl TestClient {
+    pub fn user(&self) -> &User {
+        self.user.as_ref().expect("client isn't logged in")
+    }
+
+    /// Wait for a message `matches` picks out, skipping any others.
+    /// Panics with what was received instead if it doesn't arrive in time.
+    pub fn wait_for<T>(&mut self, mut matches: impl FnMut(&MessageType) -> Option<T>) -> T {
+        let started = Instant::now();
+        let mut skipped = Vec::new();
+
+        while started.elapsed() < TIMEOUT {
+            for message in self.client.get_new_messages() {
+                match matches(&message.message) {
+                    Some(found) => return found,
+                    None => skipped.push(message.message),
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        panic!(
+            "{} timed out waiting for a message, received {:?}",
+            self.username, skipped
+        );
+    }
+
+    /// Check nothing `matches` picks out arrives for a little while
+    pub fn expect_none<T: std::fmt::Debug>(
+        &mut self,
+        mut matches: impl FnMut(&MessageType) -> Option<T>,
+    ) {
+        let started = Instant::now();
+
+        while started.elapsed() < QUIET_PERIOD {
+            for message in self.client.get_new_messages() {
+                if let Some(found) = matches(&message.message) {
+                    panic!("{} unexpectedly received {:?}", self.username, found);
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Tell the server we're leaving and wait for the connection to close
+    pub fn disconnect(&mut self) {
+        self.client.disconnect();
+        self.disconnected = true;
+    }
+
+    /// Drop anything received so far
+    pub fn clear(&mut self) {
+        self.client.get_new_messages();
+    }
+
+    /// Ask for and wait on a full copy of the realms
+    pub fn realms(&mut self) -> Vec<RealmDescription> {
+        self.client.get_realms();
+        self.wait_for(|message| match message {
+            MessageType::Realms((_, realms)) => Some(realms.clone()),
+            _ => None,
+        })
+    }
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        if !self.disconnected {
+            self.disconnect();
+        }
+    }
+}
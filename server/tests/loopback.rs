@@ -0,0 +1,190 @@
+//! End-to-end tests of a server and clients talking over loopback
+
+mod common;
+
+use common::TestServer;
+use message::message::MessageType;
+use realms::realm::ChannelType;
+
+#[test]
+fn login_and_user_join_leave_broadcast() {
+    let server = TestServer::start();
+    let mut alice = server.connect("alice");
+    let mut bob = server.connect("bob");
+
+    assert_eq!(alice.user().get_username(), "alice");
+    assert_eq!(bob.user().get_username(), "bob");
+    assert_ne!(alice.user().get_id(), bob.user().get_id());
+
+    let bob_id = bob.user().get_id();
+    let joined = alice.wait_for(|message| match message {
+        MessageType::UserJoined(user) => Some(user.clone()),
+        _ => None,
+    });
+    assert_eq!(&joined, bob.user());
+
+    bob.disconnect();
+    let left = alice.wait_for(|message| match message {
+        MessageType::UserLeft(user_id) => Some(*user_id),
+        _ => None,
+    });
+    assert_eq!(left, bob_id);
+}
+
+#[test]
+fn text_and_replies_get_server_assigned_ids() {
+    let server = TestServer::start();
+    let mut alice = server.connect("alice");
+    let mut bob = server.connect("bob");
+
+    let realm = alice.realms().remove(0);
+    let channel_id = realm.text_channels[0].0;
+
+    let chunks = vec![(String::from("hello bob"), None)];
+    alice
+        .client
+        .send_mention_message(realm.id, channel_id, chunks.clone());
+
+    let text = bob.wait_for(|message| match message {
+        MessageType::Text(text) => Some(text.clone()),
+        _ => None,
+    });
+    assert_eq!(text.0.user_id, alice.user().get_id());
+    assert_eq!(text.0.realm_id, realm.id);
+    assert_eq!(text.0.channel_id, channel_id);
+    assert_eq!(text.1, chunks);
+    let message_id = text.0.message_id.expect("server should assign an id");
+
+    // The sender gets their own message back with the same id
+    let echoed = alice.wait_for(|message| match message {
+        MessageType::Text(text) => Some(text.0.message_id),
+        _ => None,
+    });
+    assert_eq!(echoed, Some(message_id));
+
+    let reply_chunks = vec![(String::from("hi alice"), Some(alice.user().get_id()))];
+    bob.client
+        .send_reply_message(realm.id, channel_id, message_id, reply_chunks.clone());
+
+    let reply = alice.wait_for(|message| match message {
+        MessageType::Reply(reply) => Some(reply.clone()),
+        _ => None,
+    });
+    assert_eq!(reply.0.user_id, bob.user().get_id());
+    assert_eq!(reply.1, message_id);
+    assert_eq!(reply.2, reply_chunks);
+    let reply_id = reply.0.message_id.expect("server should assign an id");
+    assert_ne!(reply_id, message_id);
+}
+
+#[test]
+fn typing_reaches_everyone_else() {
+    let server = TestServer::start();
+    let mut alice = server.connect("alice");
+    let mut bob = server.connect("bob");
+
+    let realm = alice.realms().remove(0);
+    let channel_id = realm.text_channels[0].0;
+    alice.clear();
+
+    alice.client.send_typing(realm.id, channel_id);
+
+    let typing = bob.wait_for(|message| match message {
+        MessageType::Typing(header) => Some(*header),
+        _ => None,
+    });
+    assert_eq!(typing.user_id, alice.user().get_id());
+    assert_eq!(typing.channel_id, channel_id);
+
+    alice.expect_none(|message| match message {
+        MessageType::Typing(header) => Some(*header),
+        _ => None,
+    });
+}
+
+#[test]
+fn realm_and_channel_additions_are_broadcast() {
+    let server = TestServer::start();
+    let mut alice = server.connect("alice");
+    let mut bob = server.connect("bob");
+
+    alice.client.add_realm(String::from("Second Realm"));
+
+    let realm_id = bob.wait_for(|message| match message {
+        MessageType::RealmAdded((realm_id, name)) if name == "Second Realm" => Some(*realm_id),
+        _ => None,
+    });
+    alice.wait_for(|message| match message {
+        MessageType::RealmAdded((id, _)) if *id == realm_id => Some(()),
+        _ => None,
+    });
+
+    alice
+        .client
+        .add_channel(realm_id, ChannelType::VoiceChannel, String::from("Lounge"));
+
+    let (channel_type, channel_id) = bob.wait_for(|message| match message {
+        MessageType::ChannelAdded((id, channel_type, channel_id, name))
+            if *id == realm_id && name == "Lounge" =>
+        {
+            Some((channel_type.clone(), *channel_id))
+        }
+        _ => None,
+    });
+    assert_eq!(channel_type, ChannelType::VoiceChannel);
+
+    // A client joining later sees both in a full sync
+    let mut carol = server.connect("carol");
+    let realms = carol.realms();
+    let realm = realms
+        .iter()
+        .find(|realm| realm.id == realm_id)
+        .expect("new realm should be listed");
+    assert_eq!(realm.name, "Second Realm");
+    assert!(realm
+        .voice_channels
+        .iter()
+        .any(|channel| channel.0 == channel_id && channel.1 == "Lounge"));
+}
+
+#[test]
+fn voice_channel_membership_is_tracked() {
+    let server = TestServer::start();
+    let mut alice = server.connect("alice");
+    let mut bob = server.connect("bob");
+    let alice_id = alice.user().get_id();
+
+    let realm = alice.realms().remove(0);
+    let channel_id = realm.voice_channels[0].0;
+
+    alice
+        .client
+        .join_channel(realm.id, ChannelType::VoiceChannel, channel_id);
+
+    let joined = bob.wait_for(|message| match message {
+        MessageType::UserJoinedVoiceChannel(header) => Some(*header),
+        _ => None,
+    });
+    assert_eq!(joined.user_id, alice_id);
+    assert_eq!(joined.realm_id, realm.id);
+    assert_eq!(joined.channel_id, channel_id);
+
+    let members = |realms: Vec<realms::realm_desc::RealmDescription>| {
+        realms
+            .into_iter()
+            .find(|r| r.id == realm.id)
+            .and_then(|r| r.voice_channels.into_iter().find(|c| c.0 == channel_id))
+            .map(|channel| channel.2)
+            .unwrap_or_default()
+    };
+    assert_eq!(members(bob.realms()), vec![alice_id]);
+
+    alice.client.hang_up(realm.id, channel_id);
+
+    let left = bob.wait_for(|message| match message {
+        MessageType::UserLeftVoiceChannel(header) => Some(header.user_id),
+        _ => None,
+    });
+    assert_eq!(left, alice_id);
+    assert!(members(bob.realms()).is_empty());
+}
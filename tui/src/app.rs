@@ -1,12 +1,13 @@
 use chrono::DateTime;
 use chrono::Utc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error;
 use std::io;
-use std::path::Path;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use ratatui::{backend::CrosstermBackend, Terminal};
+use tracing::{debug, info};
 use tui_widget_list::widget_list::stateful_widget_list::StatefulWidgetList;
 
 use crate::command::Command;
@@ -17,8 +18,10 @@ use crate::{
     tui::Tui,
 };
 
+use audio::levels::{self, AudioLevel};
 use client::client::Client;
 use message::message::MessageType;
+use message::voice_state::VoiceState;
 use realms::channels::text_channel::TextChannelMessage;
 use realms::realm::ChannelType;
 use realms::realms_manager::RealmsManager;
@@ -37,6 +40,13 @@ use crate::popups::{
 
 use chrono::Local;
 
+/// How long to wait between attempts to reconnect after a restart
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+/// Attempts to reconnect before giving up
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+/// How long someone stays shown as speaking after they were last heard
+const SPEAKING_HOLD: Duration = Duration::from_millis(300);
+
 /// Application result type.
 pub type AppResult<T> = std::result::Result<T, Box<dyn error::Error>>;
 
@@ -57,6 +67,32 @@ impl std::fmt::Display for SettingsCategory {
     }
 }
 
+/// Which list has focus on the audio settings view
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioSettingsFocus {
+    Inputs,
+    Outputs,
+    Voice,
+}
+
+/// Rows of the voice section of the audio settings view
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoiceSetting {
+    InputVolume,
+    OutputVolume,
+    CaptureMode,
+    VoiceThreshold,
+    HangTime,
+    Dtx,
+    NoiseSuppression,
+    EchoCancellation,
+    AutoGain,
+    Bitrate,
+    Complexity,
+    Stereo,
+    Application,
+}
+
 #[derive(Debug)]
 pub enum PopupType {
     General,
@@ -187,6 +223,14 @@ pub struct App<'a> {
     pub voice_channels: StatefulList<(ChannelIdSize, String, Vec<UserIdSize>)>,
     /// Status indicating if we are connected via voice
     pub is_voice_connected: bool,
+    /// Everyone muted or deafened, shown next to them in voice channels
+    pub voice_states: HashMap<UserIdSize, VoiceState>,
+    /// Everyone heard speaking lately, and when they last were
+    pub speaking: HashMap<UserIdSize, Instant>,
+    /// How loud our microphone is, and when that was measured
+    pub input_level: Option<(f32, Instant)>,
+    /// Everyone recording the voice channel we're in
+    pub recorders: HashSet<UserIdSize>,
     /// Current Realm we are in
     pub current_realm_id: Option<RealmIdSize>,
     /// Current text channel we're in
@@ -250,7 +294,22 @@ pub struct App<'a> {
     pub _not_used: &'a bool,
     pub current_settings_category: SettingsCategory,
     pub settings_category_list: StatefulList<SettingsCategory>,
+    pub audio_inputs: StatefulList<String>,
+    pub audio_outputs: StatefulList<String>,
+    pub voice_settings: StatefulList<VoiceSetting>,
+    /// Set while picking an audio device or changing voice settings
+    pub audio_settings_focus: Option<AudioSettingsFocus>,
     pub ping_latency: Option<Duration>,
+    /// Why the server shut down, shown after the UI exits
+    pub shutdown_reason: Option<String>,
+    /// When the server said it would be back after restarting
+    pub planned_restart: Option<Instant>,
+    /// When to next try reconnecting
+    pub reconnect_at: Option<Instant>,
+    /// Reconnects tried since the connection was lost
+    pub reconnect_attempts: u32,
+    /// Set while waiting for a reconnect to go through
+    pub is_reconnecting: bool,
 }
 
 impl<'a> App<'a> {
@@ -261,6 +320,15 @@ impl<'a> App<'a> {
         commands_list
             .items
             .push((Command::Image, Command::Image.to_str()));
+        commands_list
+            .items
+            .push((Command::SlowMode, Command::SlowMode.to_str()));
+        commands_list
+            .items
+            .push((Command::Bitrate, Command::Bitrate.to_str()));
+        commands_list
+            .items
+            .push((Command::Mixing, Command::Mixing.to_str()));
 
         // Populate Settings categories
         let mut settings_categories = StatefulList::default();
@@ -286,6 +354,10 @@ impl<'a> App<'a> {
             text_channels: StatefulList::default(),
             voice_channels: StatefulList::default(),
             is_voice_connected: false,
+            voice_states: HashMap::new(),
+            speaking: HashMap::new(),
+            input_level: None,
+            recorders: HashSet::new(),
             current_realm_id: None,
             current_text_channel: None,
             current_voice_channel: None,
@@ -319,7 +391,30 @@ impl<'a> App<'a> {
             _not_used: &false,
             current_settings_category: SettingsCategory::Audio,
             settings_category_list: settings_categories,
+            audio_inputs: StatefulList::default(),
+            audio_outputs: StatefulList::default(),
+            voice_settings: StatefulList::with_items(vec![
+                VoiceSetting::InputVolume,
+                VoiceSetting::OutputVolume,
+                VoiceSetting::CaptureMode,
+                VoiceSetting::VoiceThreshold,
+                VoiceSetting::HangTime,
+                VoiceSetting::Dtx,
+                VoiceSetting::NoiseSuppression,
+                VoiceSetting::EchoCancellation,
+                VoiceSetting::AutoGain,
+                VoiceSetting::Bitrate,
+                VoiceSetting::Complexity,
+                VoiceSetting::Stereo,
+                VoiceSetting::Application,
+            ]),
+            audio_settings_focus: None,
             ping_latency: None,
+            shutdown_reason: None,
+            planned_restart: None,
+            reconnect_at: None,
+            reconnect_attempts: 0,
+            is_reconnecting: false,
         }
     }
 
@@ -348,9 +443,25 @@ impl<'a> App<'a> {
             // Update any new messages received by the Client
             for message in self.client.get_new_messages() {
                 match message.message {
-                    MessageType::ServerShutdown => {
-                        self.quit();
+                    MessageType::ServerShutdown((reason, restart_eta)) => {
+                        info!(%reason, ?restart_eta, "server is shutting down");
+
+                        let text = match restart_eta {
+                            Some(eta) => format!(
+                                "{}\n\nThe server is restarting. Reconnecting in {}s",
+                                reason,
+                                eta.as_secs()
+                            ),
+                            None => reason.clone(),
+                        };
+                        self.general_popup
+                            .setup(Some(String::from("Server Shutting Down")), Some(text));
+                        self.show_popup(PopupType::General);
+
+                        self.shutdown_reason = Some(reason);
+                        self.planned_restart = restart_eta.map(|eta| Instant::now() + eta);
                     }
+                    MessageType::ConnectionLost => self.connection_lost(),
                     MessageType::LoginSuccess(user) => {
                         // Save who we are
                         self.user_id_to_username
@@ -391,6 +502,8 @@ impl<'a> App<'a> {
                         // Remove this user from any voice channels
                         self.realms_manager
                             .remove_user_from_voice_channel_global(user_id);
+                        self.voice_states.remove(&user_id);
+                        self.recorders.remove(&user_id);
                         for channel in &mut self.voice_channels.items {
                             channel.2.retain(|u| *u != user_id);
                         }
@@ -456,6 +569,34 @@ impl<'a> App<'a> {
                             }
                         }
                     }
+                    MessageType::VoiceStateUpdate((header, state)) => {
+                        match state == VoiceState::default() {
+                            true => self.voice_states.remove(&header.user_id),
+                            false => self.voice_states.insert(header.user_id, state),
+                        };
+                    }
+                    MessageType::VoiceRecording((header, recording)) => match recording {
+                        true => {
+                            let is_us = self
+                                .user
+                                .as_ref()
+                                .is_some_and(|user| user.get_id() == header.user_id);
+
+                            // Make sure everyone knows they're being recorded
+                            if self.recorders.insert(header.user_id) && !is_us {
+                                let text = format!(
+                                    "{} is recording this voice channel",
+                                    self.get_username_from_id(header.user_id)
+                                );
+                                self.general_popup
+                                    .setup(Some(String::from("Recording")), Some(text));
+                                self.show_popup(PopupType::General);
+                            }
+                        }
+                        false => {
+                            self.recorders.remove(&header.user_id);
+                        }
+                    },
                     MessageType::AllUsers(users) => {
                         if let Some(our_user) = &self.user {
                             for user in users {
@@ -616,65 +757,47 @@ impl<'a> App<'a> {
                         //     }
                         // }
                     }
-                    MessageType::Realms(realms) => {
-                        //First clear our realms manager
-                        self.realms_manager.clear();
-
-                        // Clear our current list of realms and channels
-                        self.realms = StatefulList::default();
-
-                        for realm in &realms {
-                            // Save realm id and names
-                            self.realms.items.push((realm.id, realm.name.clone()));
-
-                            // Save text channel id and names
-                            self.text_channels = StatefulList::default();
-                            let text_channels = realm.get_text_channels();
-                            for (id, mut name) in text_channels {
-                                let hashtag = String::from("# ");
-                                name.insert_str(0, &hashtag);
-                                self.text_channels.items.push((id, name.clone()));
-                            }
+                    MessageType::Realms((_, realms)) => {
+                        // Replace what we know with the server's realms, keeping loaded history
+                        self.realms_manager.sync_realms(realms);
 
-                            // Save voice channel id and names
-                            self.voice_channels = StatefulList::default();
-                            let voice_channels = realm.get_voice_channels();
-                            for (id, name) in voice_channels {
-                                self.voice_channels.items.push((id, name, Vec::new()));
-                            }
+                        // The server follows this with everyone who is muted or deafened
+                        self.voice_states.clear();
+                        self.refresh_realms_list();
 
-                            // Auto-join the first available text channel if one wasn't already joined
-                            if self.current_text_channel.is_none()
-                                && !self.text_channels.items.is_empty()
-                            {
-                                self.join_channel(
-                                    realm.id,
-                                    ChannelType::TextChannel,
-                                    self.text_channels.items[0].0,
-                                );
+                        match self.current_realm_id {
+                            // Stay in the realm we were in if it still exists
+                            Some(realm_id) if self.realms_manager.get_realm(realm_id).is_some() => {
+                                self.refresh_realm(realm_id);
+                            }
+                            // For now, let's initally join the first text channel of the first realm
+                            _ => {
+                                if !self.realms.items.is_empty() {
+                                    self.enter_realm(self.realms.items[0].0);
+                                }
                             }
                         }
                     }
-                    MessageType::RealmsManager(rm) => {
-                        // First clear everything that we know
-                        self.realms.items.clear();
-                        self.text_channels.items.clear();
-                        self.voice_channels.items.clear();
-
-                        // Now move this new RealmsManager into our app
-                        self.realms_manager = rm;
-
-                        // Now that we have all realms and channels,
-                        // let's update references to them to be displayed
-                        for realm in self.realms_manager.get_realms() {
-                            // Update our Realms list
-                            self.realms.items.push((*realm.0, realm.1.clone()));
-                        }
-
-                        // For now, let's initally join the first text channel of the first realm
-                        if !self.realms.items.is_empty() {
-                            self.current_realm_id = Some(self.realms.items[0].0);
-                            self.enter_realm(self.current_realm_id.unwrap());
+                    MessageType::ChannelHistory((realm_id, channel_id, history)) => {
+                        if let Some(realm) = self.realms_manager.get_realm_mut(realm_id) {
+                            if let Some(channel) = realm.get_text_channel_mut(channel_id) {
+                                channel.merge_history(history);
+
+                                // Redraw the chat if we're looking at this channel
+                                if self.current_realm_id == Some(realm_id)
+                                    && self
+                                        .current_text_channel
+                                        .as_ref()
+                                        .is_some_and(|c| c.0 == channel_id)
+                                {
+                                    self.chat_history.items =
+                                        channel.chat_history.iter().map(|m| m.message_id).collect();
+
+                                    if self.input_mode != InputMode::Chat {
+                                        self.chat_history.select_last();
+                                    }
+                                }
+                            }
                         }
                     }
                     MessageType::RealmAdded(ra) => {
@@ -787,9 +910,52 @@ impl<'a> App<'a> {
                             }
                         }
                     }
+                    MessageType::Announcement(announcement) => {
+                        self.general_popup
+                            .setup(Some(String::from("Announcement")), Some(announcement));
+                        self.show_popup(PopupType::General);
+                    }
+                    MessageType::Motd(motd) => {
+                        self.general_popup
+                            .setup(Some(String::from("Message of the Day")), Some(motd));
+                        self.show_popup(PopupType::General);
+                    }
+                    MessageType::SlowModeChanged((realm_id, channel_id, slow_mode_secs)) => {
+                        self.realms_manager
+                            .set_slow_mode(realm_id, channel_id, slow_mode_secs);
+                    }
+                    MessageType::VoiceBitrateChanged((realm_id, channel_id, max_bitrate)) => {
+                        self.realms_manager
+                            .set_voice_bitrate(realm_id, channel_id, max_bitrate);
+                    }
+                    MessageType::VoiceMixingChanged((realm_id, channel_id, mixing)) => {
+                        self.realms_manager
+                            .set_voice_mixing(realm_id, channel_id, mixing);
+                    }
+                    MessageType::RateLimited(retry_after) => {
+                        self.general_popup.setup(
+                            Some(String::from("Slow Down")),
+                            Some(format!(
+                                "You're doing that too often. Try again in {:.1}s",
+                                retry_after.as_secs_f32()
+                            )),
+                        );
+                        self.show_popup(PopupType::General);
+                    }
                     MessageType::PingLatency(duration) => {
                         self.ping_latency = Some(duration);
                     }
+                    MessageType::CertificateChanged((host, pinned, presented)) => {
+                        self.general_popup.setup(
+                            Some(String::from("WARNING: SERVER CERTIFICATE CHANGED")),
+                            Some(format!(
+                                "The certificate presented by {} does not match the one pinned on first use. \
+                                 Someone may be intercepting this connection.\n\nPinned:    {}\nPresented: {}",
+                                host, pinned, presented
+                            )),
+                        );
+                        self.show_popup(PopupType::General);
+                    }
                     MessageType::Disconnect => {
                         self.quit();
                     }
@@ -797,6 +963,10 @@ impl<'a> App<'a> {
                 };
             }
 
+            self.check_reconnect();
+            self.client.check_audio_devices();
+            self.update_audio_levels();
+
             // Render the user interface
             tui.draw(self)?;
 
@@ -843,6 +1013,9 @@ impl<'a> App<'a> {
                             if let Some(channel) = realm.get_text_channel_mut(channel_id) {
                                 channel.pending_mention = false;
 
+                                // Channel history is only sent when asked for
+                                self.client.get_channel_history(realm_id, channel_id);
+
                                 self.chat_history.items.clear();
                                 self.chat_history.unselect();
 
@@ -995,7 +1168,12 @@ impl<'a> App<'a> {
 
     pub fn hang_up(&mut self) {
         if let Some(channel) = self.current_voice_channel {
+            let recording = self.client.stop_call_recording();
             self.client.hang_up(self.current_realm_id.unwrap(), channel);
+            self.recorders.clear();
+            if let Some(path) = recording {
+                self.show_recording_saved(path);
+            }
             // todo: the current_realm_id may not be correct if the user goes to a new realm
 
             if self.is_voice_connected {
@@ -1009,13 +1187,65 @@ impl<'a> App<'a> {
         }
     }
 
+    /// Start recording the voice channel we're in, or stop and save the recording
+    pub fn toggle_call_recording(&mut self) {
+        if self.client.is_call_recording() {
+            if let Some(path) = self.client.stop_call_recording() {
+                self.show_recording_saved(path);
+            }
+            return;
+        }
+
+        let text = match self.is_voice_connected {
+            true => match self.client.start_call_recording() {
+                Some(_) => return,
+                None => "Couldn't start recording, the log says why",
+            },
+            false => "Join a voice channel to record it",
+        };
+        self.general_popup
+            .setup(Some(String::from("Recording")), Some(String::from(text)));
+        self.show_popup(PopupType::General);
+    }
+
+    fn show_recording_saved(&mut self, path: PathBuf) {
+        self.general_popup.setup(
+            Some(String::from("Recording Saved")),
+            Some(format!("Saved to {}", path.display())),
+        );
+        self.show_popup(PopupType::General);
+    }
+
+    pub fn toggle_mute(&mut self) {
+        let muted = self.client.is_muted();
+        self.client.set_muted(!muted);
+    }
+
+    pub fn toggle_deafen(&mut self) {
+        let deafened = self.client.is_deafened();
+        self.client.set_deafened(!deafened);
+    }
+
     pub fn handle_input(&mut self) {
+        if let Some(command) = self.current_command {
+            debug!(?command, "running command");
+        }
+
         // First check to see if this is a command message
         match self.current_command {
             Some(command) => match command {
                 Command::Image => {
                     self.send_image();
                 }
+                Command::SlowMode => {
+                    self.set_slow_mode();
+                }
+                Command::Bitrate => {
+                    self.set_voice_bitrate();
+                }
+                Command::Mixing => {
+                    self.set_voice_mixing();
+                }
             },
             None => {
                 if self.reply_target_message_id.is_some() {
@@ -1038,6 +1268,84 @@ impl<'a> App<'a> {
         self.current_command = None;
     }
 
+    pub fn set_voice_bitrate(&mut self) {
+        // Expecting the limit in kbps for the voice channel we're in, 0 to remove it
+        let kbps = self
+            .input_buffer
+            .input
+            .last()
+            .and_then(|input| input.0.trim().parse::<u32>().ok());
+
+        match (kbps, self.current_realm_id, self.current_voice_channel) {
+            (Some(kbps), Some(realm_id), Some(channel_id)) if self.is_voice_connected => self
+                .client
+                .set_voice_bitrate(realm_id, channel_id, kbps * 1000),
+            _ => {
+                self.general_popup.setup(
+                    Some(String::from("Voice Bitrate")),
+                    Some(String::from(
+                        "While in a voice channel: /bitrate <kbps>, or /bitrate 0 to remove the limit",
+                    )),
+                );
+                self.show_popup(PopupType::General);
+            }
+        }
+    }
+
+    pub fn set_voice_mixing(&mut self) {
+        // Expecting on or off for the voice channel we're in
+        let mixing = self
+            .input_buffer
+            .input
+            .last()
+            .and_then(|input| match input.0.trim() {
+                "on" => Some(true),
+                "off" => Some(false),
+                _ => None,
+            });
+
+        match (mixing, self.current_realm_id, self.current_voice_channel) {
+            (Some(mixing), Some(realm_id), Some(channel_id)) if self.is_voice_connected => {
+                self.client.set_voice_mixing(realm_id, channel_id, mixing)
+            }
+            _ => {
+                self.general_popup.setup(
+                    Some(String::from("Voice Mixing")),
+                    Some(String::from(
+                        "While in a voice channel: /mixing on to have the server mix everyone into one stream, or /mixing off",
+                    )),
+                );
+                self.show_popup(PopupType::General);
+            }
+        }
+    }
+
+    pub fn set_slow_mode(&mut self) {
+        // Expecting the number of seconds between messages, 0 to turn slow mode off
+        let seconds = self
+            .input_buffer
+            .input
+            .last()
+            .and_then(|input| input.0.trim().parse::<u32>().ok());
+
+        match seconds {
+            Some(seconds) => self.client.set_slow_mode(
+                self.current_realm_id.unwrap(),
+                self.current_text_channel.as_ref().unwrap().0,
+                seconds,
+            ),
+            None => {
+                self.general_popup.setup(
+                    Some(String::from("Slow Mode")),
+                    Some(String::from(
+                        "Usage: /slowmode <seconds>, or /slowmode 0 to turn it off",
+                    )),
+                );
+                self.show_popup(PopupType::General);
+            }
+        }
+    }
+
     pub fn send_image(&mut self) {
         // First check to see if the image exists
         if let Some(input) = self.input_buffer.input.last() {
@@ -1147,6 +1455,13 @@ impl<'a> App<'a> {
         // or if we have any pending requests for them
         self.member_popup.is_friend = self.friends.contains(&user_id);
         self.member_popup.is_request_pending = self.pending_friend_requests.contains(&user_id);
+        self.member_popup.volume = self.client.get_user_volume(user_id);
+        self.member_popup.is_volume_available = !self.is_voice_mixed();
+        self.member_popup.is_server_muted = self
+            .voice_states
+            .get(&user_id)
+            .map(|state| state.server_muted)
+            .unwrap_or(false);
 
         // Now show the popup
         self.show_popup(PopupType::Member);
@@ -1243,6 +1558,123 @@ impl<'a> App<'a> {
         self.client.log_in();
     }
 
+    /// Reconnect if the server is restarting or we were already trying to, otherwise quit
+    fn connection_lost(&mut self) {
+        let reconnect_at = match (self.planned_restart.take(), self.is_reconnecting) {
+            (Some(restart_at), _) => restart_at,
+            (None, true) => Instant::now() + RECONNECT_INTERVAL,
+            (None, false) => {
+                info!("lost connection to the server, exiting");
+                if self.shutdown_reason.is_none() {
+                    self.shutdown_reason = Some(String::from("Lost connection to the server"));
+                }
+                self.quit();
+                return;
+            }
+        };
+
+        if self.reconnect_attempts >= MAX_RECONNECT_ATTEMPTS {
+            info!("server didn't come back after a restart, exiting");
+            self.shutdown_reason = Some(format!(
+                "Failed to reconnect after {} attempts",
+                self.reconnect_attempts
+            ));
+            self.quit();
+            return;
+        }
+
+        // Everything is sent again after logging back in
+        self.hang_up();
+        self.user = None;
+        self.users_online.items.clear();
+        self.user_id_to_username.clear();
+
+        self.is_reconnecting = false;
+        self.reconnect_at = Some(reconnect_at);
+    }
+
+    /// Pick up who's speaking and how loud our microphone is from the audio threads
+    fn update_audio_levels(&mut self) {
+        // The input meter needs the microphone open even outside a call
+        let monitoring = matches!(self.current_screen, Screen::Settings)
+            && matches!(self.current_settings_category, SettingsCategory::Audio);
+        self.client.set_input_monitoring(monitoring);
+
+        let now = Instant::now();
+        for level in self.client.get_audio_levels() {
+            match level {
+                AudioLevel::Input { level, sending } => {
+                    self.input_level = Some((level, now));
+
+                    // Only shown as speaking when others can actually hear us
+                    if let Some(user) = &self.user {
+                        if sending && levels::is_speaking(level) {
+                            self.speaking.insert(user.get_id(), now);
+                        }
+                    }
+                }
+                AudioLevel::Speakers(speakers) => {
+                    for (user_id, level) in speakers {
+                        if levels::is_speaking(level) {
+                            self.speaking.insert(user_id, now);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.speaking
+            .retain(|_, heard| now.duration_since(*heard) < SPEAKING_HOLD);
+        if let Some((_, measured)) = self.input_level {
+            if now.duration_since(measured) >= SPEAKING_HOLD {
+                self.input_level = None;
+            }
+        }
+    }
+
+    pub fn is_speaking(&self, user_id: UserIdSize) -> bool {
+        self.speaking.contains_key(&user_id)
+    }
+
+    /// Whether the voice channel we're in is mixed by the server. Everyone arrives as one
+    /// stream then, so who's speaking can't be shown and nobody can be turned up or down.
+    pub fn is_voice_mixed(&self) -> bool {
+        match (self.current_realm_id, self.current_voice_channel) {
+            (Some(realm_id), Some(channel_id)) if self.is_voice_connected => self
+                .realms_manager
+                .get_realm(realm_id)
+                .and_then(|realm| realm.get_voice_channel(channel_id))
+                .is_some_and(|voice_channel| voice_channel.mixing),
+            _ => false,
+        }
+    }
+
+    /// Start a scheduled reconnect and log in once it connects
+    fn check_reconnect(&mut self) {
+        if let Some(reconnect_at) = self.reconnect_at {
+            if Instant::now() >= reconnect_at {
+                self.reconnect_at = None;
+                self.reconnect_attempts += 1;
+                self.is_reconnecting = true;
+                self.client.reconnect();
+            }
+        }
+
+        if self.is_reconnecting && self.client.is_connected() {
+            info!("reconnected to the server");
+            self.is_reconnecting = false;
+            self.reconnect_attempts = 0;
+            self.shutdown_reason = None;
+            self.log_in();
+
+            self.general_popup.setup(
+                Some(String::from("Reconnected")),
+                Some(String::from("The server is back")),
+            );
+            self.show_popup(PopupType::General);
+        }
+    }
+
     pub fn request_realms(&self) {
         self.client.get_realms();
     }
@@ -1,16 +1,37 @@
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Command {
     Image,
+    SlowMode,
+    Bitrate,
+    Mixing,
 }
 
 impl Command {
     pub fn to_str(&self) -> String {
         match self {
             Command::Image => String::from("image"),
+            Command::SlowMode => String::from("slowmode"),
+            Command::Bitrate => String::from("bitrate"),
+            Command::Mixing => String::from("mixing"),
+        }
+    }
+
+    /// Gray text shown after the command to tell the user what to enter
+    pub fn hint(&self) -> String {
+        match self {
+            Command::Image => String::from(" file path: "),
+            Command::SlowMode => String::from(" seconds: "),
+            Command::Bitrate => String::from(" kbps: "),
+            Command::Mixing => String::from(" on or off: "),
         }
     }
 
     pub fn get_commands() -> Vec<Command> {
-        vec![Command::Image]
+        vec![
+            Command::Image,
+            Command::SlowMode,
+            Command::Bitrate,
+            Command::Mixing,
+        ]
     }
 }
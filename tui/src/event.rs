@@ -4,6 +4,7 @@ use crossterm::event::{self, Event as CrosstermEvent, KeyEvent, MouseEvent};
 use std::sync::mpsc;
 use std::thread;
 use std::time::{Duration, Instant};
+use tracing::warn;
 
 /// Terminal events.
 #[derive(Clone, Copy, Debug)]
@@ -64,7 +65,7 @@ impl EventHandler {
                         match sender.send(Event::Tick) {
                             Ok(_) => (),
                             Err(e) => {
-                                eprintln!("[event.rs] failed to send something: {}", e);
+                                warn!("failed to send tick event: {}", e);
                                 break;
                             }
                         }
@@ -2,6 +2,10 @@ use crate::app::{App, AppResult};
 use crate::app::{PopupType, Screen};
 use crate::handlers;
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::time::Duration;
+
+// Long enough to bridge the pause before a held key starts repeating
+const PUSH_TO_TALK_HOLD: Duration = Duration::from_millis(700);
 
 pub fn handle_key_events(key_event: KeyEvent, app: &mut App<'_>) -> AppResult<()> {
     match key_event.code {
@@ -18,6 +22,31 @@ pub fn handle_key_events(key_event: KeyEvent, app: &mut App<'_>) -> AppResult<()
                 return Ok(());
             }
         }
+        KeyCode::Char('u') | KeyCode::Char('U') => {
+            if key_event.modifiers == KeyModifiers::CONTROL {
+                app.toggle_mute();
+                return Ok(());
+            }
+        }
+        KeyCode::Char('e') | KeyCode::Char('E') => {
+            if key_event.modifiers == KeyModifiers::CONTROL {
+                app.toggle_deafen();
+                return Ok(());
+            }
+        }
+        KeyCode::Char('o') | KeyCode::Char('O') => {
+            if key_event.modifiers == KeyModifiers::CONTROL {
+                app.toggle_call_recording();
+                return Ok(());
+            }
+        }
+        KeyCode::Char('t') | KeyCode::Char('T') => {
+            if key_event.modifiers == KeyModifiers::CONTROL {
+                // Key repeats keep this open while the key is held
+                app.client.push_to_talk(PUSH_TO_TALK_HOLD);
+                return Ok(());
+            }
+        }
         _ => (),
     }
 
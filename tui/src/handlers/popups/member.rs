@@ -1,5 +1,6 @@
 use crate::{
     app::{App, AppResult, InputMode, Pane},
+    handlers::screens::settings_view::audio_settings::step_volume,
     popups::member_popup::{
         MemberPopupActionsUiElements, MemberPopupInputMode, MemberPopupUi, MemberPopupUiElement,
     },
@@ -66,6 +67,14 @@ pub fn handle_key_events(key_event: KeyEvent, app: &mut App<'_>) -> AppResult<()
                         app.member_popup.current_actions_ui_element =
                             MemberPopupActionsUiElements::Message
                     }
+                    MemberPopupActionsUiElements::Volume => {
+                        app.member_popup.current_actions_ui_element =
+                            MemberPopupActionsUiElements::Call
+                    }
+                    MemberPopupActionsUiElements::ServerMute => {
+                        app.member_popup.current_actions_ui_element =
+                            MemberPopupActionsUiElements::Volume
+                    }
                     _ => (), // Ignore others for now until those messages are supported
                 },
                 KeyCode::Down => match app.member_popup.current_actions_ui_element {
@@ -77,9 +86,32 @@ pub fn handle_key_events(key_event: KeyEvent, app: &mut App<'_>) -> AppResult<()
                         app.member_popup.current_actions_ui_element =
                             MemberPopupActionsUiElements::Call
                     }
-                    MemberPopupActionsUiElements::Call => (),
+                    MemberPopupActionsUiElements::Call => {
+                        app.member_popup.current_actions_ui_element =
+                            MemberPopupActionsUiElements::Volume
+                    }
+                    MemberPopupActionsUiElements::Volume => {
+                        app.member_popup.current_actions_ui_element =
+                            MemberPopupActionsUiElements::ServerMute
+                    }
+                    MemberPopupActionsUiElements::ServerMute => (),
                     _ => (), // Ignore others for now until those messages are supported
                 },
+                KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Char('-') => {
+                    // Voices in a mixed channel can't be turned up or down on their own
+                    if matches!(
+                        app.member_popup.current_actions_ui_element,
+                        MemberPopupActionsUiElements::Volume
+                    ) && app.member_popup.is_volume_available
+                    {
+                        // Remembered for next time this user is around
+                        let user_id = app.member_popup.user_id;
+                        let up = key_event.code != KeyCode::Char('-');
+                        app.client
+                            .set_user_volume(user_id, step_volume(app.member_popup.volume, up));
+                        app.member_popup.volume = app.client.get_user_volume(user_id);
+                    }
+                }
                 KeyCode::Enter => match app.member_popup.current_actions_ui_element {
                     MemberPopupActionsUiElements::AddRemoveFriend => {
                         // Add the friend only if there isn't a request pending or we aren't friends
@@ -101,6 +133,16 @@ pub fn handle_key_events(key_event: KeyEvent, app: &mut App<'_>) -> AppResult<()
                     }
                     MemberPopupActionsUiElements::Message => (),
                     MemberPopupActionsUiElements::Call => (),
+                    MemberPopupActionsUiElements::ServerMute => {
+                        app.client.server_mute(
+                            app.member_popup.user_id,
+                            !app.member_popup.is_server_muted,
+                        );
+                        app.dismiss_popup();
+                        // Set the current pane to be the Members pane
+                        app.current_pane = Pane::MembersPane;
+                        app.input_mode = InputMode::Members;
+                    }
                     _ => (), // Ignore others for now until those messages are supported
                 },
                 _ => (),
@@ -476,16 +476,16 @@ pub fn handle_key_events(key_event: KeyEvent, app: &mut App<'_>) -> AppResult<()
                         input.0.pop();
                     }
 
-                    // Insert /image in blue text
+                    // Insert the command in blue text
                     app.input_buffer.input.push((
                         command.to_str().prepend_str("/"),
                         Style::default().fg(Color::LightBlue),
                         None,
                     ));
 
-                    // Insert gray text with "file path" to tell the user what to enter
+                    // Insert gray text to tell the user what to enter
                     app.input_buffer.input.push((
-                        String::from(" file path: "),
+                        command.hint(),
                         Style::default().fg(Color::Gray),
                         None,
                     ));
@@ -1,7 +1,13 @@
-use crate::app::{App, AppResult};
+use super::settings_view;
+use crate::app::{App, AppResult, AudioSettingsFocus, SettingsCategory};
 use crossterm::event::{KeyCode, KeyEvent};
 
 pub fn handle_key_events(key_event: KeyEvent, app: &mut App<'_>) -> AppResult<()> {
+    // A settings view with focus gets the keys first
+    if app.audio_settings_focus.is_some() {
+        return settings_view::audio_settings::handle_key_events(key_event, app);
+    }
+
     match key_event.code {
         KeyCode::Down => app.settings_category_list.next(),
         KeyCode::Up => app.settings_category_list.previous(),
@@ -10,6 +16,16 @@ pub fn handle_key_events(key_event: KeyEvent, app: &mut App<'_>) -> AppResult<()
             app.current_settings_category =
                 app.settings_category_list.items[selected_category_index];
         }
+        KeyCode::Right => {
+            if let SettingsCategory::Audio = app.current_settings_category {
+                settings_view::audio_settings::focus(app, AudioSettingsFocus::Inputs);
+
+                // Without any devices there's only voice settings to change
+                if app.audio_settings_focus.is_none() {
+                    settings_view::audio_settings::focus(app, AudioSettingsFocus::Voice);
+                }
+            }
+        }
         _ => (),
     }
     Ok(())
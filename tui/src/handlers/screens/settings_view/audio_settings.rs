@@ -1,80 +1,209 @@
-use crate::{
-    app::{App, AppResult, InputMode, Pane},
-    popups::add_channel_popup::{AddChannelInputMode, AddChannelUiElement},
-};
+use std::time::Duration;
+
+use crate::app::{App, AppResult, AudioSettingsFocus, VoiceSetting};
+use crate::stateful_list::StatefulList;
+use audio::encoding::{MAX_BITRATE, MIN_BITRATE};
 use crossterm::event::{KeyCode, KeyEvent};
-use realms::realm::ChannelType;
 
-pub fn handle_key_events(key_event: KeyEvent, app: &mut App<'_>) -> AppResult<()> {
-    match app.add_channel_popup.input_mode {
-        AddChannelInputMode::Normal => match key_event.code {
-            KeyCode::Char('q') | KeyCode::Char('Q') => {
-                app.dismiss_popup();
-                app.input_mode = InputMode::ChannelType;
-                app.current_pane = Pane::ChannelsPane;
+// How far one key press moves the volumes, voice threshold, hang time and bitrate
+const VOLUME_STEP: f32 = 0.1;
+const THRESHOLD_STEP: f32 = 5.0;
+const HANG_TIME_STEP: Duration = Duration::from_millis(100);
+const MAX_HANG_TIME: Duration = Duration::from_secs(2);
+const BITRATE_STEP: u32 = 8_000;
+
+/// Give a list focus, starting on the device in use
+pub fn focus(app: &mut App<'_>, list: AudioSettingsFocus) {
+    app.audio_inputs.items = app.client.get_audio_inputs();
+    app.audio_outputs.items = app.client.get_audio_outputs();
+
+    let (devices, selected) = match list {
+        AudioSettingsFocus::Inputs => {
+            (&mut app.audio_inputs, app.client.get_selected_audio_input())
+        }
+        AudioSettingsFocus::Outputs => (
+            &mut app.audio_outputs,
+            app.client.get_selected_audio_output(),
+        ),
+        AudioSettingsFocus::Voice => {
+            app.voice_settings.state.select(Some(0));
+            app.audio_settings_focus = Some(list);
+            return;
+        }
+    };
+
+    // Nothing to pick from
+    if devices.items.is_empty() {
+        return;
+    }
+
+    let index = selected
+        .and_then(|selected| devices.items.iter().position(|device| *device == selected))
+        .unwrap_or(0);
+    devices.state.select(Some(index));
+    app.audio_settings_focus = Some(list);
+}
+
+fn unfocus(app: &mut App<'_>) {
+    app.audio_inputs.unselect();
+    app.audio_outputs.unselect();
+    app.voice_settings.unselect();
+    app.audio_settings_focus = None;
+}
+
+fn focused_list<'b>(app: &'b mut App<'_>) -> Option<&'b mut StatefulList<String>> {
+    match app.audio_settings_focus {
+        Some(AudioSettingsFocus::Inputs) => Some(&mut app.audio_inputs),
+        Some(AudioSettingsFocus::Outputs) => Some(&mut app.audio_outputs),
+        _ => None,
+    }
+}
+
+/// Turn a volume up or down a step, landing on whole steps so it reads cleanly as a percentage
+pub fn step_volume(volume: f32, up: bool) -> f32 {
+    let step = if up { VOLUME_STEP } else { -VOLUME_STEP };
+    ((volume + step) / VOLUME_STEP).round() * VOLUME_STEP
+}
+
+/// Step the highlighted voice setting up or down
+fn change_voice_setting(app: &mut App<'_>, up: bool) {
+    let setting = match app.voice_settings.state.selected() {
+        Some(index) => app.voice_settings.items[index],
+        None => return,
+    };
+
+    let volumes = app.client.get_volumes();
+    let mut capture = app.client.get_capture_settings();
+    let mut processing = app.client.get_processing_settings();
+    let mut encoder = app.client.get_encoder_settings();
+    match setting {
+        VoiceSetting::InputVolume => {
+            return app
+                .client
+                .set_input_gain(step_volume(volumes.input_gain, up))
+        }
+        VoiceSetting::OutputVolume => {
+            return app
+                .client
+                .set_output_gain(step_volume(volumes.output_gain, up))
+        }
+        VoiceSetting::CaptureMode => {
+            capture.mode = match up {
+                true => capture.mode.next(),
+                false => capture.mode.next().next(),
             }
-            KeyCode::Char(' ') => match app.add_channel_popup.current_ui_element {
-                AddChannelUiElement::TextOption => {
-                    if !app.add_channel_popup.is_text_channel {
-                        app.add_channel_popup.is_text_channel = true;
-                    }
-                }
-                AddChannelUiElement::VoiceOption => {
-                    if app.add_channel_popup.is_text_channel {
-                        app.add_channel_popup.is_text_channel = false;
-                    }
-                }
-                _ => (),
-            },
-            KeyCode::Up => match app.add_channel_popup.current_ui_element {
-                AddChannelUiElement::TextOption => (),
-                AddChannelUiElement::VoiceOption => {
-                    app.add_channel_popup.current_ui_element = AddChannelUiElement::TextOption;
-                }
-                AddChannelUiElement::ChannelName => {
-                    app.add_channel_popup.current_ui_element = AddChannelUiElement::VoiceOption;
-                }
-            },
-            KeyCode::Down => match app.add_channel_popup.current_ui_element {
-                AddChannelUiElement::TextOption => {
-                    app.add_channel_popup.current_ui_element = AddChannelUiElement::VoiceOption;
-                }
-                AddChannelUiElement::VoiceOption => {
-                    app.add_channel_popup.current_ui_element = AddChannelUiElement::ChannelName;
-                }
-                AddChannelUiElement::ChannelName => (),
-            },
-            KeyCode::Enter => {
-                if let AddChannelUiElement::ChannelName = app.add_channel_popup.current_ui_element {
-                    app.add_channel_popup.input_mode = AddChannelInputMode::Editing;
-                }
+        }
+        VoiceSetting::VoiceThreshold => {
+            let step = if up { THRESHOLD_STEP } else { -THRESHOLD_STEP };
+            capture.voice_threshold = (capture.voice_threshold + step).clamp(-90.0, 0.0);
+        }
+        VoiceSetting::HangTime => {
+            capture.hang_time = match up {
+                true => (capture.hang_time + HANG_TIME_STEP).min(MAX_HANG_TIME),
+                false => capture.hang_time.saturating_sub(HANG_TIME_STEP),
             }
-            _ => (),
+        }
+        VoiceSetting::Dtx => capture.dtx = !capture.dtx,
+        VoiceSetting::NoiseSuppression => {
+            processing.noise_suppression = !processing.noise_suppression;
+            return app.client.set_processing_settings(processing);
+        }
+        VoiceSetting::EchoCancellation => {
+            processing.echo_cancellation = !processing.echo_cancellation;
+            return app.client.set_processing_settings(processing);
+        }
+        VoiceSetting::AutoGain => {
+            processing.auto_gain = !processing.auto_gain;
+            return app.client.set_processing_settings(processing);
+        }
+        VoiceSetting::Bitrate => {
+            encoder.bitrate = match up {
+                true => (encoder.bitrate + BITRATE_STEP).min(MAX_BITRATE),
+                false => encoder
+                    .bitrate
+                    .saturating_sub(BITRATE_STEP)
+                    .max(MIN_BITRATE),
+            };
+            return app.client.set_encoder_settings(encoder);
+        }
+        VoiceSetting::Complexity => {
+            encoder.complexity = match up {
+                true => (encoder.complexity + 1).min(10),
+                false => encoder.complexity.saturating_sub(1),
+            };
+            return app.client.set_encoder_settings(encoder);
+        }
+        VoiceSetting::Stereo => {
+            encoder.stereo = !encoder.stereo;
+            return app.client.set_encoder_settings(encoder);
+        }
+        VoiceSetting::Application => {
+            encoder.application = encoder.application.next();
+            return app.client.set_encoder_settings(encoder);
+        }
+    }
+
+    // Takes effect straight away and is remembered for next time
+    app.client.set_capture_settings(capture);
+}
+
+pub fn handle_key_events(key_event: KeyEvent, app: &mut App<'_>) -> AppResult<()> {
+    match key_event.code {
+        KeyCode::Left | KeyCode::Esc => unfocus(app),
+        KeyCode::Down => match focused_list(app) {
+            Some(list) => list.next(),
+            None => app.voice_settings.next(),
+        },
+        KeyCode::Up => match focused_list(app) {
+            Some(list) => list.previous(),
+            None => app.voice_settings.previous(),
         },
-        AddChannelInputMode::Editing => match key_event.code {
-            KeyCode::Char(c) => app.add_channel_popup.channel_name_buffer.push(c),
-            KeyCode::Esc => app.add_channel_popup.input_mode = AddChannelInputMode::Normal,
-            KeyCode::Backspace => {
-                app.add_channel_popup.channel_name_buffer.pop();
+        KeyCode::Tab => {
+            let next = match app.audio_settings_focus {
+                Some(AudioSettingsFocus::Inputs) => AudioSettingsFocus::Outputs,
+                Some(AudioSettingsFocus::Outputs) => AudioSettingsFocus::Voice,
+                _ => AudioSettingsFocus::Inputs,
+            };
+            unfocus(app);
+            focus(app, next);
+            if app.audio_settings_focus.is_none() {
+                focus(app, AudioSettingsFocus::Voice);
             }
-            KeyCode::Enter => {
-                app.add_channel(
-                    match app.add_channel_popup.is_text_channel {
-                        true => ChannelType::TextChannel,
-                        false => ChannelType::VoiceChannel,
-                    },
-                    app.add_channel_popup.channel_name_buffer.clone(),
-                );
-
-                app.dismiss_popup();
-                app.text_channels.unselect();
-                app.voice_channels.unselect();
-                app.input_mode = InputMode::Normal;
-                app.current_pane = Pane::ChannelsPane;
+        }
+        KeyCode::Char('+') | KeyCode::Char('=') => {
+            if app.audio_settings_focus == Some(AudioSettingsFocus::Voice) {
+                change_voice_setting(app, true);
+            }
+        }
+        KeyCode::Char('-') => {
+            if app.audio_settings_focus == Some(AudioSettingsFocus::Voice) {
+                change_voice_setting(app, false);
+            }
+        }
+        KeyCode::Enter => {
+            if app.audio_settings_focus == Some(AudioSettingsFocus::Voice) {
+                change_voice_setting(app, true);
+                return Ok(());
             }
-            _ => (),
-        },
-    };
 
+            let device = focused_list(app).and_then(|list| {
+                list.state
+                    .selected()
+                    .and_then(|index| list.items.get(index).cloned())
+            });
+
+            // Takes effect straight away if we're in a call, and is remembered for next time
+            match (app.audio_settings_focus, device) {
+                (Some(AudioSettingsFocus::Inputs), Some(device)) => {
+                    app.client.set_audio_input(device)
+                }
+                (Some(AudioSettingsFocus::Outputs), Some(device)) => {
+                    app.client.set_audio_output(device)
+                }
+                _ => (),
+            }
+        }
+        _ => (),
+    }
     Ok(())
 }
@@ -1,5 +1,5 @@
 use ratatui::{
-    widgets::{Block, Borders, Clear, Paragraph},
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
     Frame,
 };
 
@@ -30,7 +30,8 @@ impl GeneralPopup {
         title.push_str(" (Enter to dismiss)");
 
         let alert_block = Paragraph::new(self.message.clone())
-            .block(Block::default().title(title).borders(Borders::ALL));
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .wrap(Wrap { trim: true });
         let area = self.centered_popup(60, 20, frame.size());
         frame.render_widget(Clear, area);
         frame.render_widget(alert_block, area);
@@ -23,6 +23,8 @@ pub enum MemberPopupActionsUiElements {
     AddRemoveFriend,
     Message,
     Call,
+    Volume,
+    ServerMute,
     Block,
     Kick,
     Ban,
@@ -53,6 +55,9 @@ impl Default for MemberPopup {
             current_actions_ui_element: MemberPopupActionsUiElements::AddRemoveFriend,
             is_friend: false,
             is_request_pending: false,
+            is_server_muted: false,
+            volume: 1.0,
+            is_volume_available: true,
         }
     }
 }
@@ -69,6 +74,11 @@ pub struct MemberPopup {
     pub current_actions_ui_element: MemberPopupActionsUiElements,
     pub is_friend: bool,
     pub is_request_pending: bool,
+    pub is_server_muted: bool,
+    /// How loud this user is played for us
+    pub volume: f32,
+    /// Unset in mixed voice channels, where this user's voice can't be picked out
+    pub is_volume_available: bool,
 }
 
 impl PopupTraits for MemberPopup {
@@ -81,6 +91,9 @@ impl PopupTraits for MemberPopup {
         self.dm_buffer = String::new();
         self.is_friend = false;
         self.is_request_pending = false;
+        self.is_server_muted = false;
+        self.volume = 1.0;
+        self.is_volume_available = true;
         self.current_ui = MemberPopupUi::Info;
     }
 
@@ -208,7 +221,7 @@ impl MemberPopup {
     }
 
     fn render_actions(&self, inner_content_area: Rect, frame: &mut Frame<'_>) {
-        let [add_friend_area, message_area, call_area, block_area, kick_area, ban_area] =
+        let [add_friend_area, message_area, call_area, volume_area, server_mute_area, block_area, kick_area, ban_area] =
             *Layout::default()
                 .direction(Direction::Vertical)
                 .constraints([
@@ -218,6 +231,8 @@ impl MemberPopup {
                     Constraint::Max(1),
                     Constraint::Max(1),
                     Constraint::Max(1),
+                    Constraint::Max(1),
+                    Constraint::Max(1),
                 ])
                 .margin(0)
                 .split(inner_content_area)
@@ -268,6 +283,29 @@ impl MemberPopup {
             _ => String::from("Call").with_pre_post_spaces(),
         });
 
+        let volume_label = match self.is_volume_available {
+            true => format!("Volume: {:.0}% (+/-)", self.volume * 100.0),
+            false => String::from("Volume: not in mixed channels"),
+        };
+        let volume_paragraph = Paragraph::new(match self.current_actions_ui_element {
+            MemberPopupActionsUiElements::Volume => {
+                volume_label.with_focus().with_pre_post_spaces()
+            }
+            _ => volume_label.with_pre_post_spaces(),
+        });
+
+        // The server ignores this from anyone who isn't a moderator
+        let server_mute_label = match self.is_server_muted {
+            true => String::from("Server Unmute"),
+            false => String::from("Server Mute"),
+        };
+        let server_mute_paragraph = Paragraph::new(match self.current_actions_ui_element {
+            MemberPopupActionsUiElements::ServerMute => {
+                server_mute_label.with_focus().with_pre_post_spaces()
+            }
+            _ => server_mute_label.with_pre_post_spaces(),
+        });
+
         let block_paragraph = Paragraph::new(match self.current_actions_ui_element {
             MemberPopupActionsUiElements::Block => {
                 vec![Line::from(vec![
@@ -313,6 +351,8 @@ impl MemberPopup {
         frame.render_widget(add_remove_friend_paragraph, add_friend_area);
         frame.render_widget(message_paragraph, message_area);
         frame.render_widget(call_paragraph, call_area);
+        frame.render_widget(volume_paragraph, volume_area);
+        frame.render_widget(server_mute_paragraph, server_mute_area);
         frame.render_widget(block_paragraph, block_area);
         frame.render_widget(kick_paragraph, kick_area);
         frame.render_widget(ban_paragraph, ban_area);
@@ -15,6 +15,7 @@ use tui_widget_list::widget_list::{
 
 use crate::app::{App, InputMode, KaguFormatting, Pane, PopupType, UiElement};
 use chrono::Utc;
+use message::voice_state::VoiceState;
 
 pub fn render(app: &mut App, frame: &mut Frame<'_>) {
     let top_and_bottom_layout = Layout::default()
@@ -54,6 +55,9 @@ pub fn render(app: &mut App, frame: &mut Frame<'_>) {
     let kagu_logo = Paragraph::new(kagu_text);
     let time = Paragraph::new(app.get_current_time_string()).alignment(Alignment::Right);
     let connected_label = Paragraph::new(match app.is_voice_connected {
+        true if !app.recorders.is_empty() => {
+            Span::styled("Voice recording", Style::default().fg(Color::Red))
+        }
         true => Span::styled("Voice connected", Style::default().fg(Color::LightGreen)),
         false => Span::styled("Voice off", Style::default()),
     });
@@ -238,12 +242,45 @@ pub fn render(app: &mut App, frame: &mut Frame<'_>) {
         .items
         .iter()
         .map(|channel| {
-            let mut lines = vec![Line::from(channel.1.clone().prepend_str("- "))];
+            let limit = app.current_realm_id.map_or(0, |realm_id| {
+                app.client.get_voice_bitrate_limit(realm_id, channel.0)
+            });
+            let mixing = app.current_realm_id.is_some_and(|realm_id| {
+                app.realms_manager
+                    .get_realm(realm_id)
+                    .and_then(|realm| realm.get_voice_channel(channel.0))
+                    .is_some_and(|voice_channel| voice_channel.mixing)
+            });
+            let mut name = match limit {
+                0 => channel.1.clone(),
+                limit => format!("{} ({}kbps)", channel.1, limit / 1000),
+            };
+            if mixing {
+                name.push_str(" [mixed]");
+            }
+            let mut lines = vec![Line::from(name.prepend_str("- "))];
             for id in &channel.2 {
-                lines.push(Line::from(Span::styled(
+                // Light up whoever is talking right now, which a mix doesn't tell us
+                let style = match !mixing && app.is_speaking(*id) {
+                    true => Style::default()
+                        .fg(Color::Green)
+                        .add_modifier(Modifier::BOLD),
+                    false => Style::default(),
+                };
+                let mut spans = vec![Span::styled(
                     app.get_username_from_id(*id).prepend_str("   "),
-                    Style::default(),
-                )));
+                    style,
+                )];
+                if let Some(state) = app.voice_states.get(id) {
+                    spans.extend(voice_state_icons(state));
+                }
+                if app.recorders.contains(id) {
+                    spans.push(Span::styled(
+                        " [recording]",
+                        Style::default().fg(Color::Red),
+                    ));
+                }
+                lines.push(Line::from(spans));
             }
             ListItem::new(lines).style(Style::default())
         })
@@ -297,6 +334,11 @@ pub fn render(app: &mut App, frame: &mut Frame<'_>) {
                     } else if users_typing.len() > 2 {
                         users_typing_string.push_str(" Multiple users are typing... ");
                     }
+
+                    if channel.slow_mode_secs > 0 {
+                        users_typing_string
+                            .push_str(&format!(" Slow mode: {}s ", channel.slow_mode_secs));
+                    }
                 }
             }
         }
@@ -530,6 +572,32 @@ pub fn render(app: &mut App, frame: &mut Frame<'_>) {
     }
 }
 
+/// Markers shown after someone in a voice channel who can't be heard or can't hear
+fn voice_state_icons(state: &VoiceState) -> Vec<Span<'static>> {
+    let mut icons = Vec::new();
+
+    if state.server_muted {
+        icons.push(Span::styled(
+            " [server muted]",
+            Style::default().fg(Color::Red),
+        ));
+    }
+    // Deafening also mutes, so only say the one
+    if state.deafened {
+        icons.push(Span::styled(
+            " [deafened]",
+            Style::default().fg(Color::DarkGray),
+        ));
+    } else if state.muted {
+        icons.push(Span::styled(
+            " [muted]",
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+
+    icons
+}
+
 fn build_mention_command_popup(r: Rect, input_length: &u16, num_items: usize) -> Rect {
     let popup_layout = Layout::default()
         .direction(Direction::Vertical)
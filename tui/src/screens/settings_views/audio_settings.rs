@@ -1,42 +1,229 @@
-use crate::app::App;
+use crate::app::{App, VoiceSetting};
+use audio::capture::{CaptureMode, CaptureSettings};
+use audio::encoding::{EncoderApplication, EncoderSettings, EncoderTarget};
+use audio::mixer::Volumes;
+use audio::processing::ProcessingSettings;
 use ratatui::{
     prelude::{Constraint, Direction, Layout, Rect},
-    style::{Style, Stylize},
-    widgets::{List, Paragraph},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{List, ListItem, Paragraph},
     Frame,
 };
 
+/// Mark the device in use so it stands out from the one under the cursor
+fn device_items(devices: &[String], selected: Option<String>) -> Vec<ListItem<'static>> {
+    devices
+        .iter()
+        .map(|device| match Some(device) == selected.as_ref() {
+            true => ListItem::new(format!("{} (in use)", device)),
+            false => ListItem::new(device.clone()),
+        })
+        .collect()
+}
+
+// Quietest level the input meter shows, in dBFS
+const METER_FLOOR_DB: f32 = -60.0;
+
+// Characters the input meter is drawn with
+const METER_WIDTH: usize = 30;
+
+/// A bar showing how loud the microphone is, with the speaking threshold marked on it
+fn level_meter(level: Option<f32>, threshold: f32) -> Line<'static> {
+    let position = |db: f32| {
+        let share = ((db - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0);
+        (share * METER_WIDTH as f32).round() as usize
+    };
+
+    let Some(level) = level else {
+        return Line::from("Input level: no audio");
+    };
+
+    let filled = position(level);
+    let threshold = position(threshold).min(METER_WIDTH - 1);
+    let bar: String = (0..METER_WIDTH)
+        .map(|i| match i {
+            i if i < filled => '#',
+            i if i == threshold => '|',
+            _ => '-',
+        })
+        .collect();
+
+    // Green once loud enough to be sent when sending on voice activity
+    let color = match filled > threshold {
+        true => Color::Green,
+        false => Color::Reset,
+    };
+
+    Line::from(vec![
+        Span::raw("Input level: ["),
+        Span::styled(bar, Style::default().fg(color)),
+        Span::raw(format!("] {:.0} dB", level.max(METER_FLOOR_DB))),
+    ])
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    match enabled {
+        true => "on",
+        false => "off",
+    }
+}
+
+fn voice_items(
+    settings: &[VoiceSetting],
+    volumes: &Volumes,
+    capture: CaptureSettings,
+    processing: ProcessingSettings,
+    encoder: EncoderSettings,
+    target: EncoderTarget,
+) -> Vec<ListItem<'static>> {
+    settings
+        .iter()
+        .map(|setting| {
+            ListItem::new(match setting {
+                VoiceSetting::InputVolume => {
+                    format!("Microphone volume: {:.0}%", volumes.input_gain * 100.0)
+                }
+                VoiceSetting::OutputVolume => {
+                    format!("Playback volume: {:.0}%", volumes.output_gain * 100.0)
+                }
+                VoiceSetting::CaptureMode => format!(
+                    "Send audio: {}",
+                    match capture.mode {
+                        CaptureMode::OpenMic => "always (open mic)",
+                        CaptureMode::VoiceActivity => "when speaking",
+                        CaptureMode::PushToTalk => "while holding Ctrl+T",
+                    }
+                ),
+                VoiceSetting::VoiceThreshold => {
+                    format!("Speaking threshold: {:.0} dB", capture.voice_threshold)
+                }
+                VoiceSetting::HangTime => {
+                    format!("Hang time: {}ms", capture.hang_time.as_millis())
+                }
+                VoiceSetting::Dtx => format!("Skip silence: {}", on_off(capture.dtx)),
+                VoiceSetting::NoiseSuppression => format!(
+                    "Noise suppression: {}",
+                    on_off(processing.noise_suppression)
+                ),
+                VoiceSetting::EchoCancellation => format!(
+                    "Echo cancellation: {}",
+                    on_off(processing.echo_cancellation)
+                ),
+                VoiceSetting::AutoGain => {
+                    format!("Automatic gain: {}", on_off(processing.auto_gain))
+                }
+                // Show when the connection or channel has us sending at less than asked for
+                VoiceSetting::Bitrate => match target.bitrate < encoder.bitrate {
+                    true => format!(
+                        "Bitrate: {} kbps (sending {} kbps)",
+                        encoder.bitrate / 1000,
+                        target.bitrate / 1000
+                    ),
+                    false => format!("Bitrate: {} kbps", encoder.bitrate / 1000),
+                },
+                VoiceSetting::Complexity => {
+                    format!("Encoder complexity: {}", encoder.complexity)
+                }
+                VoiceSetting::Stereo => format!(
+                    "Channels: {}",
+                    match encoder.stereo {
+                        true => "stereo",
+                        false => "mono",
+                    }
+                ),
+                VoiceSetting::Application => format!(
+                    "Tuned for: {}",
+                    match encoder.application {
+                        EncoderApplication::Voip => "voice",
+                        EncoderApplication::Music => "music",
+                    }
+                ),
+            })
+        })
+        .collect()
+}
+
 pub fn render(app: &mut App, setting_area: Rect, frame: &mut Frame<'_>) {
-    let [audio_inputs_label_area, audio_inputs_list_area, spacer_1_area, audio_ouputs_label_area, audio_outputs_list_area] =
+    let [audio_inputs_label_area, audio_inputs_list_area, input_level_area, spacer_1_area, audio_ouputs_label_area, audio_outputs_list_area, spacer_2_area, voice_label_area, voice_list_area] =
         *Layout::default()
             .direction(Direction::Vertical)
             .margin(0)
             .constraints([
-                Constraint::Max(1), // Audio inputs label
-                Constraint::Max(3), // Audio inputs
-                Constraint::Max(1), // Spacer
-                Constraint::Max(1), // Audio outputs label
-                Constraint::Max(3), // Audio outputs
+                Constraint::Max(1),  // Audio inputs label
+                Constraint::Max(3),  // Audio inputs
+                Constraint::Max(1),  // Input level
+                Constraint::Max(1),  // Spacer
+                Constraint::Max(1),  // Audio outputs label
+                Constraint::Max(3),  // Audio outputs
+                Constraint::Max(1),  // Spacer
+                Constraint::Max(1),  // Voice label
+                Constraint::Max(13), // Voice settings
             ])
             .split(setting_area)
     else {
         return;
     };
 
+    // Keep the lists still while one is being picked from
+    if app.audio_settings_focus.is_none() {
+        app.audio_inputs.items = app.client.get_audio_inputs();
+        app.audio_outputs.items = app.client.get_audio_outputs();
+    }
+
     let inputs_label = Paragraph::new(String::from("Audio Inputs")).style(Style::default().bold());
-    let inputs = app.client.get_audio_inputs();
-    let inputs_list = List::new(inputs);
+    let inputs_list = List::new(device_items(
+        &app.audio_inputs.items,
+        app.client.get_selected_audio_input(),
+    ))
+    .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+    .highlight_symbol(">");
+
+    let input_level = Paragraph::new(level_meter(
+        app.input_level.map(|(level, _)| level),
+        app.client.get_capture_settings().voice_threshold,
+    ));
 
     let outputs_label =
         Paragraph::new(String::from("Audio Outputs")).style(Style::default().bold());
-    let outputs = app.client.get_audio_outputs();
-    let outputs_list = List::new(outputs);
+    let outputs_list = List::new(device_items(
+        &app.audio_outputs.items,
+        app.client.get_selected_audio_output(),
+    ))
+    .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+    .highlight_symbol(">");
+
+    let voice_label =
+        Paragraph::new(String::from("Voice (+/- to change)")).style(Style::default().bold());
+    let voice_list = List::new(voice_items(
+        &app.voice_settings.items,
+        &app.client.get_volumes(),
+        app.client.get_capture_settings(),
+        app.client.get_processing_settings(),
+        app.client.get_encoder_settings(),
+        app.client.get_encoder_target(),
+    ))
+    .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+    .highlight_symbol(">");
 
     let spacer_1_paragraph = Paragraph::new(String::from(""));
+    let spacer_2_paragraph = Paragraph::new(String::from(""));
 
     frame.render_widget(inputs_label, audio_inputs_label_area);
-    frame.render_widget(inputs_list, audio_inputs_list_area);
+    frame.render_stateful_widget(
+        inputs_list,
+        audio_inputs_list_area,
+        &mut app.audio_inputs.state,
+    );
+    frame.render_widget(input_level, input_level_area);
     frame.render_widget(spacer_1_paragraph, spacer_1_area);
     frame.render_widget(outputs_label, audio_ouputs_label_area);
-    frame.render_widget(outputs_list, audio_outputs_list_area);
+    frame.render_stateful_widget(
+        outputs_list,
+        audio_outputs_list_area,
+        &mut app.audio_outputs.state,
+    );
+    frame.render_widget(spacer_2_paragraph, spacer_2_area);
+    frame.render_widget(voice_label, voice_label_area);
+    frame.render_stateful_widget(voice_list, voice_list_area, &mut app.voice_settings.state);
 }
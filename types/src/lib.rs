@@ -27,3 +27,9 @@ pub type FileTransferIdSize = u64;
 
 /// Internal ID for a song to broadcast
 pub type AudioFileIdSize = u64;
+
+/// Version of the server's realms, incremented with every change sent to clients
+pub type RealmsVersionSize = u64;
+
+/// Sequence number of an audio frame, counting up from each speaker and wrapping around
+pub type AudioSequenceSize = u16;